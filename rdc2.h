@@ -5,6 +5,32 @@
 
 #define ROOT_INODE 2
 
+#define FILE_SEEK_START 0
+
+#define FILE_SEEK_CURRENT 1
+
+#define FILE_SEEK_END 2
+
+#define CREATE_NOT_A_DIRECTORY -1
+
+#define CREATE_NO_FREE_INODES -2
+
+#define CREATE_NO_FREE_BLOCKS -3
+
+#define CREATE_NAME_TOO_LONG -4
+
+#define CREATE_ALREADY_EXISTS -5
+
+#define CREATE_DIRECTORY_FULL -6
+
+#define CREATE_UNKNOWN_KIND -7
+
+#define CREATE_READ_ONLY -8
+
+#define CREATE_CORRUPT -9
+
+#define CREATE_UNSUPPORTED_KIND -10
+
 enum EntryKind {
   Unkown = 0,
   RegularFile = 1,
@@ -17,28 +43,6 @@ enum EntryKind {
 };
 typedef uint8_t EntryKind;
 
-enum FsState {
-  Clean = 1,
-  Errored = 2,
-};
-typedef uint16_t FsState;
-
-enum OnError {
-  Ignore = 1,
-  RemountReadOnly = 2,
-  KernelPanic = 3,
-};
-typedef uint16_t OnError;
-
-enum OsId {
-  Linux = 0,
-  GnuHurd = 1,
-  Masix = 2,
-  FreeBSD = 3,
-  OtherLite = 4,
-};
-typedef uint16_t OsId;
-
 struct TypePermission {
   uint16_t bits;
 };
@@ -49,6 +53,7 @@ struct TypePermission {
 #define TypePermission_REGULAR_FILE (TypePermission){ .bits = 32768 }
 #define TypePermission_SYMBOLIC_LINK (TypePermission){ .bits = 40960 }
 #define TypePermission_UNIX_SOCKET (TypePermission){ .bits = 49152 }
+#define TypePermission_TYPE_MASK (TypePermission){ .bits = 61440 }
 #define TypePermission_OTHER_EXECUTE (TypePermission){ .bits = 1 }
 #define TypePermission_OTHER_WRITE (TypePermission){ .bits = 2 }
 #define TypePermission_OTHER_READ (TypePermission){ .bits = 4 }
@@ -77,7 +82,7 @@ struct InodeFlags {
 #define InodeFlags_AFS_DIR (InodeFlags){ .bits = 131072 }
 #define InodeFlags_JOURNAL_DATA (InodeFlags){ .bits = 262144 }
 
-struct InodeData {
+struct __attribute__((packed)) InodeData {
   struct TypePermission type_permission;
   uint16_t user_id;
   uint32_t size_lower_32_bits;
@@ -101,7 +106,7 @@ struct InodeData {
   uint8_t os_specific_two[12];
 };
 
-struct Superblock {
+struct __attribute__((packed)) Superblock {
   uint32_t inode_count;
   uint32_t block_count;
   uint32_t block_superuser;
@@ -118,12 +123,21 @@ struct Superblock {
   uint16_t number_of_times_mounted_since_last_consitency_check;
   uint16_t number_of_mounts_until_consistency_check;
   uint16_t ext2sig;
-  FsState state;
-  OnError on_error;
+  /**
+   * See `Superblock::state`
+   */
+  uint16_t state;
+  /**
+   * See `Superblock::on_error`
+   */
+  uint16_t on_error;
   uint16_t minor_version;
   uint32_t time_since_last_constiency_check;
   uint32_t time_between_forced_consistency_check;
-  OsId creator_system_id;
+  /**
+   * See `Superblock::creator_system_id`
+   */
+  uint32_t creator_system_id;
   uint32_t major_version;
   uint16_t user_id_allowed_to_reserve;
   uint16_t group_id_allowed_to_reserve;
@@ -159,7 +173,7 @@ typedef uint8_t Id[16];
 /**
  * bytes 236 to 1023 are not counted
  */
-struct ExtendedSuperblock {
+struct __attribute__((packed)) ExtendedSuperblock {
   uint32_t first_non_reserved_inode;
   uint16_t inode_struct_size;
   uint16_t part_of_block;
@@ -172,14 +186,18 @@ struct ExtendedSuperblock {
   uint32_t compression_algorithm;
   uint8_t number_of_blocks_to_preallocate_files;
   uint8_t number_of_blocks_to_preallocate_dirs;
-  uint16_t unused;
+  /**
+   * The blocks kept after the group descriptors to grow the filesystem, see
+   * `OptionalFeatures::RESIZEABLE`
+   */
+  uint16_t reserved_gdt_blocks;
   Id journal_id;
   uint32_t journal_inode;
   uint32_t journal_device;
   uint32_t head_of_orphan_list;
 };
 
-struct BlockGroupDescriptor {
+struct __attribute__((packed)) BlockGroupDescriptor {
   uint32_t block_address_of_block_bitmap;
   uint32_t block_address_of_inode_bitmap;
   uint32_t starting_block_of_inode_table;
@@ -189,6 +207,52 @@ struct BlockGroupDescriptor {
   uint8_t _unused[14];
 };
 
+/**
+ * Returns the current time in seconds since the unix epoch
+ */
+typedef uint32_t (*Clock)(void);
+
+struct FileSystem;
+
+/**
+ * Chooses the group in which to look for a new inode first.
+ *
+ * Called with the group of the parent directory and the kind of the new inode
+ */
+typedef uint32_t (*GroupSelector)(const struct FileSystem*, uint32_t, EntryKind);
+
+/**
+ * Asks the storage behind the filesystem to read count blocks from block ahead, see
+ * `Cursor::set_readahead`. For the filesystems in memory, the ones opened with
+ * `FileSystem::open_device` call `BlockDevice::prefetch` themselves
+ */
+typedef void (*Readahead)(const struct FileSystem*, uint32_t, uint32_t);
+
+/**
+ * How `create_inode_in_dir` places new inodes, see `FileSystem::set_group_policy`
+ */
+enum GroupPolicy_Tag {
+  /**
+   * Always start in the group of the parent directory
+   */
+  SameGroup,
+  /**
+   * Keep files near their parent and spread directories over the emptiest groups
+   */
+  Spread,
+  Custom,
+};
+typedef uint8_t GroupPolicy_Tag;
+
+struct GroupPolicy {
+  GroupPolicy_Tag tag;
+  union {
+    struct {
+      GroupSelector custom;
+    };
+  };
+};
+
 /**
  * The main way to interact with the filesystem
  */
@@ -199,6 +263,31 @@ struct FileSystem {
   struct BlockGroupDescriptor *block_group_descriptor_table;
   uintptr_t block_group_descriptor_table_len;
   uintptr_t block_size;
+  Clock clock;
+  struct GroupPolicy group_policy;
+  Readahead readahead;
+  bool read_only;
+  /**
+   * Set by mount_writable, with the state it found
+   */
+  bool mounted;
+  uint16_t mount_state;
+  /**
+   * Read-only until the journal is replayed, only `replay_journal` writes
+   */
+  bool replay_pending;
+};
+
+/**
+ * Blocks reserved after the last block of an inode, used by its next allocations
+ */
+struct Preallocation {
+  /**
+   * Only a File preallocates, as it gives the blocks back when it is dropped
+   */
+  bool enabled;
+  uint32_t next;
+  uint32_t len;
 };
 
 struct Inode {
@@ -206,23 +295,62 @@ struct Inode {
   const struct FileSystem *fs;
   uint32_t id;
   uint32_t group;
+  struct Preallocation preallocation;
 };
 
 struct Cursor {
   const struct Inode *inode;
   uint32_t total_index;
   uint32_t block_size;
+  bool privileged;
+  /**
+   * The blocks after the current one announced to the filesystem
+   */
+  uint32_t readahead;
 };
 
 struct DirectoryEntries {
   struct Cursor reader;
 };
 
+/**
+ * A regular file.
+ *
+ * Unlike a bare `Cursor` this keeps the inode consistent with what is written: the size grows
+ * with the writes and the modification time is updated when the file is synced or dropped.
+ *
+ * With the `exclusive` feature, the file is recorded as a reader or the writer of its inode
+ * until it is dropped, see `Inode::open_file`
+ */
+struct File {
+  struct Inode inode;
+  uint32_t position;
+  bool modified;
+  bool privileged;
+  bool writable;
+};
+
 /**
  * A reference to an inode
  */
 typedef uint32_t InodeRef;
 
+/**
+ * Usage of the filesystem, see `FileSystem::statistics`
+ */
+struct Statistics {
+  uint32_t block_size;
+  uint32_t total_blocks;
+  uint32_t free_blocks;
+  /**
+   * The free blocks minus the ones reserved for the superuser
+   */
+  uint32_t available_blocks;
+  uint32_t total_inodes;
+  uint32_t free_inodes;
+  uint32_t max_name_length;
+};
+
 struct RawDirEntry {
   InodeRef inode;
   uint16_t size;
@@ -231,6 +359,24 @@ struct RawDirEntry {
   const uint8_t *name;
 };
 
+/**
+ * Create an inode called name in the directory dir, write its reference in new_inode and
+ * returns 0. On failure returns one of the CREATE_* constants.
+ * perms are the permission bits of the mode, the file type is given by kind
+ *
+ * # Safety
+ *
+ * name must be valid for reads of name_len bytes
+ */
+int64_t create_inode_in_dir(const struct Inode *dir,
+                            EntryKind kind,
+                            uint16_t perms,
+                            uint16_t user_id,
+                            uint16_t group_id,
+                            const uint8_t *name,
+                            uintptr_t name_len,
+                            InodeRef *new_inode);
+
 /**
  * Write the Cursor in cursor_ptr if a Cursor can be created from this inode, and returns 0.
  * If a cursor can't be created, returns -1.
@@ -247,14 +393,116 @@ int64_t cursor_at_end(const struct Inode *inode, struct Cursor *cursor_ptr);
  */
 int64_t directory_entries(const struct Inode *inode, struct DirectoryEntries *entries);
 
-struct Inode fs_get_inode(const struct FileSystem *fs, InodeRef inode);
+/**
+ * Release the blocks reserved ahead of the writes, file_sync does it too
+ */
+void file_discard_preallocation(struct File *file);
+
+/**
+ * See cursor, opens the regular file of this inode
+ */
+int64_t file_open(const struct Inode *inode, struct File *file);
+
+/**
+ * # Safety
+ *
+ * ptr must be valid for writes of len bytes
+ */
+uintptr_t file_read(struct File *file, uint8_t *ptr, uintptr_t len);
+
+/**
+ * Move the position of the file like lseek, whence is one of the FILE_SEEK_* constants.
+ * Returns the new position, or -1 if the position would be invalid
+ */
+int64_t file_seek(struct File *file, int64_t offset, int32_t whence);
+
+/**
+ * Write the metadata of the file and release its preallocated blocks, it must be called
+ * before the file is discarded
+ */
+void file_sync(struct File *file);
+
+/**
+ * Returns 0, or -1 if the data could not be written entirely
+ *
+ * # Safety
+ *
+ * ptr must be valid for reads of len bytes
+ */
+int64_t file_write(struct File *file, const uint8_t *ptr, uintptr_t len);
+
+/**
+ * Write the inode referenced by inode in inode_ptr and returns 0, or returns -1 if there is no
+ * such inode. inode may be any value, it is checked by FileSystem::get_inode
+ */
+int64_t fs_get_inode(const struct FileSystem *fs, InodeRef inode, struct Inode *inode_ptr);
+
+/**
+ * Mark the filesystem as in use until fs_unmount, does nothing if it is read-only
+ */
+void fs_mount_writable(const struct FileSystem *fs);
+
+/**
+ * Fill statistics with the usage of the filesystem, like statfs. With recount the free blocks
+ * and inodes are counted in the bitmaps instead of read from the superblock
+ */
+void fs_statfs(const struct FileSystem *fs, bool recount, struct Statistics *statistics);
+
+/**
+ * Make the metadata of the filesystem consistent and update its backups, returns 0 or -1 on
+ * failure. Open files must be synced with file_sync first
+ */
+int64_t fs_sync(const struct FileSystem *fs);
+
+/**
+ * Mark the filesystem as clean again and sync it, returns 0 or -1 on failure. It must be called
+ * before the filesystem is discarded
+ */
+int64_t fs_unmount(const struct FileSystem *fs);
+
+/**
+ * Write in block the block of the filesystem holding the block logical_block of the file and
+ * returns 0, like FIBMAP 0 is written for a hole. Returns -1 past the end of the file or if the
+ * pointer is out of the filesystem
+ */
+int64_t inode_bmap(const struct Inode *inode, uint32_t logical_block, uint32_t *block);
 
 uint32_t inode_size(const struct Inode *inode);
 
-struct FileSystem open(uint8_t *region);
+/**
+ * Write the FileSystem of region in fs and returns 0, or returns -1 if region does not hold a
+ * supported ext2 filesystem
+ *
+ * # Safety
+ *
+ * region must point to an ext2 filesystem that stays valid for as long as the FileSystem is used
+ */
+int64_t open(uint8_t *region, struct FileSystem *fs);
 
+/**
+ * Like open, but nothing is written to region: the modifications fail like on a read-only
+ * filesystem
+ *
+ * # Safety
+ *
+ * region must point to an ext2 filesystem that stays valid for as long as the FileSystem is used
+ */
+int64_t open_read_only(uint8_t *region, struct FileSystem *fs);
+
+/**
+ * # Safety
+ *
+ * ptr must be valid for writes of len bytes
+ */
 uintptr_t read(struct Cursor *cursor, uint8_t *ptr, uintptr_t len);
 
 int64_t read_next_entry(struct DirectoryEntries *entries, struct RawDirEntry *entry);
 
-void write(struct Cursor *cursor, const uint8_t *ptr, uintptr_t len);
+/**
+ * Returns 0, or -1 if the data could not be written entirely
+ *
+ * # Safety
+ *
+ * ptr must be valid for reads of len bytes
+ */
+int64_t write(struct Cursor *cursor, const uint8_t *ptr, uintptr_t len);
@@ -0,0 +1,60 @@
+//! Print the superblocks of the ext2 filesystems of a disk image or of a block device, read
+//! through a cache without mapping the file: cargo run --features std --example file_device <path>
+
+use rdc2::device::{BlockDevice, CachedDevice, FileDevice};
+use rdc2::partition::{partitions, LINUX};
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        bytes[offset],
+        bytes[offset + 1],
+        bytes[offset + 2],
+        bytes[offset + 3],
+    ])
+}
+
+fn describe<D: BlockDevice>(device: &mut D, offset: u64) -> Result<(), D::Error> {
+    let mut superblock = [0; 1024];
+    device.read(offset + 1024, &mut superblock)?;
+    if superblock[56..58] != [0x53, 0xef] {
+        println!("  no ext2 filesystem");
+        return Ok(());
+    }
+    let name = &superblock[120..136];
+    let name = &name[..name.iter().position(|&byte| byte == 0).unwrap_or(16)];
+    println!(
+        "  volume {:?}: {} inodes, {} blocks of {} bytes, {} free",
+        String::from_utf8_lossy(name),
+        u32_at(&superblock, 0),
+        u32_at(&superblock, 4),
+        1024 << u32_at(&superblock, 24),
+        u32_at(&superblock, 12),
+    );
+    Ok(())
+}
+
+fn main() -> std::io::Result<()> {
+    let path = std::env::args().nth(1).expect("usage: file_device <image>");
+    let device = FileDevice::open_read_only(&path)?;
+    println!("{}: {} bytes", path, device.len());
+
+    let mut buffers = vec![0; 16 * 512];
+    let mut device = CachedDevice::<_, 16>::new(device, 512, &mut buffers);
+    let mut boot = [0; 512];
+    device
+        .read(0, &mut boot)
+        .expect("could not read the boot sector");
+    let linux: Vec<_> = partitions(&boot)
+        .filter(|partition| partition.kind == LINUX)
+        .collect();
+    if linux.is_empty() {
+        println!("whole device:");
+        describe(&mut device, 0).expect("could not read the superblock");
+    }
+    for partition in linux {
+        println!("partition {}:", partition.index + 1);
+        describe(&mut device, partition.offset).expect("could not read the superblock");
+    }
+    println!("{:?}", device.stats());
+    Ok(())
+}
@@ -1,7 +1,9 @@
 use memmap::MmapOptions;
 use simplelog::{Config, LevelFilter, TermLogger, TerminalMode};
 
-use rdc2::{inode::EntryKind, inode::Permission, Ext2Device, FileSystem, Inode};
+use rdc2::{
+    inode::EntryKind, inode::Permission, metadata::OnError, Error, Ext2Device, FileSystem, Inode,
+};
 
 fn list(fs: &FileSystem<'_>, inode: &Inode<'_, '_>, tabs: usize) {
     if let Some(entries) = inode.get_dir_entries() {
@@ -16,59 +18,104 @@ fn list(fs: &FileSystem<'_>, inode: &Inode<'_, '_>, tabs: usize) {
                         continue;
                     }
                     if entry.name == "thing" {
-                        let dir = fs.get_inode(entry.inode);
-                        dir.create_inode_in_dir(
+                        let dir = fs.get_inode(entry.inode).expect("corrupted entry");
+                        if let Err(e) = dir.create_inode_in_dir(
                             EntryKind::RegularFile,
                             Permission::all(),
                             0,
                             0,
                             "wtf_please".as_bytes(),
-                        );
+                        ) {
+                            println!("Could not create wtf_please: {:?}", e);
+                        }
                     }
-                    list(fs, &fs.get_inode(entry.inode), tabs + 4)
+                    list(
+                        fs,
+                        &fs.get_inode(entry.inode).expect("corrupted entry"),
+                        tabs + 4,
+                    )
                 }
                 EntryKind::RegularFile => {
                     println!("file {}", entry.name);
-                    let file = fs.get_inode(entry.inode);
+                    let file = fs.get_inode(entry.inode).expect("corrupted entry");
                     if entry.name == "niche.txt" {
                         write_things(&file);
                         let mut writer = file.cursor().expect("niche.txt is not a file");
                         writer.advance(4);
-                        writer.write("9\n".as_bytes());
+                        writer.write("9\n".as_bytes()).expect("could not write");
                         let mut append = file.end().expect("niche.txt is not a file");
-                        append.write("500\n".as_bytes());
+                        append.write("500\n".as_bytes()).expect("could not write");
                         dbg!(unsafe { &*file.get_data() });
                     }
 
                     let mut content = Vec::new();
-                    read_to_end(&file, &mut content);
+                    file.read_all(|chunk| {
+                        content.extend_from_slice(chunk);
+                        Ok::<_, ()>(())
+                    })
+                    .expect("could not read the file");
                     for _ in 0..(tabs + 2) {
                         print!(" ");
                     }
                     println!("content: {}", String::from_utf8(content).unwrap());
                 }
+                EntryKind::Symlink => {
+                    let link = fs.get_inode(entry.inode).expect("corrupted entry");
+                    match link.read_link() {
+                        Ok(target) => println!("symlink {} -> {}", entry.name, target),
+                        Err(e) => println!("symlink {}: {:?}", entry.name, e),
+                    }
+                }
                 k => println!("{:?} {}", k, entry.name),
             }
         }
     }
 }
-fn read_to_end(inode: &Inode<'_, '_>, data: &mut Vec<u8>) {
-    let mut reader = inode.cursor().expect("Is not a file");
-    let mut buffer = [0; 128];
-    loop {
-        match reader.read(&mut buffer) {
-            0 => break,
-            n => data.extend_from_slice(&buffer[0..n]),
-        }
-    }
-}
 fn write_things(inode: &Inode<'_, '_>) {
-    let mut writer = inode.cursor().expect("is not a file");
+    let mut writer = inode.as_file().expect("is not a file");
     for i in 0..500 {
-        writer.write(format!("{}\n", i).as_bytes())
+        writer
+            .write(format!("{}\n", i).as_bytes())
+            .expect("could not write the file");
     }
 }
 
+/// `fs tune [setting=value]...`, change the settings of the superblock like tune2fs then print
+/// them. The limits can be set to none
+fn tune(fs: &FileSystem<'_>, settings: impl Iterator<Item = String>) {
+    fn limit<T: std::str::FromStr>(value: &str) -> Result<Option<T>, Error> {
+        match value {
+            "none" => Ok(None),
+            value => value.parse().map(Some).map_err(|_| Error::InvalidArgument),
+        }
+    }
+    fn number<T: std::str::FromStr>(value: &str) -> Result<T, Error> {
+        value.parse().map_err(|_| Error::InvalidArgument)
+    }
+    for setting in settings {
+        let (name, value) = setting.split_once('=').unwrap_or((&setting, ""));
+        let result = match name {
+            "max-mount-count" => limit(value).and_then(|count| fs.set_max_mount_count(count)),
+            "check-interval" => limit(value).and_then(|interval| fs.set_check_interval(interval)),
+            "reserved-blocks" => number(value).and_then(|count| fs.set_reserved_block_count(count)),
+            "reserved-uid" => number(value).and_then(|uid| fs.set_reserved_uid(uid)),
+            "reserved-gid" => number(value).and_then(|gid| fs.set_reserved_gid(gid)),
+            "errors" => match value {
+                "continue" => Ok(OnError::Ignore),
+                "remount-ro" => Ok(OnError::RemountReadOnly),
+                "panic" => Ok(OnError::KernelPanic),
+                _ => Err(Error::InvalidArgument),
+            }
+            .and_then(|on_error| fs.set_default_mount_behavior(on_error)),
+            _ => Err(Error::InvalidArgument),
+        };
+        if let Err(e) = result {
+            println!("Could not set {}: {:?}", setting, e);
+        }
+    }
+    println!("{:#?}", fs.tuning());
+}
+
 fn main() {
     TermLogger::init(LevelFilter::Trace, Config::default(), TerminalMode::Mixed)
         .expect("no terminal");
@@ -78,11 +125,14 @@ fn main() {
         .write(true)
         .open("test_fs")
         .unwrap();
-    let mut device = unsafe { MmapOptions::new().map_mut(&file).unwrap() };
-    let ptr = device.as_mut_ptr();
-
-    let mut device = unsafe { Ext2Device::from_ptr(ptr) };
+    let mut map = unsafe { MmapOptions::new().map_mut(&file).unwrap() };
+    let mut device = Ext2Device::from_slice(&mut map).expect("test_fs is too small");
     let fs = device.open();
+    let mut args = std::env::args().skip(1);
+    if args.next().as_deref() == Some("tune") {
+        tune(&fs, args);
+        return;
+    }
     dbg!(fs.get_superblock());
     dbg!(fs.get_extended_superblock());
     dbg!(fs.get_block_group_descriptor_table());
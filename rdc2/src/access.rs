@@ -0,0 +1,139 @@
+//! The reads and writes of the memory of the device.
+//!
+//! The blocks are only accessed through these functions, and the `read_field!`/`write_field!`
+//! macros for the fields of the structures they hold. By default they are plain unaligned
+//! accesses. With the `volatile` feature every access is volatile and byte-wise, for devices
+//! whose memory has side effects or is written behind the driver (DMA).
+//!
+//! The names of directory entries, the extended attributes and the references returned by
+//! `FileSystem::get_superblock`, `get_extended_superblock` and
+//! `get_block_group_descriptor_table` still borrow the device. The structures they point to are
+//! only written by the `update_*` methods of `FileSystem`, with `modify`.
+
+#[cfg(feature = "volatile")]
+use core::mem::{size_of, MaybeUninit};
+
+/// Read a field of a structure of the device, `read_field!(ptr, field)` or
+/// `read_field!(ptr, array[index])`
+macro_rules! read_field {
+    ($ptr:expr, $field:ident $([$index:expr])?) => {
+        $crate::access::read(core::ptr::addr_of!((*$ptr).$field $([$index])?))
+    };
+}
+
+/// Write a field of a structure of the device, `write_field!(ptr, field, value)`
+macro_rules! write_field {
+    ($ptr:expr, $field:ident $([$index:expr])?, $value:expr) => {
+        $crate::access::write(core::ptr::addr_of_mut!((*$ptr).$field $([$index])?), $value)
+    };
+}
+
+#[cfg(not(feature = "volatile"))]
+pub(crate) unsafe fn read<T>(src: *const T) -> T {
+    src.read_unaligned()
+}
+#[cfg(feature = "volatile")]
+pub(crate) unsafe fn read<T>(src: *const T) -> T {
+    let mut value = MaybeUninit::<T>::uninit();
+    copy_from_device(
+        src as *const u8,
+        value.as_mut_ptr() as *mut u8,
+        size_of::<T>(),
+    );
+    value.assume_init()
+}
+
+#[cfg(not(feature = "volatile"))]
+pub(crate) unsafe fn write<T>(dst: *mut T, value: T) {
+    dst.write_unaligned(value)
+}
+#[cfg(feature = "volatile")]
+pub(crate) unsafe fn write<T>(dst: *mut T, value: T) {
+    let value = MaybeUninit::new(value);
+    copy_to_device(value.as_ptr() as *const u8, dst as *mut u8, size_of::<T>());
+}
+
+/// Change the structure at ptr, f works on a copy that is written back
+pub(crate) unsafe fn modify<T>(ptr: *mut T, f: impl FnOnce(&mut T)) {
+    let mut value = read(ptr);
+    f(&mut value);
+    write(ptr, value)
+}
+
+/// Copy len bytes of the device at src to dst
+pub(crate) unsafe fn copy_from_device(src: *const u8, dst: *mut u8, len: usize) {
+    #[cfg(not(feature = "volatile"))]
+    core::ptr::copy_nonoverlapping(src, dst, len);
+    #[cfg(feature = "volatile")]
+    for i in 0..len {
+        dst.add(i).write(src.add(i).read_volatile());
+    }
+}
+
+/// Copy len bytes from src to the device at dst
+pub(crate) unsafe fn copy_to_device(src: *const u8, dst: *mut u8, len: usize) {
+    #[cfg(not(feature = "volatile"))]
+    core::ptr::copy_nonoverlapping(src, dst, len);
+    #[cfg(feature = "volatile")]
+    for i in 0..len {
+        dst.add(i).write_volatile(src.add(i).read());
+    }
+}
+
+/// Copy len bytes of the device from src to dst, the two can overlap
+pub(crate) unsafe fn copy_within(src: *const u8, dst: *mut u8, len: usize) {
+    #[cfg(not(feature = "volatile"))]
+    core::ptr::copy(src, dst, len);
+    #[cfg(feature = "volatile")]
+    if (dst as *const u8) < src {
+        for i in 0..len {
+            dst.add(i).write_volatile(src.add(i).read_volatile());
+        }
+    } else {
+        for i in (0..len).rev() {
+            dst.add(i).write_volatile(src.add(i).read_volatile());
+        }
+    }
+}
+
+/// Set len bytes of the device at dst to byte
+pub(crate) unsafe fn fill(dst: *mut u8, byte: u8, len: usize) {
+    #[cfg(not(feature = "volatile"))]
+    dst.write_bytes(byte, len);
+    #[cfg(feature = "volatile")]
+    for i in 0..len {
+        dst.add(i).write_volatile(byte);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{copy_from_device, copy_to_device, copy_within, fill, modify, read, write};
+
+    #[test]
+    fn accesses() {
+        let mut device = [0u8; 16];
+        let base = device.as_mut_ptr();
+        unsafe {
+            // Misaligned on purpose
+            let word = base.add(1) as *mut u32;
+            write(word, u32::from_le_bytes([1, 2, 3, 4]));
+            assert_eq!(read(word), u32::from_le_bytes([1, 2, 3, 4]));
+            modify(word, |word| *word += u32::from_le(1));
+            assert_eq!(read(base.add(1) as *const [u8; 4]), [2, 2, 3, 4]);
+
+            copy_to_device(b"abc".as_ptr(), base.add(8), 3);
+            let mut copy = [0; 3];
+            copy_from_device(base.add(8), copy.as_mut_ptr(), 3);
+            assert_eq!(&copy, b"abc");
+            fill(base.add(9), 0xff, 7);
+            copy_within(base.add(8), base.add(10), 3);
+            copy_within(base.add(1), base, 4);
+        }
+        assert_eq!(
+            device[..12],
+            [2, 2, 3, 4, 4, 0, 0, 0, b'a', 0xff, b'a', 0xff]
+        );
+        assert_eq!(device[15], 0xff);
+    }
+}
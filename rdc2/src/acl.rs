@@ -0,0 +1,193 @@
+//! POSIX ACLs, stored in the `system.posix_acl_access` and `system.posix_acl_default` extended
+//! attributes.
+//!
+//! On the disk an ACL is a version followed by its entries. The entries naming a user or a group
+//! hold its id, the others are shorter.
+
+use bitflags::bitflags;
+
+use super::Error;
+
+pub const ACL_VERSION: u32 = 1;
+
+const USER_OBJ: u16 = 0x01;
+const USER: u16 = 0x02;
+const GROUP_OBJ: u16 = 0x04;
+const GROUP: u16 = 0x08;
+const MASK: u16 = 0x10;
+const OTHER: u16 = 0x20;
+
+bitflags! {
+    pub struct AclPermission: u16 {
+        const EXECUTE = 0x1;
+        const WRITE = 0x2;
+        const READ = 0x4;
+    }
+}
+
+/// Who an entry of an ACL applies to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AclTag {
+    /// The owner of the inode
+    UserObj,
+    User(u32),
+    /// The group of the inode
+    GroupObj,
+    Group(u32),
+    /// The most permissions the named users and the groups can get
+    Mask,
+    Other,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AclEntry {
+    pub tag: AclTag,
+    pub permissions: AclPermission,
+}
+
+/// The entries of an ACL in the order of the disk, see `Inode::acl`
+#[derive(Debug, Clone)]
+pub struct Acl<'fs> {
+    entries: &'fs [u8],
+}
+
+impl<'fs> Acl<'fs> {
+    /// Check the version and that the value is made of whole entries with known tags
+    pub(crate) fn new(value: &'fs [u8]) -> Result<Self, Error> {
+        if value.len() < 4 || value[..4] != ACL_VERSION.to_le_bytes() {
+            return Err(Error::Corrupt("ACL version"));
+        }
+        let mut acl = Acl {
+            entries: &value[4..],
+        };
+        while !acl.entries.is_empty() {
+            acl.next_entry()?;
+        }
+        acl.entries = &value[4..];
+        Ok(acl)
+    }
+    fn next_entry(&mut self) -> Result<AclEntry, Error> {
+        let corrupt = Error::Corrupt("ACL entry");
+        if self.entries.len() < 4 {
+            return Err(corrupt);
+        }
+        let tag = u16::from_le_bytes([self.entries[0], self.entries[1]]);
+        let permissions = AclPermission::from_bits_truncate(u16::from_le_bytes([
+            self.entries[2],
+            self.entries[3],
+        ]));
+        let (tag, len) = match tag {
+            USER | GROUP => {
+                if self.entries.len() < 8 {
+                    return Err(corrupt);
+                }
+                let mut id = [0; 4];
+                id.copy_from_slice(&self.entries[4..8]);
+                let id = u32::from_le_bytes(id);
+                if tag == USER {
+                    (AclTag::User(id), 8)
+                } else {
+                    (AclTag::Group(id), 8)
+                }
+            }
+            USER_OBJ => (AclTag::UserObj, 4),
+            GROUP_OBJ => (AclTag::GroupObj, 4),
+            MASK => (AclTag::Mask, 4),
+            OTHER => (AclTag::Other, 4),
+            _ => return Err(corrupt),
+        };
+        self.entries = &self.entries[len..];
+        Ok(AclEntry { tag, permissions })
+    }
+}
+
+impl Iterator for Acl<'_> {
+    type Item = AclEntry;
+
+    fn next(&mut self) -> Option<AclEntry> {
+        if self.entries.is_empty() {
+            return None;
+        }
+        // The entries were checked by new
+        self.next_entry().ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use std::vec::Vec;
+
+    use super::{Acl, AclEntry, AclPermission, AclTag};
+    use crate::tests::load_image;
+    use crate::{Error, Ext2Device};
+
+    fn entry(tag: AclTag, permissions: u16) -> AclEntry {
+        AclEntry {
+            tag,
+            permissions: AclPermission::from_bits(permissions).unwrap(),
+        }
+    }
+
+    #[test]
+    fn access_and_default() {
+        let mut image = load_image("test_fs_acl");
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.open();
+        let get = |path: &[u8]| fs.get_inode(fs.lookup_path(path).unwrap()).unwrap();
+
+        let file = get(b"/file.txt");
+        let acl: Vec<_> = file.acl().unwrap().unwrap().collect();
+        assert_eq!(
+            acl,
+            [
+                entry(AclTag::UserObj, 6),
+                entry(AclTag::User(1000), 4),
+                entry(AclTag::GroupObj, 4),
+                entry(AclTag::Group(100), 6),
+                entry(AclTag::Mask, 6),
+                entry(AclTag::Other, 0),
+            ]
+        );
+        assert!(file.default_acl().unwrap().is_none());
+
+        let dir = get(b"/dir");
+        let acl: Vec<_> = dir.acl().unwrap().unwrap().collect();
+        assert_eq!(
+            acl,
+            [
+                entry(AclTag::UserObj, 7),
+                entry(AclTag::GroupObj, 5),
+                entry(AclTag::Other, 5),
+            ]
+        );
+        let default: Vec<_> = dir.default_acl().unwrap().unwrap().collect();
+        assert_eq!(
+            default,
+            [
+                entry(AclTag::UserObj, 7),
+                entry(AclTag::User(1000), 7),
+                entry(AclTag::GroupObj, 5),
+                entry(AclTag::Mask, 7),
+                entry(AclTag::Other, 5),
+            ]
+        );
+
+        let plain = get(b"/plain.txt");
+        assert!(plain.acl().unwrap().is_none());
+        assert!(plain.default_acl().unwrap().is_none());
+    }
+
+    #[test]
+    fn corrupt_acl() {
+        let version = Error::Corrupt("ACL version");
+        let entry = Error::Corrupt("ACL entry");
+        assert_eq!(Acl::new(&[]).err(), Some(version));
+        assert_eq!(Acl::new(&[2, 0, 0, 0]).err(), Some(version));
+        assert_eq!(Acl::new(&[1, 0, 0, 0]).unwrap().count(), 0);
+        // A named user without its id
+        assert_eq!(Acl::new(&[1, 0, 0, 0, 2, 0, 4, 0]).err(), Some(entry));
+        assert_eq!(Acl::new(&[1, 0, 0, 0, 0x40, 0, 4, 0]).err(), Some(entry));
+        assert_eq!(Acl::new(&[1, 0, 0, 0, 0x20, 0, 4]).err(), Some(entry));
+    }
+}
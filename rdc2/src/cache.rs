@@ -0,0 +1,154 @@
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+
+use super::InodeRef;
+
+/// FNV-1a, names are short and this only needs to spread them
+fn name_hash(name: &[u8]) -> u64 {
+    name.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// The directory and the hash of the name
+type Key = (u32, u64);
+
+/// Remembers the entries found in directories, see `FileSystem::enable_dir_cache`.
+///
+/// The cache only holds entries that exist, it is invalidated for a directory when its entries
+/// change through the FileSystem. Writing directory blocks with a bare `Cursor` bypasses it.
+#[derive(Debug)]
+pub struct DirCache {
+    capacity: usize,
+    /// The name of the child tells collisions apart
+    entries: BTreeMap<Key, (InodeRef, Box<[u8]>)>,
+}
+
+impl DirCache {
+    pub(crate) fn new(capacity: usize) -> Self {
+        DirCache {
+            capacity,
+            entries: BTreeMap::new(),
+        }
+    }
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+    pub(crate) fn get(&self, directory: InodeRef, name: &[u8]) -> Option<InodeRef> {
+        match self.entries.get(&(directory.0, name_hash(name))) {
+            Some((child, cached_name)) if **cached_name == *name => Some(*child),
+            _ => None,
+        }
+    }
+    /// When the cache is full an arbitrary entry is evicted
+    pub(crate) fn insert(&mut self, directory: InodeRef, name: &[u8], child: InodeRef) {
+        if self.capacity == 0 {
+            return;
+        }
+        let key = (directory.0, name_hash(name));
+        if self.entries.len() >= self.capacity && !self.entries.contains_key(&key) {
+            self.entries.pop_first();
+        }
+        self.entries.insert(key, (child, name.into()));
+    }
+    /// Forget every entry
+    pub(crate) fn clear(&mut self) {
+        self.entries.clear()
+    }
+    /// Forget the entries of directory
+    pub(crate) fn invalidate(&mut self, directory: InodeRef) {
+        let stale: alloc::vec::Vec<_> = self
+            .entries
+            .range((directory.0, 0)..=(directory.0, u64::MAX))
+            .map(|(&key, _)| key)
+            .collect();
+        for key in stale {
+            self.entries.remove(&key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DirCache;
+    use crate::inode::Permission;
+    use crate::tests::load_image;
+    use crate::{Error, Ext2Device, InodeRef};
+
+    #[test]
+    fn bounded() {
+        let mut cache = DirCache::new(2);
+        cache.insert(InodeRef(2), b"a", InodeRef(12));
+        cache.insert(InodeRef(2), b"b", InodeRef(13));
+        cache.insert(InodeRef(2), b"b", InodeRef(14));
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get(InodeRef(2), b"b"), Some(InodeRef(14)));
+        cache.insert(InodeRef(12), b"c", InodeRef(15));
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get(InodeRef(12), b"c"), Some(InodeRef(15)));
+        assert_eq!(cache.get(InodeRef(2), b"c"), None);
+
+        cache.invalidate(InodeRef(12));
+        assert_eq!(cache.get(InodeRef(12), b"c"), None);
+        assert_eq!(cache.len(), 1);
+
+        let mut cache = DirCache::new(0);
+        cache.insert(InodeRef(2), b"a", InodeRef(12));
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn lookups_and_mutations() {
+        let mut image = load_image("test_fs_back");
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let mut fs = device.open();
+        fs.enable_dir_cache(64);
+
+        let never = fs.lookup_path(b"/thing/more/never.txt").unwrap();
+        let cached = || fs.dir_cache.as_ref().unwrap().borrow().len();
+        assert_eq!(cached(), 3);
+        assert_eq!(fs.lookup_path(b"/thing/more/never.txt"), Ok(never));
+        assert_eq!(cached(), 3);
+
+        fs.unlink(b"/thing/more/never.txt").unwrap();
+        assert_eq!(
+            fs.lookup_path(b"/thing/more/never.txt"),
+            Err(Error::NotFound)
+        );
+        let file = fs
+            .create_file(b"/thing/more/never.txt", Permission::all(), 0, 0)
+            .unwrap();
+        assert_eq!(fs.lookup_path(b"/thing/more/never.txt"), Ok(file));
+
+        // The inode of a removed directory can come back as another directory
+        let dir = fs.create_dir(b"/dir", Permission::all(), 0, 0).unwrap();
+        fs.create_file(b"/dir/a", Permission::all(), 0, 0).unwrap();
+        assert!(fs.lookup_path(b"/dir/a").is_ok());
+        fs.unlink(b"/dir/a").unwrap();
+        fs.get_root().remove_entry(b"dir").unwrap();
+        fs.get_inode(dir).unwrap().truncate(0).unwrap();
+        fs.release_inode(dir).unwrap();
+        let other = fs
+            .create_dir(b"/other_dir", Permission::all(), 0, 0)
+            .unwrap();
+        assert_eq!(other, dir);
+        assert_eq!(fs.lookup_path(b"/other_dir/a"), Err(Error::NotFound));
+
+        // Interleaved lookups and mutations of the same directory
+        let foo = fs.lookup_path(b"/foo.txt").unwrap();
+        for round in 0..10 {
+            let name = [b'/', b'f', b'0' + round];
+            assert_eq!(fs.lookup_path(&name), Err(Error::NotFound));
+            let inode = fs.create_file(&name, Permission::all(), 0, 0).unwrap();
+            assert_eq!(fs.lookup_path(&name), Ok(inode));
+            assert_eq!(fs.lookup_path(b"/foo.txt"), Ok(foo));
+            if round % 2 == 0 {
+                fs.unlink(&name).unwrap();
+                assert_eq!(fs.lookup_path(&name), Err(Error::NotFound));
+            }
+        }
+    }
+}
@@ -0,0 +1,1329 @@
+//! Checking the consistency of the metadata, like the passes of e2fsck. The checks return what
+//! they found without repairing it, `CheckReport::repair` fixes the bitmaps and the counters
+//! and `FileSystem::reconnect_orphans` links the orphans in lost+found.
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::inode::{root_inode, Cursor, InodeRef, MappedBlock, RawDirectoryEntry};
+use super::mkfs::set_bits;
+pub use super::report::DirectoryProblem;
+use super::report::{Finding, FindingKind, FindingSink, SeverityCounts};
+use super::{Error, FileSystem};
+
+/// The differences between the block bitmap of a group and the blocks that are in use
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GroupBlockReport {
+    pub group: u32,
+    /// Marked as used, but neither metadata nor referenced by an inode
+    pub leaked: Vec<u32>,
+    /// Metadata or referenced by an inode, but marked as free
+    pub unmarked: Vec<u32>,
+    /// The free blocks of the group descriptor
+    pub recorded_free_blocks: u32,
+    /// The blocks that are not in use
+    pub expected_free_blocks: u32,
+}
+
+/// A group whose free inode count is not the one of its bitmap
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GroupInodeReport {
+    pub group: u32,
+    /// The free inodes of the group descriptor
+    pub recorded_free_inodes: u32,
+    /// The inodes neither marked in the bitmap nor referenced by an entry
+    pub expected_free_inodes: u32,
+}
+
+/// An inode whose link count is not the number of directory entries referencing it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LinkCountReport {
+    pub inode: InodeRef,
+    /// The link count of the inode
+    pub recorded: u16,
+    /// The entries referencing it. For a directory, its name, its '.' entry and the '..'
+    /// entries of its subdirectories
+    pub expected: u32,
+}
+
+/// A block referenced by several inodes, or several times by the same inode
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DuplicateBlock {
+    pub block: u32,
+    /// The inode of each reference, in the order of the inodes
+    pub inodes: Vec<InodeRef>,
+}
+
+/// A problem in a directory, at offset bytes from its start
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DirectoryReport {
+    pub directory: InodeRef,
+    pub offset: u32,
+    pub problem: DirectoryProblem,
+}
+
+/// What the checks found, each check fills its own fields
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct CheckReport {
+    /// The groups whose bitmap or free count is wrong, see `check_block_bitmaps`
+    pub groups: Vec<GroupBlockReport>,
+    /// The pointers of the inodes that are not blocks of the filesystem
+    pub out_of_range: Vec<(InodeRef, u32)>,
+    /// The free blocks of the superblock
+    pub recorded_free_blocks: u32,
+    /// The blocks that are not in use
+    pub expected_free_blocks: u32,
+
+    /// The inodes whose link count is wrong, see `check_inodes`
+    pub link_counts: Vec<LinkCountReport>,
+    /// The inodes referenced by a directory entry but marked as free
+    pub unmarked_inodes: Vec<InodeRef>,
+    /// The inodes marked as used that no directory entry references, the reserved ones are
+    /// not included
+    pub orphans: Vec<InodeRef>,
+    /// The entries referencing an inode out of the filesystem, as (directory, entry)
+    pub bad_entries: Vec<(InodeRef, InodeRef)>,
+    /// The groups whose free inode count is wrong
+    pub inode_groups: Vec<GroupInodeReport>,
+    /// The free inodes of the superblock
+    pub recorded_free_inodes: u32,
+    /// The inodes that are not in use
+    pub expected_free_inodes: u32,
+
+    /// The blocks referenced more than once, see `check_duplicate_blocks`
+    pub duplicate_blocks: Vec<DuplicateBlock>,
+
+    /// The problems of the directories, sorted by directory and offset, see `check_directories`
+    pub directories: Vec<DirectoryReport>,
+}
+
+impl CheckReport {
+    /// Whether nothing is wrong
+    pub fn is_clean(&self) -> bool {
+        self.groups.is_empty()
+            && self.out_of_range.is_empty()
+            && self.recorded_free_blocks == self.expected_free_blocks
+            && self.link_counts.is_empty()
+            && self.unmarked_inodes.is_empty()
+            && self.orphans.is_empty()
+            && self.bad_entries.is_empty()
+            && self.inode_groups.is_empty()
+            && self.recorded_free_inodes == self.expected_free_inodes
+            && self.duplicate_blocks.is_empty()
+            && self.directories.is_empty()
+    }
+
+    /// Each problem of the report as a finding, in the order of the fields. The groups of the
+    /// inodes and blocks are those of fs, which must be the checked filesystem
+    pub fn findings<'a>(&'a self, fs: &'a FileSystem<'_>) -> impl Iterator<Item = Finding> + 'a {
+        let inode = move |kind, inode| {
+            Finding::new(kind)
+                .group(fs.group_of_inode(inode))
+                .inode(inode)
+        };
+
+        let blocks = self.groups.iter().flat_map(|report| {
+            let group = report.group;
+            let finding = move |kind, block| Finding::new(kind).group(group).block(block);
+            let leaked = report
+                .leaked
+                .iter()
+                .map(move |&block| finding(FindingKind::LeakedBlock, block));
+            let unmarked = report
+                .unmarked
+                .iter()
+                .map(move |&block| finding(FindingKind::UnmarkedBlock, block));
+            let count = (report.recorded_free_blocks != report.expected_free_blocks).then(|| {
+                Finding::new(FindingKind::FreeBlockCount {
+                    recorded: report.recorded_free_blocks,
+                    expected: report.expected_free_blocks,
+                })
+                .group(group)
+            });
+            leaked.chain(unmarked).chain(count)
+        });
+        let out_of_range = self
+            .out_of_range
+            .iter()
+            .map(move |&(owner, block)| inode(FindingKind::BlockOutOfRange, owner).block(block));
+        let free_blocks = (self.recorded_free_blocks != self.expected_free_blocks).then(|| {
+            Finding::new(FindingKind::FreeBlockCount {
+                recorded: self.recorded_free_blocks,
+                expected: self.expected_free_blocks,
+            })
+        });
+
+        let link_counts = self.link_counts.iter().map(move |report| {
+            let kind = FindingKind::LinkCount {
+                recorded: report.recorded,
+                expected: report.expected,
+            };
+            inode(kind, report.inode)
+        });
+        let unmarked_inodes = self
+            .unmarked_inodes
+            .iter()
+            .map(move |&unmarked| inode(FindingKind::UnmarkedInode, unmarked));
+        let orphans = self
+            .orphans
+            .iter()
+            .map(move |&orphan| inode(FindingKind::Orphan, orphan));
+        let bad_entries = self.bad_entries.iter().map(move |&(directory, entry)| {
+            inode(FindingKind::BadEntry { entry: entry.0 }, directory)
+        });
+        let inode_groups = self.inode_groups.iter().map(|report| {
+            Finding::new(FindingKind::FreeInodeCount {
+                recorded: report.recorded_free_inodes,
+                expected: report.expected_free_inodes,
+            })
+            .group(report.group)
+        });
+        let free_inodes = (self.recorded_free_inodes != self.expected_free_inodes).then(|| {
+            Finding::new(FindingKind::FreeInodeCount {
+                recorded: self.recorded_free_inodes,
+                expected: self.expected_free_inodes,
+            })
+        });
+
+        let duplicate_blocks = self.duplicate_blocks.iter().flat_map(move |duplicate| {
+            let block = duplicate.block;
+            duplicate.inodes.iter().map(move |&owner| {
+                Finding::new(FindingKind::DuplicateBlock)
+                    .group(fs.group_of_block(block))
+                    .inode(owner)
+                    .block(block)
+            })
+        });
+        let directories = self.directories.iter().map(move |report| {
+            let kind = FindingKind::Directory {
+                offset: report.offset,
+                problem: report.problem,
+            };
+            inode(kind, report.directory)
+        });
+
+        blocks
+            .chain(out_of_range)
+            .chain(free_blocks)
+            .chain(link_counts)
+            .chain(unmarked_inodes)
+            .chain(orphans)
+            .chain(bad_entries)
+            .chain(inode_groups)
+            .chain(free_inodes)
+            .chain(duplicate_blocks)
+            .chain(directories)
+    }
+
+    /// Send the findings of the report to sink, and count them
+    pub fn report_to(&self, fs: &FileSystem<'_>, sink: &mut dyn FindingSink) -> SeverityCounts {
+        let mut counts = SeverityCounts::default();
+        for finding in self.findings(fs) {
+            counts.add(finding.severity);
+            sink.report(finding);
+        }
+        counts
+    }
+
+    /// The number of findings of each severity
+    pub fn counts(&self, fs: &FileSystem<'_>) -> SeverityCounts {
+        self.report_to(fs, &mut |_| ())
+    }
+
+    /// Add the problems of other, which must come from other checks of the same filesystem. The
+    /// counters of the superblock are added, they are 0 in the reports of checks that don't
+    /// fill them
+    pub fn merge(&mut self, other: CheckReport) {
+        self.groups.extend(other.groups);
+        self.out_of_range.extend(other.out_of_range);
+        self.recorded_free_blocks += other.recorded_free_blocks;
+        self.expected_free_blocks += other.expected_free_blocks;
+        self.link_counts.extend(other.link_counts);
+        self.unmarked_inodes.extend(other.unmarked_inodes);
+        self.orphans.extend(other.orphans);
+        self.bad_entries.extend(other.bad_entries);
+        self.inode_groups.extend(other.inode_groups);
+        self.recorded_free_inodes += other.recorded_free_inodes;
+        self.expected_free_inodes += other.expected_free_inodes;
+        self.duplicate_blocks.extend(other.duplicate_blocks);
+        self.directories.extend(other.directories);
+    }
+}
+
+/// Run every check and send their findings to sink, a check's report is dropped before the
+/// next one runs so that only one is in memory
+pub fn check_all(fs: &FileSystem<'_>, sink: &mut dyn FindingSink) -> SeverityCounts {
+    let mut counts = SeverityCounts::default();
+    for check in [
+        check_block_bitmaps,
+        check_inodes,
+        check_duplicate_blocks,
+        check_directories,
+    ] {
+        counts.merge(check(fs).report_to(fs, sink));
+    }
+    counts
+}
+
+/// A set of blocks of the filesystem, one bit per block from the first block of group 0
+struct BlockSet {
+    first_block: u32,
+    bits: Vec<u8>,
+}
+
+impl BlockSet {
+    fn new(fs: &FileSystem<'_>) -> Self {
+        let superblock = fs.get_superblock();
+        let first_block = superblock.index_of_superblock;
+        BlockSet {
+            first_block,
+            bits: vec![0; (superblock.block_count - first_block).div_ceil(8) as usize],
+        }
+    }
+    fn insert(&mut self, block: u32) {
+        let index = (block - self.first_block) as usize;
+        self.bits[index / 8] |= 1 << (index % 8);
+    }
+    fn contains(&self, block: u32) -> bool {
+        let index = (block - self.first_block) as usize;
+        self.bits[index / 8] & (1 << (index % 8)) != 0
+    }
+}
+
+/// Rebuild the block bitmaps from the metadata of the groups and the blocks referenced by the
+/// allocated inodes, and compare them with the bitmaps and the free counts of the filesystem.
+///
+/// The metadata is the copies of the superblock and of the group descriptors with the reserved
+/// descriptor blocks, the bitmaps and the inode tables. The inodes are the ones marked in the
+/// inode bitmaps, their blocks are found with `Inode::mapped_blocks`
+pub fn check_block_bitmaps(fs: &FileSystem<'_>) -> CheckReport {
+    let geometry = fs.geometry();
+    let superblock = fs.get_superblock();
+    let block_count = superblock.block_count;
+    let first_block = superblock.index_of_superblock;
+    let mut used = BlockSet::new(fs);
+    let mut report = CheckReport {
+        recorded_free_blocks: superblock.unallocated_blocks,
+        ..CheckReport::default()
+    };
+
+    let inode_table_blocks =
+        (superblock.inode_count_in_group * fs.inode_size() as u32).div_ceil(fs.block_size as u32);
+    // Block 0 holds the superblock with blocks bigger than 1KiB, it is not a valid pointer
+    let mut mark = |first: u32, len: u32| {
+        for block in first..first.saturating_add(len) {
+            if block >= first_block && block < block_count {
+                used.insert(block);
+            }
+        }
+    };
+    for (group, descriptor) in fs.get_block_group_descriptor_table().iter().enumerate() {
+        let group = group as u32;
+        mark(
+            geometry.first_block_of_group(group),
+            core::cmp::min(
+                geometry.backup_blocks(group),
+                geometry.blocks_in_group(group, block_count),
+            ),
+        );
+        mark(descriptor.block_address_of_block_bitmap, 1);
+        mark(descriptor.block_address_of_inode_bitmap, 1);
+        mark(descriptor.starting_block_of_inode_table, inode_table_blocks);
+    }
+    for inode in fs.allocated_inodes() {
+        for mapped in fs.load_inode(inode).mapped_blocks() {
+            let block = mapped.block();
+            if !fs.is_valid_block(block) {
+                log::trace!("Inode {} references {:?}", inode.0, mapped);
+                report.out_of_range.push((inode, block));
+                continue;
+            }
+            used.insert(block);
+        }
+    }
+
+    for (group, descriptor) in fs.get_block_group_descriptor_table().iter().enumerate() {
+        let group = group as u32;
+        let first = geometry.first_block_of_group(group);
+        let bitmap = unsafe { fs.get_block(descriptor.block_address_of_block_bitmap) };
+        let mut group_report = GroupBlockReport {
+            group,
+            leaked: Vec::new(),
+            unmarked: Vec::new(),
+            recorded_free_blocks: u32::from(descriptor.unallocated_blocks_in_group),
+            expected_free_blocks: 0,
+        };
+        for index in 0..geometry.blocks_in_group(group, block_count) {
+            let block = first + index;
+            match (fs.bitmap_bit(bitmap, index), used.contains(block)) {
+                (true, false) => group_report.leaked.push(block),
+                (false, true) => group_report.unmarked.push(block),
+                _ => (),
+            }
+            if !used.contains(block) {
+                group_report.expected_free_blocks += 1;
+            }
+        }
+        report.expected_free_blocks += group_report.expected_free_blocks;
+        if !group_report.leaked.is_empty()
+            || !group_report.unmarked.is_empty()
+            || group_report.recorded_free_blocks != group_report.expected_free_blocks
+        {
+            log::trace!("Block bitmap of group {} is wrong", group);
+            report.groups.push(group_report);
+        }
+    }
+    report
+}
+
+/// Walk the directories from the root, count the entries referencing each inode and compare
+/// them with the link counts, the inode bitmaps and the free inode counts.
+///
+/// Each directory is read once, the link count of a directory is expected to be 2 and its
+/// number of subdirectories when it has a single name. The inodes that can't be reached from
+/// the root are orphans, they are still in use for the free counts
+pub fn check_inodes(fs: &FileSystem<'_>) -> CheckReport {
+    let superblock = fs.get_superblock();
+    let inode_count = superblock.inode_count;
+    let inodes_per_group = superblock.inode_count_in_group;
+    let first_inode = fs.get_extended_superblock().first_non_reserved_inode;
+    // Indexed by inode number
+    let mut references = vec![0u32; inode_count as usize + 1];
+    let mut subdirectories = vec![0u32; inode_count as usize + 1];
+    let mut report = CheckReport {
+        recorded_free_inodes: superblock.unallocated_inodes,
+        ..CheckReport::default()
+    };
+    let group_report = |group: u32| GroupInodeReport {
+        group,
+        recorded_free_inodes: u32::from(
+            fs.get_block_group_descriptor_table()[group as usize].unallocated_inodes_in_group,
+        ),
+        expected_free_inodes: 0,
+    };
+    let mut group = group_report(0);
+
+    // The '..' entry of the root references itself in place of a name
+    references[root_inode().0 as usize] = 1;
+    let mut directories = vec![root_inode()];
+    while let Some(directory) = directories.pop() {
+        let inode = fs.load_inode(directory);
+        let entries = match inode.get_dir_entries() {
+            Some(entries) => entries,
+            None => continue,
+        };
+        for entry in entries {
+            if entry.name == "." || entry.name == ".." {
+                continue;
+            }
+            let child = match fs.get_inode(entry.inode) {
+                Ok(child) => child,
+                Err(_) => {
+                    log::trace!("Entry {} references inode {}", entry.name, entry.inode.0);
+                    report.bad_entries.push((directory, entry.inode));
+                    continue;
+                }
+            };
+            references[entry.inode.0 as usize] += 1;
+            if child.is_dir() {
+                subdirectories[directory.0 as usize] += 1;
+                // A directory with several names is only read once
+                if references[entry.inode.0 as usize] == 1 {
+                    directories.push(entry.inode);
+                }
+            }
+        }
+    }
+
+    for inode in 1..=inode_count {
+        let inode = InodeRef(inode);
+        let count = references[inode.0 as usize];
+        let allocated = fs.is_inode_allocated(inode) == Ok(true);
+        if !allocated && count == 0 {
+            group.expected_free_inodes += 1;
+        }
+        if inode.0.is_multiple_of(inodes_per_group) {
+            report.expected_free_inodes += group.expected_free_inodes;
+            if group.recorded_free_inodes != group.expected_free_inodes {
+                log::trace!("Free inodes of group {} are wrong", group.group);
+                report.inode_groups.push(group);
+            }
+            if inode.0 < inode_count {
+                group = group_report(group.group + 1);
+            }
+        }
+        if count == 0 {
+            if allocated && inode.0 >= first_inode {
+                log::trace!("Inode {} is not referenced", inode.0);
+                report.orphans.push(inode);
+            }
+            continue;
+        }
+        if !allocated {
+            log::trace!("Inode {} is referenced but free", inode.0);
+            report.unmarked_inodes.push(inode);
+        }
+        let inode = fs.load_inode(inode);
+        let expected = if inode.is_dir() {
+            count + 1 + subdirectories[inode.inode_ref().0 as usize]
+        } else {
+            count
+        };
+        if u32::from(inode.link_count()) != expected {
+            report.link_counts.push(LinkCountReport {
+                inode: inode.inode_ref(),
+                recorded: inode.link_count(),
+                expected,
+            });
+        }
+    }
+    report
+}
+
+/// Find the blocks referenced more than once by the allocated inodes, with the inodes
+/// referencing them.
+///
+/// The blocks of extended attributes are shared on purpose and not counted. A first walk marks
+/// the blocks referenced once and those referenced again, the inodes are only gathered for the
+/// second set in another walk
+pub fn check_duplicate_blocks(fs: &FileSystem<'_>) -> CheckReport {
+    let mut claimed = BlockSet::new(fs);
+    let mut claimed_twice = BlockSet::new(fs);
+    let mut report = CheckReport::default();
+    let claims = |inode: InodeRef, f: &mut dyn FnMut(u32)| {
+        fs.load_inode(inode)
+            .mapped_blocks()
+            .filter(|mapped| !matches!(mapped, MappedBlock::Xattr(_)))
+            .map(MappedBlock::block)
+            .filter(|&block| fs.is_valid_block(block))
+            .for_each(f)
+    };
+
+    for inode in fs.allocated_inodes() {
+        claims(inode, &mut |block| {
+            if claimed.contains(block) {
+                claimed_twice.insert(block);
+            } else {
+                claimed.insert(block);
+            }
+        });
+    }
+    let block_count = fs.get_superblock().block_count;
+    report.duplicate_blocks = (claimed_twice.first_block..block_count)
+        .filter(|&block| claimed_twice.contains(block))
+        .map(|block| DuplicateBlock {
+            block,
+            inodes: Vec::new(),
+        })
+        .collect();
+    if report.duplicate_blocks.is_empty() {
+        return report;
+    }
+    let duplicates = &mut report.duplicate_blocks;
+    for inode in fs.allocated_inodes() {
+        claims(inode, &mut |block| {
+            if !claimed_twice.contains(block) {
+                return;
+            }
+            log::trace!("Block {} is referenced by inode {}", block, inode.0);
+            // Sorted by block, as they were found in order
+            if let Ok(index) = duplicates.binary_search_by_key(&block, |duplicate| duplicate.block)
+            {
+                duplicates[index].inodes.push(inode);
+            }
+        });
+    }
+    report
+}
+
+/// Read the records of every allocated directory and check the structure of the tree.
+///
+/// Each record must be well formed, the first two are '.' and '..', and '..' must reference a
+/// directory that lists this one. A directory listed by several records makes the tree a graph,
+/// the records after the first one are reported. Reading a block stops at the first bad record
+pub fn check_directories(fs: &FileSystem<'_>) -> CheckReport {
+    let block_size = fs.get_superblock().block_size() as u32;
+    let inode_count = fs.get_superblock().inode_count;
+    let header_size = core::mem::size_of::<RawDirectoryEntry>() as u32;
+    let mut report = CheckReport::default();
+    // The directory listing each directory first, indexed by inode number
+    let mut parents = vec![None; inode_count as usize + 1];
+    // Every (parent, child) listing of a directory
+    let mut listed = Vec::new();
+    // The '..' records, as (directory, parent, offset)
+    let mut dot_dots = Vec::new();
+
+    for directory in fs.allocated_inodes() {
+        let inode = fs.load_inode(directory);
+        if !inode.is_dir() {
+            continue;
+        }
+        let mut problem = |offset, problem| {
+            log::trace!("Directory {} at {}: {:?}", directory.0, offset, problem);
+            report.directories.push(DirectoryReport {
+                directory,
+                offset,
+                problem,
+            });
+        };
+        let mut index = 0;
+        // Where the record after the last one read starts
+        let mut end = 0;
+        for offset in (0..inode.size()).step_by(block_size as usize) {
+            let block =
+                unsafe { Cursor::at(&inode, offset).peek_with(|start, _| Some((start, 0))) };
+            let block = match block {
+                Some((block, _)) => block,
+                None => {
+                    problem(offset, DirectoryProblem::Hole);
+                    continue;
+                }
+            };
+            let mut position = 0;
+            while position < block_size {
+                let record_offset = offset + position;
+                let remain = block_size - position;
+                let record = unsafe { block.add(position as usize) } as *const RawDirectoryEntry;
+                if remain < header_size {
+                    problem(record_offset, DirectoryProblem::BadRecord);
+                    break;
+                }
+                let (child, size, name_len) = unsafe {
+                    (
+                        read_field!(record, inode),
+                        u32::from(read_field!(record, size)),
+                        u32::from(read_field!(record, name_len)),
+                    )
+                };
+                if size < header_size + name_len || size % 4 != 0 || size > remain {
+                    problem(record_offset, DirectoryProblem::BadRecord);
+                    break;
+                }
+                let name = unsafe {
+                    core::slice::from_raw_parts(
+                        block.add((position + header_size) as usize),
+                        name_len as usize,
+                    )
+                };
+                match index {
+                    0 if name != b"." || child != directory => {
+                        problem(record_offset, DirectoryProblem::BadDot)
+                    }
+                    0 => (),
+                    1 if name != b".." => problem(record_offset, DirectoryProblem::MissingDotDot),
+                    1 => dot_dots.push((directory, child, record_offset)),
+                    _ if child.0 == 0 && position != 0 => {
+                        problem(record_offset, DirectoryProblem::ZeroInode)
+                    }
+                    _ if fs.get_inode(child).is_ok_and(|child| child.is_dir()) => {
+                        listed.push((directory.0, child.0));
+                        match parents[child.0 as usize] {
+                            None => parents[child.0 as usize] = Some(directory),
+                            Some(first_parent) => problem(
+                                record_offset,
+                                DirectoryProblem::CrossLinked {
+                                    child,
+                                    first_parent,
+                                },
+                            ),
+                        }
+                    }
+                    _ => (),
+                }
+                index += 1;
+                position += size;
+                end = offset + position;
+            }
+        }
+        match index {
+            0 => problem(0, DirectoryProblem::BadDot),
+            1 => problem(end, DirectoryProblem::MissingDotDot),
+            _ => (),
+        }
+    }
+
+    listed.sort_unstable();
+    for (directory, parent, offset) in dot_dots {
+        let listed = if directory == root_inode() {
+            parent == root_inode()
+        } else {
+            listed.binary_search(&(parent.0, directory.0)).is_ok()
+        };
+        if !listed {
+            log::trace!("Directory {} is not listed by {}", directory.0, parent.0);
+            report.directories.push(DirectoryReport {
+                directory,
+                offset,
+                problem: DirectoryProblem::WrongParent(parent),
+            });
+        }
+    }
+    report
+        .directories
+        .sort_by_key(|report| (report.directory.0, report.offset));
+    report
+}
+
+/// What `CheckReport::repair` changed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RepairStats {
+    /// The blocks in use that were marked in the bitmaps
+    pub marked_blocks: u32,
+    /// The leaked blocks that were cleared from the bitmaps
+    pub released_blocks: u32,
+    /// The referenced inodes that were marked in the bitmaps
+    pub marked_inodes: u32,
+    pub link_counts: u32,
+    /// The free counts of the superblock and of the group descriptors that were rewritten
+    pub counters: u32,
+}
+
+impl CheckReport {
+    /// Fix the bitmaps, the free counts and the link counts with what the checks found: the
+    /// bitmaps are made to match the blocks and the inodes in use, the counts are set to the
+    /// expected ones.
+    ///
+    /// Only the findings of the report are written, it must describe the current state of fs.
+    /// The blocks of an inode missing from the bitmaps look leaked to `check_block_bitmaps`, the
+    /// report of `check_inodes` is repaired first. The orphans, the bad entries, the duplicate
+    /// blocks and the problems of the directories are left as they are
+    pub fn repair(&self, fs: &FileSystem<'_>) -> Result<RepairStats, Error> {
+        if fs.read_only {
+            return Err(Error::ReadOnly);
+        }
+        let mut stats = RepairStats::default();
+        let descriptors = fs.get_block_group_descriptor_table();
+        for group in &self.groups {
+            let bitmap = descriptors[group.group as usize].block_address_of_block_bitmap;
+            let bitmap = unsafe { fs.get_block(bitmap) };
+            let first = fs.first_block_of_group(group.group);
+            for &block in &group.unmarked {
+                log::trace!("Marking block {}", block);
+                unsafe { set_bits(bitmap, block - first..block - first + 1) };
+                stats.marked_blocks += 1;
+            }
+            for &block in &group.leaked {
+                log::trace!("Releasing leaked block {}", block);
+                fs.release_bitmap(bitmap, block - first);
+                stats.released_blocks += 1;
+            }
+            if group.recorded_free_blocks != group.expected_free_blocks {
+                fs.update_group_descriptor(group.group, |descriptor| {
+                    descriptor.unallocated_blocks_in_group = group.expected_free_blocks as u16
+                });
+                stats.counters += 1;
+            }
+        }
+        if self.recorded_free_blocks != self.expected_free_blocks {
+            fs.update_superblock(|superblock| {
+                superblock.unallocated_blocks = self.expected_free_blocks
+            });
+            stats.counters += 1;
+        }
+
+        let inodes_per_group = fs.get_superblock().inode_count_in_group;
+        for &inode in &self.unmarked_inodes {
+            log::trace!("Marking inode {}", inode.0);
+            let group = fs.group_of_inode(inode);
+            let bitmap = descriptors[group as usize].block_address_of_inode_bitmap;
+            let index = (inode.0 - 1) % inodes_per_group;
+            unsafe { set_bits(fs.get_block(bitmap), index..index + 1) };
+            stats.marked_inodes += 1;
+        }
+        for group in &self.inode_groups {
+            fs.update_group_descriptor(group.group, |descriptor| {
+                descriptor.unallocated_inodes_in_group = group.expected_free_inodes as u16
+            });
+            stats.counters += 1;
+        }
+        if self.recorded_free_inodes != self.expected_free_inodes {
+            fs.update_superblock(|superblock| {
+                superblock.unallocated_inodes = self.expected_free_inodes
+            });
+            stats.counters += 1;
+        }
+
+        for link in &self.link_counts {
+            log::trace!("Link count of {} is {}", link.inode.0, link.expected);
+            fs.load_inode(link.inode)
+                .set_link_count(link.expected.min(u32::from(u16::MAX)) as u16);
+            stats.link_counts += 1;
+        }
+        Ok(stats)
+    }
+}
+
+impl FileSystem<'_> {
+    /// Link the orphans found by `check_inodes` in /lost+found, named '#' and their inode
+    /// number, returns how many were linked. lost+found is created if it is missing.
+    ///
+    /// An orphan directory is linked before the orphans it contains so they stay inside it, its
+    /// '..' entry then references lost+found. The deletion time of the linked inodes is cleared,
+    /// and their link counts and the one of lost+found are set to the entries referencing them
+    pub fn reconnect_orphans(&self) -> Result<u32, Error> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+        let mut reconnected = 0;
+        // The inodes whose link count may be wrong after the loop
+        let mut updated = Vec::new();
+        let mut lost_found = None;
+        loop {
+            let orphans = check_inodes(self).orphans;
+            if orphans.is_empty() {
+                break;
+            }
+            let directory = match lost_found {
+                Some(directory) => directory,
+                None => {
+                    let directory = self.ensure_lost_and_found()?;
+                    lost_found = Some(directory);
+                    directory
+                }
+            };
+
+            let directories: Vec<_> = orphans
+                .iter()
+                .copied()
+                .filter(|&orphan| self.load_inode(orphan).is_dir())
+                .collect();
+            // The directories whose parent is reachable, or one of them if they form a cycle
+            let mut batch: Vec<_> = directories
+                .iter()
+                .copied()
+                .filter(|&directory| {
+                    self.load_inode(directory)
+                        .find_entry(b"..")
+                        .is_none_or(|parent| !directories.contains(&parent.inode))
+                })
+                .collect();
+            if batch.is_empty() {
+                batch = match directories.first() {
+                    Some(&directory) => vec![directory],
+                    None => orphans,
+                };
+            }
+
+            let lost_found = self.load_inode(directory);
+            for orphan in batch {
+                log::trace!("Reconnecting inode {}", orphan.0);
+                let inode = self.load_inode(orphan);
+                lost_found.add_link(
+                    alloc::format!("#{}", orphan.0).as_bytes(),
+                    orphan,
+                    inode.file_type(),
+                )?;
+                inode.set_deletion_time(0);
+                if inode.is_dir() {
+                    // The old parent lost a subdirectory
+                    if let Some(parent) = inode.find_entry(b"..") {
+                        updated.push(parent.inode);
+                    }
+                    inode.set_parent(lost_found.inode_ref())?;
+                }
+                updated.push(orphan);
+                reconnected += 1;
+            }
+        }
+
+        updated.extend(lost_found);
+        for link in check_inodes(self).link_counts {
+            if updated.contains(&link.inode) {
+                self.load_inode(link.inode)
+                    .set_link_count(link.expected.min(u32::from(u16::MAX)) as u16);
+            }
+        }
+        Ok(reconnected)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::vec::Vec;
+
+    use super::{
+        check_all, check_block_bitmaps, check_directories, check_duplicate_blocks, check_inodes,
+        DirectoryProblem, DirectoryReport, DuplicateBlock, GroupBlockReport, GroupInodeReport,
+        LinkCountReport, RepairStats,
+    };
+    use crate::inode::{root_inode, EntryKind, InodeRef, Permission};
+    use crate::report::{Finding, FindingBuffer, FindingKind, SeverityCounts};
+    use crate::tests::{formatted, load_image};
+    use crate::{Error, Ext2Device};
+
+    #[test]
+    fn clean_images() {
+        // test_fs has blocks shared by two files
+        for name in &[
+            "test_fs_4k",
+            "test_fs_acl",
+            "test_fs_backup",
+            "test_fs_groups",
+            "test_fs_indirect",
+            "test_fs_rev0",
+            "test_fs_special",
+            "test_fs_xattr",
+        ] {
+            let mut image = load_image(name);
+            let mut device = Ext2Device::from_slice(&mut image).unwrap();
+            let fs = device.open();
+            let report = check_block_bitmaps(&fs);
+            assert!(report.is_clean(), "{}: {:?}", name, report);
+            let report = check_inodes(&fs);
+            assert!(report.is_clean(), "{}: {:?}", name, report);
+            let report = check_duplicate_blocks(&fs);
+            assert!(report.is_clean(), "{}: {:?}", name, report);
+            let report = check_directories(&fs);
+            assert!(report.is_clean(), "{}: {:?}", name, report);
+        }
+
+        let mut image = formatted(400 * 1024);
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.open();
+        let file = fs.create_file(b"/file", Permission::all(), 0, 0).unwrap();
+        let mut file = fs.get_inode(file).unwrap().as_file().unwrap();
+        file.write(&[1; 5000]).unwrap();
+        file.sync();
+        assert!(check_block_bitmaps(&fs).is_clean());
+        assert!(check_inodes(&fs).is_clean());
+        assert!(check_directories(&fs).is_clean());
+    }
+
+    #[test]
+    fn flipped_bits() {
+        let mut image = load_image("test_fs_indirect");
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.open();
+        let bitmap = fs.get_block_group_descriptor_table()[0].block_address_of_block_bitmap;
+        let flip = |block: u32| unsafe {
+            let index = block - 1;
+            *fs.get_block(bitmap).add(index as usize / 8) ^= 1 << (index % 8);
+        };
+        // A data block, the doubly indirect block, a free block and the inode table
+        for &block in &[100, 323, 900, 39] {
+            flip(block);
+        }
+        let report = check_block_bitmaps(&fs);
+        let free_blocks = { fs.get_superblock().unallocated_blocks };
+        assert_eq!(
+            report.groups,
+            [GroupBlockReport {
+                group: 0,
+                leaked: std::vec![900],
+                unmarked: std::vec![39, 100, 323],
+                recorded_free_blocks: free_blocks,
+                expected_free_blocks: free_blocks,
+            }]
+        );
+        assert!(report.out_of_range.is_empty());
+        assert!(!report.is_clean());
+
+        // The free counts are checked even if the bitmaps are right
+        for &block in &[100, 323, 900, 39] {
+            flip(block);
+        }
+        fs.update_group_descriptor(0, |descriptor| descriptor.unallocated_blocks_in_group -= 1);
+        let report = check_block_bitmaps(&fs);
+        assert_eq!(report.groups[0].recorded_free_blocks, free_blocks - 1);
+        assert!(report.groups[0].leaked.is_empty() && report.groups[0].unmarked.is_empty());
+        assert_eq!(report.expected_free_blocks, free_blocks);
+
+        // The pointers past the end are reported, not followed
+        let big = fs.lookup_path(b"/big").unwrap();
+        let inode = unsafe { fs.get_inode_in_table(big.0) };
+        unsafe { write_field!(inode, doubly_indirect_block_pointer, 5000) };
+        let report = check_block_bitmaps(&fs);
+        assert_eq!(report.out_of_range, [(InodeRef(big.0), 5000)]);
+        assert_eq!(report.groups[0].leaked.len(), 2 + 32);
+    }
+
+    #[test]
+    fn link_counts() {
+        let mut image = load_image("test_fs_acl");
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.open();
+        let file = fs.lookup_path(b"/file.txt").unwrap();
+        let dir = fs.lookup_path(b"/dir").unwrap();
+        fs.create_dir(b"/dir/sub", Permission::all(), 0, 0).unwrap();
+        assert!(check_inodes(&fs).is_clean());
+
+        fs.load_inode(file).set_link_count(0);
+        fs.load_inode(dir).set_link_count(2);
+        let report = check_inodes(&fs);
+        assert_eq!(
+            report.link_counts,
+            [
+                LinkCountReport {
+                    inode: file,
+                    recorded: 0,
+                    expected: 1,
+                },
+                LinkCountReport {
+                    inode: dir,
+                    recorded: 2,
+                    expected: 3,
+                },
+            ]
+        );
+        assert!(report.unmarked_inodes.is_empty() && report.orphans.is_empty());
+    }
+
+    #[test]
+    fn inode_bitmap() {
+        let mut image = load_image("test_fs_acl");
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.open();
+        let plain = fs.lookup_path(b"/plain.txt").unwrap();
+        let bitmap = fs.get_block_group_descriptor_table()[0].block_address_of_inode_bitmap;
+        let flip = |inode: u32| unsafe {
+            let index = inode - 1;
+            *fs.get_block(bitmap).add(index as usize / 8) ^= 1 << (index % 8);
+        };
+        // A live inode and a free one
+        flip(plain.0);
+        flip(20);
+        let report = check_inodes(&fs);
+        assert_eq!(report.unmarked_inodes, [plain]);
+        assert_eq!(report.orphans, [InodeRef(20)]);
+        assert!(report.link_counts.is_empty() && report.bad_entries.is_empty());
+        flip(plain.0);
+        flip(20);
+
+        // An unlinked file that is still allocated
+        let file = fs.create_file(b"/file", Permission::all(), 0, 0).unwrap();
+        fs.load_inode(root_inode()).remove_entry(b"file").unwrap();
+        let report = check_inodes(&fs);
+        assert_eq!(report.orphans, [file]);
+        assert_eq!(report.link_counts, []);
+    }
+
+    #[test]
+    fn duplicate_blocks() {
+        // niche.txt and never.txt share a block
+        let mut image = load_image("test_fs");
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.open();
+        let report = check_duplicate_blocks(&fs);
+        assert_eq!(
+            report.duplicate_blocks,
+            [DuplicateBlock {
+                block: 29,
+                inodes: std::vec![InodeRef(15), InodeRef(17)],
+            }]
+        );
+        drop(fs);
+
+        let mut image = load_image("test_fs_acl");
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.open();
+        let file = fs.lookup_path(b"/file.txt").unwrap();
+        let plain = fs.lookup_path(b"/plain.txt").unwrap();
+        let block = unsafe {
+            let file = fs.get_inode_in_table(file.0);
+            let plain = fs.get_inode_in_table(plain.0);
+            let block = read_field!(file, direct_block_pointers[0]);
+            write_field!(plain, direct_block_pointers[1], block);
+            write_field!(file, direct_block_pointers[3], block);
+            block
+        };
+        let report = check_duplicate_blocks(&fs);
+        assert_eq!(
+            report.duplicate_blocks,
+            [DuplicateBlock {
+                block,
+                inodes: std::vec![file, file, plain],
+            }]
+        );
+        assert!(!report.is_clean());
+    }
+
+    #[test]
+    fn reconnect_orphans() {
+        let mut image = load_image("test_fs_acl");
+        // Clear the inode of the entry of plain.txt in the root directory
+        let name = image.windows(9).position(|w| w == b"plain.txt").unwrap();
+        image[name - 8..name - 4].copy_from_slice(&[0; 4]);
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.open();
+        assert_eq!(fs.lookup_path(b"/plain.txt"), Err(Error::NotFound));
+        let lost_found = fs.lookup_path(b"/lost+found").unwrap();
+        let dir = fs.lookup_path(b"/dir").unwrap();
+        fs.create_dir(b"/dir/sub", Permission::all(), 0, 0).unwrap();
+        let file = fs
+            .create_file(b"/dir/sub/file", Permission::all(), 0, 0)
+            .unwrap();
+        let sub = fs.lookup_path(b"/dir/sub").unwrap();
+        fs.load_inode(root_inode()).remove_entry(b"dir").unwrap();
+        assert_eq!(check_inodes(&fs).orphans, [InodeRef(13), dir, sub, file]);
+
+        // The content of the directory is not linked on its own
+        assert_eq!(fs.reconnect_orphans(), Ok(2));
+        let plain = fs.lookup_path(b"/lost+found/#13").unwrap();
+        assert_eq!(fs.load_inode(plain).link_count(), 1);
+        assert_eq!(fs.lookup_path(b"/lost+found/#14/sub/file"), Ok(file));
+        assert_eq!(fs.lookup_path(b"/lost+found/#14/.."), Ok(lost_found));
+        assert_eq!(fs.load_inode(lost_found).link_count(), 3);
+        assert_eq!(fs.load_inode(root_inode()).link_count(), 3);
+        assert!(check_inodes(&fs).is_clean());
+        assert!(check_block_bitmaps(&fs).is_clean());
+        assert_eq!(fs.reconnect_orphans(), Ok(0));
+    }
+
+    #[test]
+    fn reconnect_without_lost_found() {
+        let mut image = formatted(2 * 1024 * 1024);
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.open();
+        let root = fs.load_inode(root_inode());
+        let old = root.remove_entry(b"lost+found").unwrap();
+        let block_size = fs.get_superblock().block_size() as u32;
+        // More entries than a block of lost+found holds
+        for i in 0..150 {
+            let path = std::format!("/file{}", i);
+            fs.create_file(path.as_bytes(), Permission::all(), 0, 0)
+                .unwrap();
+            root.remove_entry(&path.as_bytes()[1..]).unwrap();
+        }
+
+        assert_eq!(fs.reconnect_orphans(), Ok(151));
+        let lost_found = fs.lookup_path(b"/lost+found").unwrap();
+        assert_ne!(lost_found, old);
+        let name = std::format!("/lost+found/#{}/..", old.0);
+        assert_eq!(fs.lookup_path(name.as_bytes()), Ok(lost_found));
+        assert!(fs.load_inode(lost_found).size() > block_size);
+        assert!(check_inodes(&fs).is_clean());
+        assert!(check_block_bitmaps(&fs).is_clean());
+
+        drop(fs);
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.try_open_read_only().unwrap();
+        assert_eq!(fs.reconnect_orphans(), Err(Error::ReadOnly));
+    }
+
+    #[test]
+    fn directories() {
+        let mut image = load_image("test_fs_acl");
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.open();
+        let file = fs.lookup_path(b"/file.txt").unwrap();
+        let dir = fs.lookup_path(b"/dir").unwrap();
+        let report = |directory, offset, problem| DirectoryReport {
+            directory,
+            offset,
+            problem,
+        };
+        let block = unsafe {
+            let inode = fs.get_inode_in_table(dir.0);
+            fs.get_block(read_field!(inode, direct_block_pointers[0]))
+        };
+
+        // '.' renamed, and '..' referencing a file
+        unsafe {
+            *block.add(8) = b',';
+            block
+                .add(12)
+                .copy_from([file.0.to_le_bytes()].as_ptr() as *const u8, 4);
+        }
+        assert_eq!(
+            check_directories(&fs).directories,
+            [
+                report(dir, 0, DirectoryProblem::BadDot),
+                report(dir, 12, DirectoryProblem::WrongParent(file)),
+            ]
+        );
+        // '..' missing, its record is too short for its name
+        let size = unsafe { *(block.add(16) as *const [u8; 2]) };
+        unsafe {
+            *block.add(8) = b'.';
+            block
+                .add(12)
+                .copy_from([2u32.to_le_bytes()].as_ptr() as *const u8, 4);
+            *(block.add(16) as *mut [u8; 2]) = 8u16.to_le_bytes();
+        }
+        assert_eq!(
+            check_directories(&fs).directories,
+            [
+                report(dir, 12, DirectoryProblem::BadRecord),
+                report(dir, 12, DirectoryProblem::MissingDotDot),
+            ]
+        );
+        unsafe { *(block.add(16) as *mut [u8; 2]) = size };
+        assert!(check_directories(&fs).is_clean());
+
+        // A second name for a directory, in another one
+        let sub = fs.create_dir(b"/sub", Permission::all(), 0, 0).unwrap();
+        let dir_inode = fs.load_inode(dir);
+        dir_inode
+            .add_link(b"again", sub, EntryKind::Directory)
+            .unwrap();
+        let mut entries = dir_inode.get_dir_entries().unwrap();
+        let again = loop {
+            let offset = entries.position();
+            if entries.next().unwrap().name == "again" {
+                break offset;
+            }
+        };
+        assert_eq!(
+            check_directories(&fs).directories,
+            [report(
+                dir,
+                again,
+                DirectoryProblem::CrossLinked {
+                    child: sub,
+                    first_parent: root_inode(),
+                }
+            )]
+        );
+
+        // A deleted record in the middle of a block
+        unsafe { block.add(again as usize).write_bytes(0, 4) };
+        assert_eq!(
+            check_directories(&fs).directories,
+            [report(dir, again, DirectoryProblem::ZeroInode)]
+        );
+    }
+
+    #[test]
+    fn repair() {
+        let mut image = load_image("test_fs_acl");
+        let pristine = image.clone();
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.open();
+        // A clean report writes nothing
+        assert_eq!(check_inodes(&fs).repair(&fs), Ok(RepairStats::default()));
+        assert_eq!(
+            check_block_bitmaps(&fs).repair(&fs),
+            Ok(RepairStats::default())
+        );
+        drop(fs);
+        assert!(image == pristine);
+
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.open();
+        let file = fs.lookup_path(b"/file.txt").unwrap();
+        let plain = fs.lookup_path(b"/plain.txt").unwrap();
+        let descriptor = &fs.get_block_group_descriptor_table()[0];
+        let (block_bitmap, inode_bitmap) = (
+            descriptor.block_address_of_block_bitmap,
+            descriptor.block_address_of_inode_bitmap,
+        );
+        let free_inodes = u32::from(descriptor.unallocated_inodes_in_group);
+        let flip = |bitmap: u32, index: u32| unsafe {
+            *fs.get_block(bitmap).add(index as usize / 8) ^= 1 << (index % 8);
+        };
+        let data = unsafe { read_field!(fs.get_inode_in_table(file.0), direct_block_pointers[0]) };
+        let free = (1..64)
+            .find(|&block| fs.is_block_allocated(block) == Ok(false))
+            .unwrap();
+        flip(inode_bitmap, plain.0 - 1);
+        flip(block_bitmap, data - 1);
+        flip(block_bitmap, free - 1);
+        fs.update_superblock(|superblock| {
+            superblock.unallocated_blocks -= 3;
+            superblock.unallocated_inodes += 2;
+        });
+        fs.update_group_descriptor(0, |descriptor| descriptor.unallocated_inodes_in_group -= 1);
+        fs.load_inode(file).set_link_count(5);
+
+        let report = check_inodes(&fs);
+        assert_eq!(report.unmarked_inodes, [plain]);
+        assert_eq!(
+            report.inode_groups,
+            [GroupInodeReport {
+                group: 0,
+                recorded_free_inodes: free_inodes - 1,
+                expected_free_inodes: free_inodes,
+            }]
+        );
+        assert_eq!(
+            report.repair(&fs),
+            Ok(RepairStats {
+                marked_inodes: 1,
+                link_counts: 1,
+                counters: 2,
+                ..RepairStats::default()
+            })
+        );
+        assert!(check_inodes(&fs).is_clean());
+        assert_eq!(fs.load_inode(file).link_count(), 1);
+
+        let report = check_block_bitmaps(&fs);
+        assert_eq!(report.groups[0].unmarked, [data]);
+        assert_eq!(report.groups[0].leaked, [free]);
+        assert_eq!(
+            report.repair(&fs),
+            Ok(RepairStats {
+                marked_blocks: 1,
+                released_blocks: 1,
+                counters: 1,
+                ..RepairStats::default()
+            })
+        );
+        assert!(check_block_bitmaps(&fs).is_clean());
+        drop(fs);
+        assert!(image == pristine);
+
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.try_open_read_only().unwrap();
+        let mut report = check_inodes(&fs);
+        report.recorded_free_inodes += 1;
+        assert_eq!(report.repair(&fs), Err(Error::ReadOnly));
+    }
+
+    #[test]
+    fn findings() {
+        let mut image = load_image("test_fs_acl");
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.open();
+        let mut buffer = FindingBuffer::<1>::new();
+        assert!(check_all(&fs, &mut buffer).total() == 0 && buffer.is_clean());
+
+        let file = fs.lookup_path(b"/file.txt").unwrap();
+        let block_bitmap = fs.get_block_group_descriptor_table()[0].block_address_of_block_bitmap;
+        let flip = |index: u32| unsafe {
+            *fs.get_block(block_bitmap).add(index as usize / 8) ^= 1 << (index % 8);
+        };
+        let data = unsafe { read_field!(fs.get_inode_in_table(file.0), direct_block_pointers[0]) };
+        let free = (1..64)
+            .find(|&block| fs.is_block_allocated(block) == Ok(false))
+            .unwrap();
+        flip(data - 1);
+        flip(free - 1);
+        fs.load_inode(file).set_link_count(5);
+
+        let expected = [
+            Finding::new(FindingKind::LeakedBlock).group(0).block(free),
+            Finding::new(FindingKind::UnmarkedBlock)
+                .group(0)
+                .block(data),
+            Finding::new(FindingKind::LinkCount {
+                recorded: 5,
+                expected: 1,
+            })
+            .group(0)
+            .inode(file),
+        ];
+        let mut streamed = Vec::new();
+        let counts = check_all(&fs, &mut |finding| streamed.push(finding));
+        assert_eq!(streamed, expected);
+        assert_eq!(
+            counts,
+            SeverityCounts {
+                warnings: 2,
+                errors: 1,
+            }
+        );
+
+        let mut report = check_block_bitmaps(&fs);
+        report.merge(check_inodes(&fs));
+        assert!(report.findings(&fs).eq(expected.iter().copied()));
+        assert_eq!(report.counts(&fs), counts);
+        assert_eq!(report.recorded_free_inodes, report.expected_free_inodes);
+
+        let counts = check_all(&fs, &mut buffer);
+        assert_eq!(buffer.findings().collect::<Vec<_>>(), [&expected[0]]);
+        assert_eq!((buffer.dropped(), buffer.counts()), (2, counts));
+    }
+}
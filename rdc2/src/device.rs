@@ -0,0 +1,904 @@
+//! The storage behind a filesystem.
+//!
+//! `BlockDevice` is the interface of disks, virtio devices and SD cards: reads and writes of
+//! whole sectors at byte offsets. `CachedDevice` keeps a fixed number of blocks of such a device
+//! in buffers given by the caller, the blocks are borrowed through `BlockGuard`s.
+//!
+//! `MemoryDevice` holds a filesystem in one region of memory, addressed directly by
+//! `Ext2Device`. It is also a `BlockDevice`, so that the code written for the trait runs on it.
+//! `FileSystem::open_device` opens the filesystem of any `BlockDevice`, its blocks are read into
+//! a pool of buffers.
+//! With the `std` feature, `FileDevice` reads and writes a file, for the images that don't fit
+//! in memory. With the `alloc` feature, `OverlayDevice` keeps the writes made to a device in
+//! memory until they are committed, for dry runs.
+
+#[cfg(feature = "alloc")]
+use alloc::{boxed::Box, collections::BTreeMap, vec};
+use core::cmp::min;
+use core::marker::PhantomData;
+use core::ops::{Deref, DerefMut};
+
+use super::Ext2Device;
+
+/// A device read and written by sectors
+pub trait BlockDevice {
+    type Error;
+
+    /// The unit of the reads and writes, the offsets and the lengths of the buffers are
+    /// multiples of it
+    fn sector_size(&self) -> usize;
+    /// Fill buffer with the bytes at offset
+    fn read(&mut self, offset: u64, buffer: &mut [u8]) -> Result<(), Self::Error>;
+    /// Write data at offset, it may only reach the device on `flush`
+    fn write(&mut self, offset: u64, data: &[u8]) -> Result<(), Self::Error>;
+    /// Wait for the writes to reach the device
+    fn flush(&mut self) -> Result<(), Self::Error>;
+    /// Hint that the len bytes at offset will be read soon, the devices with a cache can read
+    /// them ahead. Does nothing by default
+    fn prefetch(&mut self, offset: u64, len: usize) -> Result<(), Self::Error> {
+        let _ = (offset, len);
+        Ok(())
+    }
+}
+
+/// A read or write past the end of a `MemoryDevice`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutOfRange;
+
+/// A region of memory holding a filesystem, the backend of `Ext2Device`
+pub struct MemoryDevice<'memory> {
+    len: usize,
+    device: Ext2Device<'memory>,
+}
+
+impl<'memory> MemoryDevice<'memory> {
+    pub fn new(memory: &'memory mut [u8]) -> Self {
+        // Ext2Device::from_slice refuses the regions shorter than a superblock
+        MemoryDevice {
+            len: memory.len(),
+            device: Ext2Device {
+                device: memory.as_mut_ptr(),
+                len: Some(memory.len()),
+                region: PhantomData,
+            },
+        }
+    }
+    /// The device to open the filesystem of the region with
+    pub fn ext2_device(&mut self) -> &mut Ext2Device<'memory> {
+        &mut self.device
+    }
+    pub fn len(&self) -> usize {
+        self.len
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+    /// The start of the bytes at offset, if len of them are in the region
+    fn range(&self, offset: u64, len: usize) -> Result<*mut u8, OutOfRange> {
+        let end = offset.checked_add(len as u64).ok_or(OutOfRange)?;
+        if end > self.len as u64 {
+            return Err(OutOfRange);
+        }
+        Ok(unsafe { self.device.device.add(offset as usize) })
+    }
+}
+
+/// The accesses are made at the byte, like those of the filesystem
+impl BlockDevice for MemoryDevice<'_> {
+    type Error = OutOfRange;
+
+    fn sector_size(&self) -> usize {
+        1
+    }
+    fn read(&mut self, offset: u64, buffer: &mut [u8]) -> Result<(), OutOfRange> {
+        let start = self.range(offset, buffer.len())?;
+        unsafe { crate::access::copy_from_device(start, buffer.as_mut_ptr(), buffer.len()) };
+        Ok(())
+    }
+    fn write(&mut self, offset: u64, data: &[u8]) -> Result<(), OutOfRange> {
+        let start = self.range(offset, data.len())?;
+        unsafe { crate::access::copy_to_device(data.as_ptr(), start, data.len()) };
+        Ok(())
+    }
+    fn flush(&mut self) -> Result<(), OutOfRange> {
+        Ok(())
+    }
+}
+
+/// A file or a host block device, accessed with seeks, reads and writes
+#[cfg(feature = "std")]
+pub struct FileDevice {
+    file: std::fs::File,
+    len: u64,
+    writable: bool,
+}
+
+#[cfg(feature = "std")]
+impl FileDevice {
+    /// Open the file at path for reading and writing
+    pub fn open(path: impl AsRef<std::path::Path>) -> std::io::Result<Self> {
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)?;
+        Self::new(file, true)
+    }
+    /// Open the file at path for reading, the writes fail with PermissionDenied
+    pub fn open_read_only(path: impl AsRef<std::path::Path>) -> std::io::Result<Self> {
+        Self::new(std::fs::File::open(path)?, false)
+    }
+    /// The device of an open file, writable tells if it was opened for writing
+    pub fn new(mut file: std::fs::File, writable: bool) -> std::io::Result<Self> {
+        use std::io::Seek;
+        // The metadata of block devices give a length of 0
+        let len = file.seek(std::io::SeekFrom::End(0))?;
+        Ok(FileDevice {
+            file,
+            len,
+            writable,
+        })
+    }
+    /// The length of the file when it was opened, in bytes
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+    pub fn is_writable(&self) -> bool {
+        self.writable
+    }
+}
+
+/// The accesses are made at the byte, the reads past the end fail with UnexpectedEof
+#[cfg(feature = "std")]
+impl BlockDevice for FileDevice {
+    type Error = std::io::Error;
+
+    fn sector_size(&self) -> usize {
+        1
+    }
+    fn read(&mut self, offset: u64, buffer: &mut [u8]) -> std::io::Result<()> {
+        use std::io::{Read, Seek};
+        self.file.seek(std::io::SeekFrom::Start(offset))?;
+        self.file.read_exact(buffer)
+    }
+    fn write(&mut self, offset: u64, data: &[u8]) -> std::io::Result<()> {
+        use std::io::{Seek, Write};
+        if !self.writable {
+            return Err(std::io::ErrorKind::PermissionDenied.into());
+        }
+        self.file.seek(std::io::SeekFrom::Start(offset))?;
+        self.file.write_all(data)
+    }
+    /// Wait for the data to reach the disk with `File::sync_data`
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.sync_data()
+    }
+}
+
+/// The failures of an `OverlayDevice`
+#[cfg(feature = "alloc")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverlayError<E> {
+    /// The base device failed
+    Device(E),
+    /// The write would shadow more blocks than the budget of the overlay, nothing was written
+    BudgetExceeded,
+}
+
+/// A device whose writes are kept in memory, over a base device that is only read.
+///
+/// The writes go to shadow copies of the blocks they touch, made from the base on the first
+/// write, and the reads see them. Nothing is written to the base until `commit` copies the
+/// shadow down, `discard` drops it. The shadow holds at most a budget of blocks, the writes that
+/// would need more fail with `OverlayError::BudgetExceeded`
+#[cfg(feature = "alloc")]
+pub struct OverlayDevice<D: BlockDevice> {
+    device: D,
+    block_size: usize,
+    budget: usize,
+    shadow: BTreeMap<u64, Box<[u8]>>,
+}
+
+#[cfg(feature = "alloc")]
+impl<D: BlockDevice> OverlayDevice<D> {
+    /// An overlay of device shadowing up to budget blocks of block_size bytes. Panics if
+    /// block_size is not a multiple of the sectors of device
+    pub fn new(device: D, block_size: usize, budget: usize) -> Self {
+        assert!(
+            block_size != 0 && block_size.is_multiple_of(device.sector_size()),
+            "blocks are made of sectors"
+        );
+        OverlayDevice {
+            device,
+            block_size,
+            budget,
+            shadow: BTreeMap::new(),
+        }
+    }
+
+    /// The number of blocks written since the last commit or discard
+    pub fn shadowed_blocks(&self) -> usize {
+        self.shadow.len()
+    }
+    pub fn budget(&self) -> usize {
+        self.budget
+    }
+
+    /// Write the shadowed blocks to the base device and flush it, the overlay is then empty.
+    /// The blocks written before an error are dropped from the shadow, the others stay
+    pub fn commit(&mut self) -> Result<(), D::Error> {
+        while let Some((block, data)) = self.shadow.pop_first() {
+            if let Err(error) = self.device.write(block * self.block_size as u64, &data) {
+                self.shadow.insert(block, data);
+                return Err(error);
+            }
+        }
+        self.device.flush()
+    }
+
+    /// Drop the writes made since the last commit, the reads see the base device again
+    pub fn discard(&mut self) {
+        self.shadow.clear();
+    }
+
+    /// The base device, without the shadowed writes
+    pub fn device(&mut self) -> &mut D {
+        &mut self.device
+    }
+    /// The base device, the shadowed writes are lost
+    pub fn into_inner(self) -> D {
+        self.device
+    }
+
+    /// Call access with the block of each chunk of the len bytes at offset that stays in one
+    /// block, the range of the chunk in its block and its position from offset
+    fn for_each_chunk<E>(
+        &mut self,
+        offset: u64,
+        len: usize,
+        mut access: impl FnMut(&mut Self, u64, core::ops::Range<usize>, usize) -> Result<(), E>,
+    ) -> Result<(), E> {
+        let block_size = self.block_size as u64;
+        let mut done = 0;
+        while done < len {
+            let position = offset + done as u64;
+            let start = (position % block_size) as usize;
+            let chunk = min(self.block_size - start, len - done);
+            access(self, position / block_size, start..start + chunk, done)?;
+            done += chunk;
+        }
+        Ok(())
+    }
+}
+
+/// The accesses are made by sectors of the base device
+#[cfg(feature = "alloc")]
+impl<D: BlockDevice> BlockDevice for OverlayDevice<D> {
+    type Error = OverlayError<D::Error>;
+
+    fn sector_size(&self) -> usize {
+        self.device.sector_size()
+    }
+    fn read(&mut self, offset: u64, buffer: &mut [u8]) -> Result<(), Self::Error> {
+        self.for_each_chunk(offset, buffer.len(), |overlay, block, range, done| {
+            let target = &mut buffer[done..done + range.len()];
+            match overlay.shadow.get(&block) {
+                Some(data) => target.copy_from_slice(&data[range]),
+                None => {
+                    let position = block * overlay.block_size as u64 + range.start as u64;
+                    overlay
+                        .device
+                        .read(position, target)
+                        .map_err(OverlayError::Device)?
+                }
+            }
+            Ok(())
+        })
+    }
+    fn write(&mut self, offset: u64, data: &[u8]) -> Result<(), Self::Error> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        let block_size = self.block_size as u64;
+        // Past the end of the addresses the base device fails
+        let end = offset.saturating_add(data.len() as u64);
+        let new_blocks = (offset / block_size..end.div_ceil(block_size))
+            .filter(|block| !self.shadow.contains_key(block))
+            .count();
+        if self.shadow.len() + new_blocks > self.budget {
+            return Err(OverlayError::BudgetExceeded);
+        }
+        self.for_each_chunk(offset, data.len(), |overlay, block, range, done| {
+            let source = &data[done..done + range.len()];
+            if let Some(shadow) = overlay.shadow.get_mut(&block) {
+                shadow[range].copy_from_slice(source);
+                return Ok(());
+            }
+            let mut shadow = vec![0; overlay.block_size].into_boxed_slice();
+            // A whole block is not read from the base
+            if range.len() != overlay.block_size {
+                overlay
+                    .device
+                    .read(block * overlay.block_size as u64, &mut shadow)
+                    .map_err(OverlayError::Device)?;
+            }
+            shadow[range].copy_from_slice(source);
+            overlay.shadow.insert(block, shadow);
+            Ok(())
+        })
+    }
+    /// The writes are only in memory, nothing reaches the base device before `commit`
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+    fn prefetch(&mut self, offset: u64, len: usize) -> Result<(), Self::Error> {
+        self.device
+            .prefetch(offset, len)
+            .map_err(OverlayError::Device)
+    }
+}
+
+/// The block held by a buffer of a `CachedDevice`
+#[derive(Debug, Clone, Copy)]
+struct Slot {
+    block: Option<u64>,
+    dirty: bool,
+    /// The number of `pin` calls not matched by an `unpin`
+    pins: u32,
+    /// The value of `CachedDevice::uses` when the block was last borrowed
+    last_use: u64,
+}
+
+/// The failures of a `CachedDevice`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheError<E> {
+    /// The device failed, the block it was accessing is not in the cache
+    Device(E),
+    /// A block had to be read but all the buffers hold pinned blocks
+    AllPinned,
+}
+
+/// The counters of a `CachedDevice`, to tune the number of buffers
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CacheStats {
+    /// The blocks borrowed while they were in the cache
+    pub hits: u64,
+    /// The blocks read from the device
+    pub misses: u64,
+    /// The blocks dropped from the cache to make room for others
+    pub evictions: u64,
+    /// The dirty blocks written to the device, on eviction or on `flush`
+    pub write_backs: u64,
+}
+
+/// Up to N blocks of a device, kept in buffers owned by the caller.
+///
+/// A block is borrowed with `block`, the guard borrows the whole cache: there is one block
+/// borrowed at a time, and the buffer of a guard can't be reused while it lives. What must
+/// outlive the guard is copied out of it, or the block is pinned so that it stays in the cache
+/// between the borrows. The blocks written through a guard are dirty until they are evicted or
+/// `flush` is called, dropping the cache does not write them.
+///
+/// The cache is itself a `BlockDevice` addressed at the byte, its reads and writes go through
+/// the blocks
+pub struct CachedDevice<'buffers, D: BlockDevice, const N: usize> {
+    device: D,
+    block_size: usize,
+    buffers: &'buffers mut [u8],
+    slots: [Slot; N],
+    uses: u64,
+    stats: CacheStats,
+}
+
+impl<'buffers, D: BlockDevice, const N: usize> CachedDevice<'buffers, D, N> {
+    /// A cache of blocks of block_size bytes, held in buffers. Panics if buffers is not N blocks
+    /// or if block_size is not a multiple of the sectors of device
+    pub fn new(device: D, block_size: usize, buffers: &'buffers mut [u8]) -> Self {
+        assert_eq!(buffers.len(), N * block_size, "one buffer per block");
+        assert!(
+            block_size != 0 && block_size.is_multiple_of(device.sector_size()),
+            "blocks are made of sectors"
+        );
+        CachedDevice {
+            device,
+            block_size,
+            buffers,
+            slots: [Slot {
+                block: None,
+                dirty: false,
+                pins: 0,
+                last_use: 0,
+            }; N],
+            uses: 0,
+            stats: CacheStats::default(),
+        }
+    }
+
+    /// Borrow block, it is read from the device if it is not in the cache. When the cache is
+    /// full the least recently used block that is not pinned is evicted, and written back if it
+    /// is dirty
+    pub fn block(&mut self, block: u64) -> Result<BlockGuard<'_>, CacheError<D::Error>> {
+        let index = self.load(block)?;
+        let block_size = self.block_size;
+        let Slot { dirty, .. } = &mut self.slots[index];
+        Ok(BlockGuard {
+            data: &mut self.buffers[index * block_size..(index + 1) * block_size],
+            dirty,
+        })
+    }
+
+    /// Keep block in the cache until it is unpinned as many times, reading it if needed
+    pub fn pin(&mut self, block: u64) -> Result<(), CacheError<D::Error>> {
+        let index = self.load(block)?;
+        self.slots[index].pins += 1;
+        Ok(())
+    }
+
+    /// Release a pin of block, panics if it is not pinned
+    pub fn unpin(&mut self, block: u64) {
+        let slot = self
+            .slots
+            .iter_mut()
+            .find(|slot| slot.block == Some(block) && slot.pins != 0)
+            .expect("unpinning a block that is not pinned");
+        slot.pins -= 1;
+    }
+
+    /// Write the dirty blocks to the device and flush it
+    pub fn flush(&mut self) -> Result<(), D::Error> {
+        for index in 0..N {
+            self.write_back(index)?;
+        }
+        self.device.flush()
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.stats
+    }
+    pub fn reset_stats(&mut self) {
+        self.stats = CacheStats::default();
+    }
+
+    /// The device, the blocks of the cache that are dirty are not written to it yet
+    pub fn device(&mut self) -> &mut D {
+        &mut self.device
+    }
+
+    /// The index of the buffer holding block, after reading it if it was not in the cache
+    fn load(&mut self, block: u64) -> Result<usize, CacheError<D::Error>> {
+        self.uses += 1;
+        let index = match self.slots.iter().position(|slot| slot.block == Some(block)) {
+            Some(index) => {
+                self.stats.hits += 1;
+                index
+            }
+            None => {
+                let index = (0..N)
+                    .filter(|&index| self.slots[index].pins == 0)
+                    .min_by_key(|&index| {
+                        (
+                            self.slots[index].block.is_some(),
+                            self.slots[index].last_use,
+                        )
+                    })
+                    .ok_or(CacheError::AllPinned)?;
+                self.write_back(index).map_err(CacheError::Device)?;
+                if self.slots[index].block.is_some() {
+                    self.stats.evictions += 1;
+                }
+                // The slot is empty until the read succeeds
+                self.slots[index].block = None;
+                let block_size = self.block_size;
+                let buffer = &mut self.buffers[index * block_size..(index + 1) * block_size];
+                self.device
+                    .read(block * block_size as u64, buffer)
+                    .map_err(CacheError::Device)?;
+                self.stats.misses += 1;
+                self.slots[index].block = Some(block);
+                index
+            }
+        };
+        self.slots[index].last_use = self.uses;
+        Ok(index)
+    }
+
+    fn write_back(&mut self, index: usize) -> Result<(), D::Error> {
+        let slot = self.slots[index];
+        if let (Some(block), true) = (slot.block, slot.dirty) {
+            let offset = block * self.block_size as u64;
+            let block_size = self.block_size;
+            let data = &self.buffers[index * block_size..(index + 1) * block_size];
+            self.device.write(offset, data)?;
+            self.slots[index].dirty = false;
+            self.stats.write_backs += 1;
+        }
+        Ok(())
+    }
+
+    /// Call access with each block overlapping the len bytes at offset, the range of the
+    /// block in it and the position of that range from offset
+    fn for_each_block(
+        &mut self,
+        offset: u64,
+        len: usize,
+        mut access: impl FnMut(BlockGuard<'_>, core::ops::Range<usize>, usize),
+    ) -> Result<(), CacheError<D::Error>> {
+        let block_size = self.block_size as u64;
+        let mut done = 0;
+        while done < len {
+            let position = offset + done as u64;
+            let start = (position % block_size) as usize;
+            let chunk = min(self.block_size - start, len - done);
+            let guard = self.block(position / block_size)?;
+            access(guard, start..start + chunk, done);
+            done += chunk;
+        }
+        Ok(())
+    }
+}
+
+/// The accesses are made at the byte, the blocks are read before being partly written
+impl<D: BlockDevice, const N: usize> BlockDevice for CachedDevice<'_, D, N> {
+    type Error = CacheError<D::Error>;
+
+    fn sector_size(&self) -> usize {
+        1
+    }
+    fn read(&mut self, offset: u64, buffer: &mut [u8]) -> Result<(), Self::Error> {
+        self.for_each_block(offset, buffer.len(), |guard, range, done| {
+            buffer[done..done + range.len()].copy_from_slice(&guard[range]);
+        })
+    }
+    fn write(&mut self, offset: u64, data: &[u8]) -> Result<(), Self::Error> {
+        self.for_each_block(offset, data.len(), |mut guard, range, done| {
+            let len = range.len();
+            guard[range].copy_from_slice(&data[done..done + len]);
+        })
+    }
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        CachedDevice::flush(self).map_err(CacheError::Device)
+    }
+    /// The blocks are read now, up to the N the cache holds. The blocks already in the cache
+    /// are left as they are, they are not counted as hits
+    fn prefetch(&mut self, offset: u64, len: usize) -> Result<(), Self::Error> {
+        let block_size = self.block_size as u64;
+        let end = offset.saturating_add(len as u64).div_ceil(block_size);
+        for block in offset / block_size..min(end, offset / block_size + N as u64) {
+            if !self.slots.iter().any(|slot| slot.block == Some(block)) {
+                self.load(block)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A block borrowed from a `CachedDevice`, writing to it marks it dirty
+pub struct BlockGuard<'cache> {
+    data: &'cache mut [u8],
+    dirty: &'cache mut bool,
+}
+
+impl Deref for BlockGuard<'_> {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        self.data
+    }
+}
+
+impl DerefMut for BlockGuard<'_> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        *self.dirty = true;
+        self.data
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use std::vec::Vec;
+
+    use super::{BlockDevice, CacheError, CacheStats, CachedDevice, MemoryDevice, OutOfRange};
+    use crate::tests::load_image;
+
+    /// Records the accesses made to a MemoryDevice
+    struct Recorder<'memory> {
+        device: MemoryDevice<'memory>,
+        accesses: Vec<(char, u64)>,
+    }
+
+    impl BlockDevice for Recorder<'_> {
+        type Error = OutOfRange;
+        fn sector_size(&self) -> usize {
+            512
+        }
+        fn read(&mut self, offset: u64, buffer: &mut [u8]) -> Result<(), OutOfRange> {
+            self.accesses.push(('r', offset));
+            self.device.read(offset, buffer)
+        }
+        fn write(&mut self, offset: u64, data: &[u8]) -> Result<(), OutOfRange> {
+            self.accesses.push(('w', offset));
+            self.device.write(offset, data)
+        }
+        fn flush(&mut self) -> Result<(), OutOfRange> {
+            self.accesses.push(('f', 0));
+            Ok(())
+        }
+    }
+
+    /// The reads and writes every device must support, on a copy of test_fs
+    fn exercise<D: BlockDevice>(device: &mut D, len: u64)
+    where
+        D::Error: core::fmt::Debug,
+    {
+        let mut magic = [0; 2];
+        device.read(1024 + 56, &mut magic).unwrap();
+        assert_eq!(magic, [0x53, 0xef]);
+        assert!(device.read(len - 1, &mut magic).is_err());
+        assert!(device.write(u64::MAX - 1, &magic).is_err());
+
+        device.write(1024 + 120, b"written").unwrap();
+        // Across the last two blocks, they are free
+        device.write(len - 1024 - 3, b"abcdef").unwrap();
+        let mut data = [0; 8];
+        device.read(len - 1024 - 4, &mut data).unwrap();
+        assert_eq!(&data[1..7], b"abcdef");
+        device.read(len - 8, &mut data).unwrap();
+        device.flush().unwrap();
+    }
+
+    #[test]
+    fn memory_device() {
+        let mut image = load_image("test_fs");
+        let mut device = MemoryDevice::new(&mut image);
+        let len = device.len() as u64;
+        exercise(&mut device, len);
+        assert_eq!(device.read(len - 1, &mut [0; 2]), Err(OutOfRange));
+
+        // The filesystem sees the writes
+        let fs = device.ext2_device().open();
+        assert_eq!(fs.get_extended_superblock().volume_name(), "written");
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn file_device() {
+        use super::FileDevice;
+
+        let image = load_image("test_fs");
+        let path = std::env::temp_dir().join(std::format!("rdc2_device_{}", std::process::id()));
+        std::fs::write(&path, &image).unwrap();
+
+        let mut device = FileDevice::open_read_only(&path).unwrap();
+        assert_eq!(device.len(), image.len() as u64);
+        let mut magic = [0; 2];
+        device.read(1024 + 56, &mut magic).unwrap();
+        assert_eq!(magic, [0x53, 0xef]);
+        assert_eq!(
+            device.write(0, &magic).unwrap_err().kind(),
+            std::io::ErrorKind::PermissionDenied
+        );
+
+        let mut device = FileDevice::open(&path).unwrap();
+        assert!(device.is_writable());
+        exercise(&mut device, image.len() as u64);
+        let mut buffers = [0; 2 * 1024];
+        let mut cache = CachedDevice::<_, 2>::new(device, 1024, &mut buffers);
+        exercise(&mut cache, image.len() as u64);
+
+        let written = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(written.len(), image.len());
+        assert_eq!(&written[1024 + 120..1024 + 127], b"written");
+        let end = written.len() - 1024;
+        assert_eq!(&written[end - 3..end + 3], b"abcdef");
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn overlay() {
+        use super::{OverlayDevice, OverlayError};
+
+        let mut image = load_image("test_fs");
+        let pristine = image.clone();
+        let len = image.len() as u64;
+        let mut overlay = OverlayDevice::new(MemoryDevice::new(&mut image), 1024, 8);
+        // The same fixture can be modified again after each discard
+        for _ in 0..2 {
+            exercise(&mut overlay, len);
+            assert_eq!(overlay.shadowed_blocks(), 3);
+            let mut name = [0; 7];
+            overlay.read(1024 + 120, &mut name).unwrap();
+            assert_eq!(&name, b"written");
+            overlay.device().read(1024 + 120, &mut name).unwrap();
+            assert_ne!(&name, b"written");
+            overlay.discard();
+            overlay.read(1024 + 120, &mut name).unwrap();
+            assert_ne!(&name, b"written");
+        }
+
+        // Two new blocks do not fit with the 7 shadowed, nothing is written
+        overlay.write(0, &[0xaa; 7 * 1024]).unwrap();
+        assert_eq!(
+            overlay.write(6 * 1024, &[0; 3 * 1024]),
+            Err(OverlayError::BudgetExceeded)
+        );
+        let mut data = [0; 1024];
+        overlay.read(6 * 1024, &mut data).unwrap();
+        assert_eq!(data, [0xaa; 1024]);
+        overlay.write(7 * 1024 + 1022, b"last").unwrap_err();
+        overlay.write(7 * 1024, b"last").unwrap();
+        assert_eq!(overlay.shadowed_blocks(), 8);
+        overlay.discard();
+
+        overlay.write(1024 + 120, b"commit\0").unwrap();
+        overlay.commit().unwrap();
+        assert_eq!(overlay.shadowed_blocks(), 0);
+        drop(overlay);
+        assert!(image != pristine);
+        let mut device = MemoryDevice::new(&mut image);
+        let fs = device.ext2_device().open();
+        assert_eq!(fs.get_extended_superblock().volume_name(), "commit");
+    }
+
+    #[test]
+    fn cache() {
+        let mut image = load_image("test_fs");
+        let pristine = image.clone();
+        let device = Recorder {
+            device: MemoryDevice::new(&mut image),
+            accesses: Vec::new(),
+        };
+        let mut buffers = [0; 2 * 1024];
+        let mut cache = CachedDevice::<_, 2>::new(device, 1024, &mut buffers);
+
+        assert_eq!(&cache.block(1).unwrap()[56..58], [0x53, 0xef]);
+        cache.block(2).unwrap()[0] ^= 0xff;
+        // Hits
+        cache.block(1).unwrap();
+        cache.block(2).unwrap();
+        assert_eq!(cache.device().accesses, [('r', 1024), ('r', 2048)]);
+
+        // Block 1 is the least recently used, it is clean
+        cache.block(3).unwrap();
+        // Block 2 is written back when it is evicted
+        cache.block(4).unwrap();
+        assert_eq!(
+            cache.device().accesses[2..],
+            [('r', 3072), ('w', 2048), ('r', 4096)]
+        );
+
+        cache.block(4).unwrap()[0] ^= 0xff;
+        cache.block(2).unwrap()[0] ^= 0xff;
+        cache.device().accesses.clear();
+        cache.flush().unwrap();
+        let mut accesses = cache.device().accesses.clone();
+        accesses.sort_unstable();
+        assert_eq!(accesses, [('f', 0), ('w', 2048), ('w', 4096)]);
+        // Flushed blocks are clean
+        cache.device().accesses.clear();
+        cache.flush().unwrap();
+        assert_eq!(cache.device().accesses, [('f', 0)]);
+
+        assert_eq!(
+            cache.stats(),
+            CacheStats {
+                hits: 3,
+                misses: 5,
+                evictions: 3,
+                write_backs: 3,
+            }
+        );
+        assert_eq!(
+            cache.block(1 << 40).err(),
+            Some(CacheError::Device(OutOfRange))
+        );
+        // Both modified blocks were flipped twice
+        cache.block(4).unwrap()[0] ^= 0xff;
+        cache.flush().unwrap();
+        drop(cache);
+        assert!(image == pristine);
+    }
+
+    #[test]
+    fn prefetch() {
+        let mut image = load_image("test_fs");
+        let device = Recorder {
+            device: MemoryDevice::new(&mut image),
+            accesses: Vec::new(),
+        };
+        let mut buffers = [0; 4 * 1024];
+        let mut cache = CachedDevice::<_, 4>::new(device, 1024, &mut buffers);
+        cache.prefetch(2 * 1024 + 10, 2 * 1024).unwrap();
+        assert_eq!(
+            cache.device().accesses,
+            [('r', 2048), ('r', 3072), ('r', 4096)]
+        );
+        cache.block(3).unwrap();
+        assert_eq!(cache.stats().hits, 1);
+        cache.prefetch(3 * 1024, 1024).unwrap();
+        assert_eq!((cache.stats().hits, cache.stats().misses), (1, 3));
+        // No more than the cache holds
+        cache.prefetch(10 * 1024, 10 * 1024).unwrap();
+        assert_eq!(cache.stats().misses, 7);
+        // Memory is not prefetched
+        assert_eq!(cache.device().device.prefetch(0, 1 << 40), Ok(()));
+    }
+
+    #[test]
+    fn pins() {
+        let mut image = load_image("test_fs");
+        let mut buffers = [0; 2 * 1024];
+        let mut cache =
+            CachedDevice::<_, 2>::new(MemoryDevice::new(&mut image), 1024, &mut buffers);
+        cache.pin(1).unwrap();
+        cache.block(1).unwrap()[0] ^= 0xff;
+        // Block 1 is the least recently used but it stays
+        cache.block(2).unwrap();
+        cache.block(3).unwrap();
+        cache.reset_stats();
+        cache.block(1).unwrap();
+        assert_eq!(cache.stats().hits, 1);
+
+        cache.pin(3).unwrap();
+        assert_eq!(cache.block(4).err(), Some(CacheError::AllPinned));
+        // A pinned block can be pinned again
+        cache.pin(1).unwrap();
+        cache.unpin(1);
+        cache.unpin(1);
+        cache.block(4).unwrap();
+        assert_eq!(cache.stats().evictions, 1);
+        assert_eq!(cache.stats().write_backs, 1);
+    }
+
+    /// The same reads and writes through caches of various sizes and on the memory give the
+    /// same bytes
+    #[test]
+    fn same_as_memory() {
+        fn check<const N: usize>() {
+            let mut image = load_image("test_fs");
+            let mut expected = image.clone();
+            let mut buffers = [0; 4 * 1024];
+            let mut cache = CachedDevice::<_, N>::new(
+                MemoryDevice::new(&mut image),
+                1024,
+                &mut buffers[..N * 1024],
+            );
+            let mut memory = MemoryDevice::new(&mut expected);
+
+            // A xorshift generator, the accesses are clustered in the first 16 blocks so that
+            // they hit the cache
+            let mut state = 0x2545_f491_4f6c_dd1du64 ^ N as u64;
+            let mut random = |bound: u64| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state % bound
+            };
+            let (mut read, mut read_expected) = (Vec::new(), Vec::new());
+            for step in 0..2000 {
+                let offset = random(16 * 1024);
+                let len = random(3000) as usize;
+                if random(2) == 0 {
+                    let data: Vec<u8> = (0..len).map(|index| (step + index) as u8).collect();
+                    cache.write(offset, &data).unwrap();
+                    memory.write(offset, &data).unwrap();
+                } else {
+                    read.resize(len, 0);
+                    read_expected.resize(len, 0);
+                    cache.read(offset, &mut read).unwrap();
+                    memory.read(offset, &mut read_expected).unwrap();
+                    assert!(read == read_expected, "read {} bytes at {}", len, offset);
+                }
+            }
+            let stats = cache.stats();
+            assert!(stats.hits != 0 && stats.misses != 0 && stats.write_backs != 0);
+            BlockDevice::flush(&mut cache).unwrap();
+            assert!(image == expected);
+        }
+        check::<1>();
+        check::<2>();
+        check::<4>();
+    }
+}
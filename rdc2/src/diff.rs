@@ -0,0 +1,255 @@
+//! Comparing the metadata of two filesystems, see `diff`
+
+use bitflags::bitflags;
+
+use super::inode::{InodeRef, Metadata};
+use super::FileSystem;
+
+bitflags! {
+    /// The attributes of an inode that differ between two filesystems
+    pub struct InodeChanges: u8 {
+        const SIZE = 0x01;
+        /// The access, change, modification, deletion or creation time
+        const TIMES = 0x02;
+        /// The type or the permissions
+        const MODE = 0x04;
+        const LINK_COUNT = 0x08;
+        /// The user or the group
+        const OWNER = 0x10;
+        const BLOCKS_USED = 0x20;
+    }
+}
+
+impl InodeChanges {
+    fn between(a: &Metadata, b: &Metadata) -> Self {
+        let mut changes = InodeChanges::empty();
+        changes.set(InodeChanges::SIZE, a.size != b.size);
+        changes.set(
+            InodeChanges::TIMES,
+            (a.accessed, a.changed, a.modified, a.deleted, a.created)
+                != (b.accessed, b.changed, b.modified, b.deleted, b.created),
+        );
+        changes.set(
+            InodeChanges::MODE,
+            (a.kind, a.permissions) != (b.kind, b.permissions),
+        );
+        changes.set(InodeChanges::LINK_COUNT, a.link_count != b.link_count);
+        changes.set(
+            InodeChanges::OWNER,
+            (a.user_id, a.group_id) != (b.user_id, b.group_id),
+        );
+        changes.set(InodeChanges::BLOCKS_USED, a.blocks_used != b.blocks_used);
+        changes
+    }
+}
+
+/// A difference found by `diff`, a is the first filesystem and b the second one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiffEvent {
+    /// A field of `Superblock`, by name
+    Superblock { field: &'static str, a: u32, b: u32 },
+    /// A field of the `BlockGroupDescriptor` of group, by name
+    Group {
+        group: u32,
+        field: &'static str,
+        a: u32,
+        b: u32,
+    },
+    /// The inode is only allocated in a
+    Removed(InodeRef),
+    /// The inode is only allocated in b
+    Added(InodeRef),
+    /// The inode is allocated in both, with different metadata
+    Inode {
+        inode: InodeRef,
+        changes: InodeChanges,
+        a: Metadata,
+        b: Metadata,
+    },
+}
+
+/// Emit a `DiffEvent` for each listed field of a and b that differ
+macro_rules! compare_fields {
+    ($a:expr, $b:expr, $emit:expr, [$($field:ident),* $(,)?]) => {
+        $(
+            let (a, b) = (u32::from({ $a.$field }), u32::from({ $b.$field }));
+            if a != b {
+                $emit(stringify!($field), a, b);
+            }
+        )*
+    };
+}
+
+/// Call sink with the differences between the metadata of a and b, without comparing the
+/// contents of the blocks: the fields of the superblocks, the fields of the groups the two have
+/// in common, then the inodes marked in the bitmaps of either, in order.
+///
+/// The inodes are matched by number, an inode that was released and reused in b is reported
+/// as changed
+pub fn diff(a: &FileSystem<'_>, b: &FileSystem<'_>, mut sink: impl FnMut(DiffEvent)) {
+    let (superblock_a, superblock_b) = (a.get_superblock(), b.get_superblock());
+    compare_fields!(
+        superblock_a,
+        superblock_b,
+        |field, a, b| sink(DiffEvent::Superblock { field, a, b }),
+        [
+            inode_count,
+            block_count,
+            block_superuser,
+            unallocated_blocks,
+            unallocated_inodes,
+            index_of_superblock,
+            log_block_size,
+            log_fragment_size,
+            block_count_in_group,
+            fragment_count_in_group,
+            inode_count_in_group,
+            last_mounted,
+            last_written,
+            number_of_times_mounted_since_last_consitency_check,
+            number_of_mounts_until_consistency_check,
+            ext2sig,
+            state,
+            on_error,
+            minor_version,
+            time_since_last_constiency_check,
+            time_between_forced_consistency_check,
+            creator_system_id,
+            major_version,
+            user_id_allowed_to_reserve,
+            group_id_allowed_to_reserve,
+        ]
+    );
+
+    let groups = a
+        .get_block_group_descriptor_table()
+        .iter()
+        .zip(b.get_block_group_descriptor_table());
+    for (group, (descriptor_a, descriptor_b)) in groups.enumerate() {
+        let group = group as u32;
+        compare_fields!(
+            descriptor_a,
+            descriptor_b,
+            |field, a, b| sink(DiffEvent::Group { group, field, a, b }),
+            [
+                block_address_of_block_bitmap,
+                block_address_of_inode_bitmap,
+                starting_block_of_inode_table,
+                unallocated_blocks_in_group,
+                unallocated_inodes_in_group,
+                number_of_directories_in_group,
+            ]
+        );
+    }
+
+    // Both iterators are sorted, they are merged
+    let mut inodes_a = a.allocated_inodes().peekable();
+    let mut inodes_b = b.allocated_inodes().peekable();
+    loop {
+        match (inodes_a.peek(), inodes_b.peek()) {
+            (None, None) => break,
+            (Some(&inode), next) if next.is_none_or(|next| inode.0 < next.0) => {
+                inodes_a.next();
+                sink(DiffEvent::Removed(inode));
+            }
+            (next, Some(&inode)) if next.is_none_or(|next| inode.0 < next.0) => {
+                inodes_b.next();
+                sink(DiffEvent::Added(inode));
+            }
+            (Some(&inode), Some(_)) => {
+                inodes_a.next();
+                inodes_b.next();
+                let (inode_a, inode_b) = match (a.get_inode(inode), b.get_inode(inode)) {
+                    (Ok(inode_a), Ok(inode_b)) => (inode_a, inode_b),
+                    _ => continue,
+                };
+                let (metadata_a, metadata_b) = (inode_a.metadata(), inode_b.metadata());
+                let changes = InodeChanges::between(&metadata_a, &metadata_b);
+                if !changes.is_empty() {
+                    sink(DiffEvent::Inode {
+                        inode,
+                        changes,
+                        a: metadata_a,
+                        b: metadata_b,
+                    });
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use std::vec::Vec;
+
+    use super::{diff, DiffEvent, InodeChanges};
+    use crate::inode::Permission;
+    use crate::tests::formatted;
+    use crate::{Ext2Device, OpenOptions};
+
+    #[test]
+    fn changed_file() {
+        let mut image = formatted(1 << 20);
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.open();
+        let file = fs.create_file(b"/file", Permission::all(), 0, 0).unwrap();
+        fs.create_file(b"/other", Permission::all(), 0, 0).unwrap();
+        drop(fs);
+        let mut copy = image.clone();
+
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let a = device.open();
+        let mut copy_device = Ext2Device::from_slice(&mut copy).unwrap();
+        let b = copy_device.open();
+        let mut events = Vec::new();
+        diff(&a, &b, |event| events.push(event));
+        assert_eq!(events, []);
+
+        b.open(b"/file", OpenOptions::new().write(true))
+            .unwrap()
+            .write(&[1; 3000])
+            .unwrap();
+        diff(&a, &b, |event| events.push(event));
+        let inodes: Vec<_> = events
+            .iter()
+            .filter_map(|event| match event {
+                DiffEvent::Inode { inode, changes, .. } => Some((*inode, *changes)),
+                DiffEvent::Added(inode) | DiffEvent::Removed(inode) => {
+                    Some((*inode, InodeChanges::empty()))
+                }
+                _ => None,
+            })
+            .collect();
+        assert_eq!(inodes.len(), 1);
+        assert_eq!(inodes[0].0, file);
+        assert!(inodes[0]
+            .1
+            .contains(InodeChanges::SIZE | InodeChanges::BLOCKS_USED));
+        // The blocks of the file are counted as used
+        assert!(events.contains(&DiffEvent::Superblock {
+            field: "unallocated_blocks",
+            a: a.get_superblock().unallocated_blocks,
+            b: a.get_superblock().unallocated_blocks - 3,
+        }));
+        assert!(events.iter().any(|event| matches!(
+            event,
+            DiffEvent::Group {
+                group: 0,
+                field: "unallocated_blocks_in_group",
+                ..
+            }
+        )));
+
+        // Removing a file is seen from both sides
+        b.unlink(b"/other").unwrap();
+        events.clear();
+        diff(&a, &b, |event| events.push(event));
+        let other = a.lookup_path(b"/other").unwrap();
+        assert!(events.contains(&DiffEvent::Removed(other)));
+        events.clear();
+        diff(&b, &a, |event| events.push(event));
+        assert!(events.contains(&DiffEvent::Added(other)));
+    }
+}
@@ -0,0 +1,174 @@
+use super::inode::{DirectoryEntries, Inode};
+
+/// A directory
+#[repr(C)]
+pub struct Dir<'fs, 'device> {
+    inode: Inode<'fs, 'device>,
+}
+
+impl<'fs, 'device> Dir<'fs, 'device> {
+    pub(crate) fn new(inode: Inode<'fs, 'device>) -> Self {
+        Dir { inode }
+    }
+    pub fn inode(&self) -> &Inode<'fs, 'device> {
+        &self.inode
+    }
+    /// All the entries of the directory, including '.' and '..'
+    pub fn entries(&self) -> DirectoryEntries<'_, 'fs, 'device> {
+        self.inode
+            .get_dir_entries()
+            .expect("Dir was not created from a directory")
+    }
+
+    /// Number of entries in the directory, not counting '.' and '..'
+    pub fn len(&self) -> usize {
+        self.entries()
+            .filter(|entry| entry.name != "." && entry.name != "..")
+            .count()
+    }
+
+    /// A directory is empty if it only contains '.' and '..'
+    pub fn is_empty(&self) -> bool {
+        self.entries()
+            .all(|entry| entry.name == "." || entry.name == "..")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use std::vec::Vec;
+
+    use crate::inode::{Cursor, EntryKind, Permission};
+    use crate::tests::{formatted, load_image};
+    use crate::{Error, Ext2Device};
+
+    #[test]
+    fn empty() {
+        let mut image = load_image("test_fs_back");
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.open();
+
+        let dir = fs.create_dir(b"/dir", Permission::all(), 0, 0).unwrap();
+        let dir = fs.get_inode(dir).unwrap().as_dir().unwrap();
+        assert!(dir.is_empty());
+        assert_eq!(dir.len(), 0);
+
+        let thing = fs.get_inode(fs.lookup_path(b"/thing").unwrap()).unwrap();
+        assert!(!thing.as_dir().unwrap().is_empty());
+        assert_eq!(thing.as_dir().unwrap().len(), 1);
+        assert_eq!(fs.get_root().as_dir().unwrap().len(), 5);
+    }
+
+    #[test]
+    fn deleted_entries() {
+        let mut image = formatted(400 * 1024);
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.open();
+
+        let dir = fs.create_dir(b"/dir", Permission::all(), 0, 0).unwrap();
+        fs.create_file(b"/dir/a", Permission::all(), 0, 0).unwrap();
+        fs.create_file(b"/dir/b", Permission::all(), 0, 0).unwrap();
+        let dir = fs.get_inode(dir).unwrap().as_dir().unwrap();
+        assert_eq!(dir.len(), 2);
+
+        // '.' and '..' take 12 bytes each, 'a' too
+        Cursor::at(dir.inode(), 24).write(&[0; 4]).unwrap();
+        assert_eq!(dir.len(), 1);
+        Cursor::at(dir.inode(), 36).write(&[0; 4]).unwrap();
+        assert_eq!(dir.len(), 0);
+        assert!(dir.is_empty());
+    }
+
+    #[test]
+    fn invalid_kind() {
+        let mut image = formatted(400 * 1024);
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.open();
+
+        let dir = fs.create_dir(b"/dir", Permission::all(), 0, 0).unwrap();
+        fs.create_file(b"/dir/a", Permission::all(), 0, 0).unwrap();
+        let dir = fs.get_inode(dir).unwrap().as_dir().unwrap();
+        // The type of 'a', after '.' and '..' and the inode, size and name length of 'a'
+        Cursor::at(dir.inode(), 24 + 7).write(&[200]).unwrap();
+        let entry = dir.entries().find(|entry| entry.name == "a").unwrap();
+        assert!(matches!(entry.kind, EntryKind::Unkown));
+        assert!(matches!(
+            dir.entries().next().unwrap().kind,
+            EntryKind::Directory
+        ));
+    }
+
+    #[test]
+    fn corrupted_records() {
+        let mut image = formatted(400 * 1024);
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.open();
+        let dir = fs.create_dir(b"/dir", Permission::all(), 0, 0).unwrap();
+        fs.create_file(b"/dir/a", Permission::all(), 0, 0).unwrap();
+        fs.create_file(b"/dir/b", Permission::all(), 0, 0).unwrap();
+        let dir = fs.get_inode(dir).unwrap().as_dir().unwrap();
+        let names = || -> Vec<_> { dir.entries().map(|entry| entry.name).collect() };
+        assert_eq!(names(), [".", "..", "a", "b"]);
+
+        // The size and name length of 'a', then of 'b' which takes the rest of the block
+        let corruptions: [(u32, [u8; 3]); 6] = [
+            // Smaller than the header
+            (28, [4, 0, 1]),
+            (28, [1, 0, 1]),
+            (28, [0, 0, 1]),
+            // Not aligned
+            (28, [14, 0, 1]),
+            // Smaller than the name
+            (28, [12, 0, 255]),
+            // Past the end of the block
+            (40, [0xe4, 0x07, 1]),
+        ];
+        for (position, corruption) in corruptions {
+            let mut original = [0; 3];
+            Cursor::at(dir.inode(), position).read(&mut original);
+            Cursor::at(dir.inode(), position)
+                .write(&corruption)
+                .unwrap();
+            let expected: &[&str] = if position == 28 {
+                &[".", ".."]
+            } else {
+                &[".", "..", "a"]
+            };
+            assert_eq!(names(), expected, "{:?}", corruption);
+            assert_eq!(fs.lookup_path(b"/dir/b"), Err(Error::NotFound));
+            assert_eq!(
+                fs.create_file(b"/dir/c", Permission::all(), 0, 0),
+                Err(Error::Corrupt("directory entry"))
+            );
+            assert_eq!(fs.unlink(b"/dir/b"), Err(Error::NotFound));
+            Cursor::at(dir.inode(), position).write(&original).unwrap();
+        }
+        assert_eq!(names(), [".", "..", "a", "b"]);
+        assert_eq!(dir.inode().size(), 1024);
+    }
+
+    #[test]
+    fn multiple_blocks() {
+        let mut image = formatted(400 * 1024);
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.open();
+
+        let dir = fs.create_dir(b"/dir", Permission::all(), 0, 0).unwrap();
+        let mut path = [b'_'; 105];
+        path[..5].copy_from_slice(b"/dir/");
+        for i in 0..12 {
+            path[104] = b'a' + i;
+            fs.create_file(&path, Permission::all(), 0, 0).unwrap();
+        }
+
+        let dir = fs.get_inode(dir).unwrap().as_dir().unwrap();
+        assert_eq!(dir.inode().size(), 2048);
+        assert_eq!(dir.len(), 12);
+        assert!(!dir.is_empty());
+
+        // Garbage past the size of the directory must be ignored
+        Cursor::at(dir.inode(), 2048).write(&[1; 1024]).unwrap();
+        assert_eq!(dir.len(), 12);
+    }
+}
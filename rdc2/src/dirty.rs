@@ -0,0 +1,218 @@
+//! Tracking the blocks written to the device, see `FileSystem::enable_dirty_tracking`.
+//!
+//! Every block the filesystem hands out is hashed the first time, and hashed again when the
+//! dirty ranges are taken: the blocks whose content changed are dirty, whatever path wrote them,
+//! cursors, the allocators or the metadata edits. The superblock and the group descriptors, that
+//! the filesystem keeps pointers to, are always checked. A write that leaves a block as it was
+//! is not reported.
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use core::cmp::min;
+
+use super::device::BlockDevice;
+use super::{access, FileSystem};
+
+/// The hash of the blocks handed out since the last take, see the module documentation
+pub(crate) struct DirtyTracker {
+    block_size: usize,
+    hashes: BTreeMap<u32, u64>,
+}
+
+impl DirtyTracker {
+    /// Remember the content of the block index, at block, if it is the first time it is
+    /// handed out
+    pub(crate) unsafe fn hand_out(&mut self, index: u32, block: *const u8) {
+        let block_size = self.block_size;
+        self.hashes
+            .entry(index)
+            .or_insert_with(|| hash(block, block_size));
+    }
+}
+
+/// FNV-1a on words, a change of a single word always changes the hash
+pub(crate) unsafe fn hash(block: *const u8, len: usize) -> u64 {
+    let mut hash = 0xcbf2_9ce4_8422_2325;
+    let mut chunk = [0; 64];
+    // The block sizes are multiples of the chunk
+    for offset in (0..len).step_by(chunk.len()) {
+        access::copy_from_device(block.add(offset), chunk.as_mut_ptr(), chunk.len());
+        for word in chunk.chunks_exact(8) {
+            let mut bytes = [0; 8];
+            bytes.copy_from_slice(word);
+            hash = (hash ^ u64::from_le_bytes(bytes)).wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+    hash
+}
+
+impl<'device> FileSystem<'device> {
+    /// Start recording the blocks written to the device, for `take_dirty_ranges`. Every block
+    /// handed out is hashed, which slows the reads down.
+    ///
+    /// Does nothing on a filesystem opened with `open_device`, its pool already writes the
+    /// blocks that changed
+    pub fn enable_dirty_tracking(&mut self) {
+        if self.pool.is_some() {
+            return;
+        }
+        self.dirty = Some(RefCell::new(DirtyTracker {
+            block_size: self.block_size,
+            hashes: BTreeMap::new(),
+        }));
+        self.dirty_ranges(true);
+    }
+    pub fn disable_dirty_tracking(&mut self) {
+        self.dirty = None
+    }
+
+    /// The blocks written since the tracking was enabled or the ranges were last taken, as
+    /// (offset, len) byte ranges from the start of the filesystem, in order. Contiguous blocks
+    /// are coalesced. Nothing if the tracking is not enabled
+    pub fn take_dirty_ranges(&mut self) -> impl Iterator<Item = (u64, u64)> {
+        self.dirty_ranges(true).into_iter()
+    }
+
+    /// Write the dirty ranges to device at the same offsets, then flush it: the filesystem is
+    /// a copy of device in memory. The ranges are rounded to the sectors of device. Returns
+    /// the number of bytes written.
+    ///
+    /// The ranges are only taken once device is flushed, they are written again after an
+    /// error
+    pub fn write_back<D: BlockDevice>(&mut self, device: &mut D) -> Result<u64, D::Error> {
+        let sector = device.sector_size() as u64;
+        let end = u64::from(self.get_superblock().block_count) * self.block_size as u64;
+        let mut written = 0;
+        for (offset, len) in self.dirty_ranges(false) {
+            let start = offset - offset % sector;
+            let stop = min((offset + len).div_ceil(sector) * sector, end);
+            // The filesystem is borrowed exclusively, nothing writes to it
+            let data = unsafe {
+                core::slice::from_raw_parts(self.fs.add(start as usize), (stop - start) as usize)
+            };
+            device.write(start, data)?;
+            written += stop - start;
+        }
+        device.flush()?;
+        self.dirty_ranges(true);
+        Ok(written)
+    }
+
+    /// The dirty ranges, if clear the tracking starts over from the current content
+    fn dirty_ranges(&mut self, clear: bool) -> Vec<(u64, u64)> {
+        let metadata = self.metadata_blocks();
+        let block_size = self.block_size;
+        let device = self.fs;
+        let tracker = match &mut self.dirty {
+            Some(tracker) => tracker.get_mut(),
+            None => return Vec::new(),
+        };
+        let block = |index: u32| unsafe { device.add(index as usize * block_size) };
+
+        let mut ranges: Vec<(u64, u64)> = Vec::new();
+        for (&index, &hashed) in &tracker.hashes {
+            if unsafe { hash(block(index), block_size) } == hashed {
+                continue;
+            }
+            let offset = u64::from(index) * block_size as u64;
+            match ranges.last_mut() {
+                Some((start, len)) if *start + *len == offset => *len += block_size as u64,
+                _ => ranges.push((offset, block_size as u64)),
+            }
+        }
+        if clear {
+            tracker.hashes.clear();
+            for index in metadata {
+                unsafe { tracker.hand_out(index, block(index)) };
+            }
+        }
+        ranges
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use std::vec::Vec;
+
+    use crate::device::MemoryDevice;
+    use crate::inode::Permission;
+    use crate::tests::formatted;
+    use crate::{Ext2Device, OpenOptions};
+
+    #[test]
+    fn small_edit() {
+        let mut image = formatted(1 << 20);
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let mut fs = device.open();
+        fs.create_file(b"/file", Permission::all(), 0, 0).unwrap();
+        fs.open(b"/file", OpenOptions::new().write(true))
+            .unwrap()
+            .write(&[1; 1024])
+            .unwrap();
+        assert_eq!(fs.take_dirty_ranges().count(), 0);
+
+        fs.enable_dirty_tracking();
+        let inode = fs.lookup_path(b"/file").unwrap();
+        // Reads are not writes
+        fs.read_file(b"/file", false, |_| Ok::<_, ()>(())).unwrap();
+        assert_eq!(fs.take_dirty_ranges().count(), 0);
+
+        // A block is added to the file
+        fs.open(b"/file", OpenOptions::new().append(true))
+            .unwrap()
+            .write(&[2; 1024])
+            .unwrap();
+        let ranges: Vec<_> = fs.take_dirty_ranges().collect();
+        let group = fs.group_statistics(0).unwrap();
+        let data = fs.get_inode(inode).unwrap().block_at(1).unwrap();
+        let table = group.inode_table + (inode.0 - 1) * 128 / 1024;
+        // The superblock, the descriptors and the bitmap that follows them are coalesced
+        assert_eq!(group.block_bitmap, 3);
+        assert_eq!(
+            ranges,
+            [
+                (1024, 3 * 1024),
+                (u64::from(table) * 1024, 1024),
+                (u64::from(data) * 1024, 1024)
+            ]
+        );
+        assert_eq!(fs.take_dirty_ranges().count(), 0);
+
+        // Writing the same content is not a change
+        fs.open(b"/file", OpenOptions::new().write(true))
+            .unwrap()
+            .write(&[1; 1024])
+            .unwrap();
+        assert_eq!(fs.take_dirty_ranges().count(), 0);
+
+        fs.disable_dirty_tracking();
+        fs.unlink(b"/file").unwrap();
+        assert_eq!(fs.take_dirty_ranges().count(), 0);
+    }
+
+    #[test]
+    fn write_back() {
+        let mut image = formatted(1 << 20);
+        let mut backup = image.clone();
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let mut fs = device.open();
+        fs.enable_dirty_tracking();
+        fs.mount_writable();
+        fs.create_dir(b"/dir", Permission::all(), 0, 0).unwrap();
+        fs.open(b"/dir/file", OpenOptions::new().write(true).create(true))
+            .unwrap()
+            .write(&[3; 5000])
+            .unwrap();
+        fs.unmount().unwrap();
+
+        let mut memory = MemoryDevice::new(&mut backup);
+        let written = fs.write_back(&mut memory).unwrap();
+        // Far less than the 1024 blocks
+        assert!(written < 20 * 1024, "{}", written);
+        assert_eq!(fs.write_back(&mut memory).unwrap(), 0);
+        drop(fs);
+        assert!(image == backup);
+    }
+}
@@ -0,0 +1,147 @@
+use crate::inode::InodeRef;
+
+/// The errors that can happen when manipulating the filesystem
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// A component of the path does not exist
+    NotFound,
+    /// The file already exists
+    AlreadyExists,
+    /// A directory was found where a regular file was expected
+    IsADirectory,
+    /// A component of the path was used as a directory but is not one
+    NotADirectory,
+    /// The inode is neither a regular file nor a directory
+    NotAFile,
+    /// A name is longer than 255 bytes
+    NameTooLong,
+    /// The arguments given are not consistent
+    InvalidArgument,
+    /// The inode number is 0 or past the inodes of the filesystem
+    BadInodeRef,
+    /// All the inodes of the filesystem are used
+    NoFreeInodes,
+    /// All the blocks of the filesystem are used
+    NoFreeBlocks,
+    /// The file would need more blocks than an inode can reference
+    FileTooLarge,
+    /// The superblock is missing the ext2 signature or describes an impossible layout
+    InvalidSuperblock,
+    /// The filesystem uses something this driver does not handle
+    UnsupportedFeature(&'static str),
+    /// The filesystem is read-only, see `FileSystem::is_read_only`
+    ReadOnly,
+    /// The metadata of the filesystem is inconsistent
+    Corrupt(&'static str),
+    /// A block is past what a pointer can address on this target
+    OffsetOverflow,
+    /// The extended attributes of an inode do not fit in their block
+    XattrTooLarge,
+    /// The block is in use past the end given to `FileSystem::shrink`, its content must be
+    /// moved first
+    BlockInUse(u32),
+    /// The inode is in use in a group removed by `FileSystem::shrink`
+    InodeInUse(InodeRef),
+    /// The writer given to `FileSystem::export_tar` or the sink given to `Inode::read_all`
+    /// failed
+    WriteFailed,
+    /// A path goes through more than `MAX_SYMLINKS` symlinks, see `FileSystem::read_file`
+    TooManySymlinks,
+    /// The file was opened without write or append
+    NotWritable,
+    /// The inode is already open by a writer, or by anyone when opening it for writing, see
+    /// the `exclusive` feature. Or a `Transaction` of the filesystem was leaked
+    Busy,
+    /// `registry::MAX_OPEN_INODES` inodes are already open
+    TooManyOpenFiles,
+    /// The directory has entries other than '.' and '..', see `FileSystem::rmdir`
+    DirectoryNotEmpty,
+    /// The `BlockDevice` of a filesystem opened with `FileSystem::open_device` failed
+    DeviceFailed,
+    /// The pool of a filesystem opened with `FileSystem::open_device` holds its limit of
+    /// blocks, `FileSystem::flush_device` gives them back
+    PoolFull,
+}
+
+/// The bits of the required or write features of the superblock that this driver does not
+/// implement
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnsupportedFeatures(pub u32);
+
+/// The reasons a region can't be opened as an ext2 filesystem, see `Ext2Device::try_open`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpenError {
+    /// The superblock does not have the ext2 signature
+    BadSignature,
+    /// The revision of the superblock is not 0 or 1
+    UnsupportedRevision(u32),
+    /// The filesystem requires features this driver does not implement
+    Unsupported(UnsupportedFeatures),
+    /// The sizes and counts of the superblock do not fit together
+    InvalidGeometry(&'static str),
+    /// The blocks are 64KiB, their directory entries would need the encoding of record lengths
+    /// that don't fit in 16 bits, which is not implemented
+    UnsupportedBlockSize(u32),
+    /// The filesystem has errors and asks for a panic when they are found, see
+    /// `Superblock::on_error`
+    Errored,
+    /// The `BlockDevice` given to `FileSystem::open_device` failed
+    DeviceFailed,
+}
+
+impl From<OpenError> for Error {
+    fn from(error: OpenError) -> Self {
+        match error {
+            OpenError::BadSignature | OpenError::InvalidGeometry(_) => Error::InvalidSuperblock,
+            OpenError::UnsupportedRevision(_) => Error::UnsupportedFeature("superblock revision"),
+            OpenError::Unsupported(_) => Error::UnsupportedFeature("required feature"),
+            OpenError::UnsupportedBlockSize(_) => Error::UnsupportedFeature("64KiB blocks"),
+            OpenError::Errored => Error::Corrupt("filesystem has errors"),
+            OpenError::DeviceFailed => Error::DeviceFailed,
+        }
+    }
+}
+
+/// The errors that can happen when creating an inode in a directory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CreateError {
+    /// The parent is not a directory
+    NotADirectory,
+    /// All the inodes of the filesystem are used
+    NoFreeInodes,
+    /// A block was needed but all the blocks of the filesystem are used
+    NoFreeBlocks,
+    /// The name is longer than 255 bytes
+    NameTooLong,
+    /// The directory already contains an entry with that name
+    AlreadyExists,
+    /// The directory can't reference more blocks
+    DirectoryFull,
+    /// EntryKind::Unkown can't be created
+    UnknownKind,
+    /// Devices and symlinks can't be created yet
+    UnsupportedKind,
+    /// The filesystem is read-only
+    ReadOnly,
+    /// An entry of the directory is corrupted
+    Corrupt,
+}
+
+impl From<CreateError> for Error {
+    fn from(error: CreateError) -> Self {
+        match error {
+            CreateError::NotADirectory => Error::NotADirectory,
+            CreateError::NoFreeInodes => Error::NoFreeInodes,
+            CreateError::NoFreeBlocks => Error::NoFreeBlocks,
+            CreateError::NameTooLong => Error::NameTooLong,
+            CreateError::AlreadyExists => Error::AlreadyExists,
+            CreateError::DirectoryFull => Error::FileTooLarge,
+            CreateError::UnknownKind => Error::InvalidArgument,
+            CreateError::UnsupportedKind => {
+                Error::UnsupportedFeature("creating devices or symlinks")
+            }
+            CreateError::ReadOnly => Error::ReadOnly,
+            CreateError::Corrupt => Error::Corrupt("directory entry"),
+        }
+    }
+}
@@ -0,0 +1,711 @@
+use core::convert::TryFrom;
+
+use super::inode::{Cursor, Inode, Permission};
+use super::Error;
+
+/// The position to move to in `File::seek`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeekFrom {
+    Start(u32),
+    End(i64),
+    Current(i64),
+}
+
+/// Options to configure how a file is opened by `FileSystem::open`, modeled on
+/// `std::fs::OpenOptions`
+#[derive(Debug, Clone, Copy)]
+pub struct OpenOptions {
+    pub(crate) read: bool,
+    pub(crate) write: bool,
+    pub(crate) append: bool,
+    pub(crate) truncate: bool,
+    pub(crate) create: bool,
+    pub(crate) create_new: bool,
+
+    pub(crate) permissions: Permission,
+    pub(crate) user_id: u16,
+    pub(crate) group_id: u16,
+}
+
+impl Default for OpenOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl OpenOptions {
+    /// All the options are false, files are created with rw-r--r-- owned by root
+    pub fn new() -> Self {
+        OpenOptions {
+            read: false,
+            write: false,
+            append: false,
+            truncate: false,
+            create: false,
+            create_new: false,
+            permissions: Permission::USER_READ
+                | Permission::USER_WRITE
+                | Permission::GROUP_READ
+                | Permission::OTHER_READ,
+            user_id: 0,
+            group_id: 0,
+        }
+    }
+    pub fn read(mut self, read: bool) -> Self {
+        self.read = read;
+        self
+    }
+    pub fn write(mut self, write: bool) -> Self {
+        self.write = write;
+        self
+    }
+    /// Start at the end of the file, implies write
+    pub fn append(mut self, append: bool) -> Self {
+        self.append = append;
+        self
+    }
+    /// Truncate the file to 0 bytes, needs write
+    pub fn truncate(mut self, truncate: bool) -> Self {
+        self.truncate = truncate;
+        self
+    }
+    /// Create the file if it does not exist, needs write
+    pub fn create(mut self, create: bool) -> Self {
+        self.create = create;
+        self
+    }
+    /// Create the file, failing if it already exists. Needs write
+    pub fn create_new(mut self, create_new: bool) -> Self {
+        self.create_new = create_new;
+        self
+    }
+    /// Permissions of a created file
+    pub fn permissions(mut self, permissions: Permission) -> Self {
+        self.permissions = permissions;
+        self
+    }
+    /// Owner of a created file. Unless it is privileged (see `FileSystem::is_privileged`) the
+    /// blocks reserved for the superuser can't be used by the writes
+    pub fn owner(mut self, user_id: u16, group_id: u16) -> Self {
+        self.user_id = user_id;
+        self.group_id = group_id;
+        self
+    }
+
+    pub(crate) fn check(&self) -> Result<(), Error> {
+        let write = self.write || self.append;
+        if !self.read && !write {
+            return Err(Error::InvalidArgument);
+        }
+        if !write && (self.truncate || self.create || self.create_new) {
+            return Err(Error::InvalidArgument);
+        }
+        if self.append && self.truncate {
+            return Err(Error::InvalidArgument);
+        }
+        Ok(())
+    }
+}
+
+/// A regular file.
+///
+/// Unlike a bare `Cursor` this keeps the inode consistent with what is written: the size grows
+/// with the writes and the modification time is updated when the file is synced or dropped.
+///
+/// With the `exclusive` feature, the file is recorded as a reader or the writer of its inode
+/// until it is dropped, see `Inode::open_file`
+#[repr(C)]
+pub struct File<'fs, 'device> {
+    inode: Inode<'fs, 'device>,
+    position: u32,
+    modified: bool,
+    privileged: bool,
+    writable: bool,
+}
+
+impl<'fs, 'device> File<'fs, 'device> {
+    /// The inode must have been acquired in the registry of the filesystem
+    pub(crate) fn new(inode: Inode<'fs, 'device>, writable: bool) -> Self {
+        inode.enable_preallocation();
+        File {
+            inode,
+            position: 0,
+            modified: false,
+            privileged: true,
+            writable,
+        }
+    }
+    /// Whether the writes can use the blocks reserved for the superuser, true by default
+    pub fn set_privileged(&mut self, privileged: bool) {
+        self.privileged = privileged;
+    }
+    pub fn inode(&self) -> &Inode<'fs, 'device> {
+        &self.inode
+    }
+    pub fn size(&self) -> u32 {
+        self.inode.size()
+    }
+    pub fn position(&self) -> u32 {
+        self.position
+    }
+
+    /// Read at most buffer.len() bytes, stopping at the end of the file
+    pub fn read(&mut self, buffer: &mut [u8]) -> usize {
+        let remaining = self.size().saturating_sub(self.position) as usize;
+        let len = core::cmp::min(remaining, buffer.len());
+        let read = Cursor::at(&self.inode, self.position).read(&mut buffer[..len]);
+        self.position += read as u32;
+        read
+    }
+
+    /// Write all of data at the current position, growing the file if needed.
+    /// Writing past the end of the file fills the gap with zeros.
+    ///
+    /// Returns NoFreeBlocks if the filesystem is full, or FileTooLarge past the blocks an inode
+    /// can reference. What could be written before that is kept. NotWritable if the file was
+    /// opened for reading
+    pub fn write(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.check_writable()?;
+        if self.position > self.size() {
+            self.extend(self.position)?;
+        }
+        let mut cursor = Cursor::at(&self.inode, self.position).privileged(self.privileged);
+        let written = cursor.write(data);
+        self.position = cursor.position();
+        if self.position > self.size() {
+            self.inode.set_size(self.position);
+        }
+        self.modified = true;
+        written
+    }
+
+    /// Move the position in the file, returns the new position or None if it would be out of
+    /// the range of the file offsets. Seeking past the end is allowed
+    pub fn seek(&mut self, pos: SeekFrom) -> Option<u32> {
+        let new_position = match pos {
+            SeekFrom::Start(offset) => i64::from(offset),
+            SeekFrom::End(offset) => i64::from(self.size()).checked_add(offset)?,
+            SeekFrom::Current(offset) => i64::from(self.position).checked_add(offset)?,
+        };
+        self.position = u32::try_from(new_position).ok()?;
+        Some(self.position)
+    }
+
+    /// Truncate or extend the file to len bytes, extending fills the file with zeros.
+    /// The position is left untouched
+    pub fn set_len(&mut self, len: u32) -> Result<(), Error> {
+        self.check_writable()?;
+        self.modified = true;
+        if len < self.size() {
+            self.discard_preallocation();
+            self.inode.truncate_blocks(len)
+        } else {
+            self.extend(len)
+        }
+    }
+
+    /// Write the metadata that is not updated on each write. The preallocated blocks are given
+    /// back with `discard_preallocation`, the bitmaps only count the blocks of the file
+    pub fn sync(&mut self) {
+        if self.modified {
+            if let Some(now) = self.inode.fs.now() {
+                self.inode.set_modification_time(now);
+            }
+            self.modified = false;
+        }
+        self.discard_preallocation();
+    }
+
+    /// Give back the blocks reserved ahead of the writes when the filesystem asks for
+    /// preallocation (see `OptionalFeatures::PREALLOCATE`). This is done by `sync` and when the
+    /// file is dropped
+    pub fn discard_preallocation(&mut self) {
+        self.inode.discard_preallocation()
+    }
+
+    fn check_writable(&self) -> Result<(), Error> {
+        if self.inode.fs.read_only {
+            return Err(Error::ReadOnly);
+        }
+        if !self.writable {
+            return Err(Error::NotWritable);
+        }
+        Ok(())
+    }
+
+    fn extend(&mut self, len: u32) -> Result<(), Error> {
+        const ZEROES: [u8; 128] = [0; 128];
+
+        let mut cursor = Cursor::at(&self.inode, self.size()).privileged(self.privileged);
+        while cursor.position() < len {
+            let amount = core::cmp::min(ZEROES.len() as u32, len - cursor.position());
+            if let Err(error) = cursor.write(&ZEROES[..amount as usize]) {
+                self.inode.set_size(cursor.position());
+                return Err(error);
+            }
+        }
+        self.inode.set_size(len);
+        Ok(())
+    }
+}
+
+impl<'fs, 'device> Drop for File<'fs, 'device> {
+    fn drop(&mut self) {
+        self.sync();
+        #[cfg(feature = "exclusive")]
+        self.inode
+            .fs
+            .open_inodes
+            .release(self.inode.inode_ref().0, self.writable);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use std::vec::Vec;
+
+    use super::{OpenOptions, Permission, SeekFrom};
+    use crate::inode::InodeData;
+    use crate::metadata::OptionalFeatures;
+    use crate::tests::{check_group_counters, formatted, load_image};
+    use crate::{Error, Ext2Device, FileSystem, Inode};
+
+    fn find<'fs, 'device>(fs: &'fs FileSystem<'device>, name: &str) -> Inode<'fs, 'device> {
+        let entry = fs
+            .get_root()
+            .get_dir_entries()
+            .unwrap()
+            .find(|entry| entry.name == name)
+            .unwrap();
+        fs.get_inode(entry.inode).unwrap()
+    }
+
+    fn content(inode: &Inode<'_, '_>) -> Vec<u8> {
+        let mut data = Vec::new();
+        inode
+            .read_all(|block| {
+                data.extend_from_slice(block);
+                Ok::<_, ()>(())
+            })
+            .unwrap();
+        data
+    }
+
+    #[test]
+    fn read_write() {
+        let mut image = load_image("test_fs_back");
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.open();
+        let foo = find(&fs, "foo.txt");
+
+        assert_eq!(content(&foo), b"ZING\n");
+
+        let mut file = foo.as_file().unwrap();
+        assert_eq!(file.seek(SeekFrom::End(-1)), Some(4));
+        file.write(b" ZANG\n").unwrap();
+        assert_eq!(file.size(), 10);
+        assert_eq!(file.seek(SeekFrom::Current(-11)), None);
+        drop(file);
+
+        assert_eq!(content(&foo), b"ZING ZANG\n");
+    }
+
+    #[test]
+    fn write_past_end() {
+        let mut image = load_image("test_fs_back");
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.open();
+        let foo = find(&fs, "foo.txt");
+
+        let mut file = foo.as_file().unwrap();
+        file.seek(SeekFrom::Start(2000));
+        file.write(b"end").unwrap();
+        assert_eq!(file.size(), 2003);
+        drop(file);
+
+        let data = content(&foo);
+        assert_eq!(&data[..5], b"ZING\n");
+        assert!(data[5..2000].iter().all(|&b| b == 0));
+        assert_eq!(&data[2000..], b"end");
+    }
+
+    #[test]
+    fn set_len() {
+        let mut image = load_image("test_fs_back");
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.open();
+        let foo = find(&fs, "foo.txt");
+
+        let mut file = foo.as_file().unwrap();
+        file.set_len(3).unwrap();
+        assert_eq!(file.size(), 3);
+        file.set_len(1500).unwrap();
+        assert_eq!(file.size(), 1500);
+        drop(file);
+
+        let data = content(&foo);
+        assert_eq!(&data[..3], b"ZIN");
+        assert!(data[3..].iter().all(|&b| b == 0));
+
+        let mut file = foo.as_file().unwrap();
+        file.set_len(0).unwrap();
+        drop(file);
+        assert_eq!(unsafe { (*foo.get_data()).direct_block_pointers }, [0; 12]);
+    }
+
+    #[test]
+    fn blocks_used() {
+        let mut image = load_image("test_fs_back");
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.open();
+        let foo = find(&fs, "foo.txt");
+        assert_eq!(foo.blocks_used(), 2);
+
+        let inode = fs.create_file(b"/file", Permission::all(), 0, 0).unwrap();
+        let inode = fs.get_inode(inode).unwrap();
+        assert_eq!(inode.blocks_used(), 0);
+        let mut file = inode.as_file().unwrap();
+        file.write(&[1; 5 * 1024]).unwrap();
+        assert_eq!(inode.blocks_used(), 5 * 1024 / 512);
+        file.set_len(1025).unwrap();
+        assert_eq!(inode.blocks_used(), 2 * 1024 / 512);
+        file.set_len(0).unwrap();
+        assert_eq!(inode.blocks_used(), 0);
+    }
+
+    #[test]
+    fn reserved_blocks() {
+        let mut image = load_image("test_fs_tiny");
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.open();
+        fs.update_superblock(|superblock| superblock.block_superuser = 20);
+        assert_eq!(fs.statistics(false).free_blocks, 43);
+        let block = [1; 1024];
+
+        let user = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .owner(1000, 1000);
+        let mut file = fs.open(b"/user", user).unwrap();
+        for _ in 0..12 {
+            file.write(&block).unwrap();
+        }
+        let mut file = fs.open(b"/user_2", user).unwrap();
+        for _ in 0..11 {
+            file.write(&block).unwrap();
+        }
+        assert_eq!(fs.statistics(false).free_blocks, 20);
+        assert_eq!(file.write(&block), Err(Error::NoFreeBlocks));
+        assert_eq!(
+            fs.create_dir(b"/dir", Permission::all(), 1000, 1000),
+            Err(Error::NoFreeBlocks)
+        );
+
+        let root = OpenOptions::new().write(true).create(true);
+        let mut file = fs.open(b"/root", root).unwrap();
+        for _ in 0..12 {
+            file.write(&block).unwrap();
+        }
+        fs.create_dir(b"/dir", Permission::all(), 0, 0).unwrap();
+        assert_eq!(fs.statistics(false).free_blocks, 7);
+    }
+
+    #[test]
+    fn too_large() {
+        let mut image = formatted(400 * 1024);
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.open();
+        let mut file = fs
+            .open(b"/file", OpenOptions::new().write(true).create(true))
+            .unwrap();
+        // Only the 12 direct blocks can be used
+        assert_eq!(file.write(&[1; 13 * 1024]), Err(Error::FileTooLarge));
+        assert_eq!(file.size(), 12 * 1024);
+        assert_eq!(file.set_len(13 * 1024), Err(Error::FileTooLarge));
+        assert_eq!(file.size(), 12 * 1024);
+        file.set_len(0).unwrap();
+        file.sync();
+        check_group_counters(&fs);
+    }
+
+    #[test]
+    fn corrupted_block_pointers() {
+        let mut image = load_image("test_fs_back");
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.open();
+        let free = fs.statistics(true);
+        let inode = find(&fs, "foo.txt");
+        let data = inode.get_data() as *mut InodeData;
+        unsafe { (*data).direct_block_pointers[0] = u32::MAX };
+
+        let mut file = inode.as_file().unwrap();
+        assert_eq!(file.read(&mut [0; 16]), 0);
+        assert_eq!(
+            file.write(b"wild"),
+            Err(Error::Corrupt("block number out of range"))
+        );
+        file.set_len(0).unwrap();
+        assert_eq!(unsafe { (*data).direct_block_pointers[0] }, 0);
+        assert_eq!(fs.statistics(true), free);
+
+        // A directory whose first block is out of the filesystem
+        let thing = find(&fs, "thing");
+        unsafe { (*(thing.get_data() as *mut InodeData)).direct_block_pointers[0] = 400 };
+        assert_eq!(thing.get_dir_entries().unwrap().count(), 0);
+        assert_eq!(fs.lookup_path(b"/thing/more"), Err(Error::NotFound));
+        assert_eq!(
+            fs.create_file(b"/thing/new", Permission::all(), 0, 0),
+            Err(Error::Corrupt("directory entry"))
+        );
+        assert_eq!(fs.statistics(true), free);
+    }
+
+    #[test]
+    fn random_block_pointers() {
+        let image = load_image("test_fs_back");
+        // xorshift, the test must be reproducible
+        let mut state = 0x9e37_79b9_7f4a_7c15u64;
+        let mut random = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u32
+        };
+        for _ in 0..500 {
+            let mut image = image.clone();
+            let mut device = Ext2Device::from_slice(&mut image).unwrap();
+            let fs = device.open();
+            let inode = find(&fs, "foo.txt");
+            let data = inode.get_data() as *mut InodeData;
+            for index in 0..12 {
+                let pointer = match random() % 3 {
+                    0 => 0,
+                    1 => random() % 400,
+                    _ => random(),
+                };
+                unsafe { (*data).direct_block_pointers[index] = pointer };
+            }
+            unsafe { (*data).size_lower_32_bits = random() % (12 * 1024) };
+            let mut file = inode.as_file().unwrap();
+            file.read(&mut [0; 12 * 1024]);
+            file.seek(SeekFrom::Start(random() % (12 * 1024))).unwrap();
+            let _ = file.write(&[0xaa; 2048]);
+        }
+    }
+
+    #[test]
+    fn preallocation() {
+        let mut image = load_image("test_fs_back");
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.open();
+        fs.update_extended_superblock(|extended| {
+            extended.optional_features =
+                { extended.optional_features } | OptionalFeatures::PREALLOCATE;
+            extended.number_of_blocks_to_preallocate_files = 4;
+        });
+        let free = fs.statistics(false).free_blocks;
+        let block = [1; 1024];
+
+        let mut file = fs
+            .open(b"/file", OpenOptions::new().write(true).create(true))
+            .unwrap();
+        file.write(&block).unwrap();
+        assert_eq!(file.inode().blocks_used(), 2);
+        assert_eq!(fs.statistics(false).free_blocks, free - 4);
+        file.discard_preallocation();
+        assert_eq!(fs.statistics(false).free_blocks, free - 1);
+        check_group_counters(&fs);
+
+        // Another allocation aiming right after the file lands after the preallocated blocks
+        file.write(&block).unwrap();
+        let first = unsafe { (*file.inode().get_data()).direct_block_pointers[0] };
+        let other = fs.reserve_block(first + 2, true).unwrap();
+        assert_eq!(other, first + 5);
+        for _ in 0..3 {
+            file.write(&block).unwrap();
+        }
+        let blocks = unsafe { (*file.inode().get_data()).direct_block_pointers };
+        assert_eq!(
+            blocks[..5],
+            [first, first + 1, first + 2, first + 3, first + 4]
+        );
+
+        // The unused blocks are given back when the file is synced or dropped
+        file.write(&block).unwrap();
+        assert_eq!(fs.statistics(false).free_blocks, free - 10);
+        file.sync();
+        assert_eq!(fs.statistics(false).free_blocks, free - 7);
+        file.write(&block).unwrap();
+        assert_eq!(fs.statistics(false).free_blocks, free - 11);
+        drop(file);
+        assert_eq!(fs.statistics(false).free_blocks, free - 8);
+        check_group_counters(&fs);
+    }
+
+    #[test]
+    fn modification_time() {
+        let mut image = load_image("test_fs_back");
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let mut fs = device.open();
+        extern "C" fn clock() -> u32 {
+            1_000_000
+        }
+        fs.set_clock(clock);
+        let foo = find(&fs, "foo.txt");
+
+        let mut file = foo.as_file().unwrap();
+        file.write(b"ZOUNG").unwrap();
+        assert_ne!(
+            unsafe { (*foo.get_data()).last_modification_time },
+            1_000_000
+        );
+        file.sync();
+        assert_eq!(
+            unsafe { (*foo.get_data()).last_modification_time },
+            1_000_000
+        );
+    }
+
+    #[test]
+    fn open() {
+        let mut image = load_image("test_fs_back");
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.open();
+        let read = OpenOptions::new().read(true);
+        let write = OpenOptions::new().write(true);
+
+        assert_eq!(fs.open(b"/foo.txt", read).unwrap().size(), 5);
+        assert_eq!(fs.open(b"/bar.txt", read).err(), Some(Error::NotFound));
+        assert_eq!(fs.open(b"/thing", read).err(), Some(Error::IsADirectory));
+        assert_eq!(
+            fs.open(b"/foo.txt/bar", read).err(),
+            Some(Error::NotADirectory)
+        );
+        assert_eq!(
+            fs.open(b"/foo.txt", read.create(true)).err(),
+            Some(Error::InvalidArgument)
+        );
+        assert_eq!(
+            fs.open(b"/foo.txt", write.create_new(true)).err(),
+            Some(Error::AlreadyExists)
+        );
+
+        let mut file = fs
+            .open(b"/foo.txt", OpenOptions::new().append(true))
+            .unwrap();
+        assert_eq!(file.position(), 5);
+        file.write(b"ZANG\n").unwrap();
+        drop(file);
+        assert_eq!(
+            content(fs.open(b"/foo.txt", read).unwrap().inode()),
+            b"ZING\nZANG\n"
+        );
+
+        let file = fs.open(b"/foo.txt", write.truncate(true)).unwrap();
+        assert_eq!(file.size(), 0);
+    }
+
+    #[test]
+    fn open_create() {
+        let mut image = load_image("test_fs_back");
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.open();
+
+        let mut file = fs
+            .open(
+                b"/thing/more/new.txt",
+                OpenOptions::new().write(true).create(true),
+            )
+            .unwrap();
+        file.write(b"new file").unwrap();
+        drop(file);
+
+        let file = fs
+            .open(b"/thing/more/new.txt", OpenOptions::new().read(true))
+            .unwrap();
+        assert_eq!(content(file.inode()), b"new file");
+        assert_eq!(
+            fs.open(
+                b"/thing/nope/new.txt",
+                OpenOptions::new().write(true).create(true)
+            )
+            .err(),
+            Some(Error::NotFound)
+        );
+    }
+
+    #[test]
+    fn not_writable() {
+        let mut image = load_image("test_fs_back");
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.open();
+        let mut file = fs.open(b"/foo.txt", OpenOptions::new().read(true)).unwrap();
+        assert_eq!(file.write(b"no"), Err(Error::NotWritable));
+        assert_eq!(file.set_len(0), Err(Error::NotWritable));
+        let mut data = [0; 8];
+        assert_eq!(file.read(&mut data), 5);
+        assert_eq!(
+            fs.get_root().open_file(false).err(),
+            Some(Error::IsADirectory)
+        );
+    }
+
+    #[cfg(feature = "exclusive")]
+    #[test]
+    fn exclusive() {
+        let mut image = formatted(1 << 20);
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.open();
+        let read = OpenOptions::new().read(true);
+        let write = OpenOptions::new().write(true).create(true);
+
+        let mut writer = fs.open(b"/file", write).unwrap();
+        assert_eq!(fs.open(b"/file", write).err(), Some(Error::Busy));
+        assert_eq!(fs.open(b"/file", read).err(), Some(Error::Busy));
+        assert!(writer.inode().as_file().is_none());
+        // The inode only changes through its file
+        let inode = writer.inode();
+        assert_eq!(inode.truncate(0).err(), Some(Error::Busy));
+        assert_eq!(inode.allocate(0, 1024, false).err(), Some(Error::Busy));
+        assert_eq!(inode.set_flags(inode.flags()).err(), Some(Error::Busy));
+        assert_eq!(inode.set_xattr(b"user.a", b"b").err(), Some(Error::Busy));
+        assert_eq!(inode.remove_xattr(b"user.a").err(), Some(Error::Busy));
+        writer.set_len(0).unwrap();
+        // Other inodes are not held
+        let other = fs.open(b"/other", write).unwrap();
+        drop(writer);
+
+        let reader = fs.open(b"/file", read).unwrap();
+        let second = fs.open(b"/file", read).unwrap();
+        assert_eq!(fs.open(b"/file", write).err(), Some(Error::Busy));
+        drop(reader);
+        assert_eq!(fs.open(b"/file", write).err(), Some(Error::Busy));
+        drop(second);
+        let mut writer = fs.open(b"/file", write).unwrap();
+        writer.write(b"alone").unwrap();
+        drop((writer, other));
+
+        // The table of open inodes is full
+        let mut files = Vec::new();
+        for index in 0..crate::registry::MAX_OPEN_INODES {
+            let path = std::format!("/{}", index);
+            files.push(fs.open(path.as_bytes(), write).unwrap());
+        }
+        assert_eq!(fs.open(b"/file", read).err(), Some(Error::TooManyOpenFiles));
+        files.pop();
+        let reader = fs.open(b"/file", read).unwrap();
+        // Another reader of an open inode fits in its slot
+        let second = fs.open(b"/file", read).unwrap();
+        assert_eq!(
+            fs.open(b"/other", read).err(),
+            Some(Error::TooManyOpenFiles)
+        );
+        drop((reader, second));
+        drop(files);
+        assert_eq!(
+            content(&fs.get_inode(fs.lookup_path(b"/file").unwrap()).unwrap()),
+            b"alone"
+        );
+    }
+}
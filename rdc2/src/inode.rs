@@ -1,7 +1,10 @@
 use bitflags::bitflags;
 use bstr::{BStr, ByteSlice};
 
-use super::FileSystem;
+use super::acl::Acl;
+use super::xattr::{self, Xattrs};
+use super::{access, CreateError, Dir, Error, File, FileSystem};
+use core::cell::Cell;
 use core::convert::TryFrom;
 
 /// A reference to an inode
@@ -9,17 +12,35 @@ use core::convert::TryFrom;
 #[repr(transparent)]
 pub struct InodeRef(pub(crate) u32);
 
+impl InodeRef {
+    /// None for 0, which never references an inode. Whether the inode exists in a filesystem is
+    /// checked by `FileSystem::get_inode`
+    pub const fn new(inode: u32) -> Option<InodeRef> {
+        match inode {
+            0 => None,
+            _ => Some(InodeRef(inode)),
+        }
+    }
+    pub const fn root() -> InodeRef {
+        InodeRef(2)
+    }
+    pub const fn get(self) -> u32 {
+        self.0
+    }
+}
+
 pub const fn root_inode() -> InodeRef {
-    InodeRef(2)
+    InodeRef::root()
 }
 
 #[derive(Debug)]
-#[repr(C)]
-struct RawDirectoryEntry {
+#[repr(C, packed)]
+pub(crate) struct RawDirectoryEntry {
     pub inode: InodeRef,
     pub size: u16,
     pub name_len: u8,
-    pub kind: EntryKind,
+    /// Read from the image, it may be any value, see `EntryKind::from`
+    pub kind: u8,
 }
 
 #[derive(Debug)]
@@ -31,11 +52,13 @@ pub struct DirectoryEntry<'fs> {
 }
 
 impl RawDirectoryEntry {
+    /// The record must have been checked to hold the name, see `DirectoryEntries::read_raw_entry`
     unsafe fn from_ptr_mut<'fs>(entry: *mut u8) -> (*mut RawDirectoryEntry, &'fs BStr) {
         let dir_entry = entry as *mut RawDirectoryEntry;
-        let name_start = entry.offset(core::mem::size_of::<RawDirectoryEntry>() as isize);
+        let name_start = entry.add(core::mem::size_of::<RawDirectoryEntry>());
         let name_slice =
-            core::slice::from_raw_parts(name_start, (*dir_entry).name_len as usize).as_bstr();
+            core::slice::from_raw_parts(name_start, read_field!(dir_entry, name_len) as usize)
+                .as_bstr();
         (dir_entry, name_slice)
     }
 }
@@ -46,16 +69,16 @@ impl<'fs> DirectoryEntry<'fs> {
         name: &'fs BStr,
     ) -> DirectoryEntry<'fs> {
         DirectoryEntry {
-            inode: (*dir_entry).inode,
-            kind: (*dir_entry).kind,
-            size: (*dir_entry).size,
+            inode: read_field!(dir_entry, inode),
+            kind: EntryKind::from(read_field!(dir_entry, kind)),
+            size: read_field!(dir_entry, size),
             name,
         }
     }
 }
 
 #[repr(u8)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum EntryKind {
     Unkown = 0,
     RegularFile = 1,
@@ -66,10 +89,28 @@ pub enum EntryKind {
     Socket = 6,
     Symlink = 7,
 }
-impl EntryKind {
-    fn to_typeperm(&self) -> TypePermission {
-        match self {
-            EntryKind::Unkown => panic!("Unkown has no type"),
+/// The values that are not a known type are Unkown
+impl From<u8> for EntryKind {
+    fn from(kind: u8) -> Self {
+        match kind {
+            1 => EntryKind::RegularFile,
+            2 => EntryKind::Directory,
+            3 => EntryKind::CharDevice,
+            4 => EntryKind::BlockDevice,
+            5 => EntryKind::Fifo,
+            6 => EntryKind::Socket,
+            7 => EntryKind::Symlink,
+            _ => EntryKind::Unkown,
+        }
+    }
+}
+
+/// Unkown has no type
+impl TryFrom<EntryKind> for TypePermission {
+    type Error = CreateError;
+    fn try_from(kind: EntryKind) -> Result<Self, CreateError> {
+        Ok(match kind {
+            EntryKind::Unkown => return Err(CreateError::UnknownKind),
             EntryKind::RegularFile => TypePermission::REGULAR_FILE,
             EntryKind::Directory => TypePermission::DIR,
             EntryKind::CharDevice => TypePermission::CHAR_DEVICE,
@@ -77,10 +118,40 @@ impl EntryKind {
             EntryKind::Fifo => TypePermission::FIFO,
             EntryKind::Socket => TypePermission::UNIX_SOCKET,
             EntryKind::Symlink => TypePermission::SYMBOLIC_LINK,
-        }
+        })
     }
 }
 
+impl EntryKind {
+    /// The types are not single bits, a symlink contains the bits of a regular file
+    pub(crate) fn from_typeperm(type_permission: TypePermission) -> Self {
+        let ty = type_permission & TypePermission::TYPE_MASK;
+        [
+            EntryKind::RegularFile,
+            EntryKind::Directory,
+            EntryKind::CharDevice,
+            EntryKind::BlockDevice,
+            EntryKind::Fifo,
+            EntryKind::Socket,
+            EntryKind::Symlink,
+        ]
+        .iter()
+        .copied()
+        .find(|&kind| TypePermission::try_from(kind) == Ok(ty))
+        .unwrap_or(EntryKind::Unkown)
+    }
+}
+
+/// Blocks reserved after the last block of an inode, used by its next allocations
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct Preallocation {
+    /// Only a File preallocates, as it gives the blocks back when it is dropped
+    enabled: bool,
+    next: u32,
+    len: u32,
+}
+
 #[repr(C)]
 pub struct Inode<'fs, 'device> {
     data: *mut InodeData,
@@ -88,7 +159,7 @@ pub struct Inode<'fs, 'device> {
 
     id: u32,
     group: u32,
-
+    preallocation: Cell<Preallocation>,
 }
 
 impl<'fs, 'device> Inode<'fs, 'device> {
@@ -103,12 +174,112 @@ impl<'fs, 'device> Inode<'fs, 'device> {
             data: inode,
             fs,
             id,
+            preallocation: Cell::new(Preallocation::default()),
         }
     }
     pub fn get_data(&self) -> *const InodeData {
         self.data
     }
+    /// The fields after the first 128 bytes of the inode, None if the inodes of the filesystem
+    /// are not bigger
+    pub fn get_extra_data(&self) -> Option<*const InodeExtra> {
+        self.extra().map(|extra| extra as *const InodeExtra)
+    }
+    fn extra(&self) -> Option<*mut InodeExtra> {
+        if self.fs.inode_size() > core::mem::size_of::<InodeData>() {
+            // The inode table holds inode_size bytes for each inode
+            Some(unsafe { self.data.add(1) as *mut InodeExtra })
+        } else {
+            None
+        }
+    }
+    /// The field of the extra data returned by field, None if it is not in use
+    unsafe fn extra_field<T>(
+        &self,
+        field: impl FnOnce(*mut InodeExtra) -> *mut T,
+    ) -> Option<*mut T> {
+        let extra = self.extra()?;
+        let in_use = usize::from(read_field!(extra, extra_isize))
+            .min(self.fs.inode_size() - core::mem::size_of::<InodeData>());
+        let field = field(extra);
+        let end = field as usize - extra as usize + core::mem::size_of::<T>();
+        if end <= in_use {
+            Some(field)
+        } else {
+            None
+        }
+    }
+    /// Mark the extra fields in use and zero them, with a creation time of `now`
+    unsafe fn init_extra(&self, now: u32) {
+        let extra = match self.extra() {
+            Some(extra) => extra,
+            None => return,
+        };
+        let size = core::mem::size_of::<InodeExtra>();
+        if self.fs.inode_size() - core::mem::size_of::<InodeData>() < size {
+            return;
+        }
+        access::write(
+            extra,
+            InodeExtra {
+                extra_isize: size as u16,
+                checksum_hi: 0,
+                creation_time_extra: Timestamp::extra_of(now),
+                last_modification_time_extra: Timestamp::extra_of(now),
+                last_access_time_extra: Timestamp::extra_of(now),
+                crtime: now,
+                crtime_extra: Timestamp::extra_of(now),
+                version_hi: 0,
+                project_id: 0,
+            },
+        );
+    }
+    /// The type, owner, size and timestamps of the inode.
+    ///
+    /// The nanoseconds and creation time are only recorded in the inodes bigger than 128 bytes
+    pub fn metadata(&self) -> Metadata {
+        unsafe {
+            let extra = |field: fn(*mut InodeExtra) -> *mut u32| {
+                self.extra_field(field).map(|field| access::read(field))
+            };
+            let type_permission = read_field!(self.data, type_permission);
+            Metadata {
+                kind: EntryKind::from_typeperm(type_permission),
+                permissions: Permission::from_bits_truncate(type_permission.bits()),
+                user_id: read_field!(self.data, user_id),
+                group_id: read_field!(self.data, group_id),
+                size: self.size(),
+                link_count: self.link_count(),
+                blocks_used: self.blocks_used(),
+                accessed: Timestamp::decode(
+                    read_field!(self.data, last_access_time),
+                    extra(|extra| core::ptr::addr_of_mut!((*extra).last_access_time_extra)),
+                ),
+                changed: Timestamp::decode(
+                    read_field!(self.data, creation_time),
+                    extra(|extra| core::ptr::addr_of_mut!((*extra).creation_time_extra)),
+                ),
+                modified: Timestamp::decode(
+                    read_field!(self.data, last_modification_time),
+                    extra(|extra| core::ptr::addr_of_mut!((*extra).last_modification_time_extra)),
+                ),
+                deleted: read_field!(self.data, deletion_time),
+                created: extra(|extra| core::ptr::addr_of_mut!((*extra).crtime)).map(|crtime| {
+                    Timestamp::decode(
+                        crtime,
+                        extra(|extra| core::ptr::addr_of_mut!((*extra).crtime_extra)),
+                    )
+                }),
+            }
+        }
+    }
 
+    /// Create a new inode called `name` in this directory.
+    ///
+    /// Fifos and sockets hold no data and are created like files. Devices and symlinks need a
+    /// device number or a target that can't be given yet, they are refused with UnsupportedKind.
+    ///
+    /// If the creation fails nothing is left allocated
     pub fn create_inode_in_dir(
         &self,
         kind: EntryKind,
@@ -116,69 +287,294 @@ impl<'fs, 'device> Inode<'fs, 'device> {
         user_id: u16,
         group_id: u16,
         name: &[u8],
-    ) -> Option<InodeRef> {
+    ) -> Result<InodeRef, CreateError> {
+        let kind_type = TypePermission::try_from(kind)?;
+        if let EntryKind::CharDevice | EntryKind::BlockDevice | EntryKind::Symlink = kind {
+            return Err(CreateError::UnsupportedKind);
+        }
+        if self.fs.read_only {
+            return Err(CreateError::ReadOnly);
+        }
+        if !self.is_dir() {
+            return Err(CreateError::NotADirectory);
+        }
+        if name.len() > 255 {
+            return Err(CreateError::NameTooLong);
+        }
+        if self.find_entry(name).is_some() {
+            return Err(CreateError::AlreadyExists);
+        }
+        let new_inode_ref = self
+            .fs
+            .reserve_inode(self.fs.group_for_new_inode(self.group, kind))
+            .ok_or(CreateError::NoFreeInodes)?;
+        log::trace!(
+            "Assigning inode {:?} (name: {})",
+            new_inode_ref,
+            name.as_bstr()
+        );
+        let inode = unsafe { self.fs.get_inode_in_table(new_inode_ref.0) };
+
+        // The inode table may still hold the data of a deleted inode, only its generation is
+        // kept to tell the new inode from the old one
+        let now = self.fs.now().unwrap_or(0);
+        unsafe {
+            let generation = read_field!(inode, generation_number).wrapping_add(1);
+            access::fill(inode as *mut u8, 0, self.fs.inode_size());
+            write_field!(inode, generation_number, generation);
+            write_field!(inode, type_permission, kind_type | perms.to_typeperm());
+            write_field!(inode, hard_link_to_inode, 1);
+            write_field!(inode, user_id, user_id);
+            write_field!(inode, group_id, group_id);
+            write_field!(inode, last_access_time, now);
+            write_field!(inode, creation_time, now);
+            write_field!(inode, last_modification_time, now);
+        }
+        let new_inode = self.fs.load_inode(new_inode_ref);
+        unsafe { new_inode.init_extra(now) };
+        let privileged = self.fs.is_privileged(user_id, group_id);
         if let EntryKind::Directory = kind {
-            unimplemented!("Can't create a directory")
-        }
-        let ty_perm = unsafe { (*self.data).type_permission };
-        if ty_perm.contains(TypePermission::DIR) {
-            let new_inode_ref = self.fs.reserve_inode(self.group);
-            log::trace!(
-                "Assigning inode {:?} (name: {})",
-                new_inode_ref,
-                name.as_bstr()
-            );
-            let inode = unsafe { self.fs.get_inode_in_table(new_inode_ref.0) };
+            // Undone by release_inode_bit if the creation fails
+            self.fs
+                .update_group_descriptor(new_inode.group, |descriptor| {
+                    descriptor.number_of_directories_in_group += 1
+                });
+            if let Err(e) = new_inode.init_dir(self.inode_ref(), privileged) {
+                self.fs.release_inode_bit(new_inode_ref);
+                return Err(e);
+            }
+        }
 
-            let mut entries = DirectoryEntries {
-                reader: Cursor::new(self),
+        let mut entries = DirectoryEntries {
+            reader: Cursor::new(self).privileged(privileged),
+        };
+        if let Err(e) = entries.add_entry(kind, name, new_inode_ref) {
+            // Only fails on a read-only filesystem, that was checked
+            let _ = new_inode.truncate_blocks(0);
+            self.fs.release_inode_bit(new_inode_ref);
+            return Err(e);
+        }
+        self.fs.invalidate_cached_entries(self.inode_ref());
+        if let EntryKind::Directory = kind {
+            // The '..' entry of the new directory
+            self.set_link_count(self.link_count() + 1);
+        }
+        Ok(new_inode_ref)
+    }
+    /// Write the '.' and '..' entries of a new directory
+    pub(crate) fn init_dir(&self, parent: InodeRef, privileged: bool) -> Result<(), CreateError> {
+        log::trace!("Initializing directory {} in {:?}", self.id, parent);
+        let mut entries = DirectoryEntries {
+            reader: Cursor::new(self).privileged(privileged),
+        };
+        let dot_size = record_size(1);
+        unsafe {
+            entries.write_dir_entry(
+                RawDirectoryEntry {
+                    inode: self.inode_ref(),
+                    size: dot_size,
+                    name_len: 1,
+                    kind: self.fs.entry_kind_byte(EntryKind::Directory),
+                },
+                b".\0\0\0",
+            )?;
+            entries.reader = Cursor::at(self, u32::from(dot_size)).privileged(privileged);
+            entries.write_dir_entry(
+                RawDirectoryEntry {
+                    inode: parent,
+                    size: self.fs.block_size as u16 - dot_size,
+                    name_len: 2,
+                    kind: self.fs.entry_kind_byte(EntryKind::Directory),
+                },
+                b"..\0\0",
+            )?;
+        }
+        self.set_size(self.fs.block_size as u32);
+        // The entry in the parent and '.'
+        self.set_link_count(2);
+        Ok(())
+    }
+    /// Append count blocks from first to this directory, each with a single empty record. The
+    /// blocks must be reserved, FileTooLarge if they don't fit in the direct blocks
+    pub(crate) fn append_empty_dir_blocks(&self, first: u32, count: u32) -> Result<(), Error> {
+        let block_size = self.fs.block_size as u32;
+        let index = self.size() / block_size;
+        if index + count > 12 {
+            return Err(Error::FileTooLarge);
+        }
+        log::trace!("Appending {} blocks from {} to {}", count, first, self.id);
+        self.fs.note_write();
+        for (index, block) in (index..index + count).zip(first..first + count) {
+            let record = RawDirectoryEntry {
+                inode: InodeRef(0),
+                size: block_size as u16,
+                name_len: 0,
+                kind: 0,
             };
-            entries.add_entry(kind, name, new_inode_ref);
-
             unsafe {
-                (*inode).type_permission = kind.to_typeperm() | perms.to_typeperm();
-                (*inode).hard_link_to_inode = 1;
-                (*inode).user_id = user_id;
-                (*inode).group_id = group_id;
+                let data = self.fs.get_block(block);
+                access::fill(data, 0, block_size as usize);
+                access::write(data as *mut RawDirectoryEntry, record);
+                write_field!(self.data, direct_block_pointers[index as usize], block);
+                let sectors = read_field!(self.data, disk_sectors_used);
+                write_field!(
+                    self.data,
+                    disk_sectors_used,
+                    sectors + self.sectors_per_block()
+                );
             }
-            Some(new_inode_ref)
-        } else {
-            None
         }
+        self.set_size(self.size() + count * block_size);
+        Ok(())
     }
-    pub fn cursor(&self) -> Option<Cursor<'_, 'fs, 'device>> {
-        let ty_perm = unsafe { (*self.data).type_permission };
-        log::trace!("Getting cursor on inode {}, perms: {:?}", self.id, ty_perm);
-        if ty_perm.contains(TypePermission::DIR) {
-            None
-        } else if ty_perm.contains(TypePermission::REGULAR_FILE) {
-            Some(Cursor::new(self))
+    /// A cursor on the data of a regular file, IsADirectory or NotAFile for the other types
+    pub fn cursor(&self) -> Result<Cursor<'_, 'fs, 'device>, Error> {
+        let kind = self.file_type();
+        log::trace!("Getting cursor on inode {}, type: {:?}", self.id, kind);
+        match kind {
+            EntryKind::RegularFile => Ok(Cursor::new(self)),
+            EntryKind::Directory => Err(Error::IsADirectory),
+            _ => Err(Error::NotAFile),
+        }
+    }
+    /// Open a regular file for writing, this is the prefered way to do file IO as it keeps the
+    /// metadata of the inode in sync with the data. None if this is not a regular file, or if
+    /// it is open elsewhere with the `exclusive` feature, see `open_file`
+    pub fn as_file(&self) -> Option<File<'fs, 'device>> {
+        self.open_file(true).ok()
+    }
+    /// Open a regular file, the writes through it fail with NotWritable unless writable is set.
+    ///
+    /// With the `exclusive` feature, the inode can be open by any number of readers or by one
+    /// writer: Busy is returned on the conflicting opens, until the files holding the inode are
+    /// dropped. `truncate`, `allocate`, `set_flags`, `set_xattr` and `remove_xattr` return Busy
+    /// too while the inode is open, only the changes made through its files are allowed
+    pub fn open_file(&self, writable: bool) -> Result<File<'fs, 'device>, Error> {
+        match self.file_type() {
+            EntryKind::RegularFile => (),
+            EntryKind::Directory => return Err(Error::IsADirectory),
+            _ => return Err(Error::NotAFile),
+        }
+        #[cfg(feature = "exclusive")]
+        self.fs.open_inodes.acquire(self.id, writable)?;
+        Ok(File::new(self.fs.load_inode(self.inode_ref()), writable))
+    }
+    /// Busy if the inode is open as a file with the `exclusive` feature, see `open_file`
+    fn check_closed(&self) -> Result<(), Error> {
+        #[cfg(feature = "exclusive")]
+        self.fs.open_inodes.check_closed(self.id)?;
+        Ok(())
+    }
+    /// Open a directory
+    pub fn as_dir(&self) -> Option<Dir<'fs, 'device>> {
+        if self.is_dir() {
+            Some(Dir::new(self.fs.load_inode(self.inode_ref())))
         } else {
-            panic!("file type is unsuported, neither dir or file")
+            None
         }
     }
-    pub fn end(&self) -> Option<Cursor<'_, 'fs, 'device>> {
+    /// See cursor, the cursor is at the end of the file
+    pub fn end(&self) -> Result<Cursor<'_, 'fs, 'device>, Error> {
         self.cursor().map(|mut cursor| {
             cursor.advance_to_end();
             cursor
         })
     }
+    /// The type in the mode of the inode, Unkown if it is not a known type
+    pub fn file_type(&self) -> EntryKind {
+        EntryKind::from_typeperm(unsafe { read_field!(self.data, type_permission) })
+    }
     pub fn inode_ref(&self) -> InodeRef {
         InodeRef(self.id)
     }
-    fn reserve_block(&self) -> u32 {
-        let new_block = self.fs.reserve_block(self.group);
-        for block in unsafe { &mut (*self.data).direct_block_pointers } {
-            if *block == 0 {
-                *block = new_block;
-                break;
+    /// Reserve the block number index of the inode
+    fn reserve_block(&self, index: u32, privileged: bool) -> Option<u32> {
+        let new_block = match self.take_preallocated_block() {
+            Some(block) => block,
+            None => {
+                let block = self.fs.reserve_block(self.block_goal(), privileged)?;
+                self.preallocate_after(block, privileged);
+                block
             }
+        };
+        unsafe {
+            write_field!(self.data, direct_block_pointers[index as usize], new_block);
+            let sectors = read_field!(self.data, disk_sectors_used);
+            write_field!(
+                self.data,
+                disk_sectors_used,
+                sectors + self.sectors_per_block()
+            );
+        }
+        Some(new_block)
+    }
+    fn take_preallocated_block(&self) -> Option<u32> {
+        let mut preallocation = self.preallocation.get();
+        if preallocation.len == 0 {
+            return None;
+        }
+        let block = preallocation.next;
+        preallocation.next += 1;
+        preallocation.len -= 1;
+        self.preallocation.set(preallocation);
+        Some(block)
+    }
+    /// Reserve the free blocks following block, up to the hint of the superblock
+    fn preallocate_after(&self, block: u32, privileged: bool) {
+        let mut preallocation = self.preallocation.get();
+        if !preallocation.enabled {
+            return;
+        }
+        let wanted = self.fs.preallocation_hint(self.is_dir()).saturating_sub(1);
+        preallocation.next = block + 1;
+        preallocation.len = 0;
+        while preallocation.len < wanted
+            && self
+                .fs
+                .reserve_block_at(preallocation.next + preallocation.len, privileged)
+        {
+            preallocation.len += 1;
+        }
+        log::trace!(
+            "Preallocated {} blocks after {} for inode {}",
+            preallocation.len,
+            block,
+            self.id
+        );
+        self.preallocation.set(preallocation);
+    }
+    pub(crate) fn enable_preallocation(&self) {
+        let mut preallocation = self.preallocation.get();
+        preallocation.enabled = true;
+        self.preallocation.set(preallocation);
+    }
+    /// Release the blocks that were preallocated but not used
+    pub(crate) fn discard_preallocation(&self) {
+        let mut preallocation = self.preallocation.get();
+        for block in preallocation.next..preallocation.next + preallocation.len {
+            self.fs.free_block(block);
+        }
+        preallocation.len = 0;
+        self.preallocation.set(preallocation);
+    }
+    /// Where the next block of the inode should be, to keep the file contiguous
+    fn block_goal(&self) -> u32 {
+        let last = unsafe { read_field!(self.data, direct_block_pointers) }
+            .iter()
+            .copied()
+            .max()
+            .unwrap_or(0);
+        if last != 0 {
+            last + 1
+        } else {
+            // Spread the files of the group so they can grow without being interleaved
+            let blocks = self.fs.block_count_of_group(self.group);
+            self.fs.first_block_of_group(self.group) + (self.id % 16) * (blocks / 16)
         }
-        new_block
     }
     pub fn get_dir_entries(&self) -> Option<DirectoryEntries<'_, 'fs, 'device>> {
         log::trace!("Getting entries on inode {}", self.id);
-        if !unsafe { (*self.data).type_permission }.contains(TypePermission::DIR) {
+        if !self.is_dir() {
             None
         } else {
             log::trace!("reading entries for {}", self.id);
@@ -187,8 +583,682 @@ impl<'fs, 'device> Inode<'fs, 'device> {
             })
         }
     }
+    pub fn is_dir(&self) -> bool {
+        self.file_type() == EntryKind::Directory
+    }
+    /// Find the entry called name in this directory, returns None if it does not exist or if
+    /// this is not a directory
+    pub fn find_entry(&self, name: &[u8]) -> Option<DirectoryEntry<'fs>> {
+        let entry = self.get_dir_entries()?.find(|entry| entry.name == name)?;
+        self.fs.cache_entry(self.inode_ref(), name, entry.inode);
+        Some(entry)
+    }
     pub fn size(&self) -> u32 {
-        unsafe { (*self.data).size_lower_32_bits }
+        unsafe { read_field!(self.data, size_lower_32_bits) }
+    }
+    /// Call sink with the content of the regular file, one block at a time and without copying
+    /// it. Holes, and pointers out of the filesystem, are given as zeros. Returns the size of the
+    /// file, a failure of sink gives WriteFailed
+    pub fn read_all<E>(&self, mut sink: impl FnMut(&[u8]) -> Result<(), E>) -> Result<u64, Error> {
+        const ZEROS: [u8; 1024] = [0; 1024];
+        match self.file_type() {
+            EntryKind::RegularFile => (),
+            EntryKind::Directory => return Err(Error::IsADirectory),
+            _ => return Err(Error::NotAFile),
+        }
+        let size = self.size();
+        let block_size = self.fs.block_size as u32;
+        for index in 0..size.div_ceil(block_size) {
+            let len = core::cmp::min(block_size, size - index * block_size) as usize;
+            match self.block_at(index) {
+                Some(block) => self
+                    .fs
+                    .with_block(block, |data| sink(&data[..len]))?
+                    .map_err(|_| Error::WriteFailed)?,
+                None => {
+                    for start in (0..len).step_by(ZEROS.len()) {
+                        sink(&ZEROS[..core::cmp::min(ZEROS.len(), len - start)])
+                            .map_err(|_| Error::WriteFailed)?;
+                    }
+                }
+            }
+        }
+        Ok(u64::from(size))
+    }
+    /// The target of the symlink, InvalidArgument if the inode is not a symlink
+    pub fn read_link(&self) -> Result<&'fs BStr, Error> {
+        if self.file_type() != EntryKind::Symlink {
+            return Err(Error::InvalidArgument);
+        }
+        let size = self.size() as usize;
+        let target = if self.is_fast_symlink() {
+            // The target takes the place of the block pointers
+            if size > 60 {
+                return Err(Error::Corrupt("symlink target"));
+            }
+            unsafe { core::ptr::addr_of!((*self.data).direct_block_pointers) as *const u8 }
+        } else {
+            if size >= self.fs.block_size {
+                return Err(Error::Corrupt("symlink target"));
+            }
+            let block = self.block_at(0).ok_or(Error::Corrupt("symlink block"))?;
+            unsafe { self.fs.checked_block(block)? }
+        };
+        Ok(unsafe { core::slice::from_raw_parts(target, size) }.as_bstr())
+    }
+    /// Whether the target of the symlink is stored in the inode, it then has no block but the
+    /// one of the extended attributes
+    fn is_fast_symlink(&self) -> bool {
+        let has_xattr_block = unsafe { read_field!(self.data, acl) } != 0;
+        self.blocks_used() <= u32::from(has_xattr_block) * self.sectors_per_block()
+    }
+    /// Changes each time the inode number is reused by a new file
+    pub fn generation(&self) -> u32 {
+        unsafe { read_field!(self.data, generation_number) }
+    }
+    pub fn flags(&self) -> InodeFlags {
+        unsafe { read_field!(self.data, flags) }
+    }
+    pub fn set_flags(&self, flags: InodeFlags) -> Result<(), Error> {
+        if self.fs.read_only {
+            return Err(Error::ReadOnly);
+        }
+        self.check_closed()?;
+        self.fs.note_write();
+        unsafe { write_field!(self.data, flags, flags) };
+        Ok(())
+    }
+    /// The extended attributes of the inode, Corrupt if its attribute block is invalid
+    pub fn xattrs(&self) -> Result<Xattrs<'fs>, Error> {
+        let block = unsafe { read_field!(self.data, acl) };
+        if block == 0 {
+            return Ok(Xattrs::empty());
+        }
+        let block = unsafe {
+            core::slice::from_raw_parts(self.fs.checked_block(block)?, self.fs.block_size)
+        };
+        Xattrs::new(block)
+    }
+    /// The value of the extended attribute with the full name, like `security.selinux`
+    pub fn get_xattr(&self, name: &[u8]) -> Result<Option<&'fs [u8]>, Error> {
+        Ok(self
+            .xattrs()?
+            .find(|xattr| xattr.has_name(name))
+            .map(|xattr| xattr.value))
+    }
+    /// The access ACL of the inode, None if it only has the permissions of its mode
+    pub fn acl(&self) -> Result<Option<Acl<'fs>>, Error> {
+        self.get_xattr(b"system.posix_acl_access")?
+            .map(Acl::new)
+            .transpose()
+    }
+    /// The ACL the inodes created in this directory inherit, None if there is none
+    pub fn default_acl(&self) -> Result<Option<Acl<'fs>>, Error> {
+        self.get_xattr(b"system.posix_acl_default")?
+            .map(Acl::new)
+            .transpose()
+    }
+    /// Set the extended attribute with the full name, like `user.comment`.
+    ///
+    /// The attributes of an inode are kept in a single block, XattrTooLarge if they would not
+    /// fit. A block shared with other inodes is copied first
+    pub fn set_xattr(&self, name: &[u8], value: &[u8]) -> Result<(), Error> {
+        if self.fs.read_only {
+            return Err(Error::ReadOnly);
+        }
+        self.check_closed()?;
+        let (index, name) = xattr::split_name(name)?;
+        let current = self.xattr_block()?;
+        xattr::check_space(
+            current.map(|block| unsafe {
+                core::slice::from_raw_parts(block as *const u8, self.fs.block_size)
+            }),
+            self.fs.block_size,
+            index,
+            name,
+            value,
+        )?;
+        let block = self.own_xattr_block(current)?;
+        self.fs.note_write();
+        unsafe { xattr::set(block, self.fs.block_size, index, name, value) };
+        Ok(())
+    }
+    /// Remove the extended attribute with the full name, NotFound if the inode does not have
+    /// it. The block of the attributes is released with the last one
+    pub fn remove_xattr(&self, name: &[u8]) -> Result<(), Error> {
+        if self.fs.read_only {
+            return Err(Error::ReadOnly);
+        }
+        self.check_closed()?;
+        let (index, name) = xattr::split_name(name)?;
+        let current = self.xattr_block()?.ok_or(Error::NotFound)?;
+        let entries = unsafe { core::slice::from_raw_parts(current, self.fs.block_size) };
+        if !xattr::contains(entries, index, name) {
+            return Err(Error::NotFound);
+        }
+        if xattr::entry_count(entries) == 1 {
+            self.release_xattr_block();
+            return Ok(());
+        }
+        let block = self.own_xattr_block(Some(current))?;
+        self.fs.note_write();
+        unsafe { xattr::remove(block, self.fs.block_size, index, name) };
+        Ok(())
+    }
+    /// The checked block of the extended attributes, None if the inode has none
+    fn xattr_block(&self) -> Result<Option<*mut u8>, Error> {
+        let block = unsafe { read_field!(self.data, acl) };
+        if block == 0 {
+            return Ok(None);
+        }
+        // Checks the content of the block
+        self.xattrs()?;
+        Ok(Some(unsafe { self.fs.checked_block(block)? }))
+    }
+    /// The block of the extended attributes that only this inode uses: current is copied if it
+    /// is shared, a new block is reserved if there is none
+    fn own_xattr_block(&self, current: Option<*mut u8>) -> Result<*mut u8, Error> {
+        if let Some(block) = current {
+            if unsafe { xattr::refcount(block) } == 1 {
+                return Ok(block);
+            }
+        }
+        let privileged = unsafe {
+            self.fs.is_privileged(
+                read_field!(self.data, user_id),
+                read_field!(self.data, group_id),
+            )
+        };
+        let new_block = self
+            .fs
+            .reserve_block(self.block_goal(), privileged)
+            .ok_or(Error::NoFreeBlocks)?;
+        log::trace!(
+            "Inode {} uses block {} for its attributes",
+            self.id,
+            new_block
+        );
+        let block_size = self.fs.block_size;
+        unsafe {
+            let data = self.fs.get_block(new_block);
+            match current {
+                None => {
+                    xattr::init(data, block_size);
+                    let sectors = read_field!(self.data, disk_sectors_used);
+                    write_field!(
+                        self.data,
+                        disk_sectors_used,
+                        sectors + self.sectors_per_block()
+                    );
+                }
+                Some(shared) => {
+                    access::copy_within(shared, data, block_size);
+                    xattr::set_refcount(shared, xattr::refcount(shared) - 1);
+                    xattr::set_refcount(data, 1);
+                }
+            }
+            write_field!(self.data, acl, new_block);
+            Ok(data)
+        }
+    }
+    /// Drop the reference of the inode to its block of extended attributes, the block is
+    /// released if no other inode uses it. A corrupted block is left alone
+    pub(crate) fn release_xattr_block(&self) {
+        let block = unsafe { read_field!(self.data, acl) };
+        if block == 0 {
+            return;
+        }
+        if let Ok(Some(data)) = self.xattr_block() {
+            let refcount = unsafe { xattr::refcount(data) };
+            if refcount > 1 {
+                unsafe { xattr::set_refcount(data, refcount - 1) };
+            } else {
+                self.fs.free_block(block);
+            }
+            let sectors = self.blocks_used().saturating_sub(self.sectors_per_block());
+            unsafe { write_field!(self.data, disk_sectors_used, sectors) };
+        }
+        unsafe { write_field!(self.data, acl, 0) };
+    }
+    /// The blocks of the content, the indirect blocks and the block of extended attributes.
+    ///
+    /// Devices, fifos, sockets and the symlinks stored in the inode only have the block of
+    /// extended attributes, their pointers hold something else
+    pub fn mapped_blocks(&self) -> MappedBlocks<'_, 'fs, 'device> {
+        let maps_blocks = match self.file_type() {
+            EntryKind::CharDevice
+            | EntryKind::BlockDevice
+            | EntryKind::Fifo
+            | EntryKind::Socket => false,
+            EntryKind::Symlink => !self.is_fast_symlink(),
+            _ => true,
+        };
+        MappedBlocks {
+            inode: self,
+            next: if maps_blocks { 0 } else { 15 },
+            stack: [(core::ptr::null(), 0, 0); 3],
+            depth: 0,
+        }
+    }
+    /// The block of the filesystem holding the block number index of the content, following the
+    /// indirect blocks. None for a hole or a pointer out of the filesystem
+    pub(crate) fn block_at(&self, index: u32) -> Option<u32> {
+        let (block, _) = self.pointer_at(index);
+        if self.fs.is_valid_block(block) {
+            Some(block)
+        } else {
+            None
+        }
+    }
+    /// The block of the filesystem holding the block logical_block of the content, like FIBMAP.
+    /// None for a hole, InvalidArgument past the blocks of the content (see `blocks`) and
+    /// Corrupt if the pointer is out of the filesystem
+    pub fn bmap(&self, logical_block: u32) -> Result<Option<u32>, Error> {
+        if logical_block >= self.blocks().end {
+            return Err(Error::InvalidArgument);
+        }
+        match self.pointer_at(logical_block) {
+            (0, _) => Ok(None),
+            (block, _) if self.fs.is_valid_block(block) => Ok(Some(block)),
+            _ => Err(Error::Corrupt("block number out of range")),
+        }
+    }
+    /// The run of the content starting at offset that is contiguous on the device, or the hole
+    /// at offset, up to the end of the file. InvalidArgument if offset is not before the end of
+    /// the file, Corrupt if its block is out of the filesystem
+    pub fn extent_at(&self, offset: u32) -> Result<Extent, Error> {
+        let size = self.size();
+        if offset >= size {
+            return Err(Error::InvalidArgument);
+        }
+        let block_size = self.fs.block_size as u32;
+        let index = offset / block_size;
+        let first = self.bmap(index)?;
+        let blocks = self.contiguous_blocks(index, first, size.div_ceil(block_size) - index);
+        let in_block = offset % block_size;
+        let len = u64::from(blocks) * u64::from(block_size) - u64::from(in_block);
+        Ok(Extent {
+            start: first.map_or(0, |block| {
+                u64::from(block) * u64::from(block_size) + u64::from(in_block)
+            }),
+            len: core::cmp::min(len, u64::from(size - offset)) as u32,
+            hole: first.is_none(),
+        })
+    }
+    /// The number of blocks of the content from index, at most count, that follow first on the
+    /// device, or that are holes if first is None
+    fn contiguous_blocks(&self, index: u32, first: Option<u32>, count: u32) -> u32 {
+        (1..count)
+            .find(|&next| self.block_at(index + next) != first.map(|block| block + next))
+            .unwrap_or(count)
+    }
+    /// The pointer to the block number index of the content and the indirect blocks followed to
+    /// reach it, the pointer is 0 when one of them is missing or out of the filesystem
+    fn pointer_at(&self, index: u32) -> (u32, u8) {
+        let per_block = self.fs.block_size as u32 / 4;
+        let (pointer, mut index, levels) = block_path(index, per_block);
+        let mut block = unsafe { InodeData::pointer(self.data, pointer) };
+        for level in (0..levels).rev() {
+            let pointers = match unsafe { self.fs.checked_block(block) } {
+                Ok(pointers) => pointers as *const u32,
+                Err(_) => return (0, levels as u8),
+            };
+            let covered = per_block.pow(level);
+            block = unsafe { access::read(pointers.add((index / covered) as usize)) };
+            index %= covered;
+        }
+        (block, levels as u8)
+    }
+    /// Where each block of the content is, from the first to the one holding the end of the
+    /// file, holes included.
+    ///
+    /// Devices, fifos, sockets and the symlinks stored in the inode have no content blocks
+    pub fn blocks(&self) -> BlockMappings<'_, 'fs, 'device> {
+        let end = match self.file_type() {
+            EntryKind::CharDevice
+            | EntryKind::BlockDevice
+            | EntryKind::Fifo
+            | EntryKind::Socket => 0,
+            EntryKind::Symlink if self.is_fast_symlink() => 0,
+            _ => self.size().div_ceil(self.fs.block_size as u32),
+        };
+        BlockMappings {
+            inode: self,
+            next: 0,
+            end,
+        }
+    }
+    /// Number of 512 bytes sectors used by the inode on the disk
+    pub fn blocks_used(&self) -> u32 {
+        unsafe { read_field!(self.data, disk_sectors_used) }
+    }
+    fn sectors_per_block(&self) -> u32 {
+        self.fs.block_size as u32 / 512
+    }
+    /// Number of directory entries referencing this inode
+    pub fn link_count(&self) -> u16 {
+        unsafe { read_field!(self.data, hard_link_to_inode) }
+    }
+    pub(crate) fn set_link_count(&self, count: u16) {
+        self.fs.note_write();
+        unsafe { write_field!(self.data, hard_link_to_inode, count) }
+    }
+    pub(crate) fn set_size(&self, size: u32) {
+        self.fs.note_write();
+        unsafe { write_field!(self.data, size_lower_32_bits, size) }
+    }
+    /// The nanoseconds of the time are reset
+    pub(crate) fn set_modification_time(&self, time: u32) {
+        self.fs.note_write();
+        unsafe {
+            write_field!(self.data, last_modification_time, time);
+            if let Some(extra) = self
+                .extra_field(|extra| core::ptr::addr_of_mut!((*extra).last_modification_time_extra))
+            {
+                access::write(extra, Timestamp::extra_of(time))
+            }
+        }
+    }
+    /// Give this inode the access, change and modification times of other, with their
+    /// nanoseconds when both inodes record them
+    pub(crate) fn copy_times(&self, other: &Inode<'_, '_>) {
+        self.fs.note_write();
+        unsafe {
+            write_field!(
+                self.data,
+                last_access_time,
+                read_field!(other.data, last_access_time)
+            );
+            write_field!(
+                self.data,
+                creation_time,
+                read_field!(other.data, creation_time)
+            );
+            write_field!(
+                self.data,
+                last_modification_time,
+                read_field!(other.data, last_modification_time)
+            );
+            let fields: [fn(*mut InodeExtra) -> *mut u32; 3] = [
+                |extra| core::ptr::addr_of_mut!((*extra).last_access_time_extra),
+                |extra| core::ptr::addr_of_mut!((*extra).creation_time_extra),
+                |extra| core::ptr::addr_of_mut!((*extra).last_modification_time_extra),
+            ];
+            for field in fields {
+                if let (Some(to), Some(from)) = (self.extra_field(field), other.extra_field(field))
+                {
+                    access::write(to, access::read(from));
+                }
+            }
+        }
+    }
+    pub(crate) fn set_deletion_time(&self, time: u32) {
+        self.fs.note_write();
+        unsafe { write_field!(self.data, deletion_time, time) }
+    }
+    /// Add an entry called name referencing an existing inode of the given kind to this
+    /// directory. The link count of the inode is not changed
+    #[cfg(feature = "alloc")]
+    pub(crate) fn add_link(
+        &self,
+        name: &[u8],
+        inode: InodeRef,
+        kind: EntryKind,
+    ) -> Result<(), CreateError> {
+        if self.fs.read_only {
+            return Err(CreateError::ReadOnly);
+        }
+        if !self.is_dir() {
+            return Err(CreateError::NotADirectory);
+        }
+        if name.len() > 255 {
+            return Err(CreateError::NameTooLong);
+        }
+        if self.find_entry(name).is_some() {
+            return Err(CreateError::AlreadyExists);
+        }
+        log::trace!("Linking {:?} as {} in {}", inode, name.as_bstr(), self.id);
+        self.fs.note_write();
+        let mut entries = DirectoryEntries {
+            reader: Cursor::new(self),
+        };
+        entries.add_entry(kind, name, inode)?;
+        self.fs.invalidate_cached_entries(self.inode_ref());
+        Ok(())
+    }
+    /// Make the '..' entry of this directory reference parent, Corrupt if it is not the second
+    /// entry like init_dir writes it
+    #[cfg(feature = "alloc")]
+    pub(crate) fn set_parent(&self, parent: InodeRef) -> Result<(), Error> {
+        let corrupt = Error::Corrupt("directory without '..'");
+        let mut entries = DirectoryEntries::at(self, 0);
+        let (dot, _) = unsafe { entries.peek() }.ok_or(corrupt)?;
+        entries
+            .reader
+            .advance(u32::from(unsafe { read_field!(dot, size) }));
+        match unsafe { entries.peek() } {
+            Some((dot_dot, name)) if name == ".." => {
+                self.fs.note_write();
+                unsafe { write_field!(dot_dot, inode, parent) };
+                self.fs.invalidate_cached_entries(self.inode_ref());
+                Ok(())
+            }
+            _ => Err(corrupt),
+        }
+    }
+    /// Remove the entry called name from this directory, returns the inode it referenced.
+    ///
+    /// The space of the entry is given to the previous entry of the block, or the entry is
+    /// marked as deleted if it is the first of its block
+    pub(crate) fn remove_entry(&self, name: &[u8]) -> Option<InodeRef> {
+        self.fs.invalidate_cached_entries(self.inode_ref());
+        let block_size = self.fs.block_size as u32;
+        let mut position = 0;
+        let mut previous: Option<*mut RawDirectoryEntry> = None;
+        while position < self.size() {
+            if position % block_size == 0 {
+                previous = None;
+            }
+            let entries = DirectoryEntries {
+                reader: Cursor::at(self, position),
+            };
+            let (entry, entry_name) = unsafe { entries.peek()? };
+            unsafe {
+                let inode = read_field!(entry, inode);
+                let size = read_field!(entry, size);
+                if inode.0 != 0 && entry_name == name {
+                    log::trace!("Removing {} from {}", entry_name, self.id);
+                    self.fs.note_write();
+                    match previous {
+                        Some(previous) => {
+                            write_field!(previous, size, read_field!(previous, size) + size)
+                        }
+                        None => write_field!(entry, inode, InodeRef(0)),
+                    }
+                    return Some(inode);
+                }
+                position += u32::from(size);
+            }
+            previous = Some(entry);
+        }
+        None
+    }
+    /// Shrink the inode to `len` bytes, giving back the blocks that are no longer used and the
+    /// indirect blocks left without pointers. With SECURE_DELETION they are erased first.
+    /// Does nothing if the inode is not bigger than `len`
+    pub fn truncate(&self, len: u32) -> Result<(), Error> {
+        if self.fs.read_only {
+            return Err(Error::ReadOnly);
+        }
+        self.check_closed()?;
+        self.truncate_blocks(len)
+    }
+    /// Like truncate without its checks, that the `File` holding the inode and the removals of
+    /// inodes already made
+    pub(crate) fn truncate_blocks(&self, len: u32) -> Result<(), Error> {
+        if len >= self.size() {
+            return Ok(());
+        }
+        log::trace!("Truncating inode {} to {} bytes", self.id, len);
+        let kept_blocks = u64::from(len.div_ceil(self.fs.block_size as u32));
+        let secure = self.flags().contains(InodeFlags::SECURE_DELETION);
+        let per_block = u64::from(self.fs.block_size as u32 / 4);
+        self.fs.note_write();
+        let mut first = 0;
+        for slot in 0..15usize {
+            let levels = slot.saturating_sub(11) as u32;
+            let covered = per_block.pow(levels);
+            if first + covered > kept_blocks {
+                let block = unsafe { InodeData::pointer(self.data, slot) };
+                if self.release_tree(block, levels, first, kept_blocks, secure)? {
+                    unsafe { InodeData::set_pointer(self.data, slot, 0) };
+                }
+            }
+            first += covered;
+        }
+        self.set_size(len);
+        Ok(())
+    }
+    /// Release the blocks of the tree below block, an indirect block of the given level or a
+    /// data block at level 0, holding the blocks of the content from first. Only the content
+    /// blocks from kept on are released, with the indirect blocks left without pointers.
+    /// Returns if block was released and its pointer must be cleared. The content blocks are
+    /// only read to be erased, the indirect blocks that can't be read are an error
+    fn release_tree(
+        &self,
+        block: u32,
+        level: u32,
+        first: u64,
+        kept: u64,
+        secure: bool,
+    ) -> Result<bool, Error> {
+        if block == 0 {
+            return Ok(false);
+        }
+        // A corrupted pointer has nothing to release
+        if !self.fs.is_valid_block(block) {
+            return Ok(true);
+        }
+        if level > 0 {
+            let pointers = unsafe { self.fs.checked_block(block)? } as *mut u32;
+            let per_block = self.fs.block_size / 4;
+            let covered = (per_block as u64).pow(level - 1);
+            let mut empty = true;
+            for index in 0..per_block {
+                let pointer = unsafe { pointers.add(index) };
+                let start = first + index as u64 * covered;
+                let child = unsafe { access::read(pointer) };
+                if start + covered > kept
+                    && self.release_tree(child, level - 1, start, kept, secure)?
+                {
+                    unsafe { access::write(pointer, 0) };
+                } else if child != 0 {
+                    empty = false;
+                }
+            }
+            if !empty {
+                return Ok(false);
+            }
+        }
+        if secure {
+            unsafe { access::fill(self.fs.checked_block(block)?, 0, self.fs.block_size) };
+        }
+        self.fs.free_block(block);
+        let sectors = self.blocks_used() - self.sectors_per_block();
+        unsafe { write_field!(self.data, disk_sectors_used, sectors) };
+        Ok(true)
+    }
+    /// Reserve the blocks covering len bytes at offset without writing the content, like
+    /// fallocate. The new blocks are zeroed, the blocks already in the range are left alone.
+    /// The size grows to the end of the range unless keep_size is set.
+    ///
+    /// The missing blocks are taken from a single run of the group of the inode if there is
+    /// one. If they can't all be reserved nothing is changed and NoFreeBlocks is returned. Only
+    /// the direct blocks can be allocated, FileTooLarge past them
+    pub fn allocate(&self, offset: u32, len: u32, keep_size: bool) -> Result<(), Error> {
+        self.cursor()?;
+        if self.fs.read_only {
+            return Err(Error::ReadOnly);
+        }
+        self.check_closed()?;
+        let end = offset.checked_add(len).ok_or(Error::FileTooLarge)?;
+        if len == 0 {
+            return Ok(());
+        }
+        let block_size = self.fs.block_size as u32;
+        let blocks = offset / block_size..end.div_ceil(block_size);
+        if blocks.end > 12 {
+            return Err(Error::FileTooLarge);
+        }
+        let mut missing = [0; 12];
+        let mut count = 0;
+        for index in blocks {
+            if unsafe { read_field!(self.data, direct_block_pointers[index as usize]) } == 0 {
+                missing[count] = index;
+                count += 1;
+            }
+        }
+        let missing = &missing[..count];
+
+        let metadata = self.metadata();
+        let privileged = self.fs.is_privileged(metadata.user_id, metadata.group_id);
+        let superblock = self.fs.get_superblock();
+        let reserved = if privileged {
+            0
+        } else {
+            superblock.block_superuser
+        };
+        if superblock.unallocated_blocks.saturating_sub(reserved) < count as u32 {
+            return Err(Error::NoFreeBlocks);
+        }
+        log::trace!("Allocating {} blocks in inode {}", count, self.id);
+        self.fs.note_write();
+        let mut reserved = [0; 12];
+        match self
+            .fs
+            .reserve_contiguous(self.group, count as u32, privileged)
+        {
+            Ok(first) => {
+                for (block, reserved) in (first..).zip(&mut reserved[..count]) {
+                    *reserved = block;
+                }
+            }
+            Err(_) => {
+                let mut goal = self.block_goal();
+                for done in 0..count {
+                    match self.fs.reserve_block(goal, privileged) {
+                        Some(block) => {
+                            reserved[done] = block;
+                            goal = block + 1;
+                        }
+                        None => {
+                            for &block in &reserved[..done] {
+                                self.fs.free_block(block);
+                            }
+                            return Err(Error::NoFreeBlocks);
+                        }
+                    }
+                }
+            }
+        }
+        for (&index, &block) in missing.iter().zip(&reserved) {
+            unsafe {
+                access::fill(self.fs.get_block(block), 0, block_size as usize);
+                write_field!(self.data, direct_block_pointers[index as usize], block);
+            }
+        }
+        unsafe {
+            let sectors = read_field!(self.data, disk_sectors_used);
+            write_field!(
+                self.data,
+                disk_sectors_used,
+                sectors + count as u32 * self.sectors_per_block()
+            );
+        }
+        if !keep_size && end > self.size() {
+            self.set_size(end);
+        }
+        Ok(())
     }
 }
 
@@ -198,44 +1268,59 @@ pub struct Cursor<'inode, 'fs, 'device> {
 
     total_index: u32,
     block_size: u32,
+    privileged: bool,
+    /// The blocks after the current one announced to the filesystem
+    readahead: u32,
 }
 impl<'inode, 'fs, 'device> Cursor<'inode, 'fs, 'device> {
     fn new(inode: &'inode Inode<'fs, 'device>) -> Self {
+        Self::at(inode, 0)
+    }
+    pub(crate) fn at(inode: &'inode Inode<'fs, 'device>, index: u32) -> Self {
         Self {
             inode,
-            total_index: 0,
+            total_index: index,
             block_size: inode.fs.block_size as u32,
+            privileged: true,
+            readahead: 0,
         }
     }
+    /// Whether the writes can use the blocks reserved for the superuser, true by default
+    pub fn privileged(mut self, privileged: bool) -> Self {
+        self.privileged = privileged;
+        self
+    }
+    /// When a read enters a block, announce the blocks of the next count ones of the file to
+    /// the filesystem, one run of contiguous blocks at a time: they are prefetched from the
+    /// device of `FileSystem::open_device` and given to the readahead hook (see
+    /// `FileSystem::set_readahead_hook`). 0 by default
+    pub fn set_readahead(&mut self, count: u32) {
+        self.readahead = count;
+    }
+    pub fn position(&self) -> u32 {
+        self.total_index
+    }
     /// This returns a ptr aligned to the start of the place you want
     /// to do something on, with the maximum bytes available
     #[inline]
     fn get_ptr(&self) -> Option<(*mut u8, u32)> {
-        let block_ptr = unsafe { self.inode.fs.get_block(self.get_current_block_index()?) };
+        let block_ptr =
+            unsafe { self.inode.fs.checked_block(self.get_current_block_index()?) }.ok()?;
         let index_in_block = self.total_index % self.block_size;
         Some((
-            unsafe { block_ptr.offset(index_in_block as isize) },
+            unsafe { block_ptr.add(index_in_block as usize) },
             self.block_size - index_in_block,
         ))
     }
     #[inline]
-    fn remain_in_block(&self) -> u32 {
-        self.block_size - (self.total_index % self.block_size)
-    }
-    #[inline]
     fn get_current_block_index(&self) -> Option<u32> {
-        let block_count = self.total_index / self.block_size;
-        if block_count > 12 {
-            panic!("Can't use more than 12 blocks");
-        } else {
-            match unsafe { (*self.inode.get_data()).direct_block_pointers[block_count as usize] } {
-                0 => None,
-                b => {
-                    log::trace!("Got ptr the block index {} for inode {}", b, self.inode.id);
-                    Some(b)
-                }
-            }
-        }
+        let block = self.inode.block_at(self.total_index / self.block_size)?;
+        log::trace!(
+            "Got ptr the block index {} for inode {}",
+            block,
+            self.inode.id
+        );
+        Some(block)
     }
     #[inline]
     unsafe fn peek_access_with<T>(
@@ -258,6 +1343,10 @@ impl<'inode, 'fs, 'device> Cursor<'inode, 'fs, 'device> {
             }
         }
     }
+    /// # Safety
+    ///
+    /// `f` is given a pointer to the current position and the number of bytes that can be read
+    /// from it, it must not read past that
     #[inline]
     pub unsafe fn peek_with<T>(
         &self,
@@ -266,6 +1355,9 @@ impl<'inode, 'fs, 'device> Cursor<'inode, 'fs, 'device> {
         let (current_position, remain) = self.get_ptr()?;
         f(current_position, remain)
     }
+    /// # Safety
+    ///
+    /// See peek_with
     #[inline]
     pub unsafe fn read_with<T>(
         &mut self,
@@ -279,21 +1371,76 @@ impl<'inode, 'fs, 'device> Cursor<'inode, 'fs, 'device> {
             }
         }
     }
+    /// Give the runs of blocks of the readahead after the current one to the filesystem
+    fn read_ahead(&self) {
+        let fs = self.inode.fs;
+        if self.readahead == 0 || !fs.reads_ahead() {
+            return;
+        }
+        let index = self.total_index / self.block_size;
+        let end = core::cmp::min(
+            index.saturating_add(self.readahead).saturating_add(1),
+            self.inode.size().div_ceil(self.block_size),
+        );
+        let mut run: Option<(u32, u32)> = None;
+        for next in index.saturating_add(1)..end {
+            let block = self.inode.block_at(next);
+            match (&mut run, block) {
+                (Some((start, count)), Some(block)) if *start + *count == block => *count += 1,
+                _ => {
+                    if let Some((start, count)) = run {
+                        fs.read_ahead(start, count);
+                    }
+                    run = block.map(|block| (block, 1));
+                }
+            }
+        }
+        if let Some((start, count)) = run {
+            fs.read_ahead(start, count);
+        }
+    }
     fn read_to_end_of_block_at_most(&mut self, buffer: &mut [u8]) -> Option<u32> {
-        let (ptr, remain) = self.get_ptr()?;
+        if self.total_index.is_multiple_of(self.block_size) {
+            self.read_ahead();
+        }
+        let block = self.get_current_block_index()?;
+        let index_in_block = self.total_index % self.block_size;
+        let remain = self.contiguous_remain(self.block_size - index_in_block, buffer.len());
 
         let read_amount = core::cmp::min(remain, buffer.len() as u32);
-        unsafe {
-            core::ptr::copy_nonoverlapping(ptr, buffer.as_mut_ptr(), read_amount as usize);
-        }
+        self.inode
+            .fs
+            .read_block(
+                block,
+                index_in_block as usize,
+                &mut buffer[..read_amount as usize],
+            )
+            .ok()?;
 
         self.total_index += read_amount;
         Some(read_amount)
     }
+    /// The remain bytes of the current block, followed by those of the next blocks of the
+    /// content that come after it on the device, until wanted bytes
+    fn contiguous_remain(&self, remain: u32, wanted: usize) -> u32 {
+        let wanted = core::cmp::min(wanted, u32::MAX as usize) as u32;
+        if wanted <= remain || !self.inode.fs.blocks_in_place() {
+            return remain;
+        }
+        let index = self.total_index / self.block_size;
+        let blocks = 1 + (wanted - remain).div_ceil(self.block_size);
+        let block = self.inode.block_at(index);
+        let extra = self.inode.contiguous_blocks(index, block, blocks) - 1;
+        remain.saturating_add(extra.saturating_mul(self.block_size))
+    }
     #[inline]
     pub fn read(&mut self, buffer: &mut [u8]) -> usize {
         let mut index = 0;
-        log::trace!("Reading at most {} bytes from inode {}", buffer.len(), self.inode.id);
+        log::trace!(
+            "Reading at most {} bytes from inode {}",
+            buffer.len(),
+            self.inode.id
+        );
         while index < buffer.len() {
             match self.read_to_end_of_block_at_most(&mut buffer[index..]) {
                 None => break,
@@ -302,29 +1449,57 @@ impl<'inode, 'fs, 'device> Cursor<'inode, 'fs, 'device> {
         }
         index
     }
-    fn allocate_new_block(&mut self) -> *mut u8 {
-        let new_block_index = self.inode.reserve_block();
-        unsafe { self.inode.fs.get_block(new_block_index) }
+    fn allocate_new_block(&mut self) -> Result<*mut u8, Error> {
+        let index = self.total_index / self.block_size;
+        if index >= 12 {
+            return Err(Error::FileTooLarge);
+        }
+        // The block is not a hole, get_ptr refused it
+        if unsafe { read_field!(self.inode.data, direct_block_pointers[index as usize]) } != 0 {
+            return Err(Error::Corrupt("block number out of range"));
+        }
+        let new_block_index = self
+            .inode
+            .reserve_block(index, self.privileged)
+            .ok_or(Error::NoFreeBlocks)?;
+        let block = unsafe { self.inode.fs.get_block(new_block_index) };
+        // The block may still hold the data of a deleted file
+        unsafe { access::fill(block, 0, self.block_size as usize) };
+        Ok(block)
     }
-    fn write_to_end_of_block_at_most(&mut self, data: &[u8]) -> u32 {
-        let (ptr, remain) = self
-            .get_ptr()
-            .unwrap_or_else(|| (self.allocate_new_block(), self.block_size));
+    fn write_to_end_of_block_at_most(&mut self, data: &[u8]) -> Result<u32, Error> {
+        let (ptr, remain) = match self.get_ptr() {
+            Some(place) => place,
+            None => {
+                let index_in_block = self.total_index % self.block_size;
+                let block = self.allocate_new_block()?;
+                (
+                    unsafe { block.add(index_in_block as usize) },
+                    self.block_size - index_in_block,
+                )
+            }
+        };
         let write_amount = core::cmp::min(remain, data.len() as u32);
 
         unsafe {
-            core::ptr::copy_nonoverlapping(data.as_ptr(), ptr, write_amount as usize);
+            access::copy_to_device(data.as_ptr(), ptr, write_amount as usize);
         }
 
         self.total_index += write_amount;
-        write_amount
+        Ok(write_amount)
     }
-    #[inline]
-    pub fn write(&mut self, data: &[u8]) {
+    /// Write all of data, allocating the blocks as needed. If a block can't be allocated the
+    /// data before it is still written
+    pub fn write(&mut self, data: &[u8]) -> Result<(), Error> {
+        if self.inode.fs.read_only {
+            return Err(Error::ReadOnly);
+        }
+        self.inode.fs.note_write();
         let mut index = 0;
         while index < data.len() {
-            index += self.write_to_end_of_block_at_most(&data[index..]) as usize;
+            index += self.write_to_end_of_block_at_most(&data[index..])? as usize;
         }
+        Ok(())
     }
     #[inline]
     pub fn advance(&mut self, amount: u32) {
@@ -335,13 +1510,172 @@ impl<'inode, 'fs, 'device> Cursor<'inode, 'fs, 'device> {
     pub fn advance_to_end(&mut self) {
         self.advance(self.inode.size() - self.total_index)
     }
-    fn align(&mut self, align_to: u32) -> Option<u32> {
-        let index_in_block = self.total_index % self.block_size;
-        let misalign = index_in_block % align_to;
-        if misalign > self.remain_in_block() {
-            None
-        } else {
-            Some(misalign)
+}
+
+/// A block referenced by an inode, see `Inode::mapped_blocks`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MappedBlock {
+    /// A block of the content
+    Data(u32),
+    /// A block of pointers to other blocks, the level is 1 for the indirect block, 2 for the
+    /// doubly indirect one and 3 for the triply indirect one
+    Indirect { block: u32, level: u8 },
+    /// The block of extended attributes, it may be shared with other inodes
+    Xattr(u32),
+}
+
+impl MappedBlock {
+    pub fn block(self) -> u32 {
+        match self {
+            MappedBlock::Data(block) | MappedBlock::Xattr(block) => block,
+            MappedBlock::Indirect { block, .. } => block,
+        }
+    }
+}
+
+/// Where a block of the content of an inode is, see `Inode::blocks`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockMapping {
+    /// The position of the block in the content, in blocks
+    pub index: u32,
+    pub block: PhysicalBlock,
+    /// The indirect blocks followed to reach the pointer: 0 for the direct pointers of the
+    /// inode, 1 through the indirect block, 2 and 3 through the doubly and triply indirect ones
+    pub level: u8,
+}
+
+/// A run of the content of an inode, see `Inode::extent_at`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Extent {
+    /// The offset of the run from the start of the filesystem, 0 for a hole
+    pub start: u64,
+    /// The length of the run in bytes
+    pub len: u32,
+    pub hole: bool,
+}
+
+/// The block a pointer of an inode references
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PhysicalBlock {
+    /// The pointer as it is stored, it may be out of the filesystem
+    Block(u32),
+    /// The pointer is 0, or an indirect block on the way is missing or out of the filesystem
+    Hole,
+}
+
+/// The blocks of the content of an inode, see `Inode::blocks`
+pub struct BlockMappings<'inode, 'fs, 'device> {
+    inode: &'inode Inode<'fs, 'device>,
+    next: u32,
+    end: u32,
+}
+
+impl Iterator for BlockMappings<'_, '_, '_> {
+    type Item = BlockMapping;
+
+    fn next(&mut self) -> Option<BlockMapping> {
+        if self.next == self.end {
+            return None;
+        }
+        let index = self.next;
+        self.next += 1;
+        let (block, level) = self.inode.pointer_at(index);
+        Some(BlockMapping {
+            index,
+            block: match block {
+                0 => PhysicalBlock::Hole,
+                block => PhysicalBlock::Block(block),
+            },
+            level,
+        })
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = (self.end - self.next) as usize;
+        (len, Some(len))
+    }
+}
+
+impl ExactSizeIterator for BlockMappings<'_, '_, '_> {}
+
+/// The blocks referenced by an inode, in the order of the file. The holes are skipped.
+///
+/// The pointers that are not blocks of the filesystem are returned, the blocks they would
+/// reference are not
+pub struct MappedBlocks<'inode, 'fs, 'device> {
+    inode: &'inode Inode<'fs, 'device>,
+    /// The next pointer of the inode, the 12 direct ones then the indirect ones, followed by the
+    /// block of extended attributes
+    next: usize,
+    /// The indirect blocks being read, with the next pointer in each and the level of the
+    /// blocks they reference
+    stack: [(*const u32, u32, u8); 3],
+    depth: usize,
+}
+
+impl<'inode, 'fs, 'device> MappedBlocks<'inode, 'fs, 'device> {
+    /// A block referenced at level, it is read next if it is an indirect block
+    fn visit(&mut self, block: u32, level: u8) -> MappedBlock {
+        if level == 0 {
+            return MappedBlock::Data(block);
+        }
+        if let Ok(pointers) = unsafe { self.inode.fs.checked_block(block) } {
+            self.stack[self.depth] = (pointers as *const u32, 0, level - 1);
+            self.depth += 1;
+        }
+        MappedBlock::Indirect { block, level }
+    }
+}
+
+impl<'inode, 'fs, 'device> Iterator for MappedBlocks<'inode, 'fs, 'device> {
+    type Item = MappedBlock;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let pointers_per_block = self.inode.fs.block_size as u32 / 4;
+        loop {
+            if self.depth > 0 {
+                let (pointers, next, level) = &mut self.stack[self.depth - 1];
+                if *next == pointers_per_block {
+                    self.depth -= 1;
+                    continue;
+                }
+                let block = unsafe { access::read(pointers.add(*next as usize)) };
+                *next += 1;
+                let level = *level;
+                if block != 0 {
+                    return Some(self.visit(block, level));
+                }
+                continue;
+            }
+            let data = self.inode.data;
+            let index = self.next;
+            self.next += 1;
+            let (block, level) = match index {
+                0..=11 => (
+                    unsafe { read_field!(data, direct_block_pointers[index]) },
+                    0,
+                ),
+                12 => (
+                    unsafe { read_field!(data, singly_indirect_block_pointer) },
+                    1,
+                ),
+                13 => (
+                    unsafe { read_field!(data, doubly_indirect_block_pointer) },
+                    2,
+                ),
+                14 => (
+                    unsafe { read_field!(data, triply_indirect_block_pointer) },
+                    3,
+                ),
+                15 => match unsafe { read_field!(data, acl) } {
+                    0 => continue,
+                    block => return Some(MappedBlock::Xattr(block)),
+                },
+                _ => return None,
+            };
+            if block != 0 {
+                return Some(self.visit(block, level));
+            }
         }
     }
 }
@@ -351,93 +1685,141 @@ pub struct DirectoryEntries<'inode, 'fs, 'device> {
     reader: Cursor<'inode, 'fs, 'device>,
 }
 
+/// The size of a directory entry with a name of name_len bytes, rounded up to the 4 bytes
+/// alignment of entries
+fn record_size(name_len: usize) -> u16 {
+    ((core::mem::size_of::<RawDirectoryEntry>() + name_len + 3) & !3) as u16
+}
+
 impl<'inode, 'fs, 'device> DirectoryEntries<'inode, 'fs, 'device> {
+    /// The entries of a directory starting with the one at position
+    pub(crate) fn at(inode: &'inode Inode<'fs, 'device>, position: u32) -> Self {
+        DirectoryEntries {
+            reader: Cursor::at(inode, position),
+        }
+    }
+    /// The position of the next entry in the directory
+    pub(crate) fn position(&self) -> u32 {
+        self.reader.position()
+    }
     /// Make sure thant name.len() < 255
-    fn add_entry(&mut self, kind: EntryKind, name: &[u8], inode: InodeRef) {
-        let new_entry_size = (name.len() + core::mem::size_of::<RawDirectoryEntry>()) as u16;
+    fn add_entry(
+        &mut self,
+        kind: EntryKind,
+        name: &[u8],
+        new_inode: InodeRef,
+    ) -> Result<(), CreateError> {
+        let new_entry_size = record_size(name.len());
         loop {
             match unsafe { self.peek() } {
-                None => todo!("No peeking in entries"),
+                None if self.reader.total_index < self.reader.inode.size() => {
+                    // Growing the directory would hide the entry behind the corrupted one
+                    return Err(CreateError::Corrupt);
+                }
+                None => {
+                    // No entry has enough space left, the directory must grow by a block
+                    let inode = self.reader.inode;
+                    let size = inode.size();
+                    log::trace!("Growing directory {} to add {}", inode.id, name.as_bstr());
+                    self.reader = Cursor::at(inode, size).privileged(self.reader.privileged);
+                    let new_raw_entry = RawDirectoryEntry {
+                        inode: new_inode,
+                        size: self.reader.block_size as u16,
+                        name_len: u8::try_from(name.len()).expect("name was more than 255"),
+                        kind: self.reader.inode.fs.entry_kind_byte(kind),
+                    };
+                    unsafe {
+                        self.write_dir_entry(new_raw_entry, name)?;
+                    }
+                    inode.set_size(size + self.reader.block_size);
+                    return Ok(());
+                }
                 Some((dir_entry, split_name)) => {
-                    let padding_size: u16 = unsafe {
-                        (*dir_entry).size
-                            - (u16::from((*dir_entry).name_len)
-                                + core::mem::size_of::<RawDirectoryEntry>() as u16)
+                    // Entries are 4 bytes aligned, so the space taken by the current entry
+                    // must be rounded up
+                    let used_size = unsafe {
+                        if read_field!(dir_entry, inode).0 == 0 {
+                            0
+                        } else {
+                            record_size(split_name.len())
+                        }
                     };
+                    let padding_size = unsafe { read_field!(dir_entry, size) } - used_size;
                     // We don't have the space to insert our entry, let's try the next one
                     if padding_size < new_entry_size {
                         log::trace!("Skipping {}, only has {} padding", split_name, padding_size);
                         self.next();
                         continue;
                     }
-                    match self.reader.align(4) {
-                        // We are in the next block, we do what we must
-                        None => todo!("Writing to next block for dir entries"),
-                        Some(correction) => {
-                            log::trace!("Trying {}, checking align", split_name);
-                            let remaining_padding = padding_size - correction as u16;
-                            if remaining_padding < new_entry_size {
-                                log::trace!("Skipping {}, only has {} padding after align (corrected by {} bytes)", split_name, remaining_padding, correction);
-                                self.next();
-                                continue;
-                            } else {
-                                // We can now change the length of the current entry to leave space
-                                // for ours
-                                // The space of the new entry is (*dir_entry).size + correction
-                                log::trace!("Splitting {} to write new entry", split_name);
-                                unsafe {
-                                    (*dir_entry).size = correction as u16
-                                        + u16::try_from(split_name.len())
-                                            .expect("name was not an u16")
-                                        + core::mem::size_of::<RawDirectoryEntry>() as u16;
-                                    self.reader.advance((*dir_entry).size as u32);
-                                };
-                                let new_raw_entry = RawDirectoryEntry {
-                                    inode,
-                                    size: remaining_padding,
-                                    name_len: u8::try_from(name.len())
-                                        .expect("name was more than 255"),
-                                    kind,
-                                };
-                                unsafe {
-                                    self.write_dir_entry(new_raw_entry, name);
-                                }
-                                break;
-                            }
-                        }
+                    // We can now change the length of the current entry to leave space
+                    // for ours
+                    log::trace!("Splitting {} to write new entry", split_name);
+                    if used_size != 0 {
+                        unsafe { write_field!(dir_entry, size, used_size) };
+                        self.reader.advance(used_size as u32);
                     }
+                    let new_raw_entry = RawDirectoryEntry {
+                        inode: new_inode,
+                        size: padding_size,
+                        name_len: u8::try_from(name.len()).expect("name was more than 255"),
+                        kind: self.reader.inode.fs.entry_kind_byte(kind),
+                    };
+                    unsafe {
+                        self.write_dir_entry(new_raw_entry, name)?;
+                    }
+                    return Ok(());
                 }
             }
         }
     }
-    unsafe fn write_dir_entry(&mut self, entry: RawDirectoryEntry, name: &[u8]) {
-        self.reader.write(core::slice::from_raw_parts(
-            &entry as *const RawDirectoryEntry as *const u8,
-            core::mem::size_of::<RawDirectoryEntry>(),
-        ));
-        self.reader.write(name);
+    unsafe fn write_dir_entry(
+        &mut self,
+        entry: RawDirectoryEntry,
+        name: &[u8],
+    ) -> Result<(), CreateError> {
+        let growth_error = |error| match error {
+            Error::FileTooLarge => CreateError::DirectoryFull,
+            _ => CreateError::NoFreeBlocks,
+        };
+        self.reader
+            .write(core::slice::from_raw_parts(
+                &entry as *const RawDirectoryEntry as *const u8,
+                core::mem::size_of::<RawDirectoryEntry>(),
+            ))
+            .map_err(growth_error)?;
+        self.reader.write(name).map_err(growth_error)
     }
 
     unsafe fn peek(&self) -> Option<(*mut RawDirectoryEntry, &'fs BStr)> {
-        let ((dir_entry, name), _) = self.reader.peek_access_with(|input, remain| {
-            if remain < core::mem::size_of::<RawDirectoryEntry>() as u32 {
-                None
-            } else {
-                Some(DirectoryEntries::read_raw_entry(input))
-            }
-        })?;
-        if (*dir_entry).size == 0 {
-            None
-        } else {
-            Some((dir_entry, name))
+        if self.reader.total_index >= self.reader.inode.size() {
+            return None;
         }
+        let (entry, _) = self
+            .reader
+            .peek_access_with(|input, remain| DirectoryEntries::read_raw_entry(input, remain))?;
+        Some(entry)
     }
 
-    unsafe fn read_raw_entry(start: *mut u8) -> ((*mut RawDirectoryEntry, &'fs BStr), u32) {
+    /// Read the entry at start, where remain bytes are left in the block. Returns None if the
+    /// record is corrupted: it must be 4 bytes aligned, hold its name and fit in the block
+    unsafe fn read_raw_entry(
+        start: *mut u8,
+        remain: u32,
+    ) -> Option<((*mut RawDirectoryEntry, &'fs BStr), u32)> {
         log::trace!("Reading directory entry from {:?}", start);
+        if remain < DIRECTORY_ENTRY_HEADER {
+            return None;
+        }
+        let dir_entry = start as *const RawDirectoryEntry;
+        let size = u32::from(read_field!(dir_entry, size));
+        let name_len = u32::from(read_field!(dir_entry, name_len));
+        if !record_fits(size, name_len, remain) {
+            log::trace!("Corrupted directory entry of {} bytes", size);
+            return None;
+        }
         let (dir_entry, name) = RawDirectoryEntry::from_ptr_mut(start);
         log::trace!("name {:?}, entry", name);
-        ((dir_entry, name), (*dir_entry).size as u32)
+        Some(((dir_entry, name), size))
     }
 }
 
@@ -445,21 +1827,30 @@ impl<'inode, 'fs, 'device> core::iter::Iterator for DirectoryEntries<'inode, 'fs
     type Item = DirectoryEntry<'fs>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        unsafe {
-            let ((dir_entry, name), _) = self.reader.access_with(|input, remain| {
-                if remain < core::mem::size_of::<RawDirectoryEntry>() as u32 {
-                    None
-                } else {
-                    Some(DirectoryEntries::read_raw_entry(input))
-                }
-            })?;
+        loop {
+            // Anything past the size of the directory is not part of it
+            if self.reader.total_index >= self.reader.inode.size() {
+                return None;
+            }
+            unsafe {
+                // A corrupted record ends the iteration
+                let ((dir_entry, name), _) = self
+                    .reader
+                    .access_with(|input, remain| DirectoryEntries::read_raw_entry(input, remain))?;
 
-            log::trace!("Reading raw entry {:?}", *dir_entry);
-            let entry = DirectoryEntry::from_raw(dir_entry, name);
-            if entry.size == 0 {
-                None
-            } else {
-                Some(entry)
+                log::trace!("Reading raw entry {:?}", *dir_entry);
+                let mut entry = DirectoryEntry::from_raw(dir_entry, name);
+                if entry.inode.0 != 0 {
+                    let fs = self.reader.inode.fs;
+                    if !fs.typed_directories() {
+                        // Only the inode knows its type
+                        entry.kind = fs
+                            .get_inode(entry.inode)
+                            .map_or(EntryKind::Unkown, |inode| inode.file_type());
+                    }
+                    return Some(entry);
+                }
+                // Inode 0 marks a deleted entry
             }
         }
     }
@@ -483,7 +1874,7 @@ bitflags! {
     }
 }
 impl Permission {
-    fn to_typeperm(&self) -> TypePermission {
+    fn to_typeperm(self) -> TypePermission {
         // Safety: just compare the two definitions
         unsafe { TypePermission::from_bits_unchecked(self.bits()) }
     }
@@ -498,6 +1889,7 @@ bitflags! {
         const REGULAR_FILE = 0x8000;
         const SYMBOLIC_LINK = 0xA000;
         const UNIX_SOCKET = 0xC000;
+        const TYPE_MASK = 0xF000;
 
         const OTHER_EXECUTE = 0o00001;
         const OTHER_WRITE = 0o00002;
@@ -531,7 +1923,7 @@ bitflags! {
 }
 
 #[derive(Debug)]
-#[repr(C)]
+#[repr(C, packed)]
 pub struct InodeData {
     pub type_permission: TypePermission,
     pub user_id: u16,
@@ -557,7 +1949,122 @@ pub struct InodeData {
 }
 
 impl InodeData {
-    pub(crate) unsafe fn from_ptr<'a>(inode: *mut u8) -> *mut InodeData {
+    pub(crate) unsafe fn from_ptr(inode: *mut u8) -> *mut InodeData {
         inode as *mut InodeData
     }
+    /// The block pointer at slot of `block_path`: the 12 direct ones, then the singly, doubly
+    /// and triply indirect ones
+    pub(crate) unsafe fn pointer(inode: *const InodeData, slot: usize) -> u32 {
+        match slot {
+            0..=11 => read_field!(inode, direct_block_pointers[slot]),
+            12 => read_field!(inode, singly_indirect_block_pointer),
+            13 => read_field!(inode, doubly_indirect_block_pointer),
+            _ => read_field!(inode, triply_indirect_block_pointer),
+        }
+    }
+    pub(crate) unsafe fn set_pointer(inode: *mut InodeData, slot: usize, block: u32) {
+        match slot {
+            0..=11 => write_field!(inode, direct_block_pointers[slot], block),
+            12 => write_field!(inode, singly_indirect_block_pointer, block),
+            13 => write_field!(inode, doubly_indirect_block_pointer, block),
+            _ => write_field!(inode, triply_indirect_block_pointer, block),
+        }
+    }
+}
+
+/// Where the block at index of a file is referenced, with per_block pointers in each indirect
+/// block: the slot of the pointer in the inode, see `InodeData::pointer`, the index of the
+/// block below that pointer and the number of indirect blocks to go through.
+///
+/// At each of the levels, the pointer is the entry `index / per_block.pow(level)` of the
+/// indirect block and the index becomes the remainder
+pub(crate) fn block_path(index: u32, per_block: u32) -> (usize, u32, u32) {
+    if index < 12 {
+        (index as usize, 0, 0)
+    } else if index - 12 < per_block {
+        (12, index - 12, 1)
+    } else if u64::from(index - 12 - per_block) < u64::from(per_block) * u64::from(per_block) {
+        (13, index - 12 - per_block, 2)
+    } else {
+        (14, index - 12 - per_block - per_block * per_block, 3)
+    }
+}
+
+/// The size of the fixed part of a directory record, before the name
+pub(crate) const DIRECTORY_ENTRY_HEADER: u32 = core::mem::size_of::<RawDirectoryEntry>() as u32;
+
+/// If a directory record of size bytes, with a name of name_len bytes, is valid where remain
+/// bytes are left in the block: it must be 4 bytes aligned, hold its name and fit in the block
+pub(crate) fn record_fits(size: u32, name_len: u32, remain: u32) -> bool {
+    size >= DIRECTORY_ENTRY_HEADER + name_len && size.is_multiple_of(4) && size <= remain
+}
+
+/// The fields after the first 128 bytes of an inode, when the inodes of the filesystem are
+/// bigger. Only the first `extra_isize` bytes are in use.
+///
+/// Each `*_extra` field extends the timestamp of InodeData: the two low bits are added to the
+/// seconds past 2038, the others are the nanoseconds
+#[derive(Debug)]
+#[repr(C, packed)]
+pub struct InodeExtra {
+    pub extra_isize: u16,
+    pub checksum_hi: u16,
+    pub creation_time_extra: u32,
+    pub last_modification_time_extra: u32,
+    pub last_access_time_extra: u32,
+    /// When the inode was created, the `creation_time` of InodeData changes with the inode
+    pub crtime: u32,
+    pub crtime_extra: u32,
+    pub version_hi: u32,
+    pub project_id: u32,
+}
+
+/// A time in seconds since the epoch
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Timestamp {
+    pub seconds: i64,
+    /// Always 0 on filesystems with 128 bytes inodes
+    pub nanoseconds: u32,
+}
+
+impl Timestamp {
+    /// The seconds of a timestamp and its extra field, if the inode has one. The seconds are
+    /// signed when they are extended
+    fn decode(seconds: u32, extra: Option<u32>) -> Self {
+        match extra {
+            None => Timestamp {
+                seconds: i64::from(seconds),
+                nanoseconds: 0,
+            },
+            Some(extra) => Timestamp {
+                seconds: i64::from(seconds as i32) + (i64::from(extra & 0b11) << 32),
+                nanoseconds: extra >> 2,
+            },
+        }
+    }
+    /// The extra field of a whole number of seconds
+    fn extra_of(seconds: u32) -> u32 {
+        u32::from(seconds > i32::MAX as u32)
+    }
+}
+
+/// The attributes of an inode, see `Inode::metadata`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Metadata {
+    pub kind: EntryKind,
+    pub permissions: Permission,
+    pub user_id: u16,
+    pub group_id: u16,
+    pub size: u32,
+    pub link_count: u16,
+    /// Number of 512 bytes sectors
+    pub blocks_used: u32,
+    pub accessed: Timestamp,
+    /// The last change of the inode, `creation_time` in InodeData
+    pub changed: Timestamp,
+    pub modified: Timestamp,
+    /// 0 unless the inode was released
+    pub deleted: u32,
+    /// Only recorded in the inodes bigger than 128 bytes
+    pub created: Option<Timestamp>,
 }
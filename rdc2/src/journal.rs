@@ -0,0 +1,673 @@
+//! Reading the journal of ext3 filesystems, see `FileSystem::journal`. Nothing is replayed.
+//!
+//! The journal is a circular log in the blocks of an inode. Its first block is a superblock,
+//! the log is made of transactions: descriptor blocks followed by the copies of the blocks of
+//! the filesystem they list, revoke blocks, and a commit block ending the transaction. The
+//! metadata blocks start with a header and all their fields are big endian.
+
+use bitflags::bitflags;
+
+use super::{access, Error, FileSystem, Inode, InodeRef};
+use crate::metadata::{OptionalFeatures, RequiredFeatures};
+
+pub const JOURNAL_MAGIC: u32 = 0xC03B_3998;
+
+const DESCRIPTOR_BLOCK: u32 = 1;
+const COMMIT_BLOCK: u32 = 2;
+const SUPERBLOCK_V1: u32 = 3;
+const SUPERBLOCK_V2: u32 = 4;
+const REVOKE_BLOCK: u32 = 5;
+
+/// The magic, the type of the block and the sequence number of its transaction
+const HEADER_SIZE: usize = 12;
+/// The checksum at the end of the descriptor and revoke blocks of checksummed journals
+const TAIL_SIZE: usize = 4;
+const UUID_SIZE: usize = 16;
+
+const TAG_ESCAPED: u32 = 0x1;
+const TAG_SAME_UUID: u32 = 0x2;
+const TAG_LAST: u32 = 0x8;
+
+bitflags! {
+    /// The features of the journal that change its format, only recorded by version 2
+    pub struct JournalIncompatFeatures: u32 {
+        const REVOKE = 0x01;
+        const BLOCK_64BIT = 0x02;
+        const ASYNC_COMMIT = 0x04;
+        const CHECKSUM_V2 = 0x08;
+        const CHECKSUM_V3 = 0x10;
+        const FAST_COMMIT = 0x20;
+    }
+}
+
+/// The superblock of a journal, in the first block of its inode
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct JournalSuperblock {
+    /// 1 or 2, the features are only recorded by version 2
+    pub version: u8,
+    pub block_size: u32,
+    /// The number of blocks of the journal
+    pub len: u32,
+    /// The first block of the log, the ones before are the superblock
+    pub first: u32,
+    /// The sequence number of the first transaction to replay
+    pub sequence: u32,
+    /// The block of that transaction, 0 if the journal is empty
+    pub start: u32,
+    /// The error recorded by the kernel, 0 if there is none
+    pub errno: i32,
+    pub compat_features: u32,
+    pub incompat_features: JournalIncompatFeatures,
+    pub ro_compat_features: u32,
+    pub uuid: [u8; 16],
+}
+
+/// A block of the filesystem logged by a descriptor block
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoggedBlock {
+    /// The block of the filesystem. The high bits of 64 bits journals are not kept, the
+    /// filesystem has 32 bits block numbers
+    pub block: u32,
+    /// The block of the journal holding the copy
+    pub position: u32,
+    /// The copy started with the journal magic, it was replaced by zeroes
+    pub escaped: bool,
+}
+
+/// The blocks of the filesystem listed by a descriptor block, in the order of their copies
+#[derive(Debug, Clone)]
+pub struct LoggedBlocks<'fs> {
+    tags: &'fs [u8],
+    tag_size: usize,
+    position: u32,
+    first: u32,
+    len: u32,
+}
+
+impl Iterator for LoggedBlocks<'_> {
+    type Item = LoggedBlock;
+
+    fn next(&mut self) -> Option<LoggedBlock> {
+        if self.tags.len() < self.tag_size {
+            return None;
+        }
+        let block = be32(self.tags, 0);
+        // Version 3 tags have 32 bits flags, the older ones a 16 bits checksum then the flags
+        let flags = if self.tag_size >= 16 {
+            be32(self.tags, 4)
+        } else {
+            be32(self.tags, 4) & 0xFFFF
+        };
+        let logged = LoggedBlock {
+            block,
+            position: self.position,
+            escaped: flags & TAG_ESCAPED != 0,
+        };
+        let mut size = self.tag_size;
+        if flags & TAG_SAME_UUID == 0 {
+            size += UUID_SIZE;
+        }
+        self.tags = if flags & TAG_LAST != 0 || size > self.tags.len() {
+            &[]
+        } else {
+            &self.tags[size..]
+        };
+        self.position = wrap(self.position + 1, self.first, self.len);
+        Some(logged)
+    }
+}
+
+/// The blocks of the filesystem listed by a revoke block, their older copies must not be
+/// replayed
+#[derive(Debug, Clone)]
+pub struct RevokedBlocks<'fs> {
+    records: &'fs [u8],
+    record_size: usize,
+}
+
+impl Iterator for RevokedBlocks<'_> {
+    type Item = u32;
+
+    fn next(&mut self) -> Option<u32> {
+        if self.records.len() < self.record_size {
+            return None;
+        }
+        // The low bits of 64 bits records are last
+        let block = be32(self.records, self.record_size - 4);
+        self.records = &self.records[self.record_size..];
+        Some(block)
+    }
+}
+
+/// What a metadata block of the log holds
+#[derive(Debug, Clone)]
+pub enum JournalContent<'fs> {
+    Descriptor(LoggedBlocks<'fs>),
+    /// The end of the transaction, with the time of the commit in seconds if it was recorded
+    Commit {
+        seconds: u64,
+    },
+    Revoke(RevokedBlocks<'fs>),
+}
+
+/// A metadata block of the log, see `Journal::blocks`
+#[derive(Debug, Clone)]
+pub struct JournalBlock<'fs> {
+    /// The transaction it belongs to
+    pub sequence: u32,
+    /// Its block in the journal
+    pub position: u32,
+    pub content: JournalContent<'fs>,
+}
+
+/// What `FileSystem::replay_journal` did
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ReplayStats {
+    /// The committed transactions
+    pub transactions: u32,
+    /// The blocks copied back to the filesystem
+    pub replayed_blocks: u32,
+    /// The logged blocks that were not copied, as a revoke record lists them
+    pub revoked_blocks: u32,
+}
+
+/// The journal of a filesystem, see `FileSystem::journal`
+pub struct Journal<'fs, 'device> {
+    fs: &'fs FileSystem<'device>,
+    inode: Inode<'fs, 'device>,
+    superblock: JournalSuperblock,
+}
+
+impl<'fs, 'device> Journal<'fs, 'device> {
+    pub fn superblock(&self) -> &JournalSuperblock {
+        &self.superblock
+    }
+    /// The inode holding the journal
+    pub fn inode(&self) -> &Inode<'fs, 'device> {
+        &self.inode
+    }
+    /// The metadata blocks of the log from its start, in order.
+    ///
+    /// The log ends at the first block without the magic or with an unexpected sequence
+    /// number. The last transaction was not fully written if it is not followed by its commit
+    /// block
+    pub fn blocks(&self) -> JournalBlocks<'_, 'fs, 'device> {
+        JournalBlocks {
+            journal: self,
+            position: self.superblock.start,
+            sequence: self.superblock.sequence,
+            // Each block is read at most once
+            remaining: match self.superblock.start {
+                0 => 0,
+                _ => self.superblock.len - self.superblock.first,
+            },
+        }
+    }
+    /// The block position of the journal
+    fn block(&self, position: u32) -> Option<&'fs [u8]> {
+        read_block(self.fs, &self.inode, position)
+    }
+    /// Whether a revoke record of a committed transaction from sequence to end lists block
+    fn is_revoked(&self, block: u32, sequence: u32, end: u32) -> bool {
+        self.blocks()
+            .take_while(|record| record.sequence != end)
+            .filter(|record| !after(sequence, record.sequence))
+            .any(|record| match record.content {
+                JournalContent::Revoke(mut revoked) => revoked.any(|revoked| revoked == block),
+                _ => false,
+            })
+    }
+    fn tail_size(&self) -> usize {
+        let checksummed =
+            JournalIncompatFeatures::CHECKSUM_V2 | JournalIncompatFeatures::CHECKSUM_V3;
+        if self.superblock.incompat_features.intersects(checksummed) {
+            TAIL_SIZE
+        } else {
+            0
+        }
+    }
+    fn tag_size(&self) -> usize {
+        let features = self.superblock.incompat_features;
+        if features.contains(JournalIncompatFeatures::CHECKSUM_V3) {
+            return 16;
+        }
+        let mut size = 8;
+        if features.contains(JournalIncompatFeatures::CHECKSUM_V2) {
+            size += 2;
+        }
+        if features.contains(JournalIncompatFeatures::BLOCK_64BIT) {
+            size += 4;
+        }
+        size
+    }
+}
+
+/// The metadata blocks of a journal, see `Journal::blocks`
+pub struct JournalBlocks<'journal, 'fs, 'device> {
+    journal: &'journal Journal<'fs, 'device>,
+    position: u32,
+    sequence: u32,
+    remaining: u32,
+}
+
+impl<'fs> Iterator for JournalBlocks<'_, 'fs, '_> {
+    type Item = JournalBlock<'fs>;
+
+    fn next(&mut self) -> Option<JournalBlock<'fs>> {
+        if self.remaining == 0 {
+            return None;
+        }
+        let journal = self.journal;
+        let superblock = &journal.superblock;
+        let data = journal.block(self.position)?;
+        if be32(data, 0) != JOURNAL_MAGIC || be32(data, 8) != self.sequence {
+            log::trace!("End of the journal at {}", self.position);
+            self.remaining = 0;
+            return None;
+        }
+        let position = self.position;
+        let mut used = 1;
+        let content = match be32(data, 4) {
+            DESCRIPTOR_BLOCK => {
+                let tags = LoggedBlocks {
+                    tags: &data[HEADER_SIZE..data.len() - journal.tail_size()],
+                    tag_size: journal.tag_size(),
+                    position: wrap(position + 1, superblock.first, superblock.len),
+                    first: superblock.first,
+                    len: superblock.len,
+                };
+                // The copies follow the descriptor
+                used += tags.clone().count() as u32;
+                JournalContent::Descriptor(tags)
+            }
+            COMMIT_BLOCK => {
+                self.sequence = self.sequence.wrapping_add(1);
+                JournalContent::Commit {
+                    seconds: u64::from(be32(data, 48)) << 32 | u64::from(be32(data, 52)),
+                }
+            }
+            REVOKE_BLOCK => {
+                // The bytes used by the block, including its header and the count
+                let count = be32(data, HEADER_SIZE) as usize;
+                let end = core::cmp::min(count, data.len() - journal.tail_size());
+                let records = data.get(HEADER_SIZE + 4..end).unwrap_or(&[]);
+                let record_size = if superblock
+                    .incompat_features
+                    .contains(JournalIncompatFeatures::BLOCK_64BIT)
+                {
+                    8
+                } else {
+                    4
+                };
+                JournalContent::Revoke(RevokedBlocks {
+                    records,
+                    record_size,
+                })
+            }
+            kind => {
+                log::trace!("Unknown journal block {} at {}", kind, position);
+                self.remaining = 0;
+                return None;
+            }
+        };
+        self.remaining = self.remaining.saturating_sub(used);
+        self.position = wrap(position + used, superblock.first, superblock.len);
+        Some(JournalBlock {
+            sequence: be32(data, 8),
+            position,
+            content,
+        })
+    }
+}
+
+impl<'device> FileSystem<'device> {
+    /// The journal of the filesystem, NotFound if it has none.
+    ///
+    /// Only the journals in an inode can be read. The superblock of the journal must describe a
+    /// log in the blocks of the inode, with the block size of the filesystem
+    pub fn journal(&self) -> Result<Journal<'_, 'device>, Error> {
+        let extended = self.get_extended_superblock();
+        if !{ extended.optional_features }.contains(OptionalFeatures::JOURNALING) {
+            return Err(Error::NotFound);
+        }
+        let (inode, device) = (extended.journal_inode, extended.journal_device);
+        if inode == 0 {
+            return Err(match device {
+                0 => Error::NotFound,
+                _ => Error::UnsupportedFeature("external journal"),
+            });
+        }
+        let inode = self.get_inode(InodeRef::new(inode).ok_or(Error::BadInodeRef)?)?;
+        let corrupt = Error::Corrupt("journal superblock");
+        let data = read_block(self, &inode, 0).ok_or(corrupt)?;
+        let version = match be32(data, 4) {
+            SUPERBLOCK_V1 => 1,
+            SUPERBLOCK_V2 => 2,
+            _ => return Err(corrupt),
+        };
+        if be32(data, 0) != JOURNAL_MAGIC {
+            return Err(corrupt);
+        }
+        let mut uuid = [0; 16];
+        uuid.copy_from_slice(&data[48..64]);
+        let features = |offset| if version == 2 { be32(data, offset) } else { 0 };
+        let superblock = JournalSuperblock {
+            version,
+            block_size: be32(data, 12),
+            len: be32(data, 16),
+            first: be32(data, 20),
+            sequence: be32(data, 24),
+            start: be32(data, 28),
+            errno: be32(data, 32) as i32,
+            compat_features: features(36),
+            incompat_features: JournalIncompatFeatures::from_bits_truncate(features(40)),
+            ro_compat_features: features(44),
+            uuid,
+        };
+        log::trace!("Journal superblock {:?}", superblock);
+        if superblock.block_size as usize != self.block_size {
+            return Err(Error::Corrupt("journal block size"));
+        }
+        let blocks = inode.size() / superblock.block_size;
+        if superblock.first == 0
+            || superblock.first >= superblock.len
+            || superblock.len > blocks
+            || (superblock.start != 0
+                && (superblock.start < superblock.first || superblock.start >= superblock.len))
+        {
+            return Err(corrupt);
+        }
+        Ok(Journal {
+            fs: self,
+            inode,
+            superblock,
+        })
+    }
+}
+
+impl FileSystem<'_> {
+    /// Copy the blocks logged by the committed transactions of the journal to their place in
+    /// the filesystem, then mark the journal empty and clear `RequiredFeatures::REPLAY_JOURNAL`.
+    ///
+    /// The transactions after the last commit block were not fully written and are discarded.
+    /// A block is not copied if a revoke record of its transaction or of a later one lists it.
+    /// Does nothing if the journal does not need to be replayed, ReadOnly if the filesystem was
+    /// opened read-only on purpose. The FileSystem stays read-only, the device must be opened
+    /// again to be written
+    pub fn replay_journal(&self) -> Result<ReplayStats, Error> {
+        let required = { self.get_extended_superblock().required_features };
+        if !required.contains(RequiredFeatures::REPLAY_JOURNAL) {
+            return Ok(ReplayStats::default());
+        }
+        if !self.replay_pending.get() {
+            return Err(Error::ReadOnly);
+        }
+        let journal = self.journal()?;
+        let start = journal.superblock.sequence;
+        // The sequence number after the last committed transaction
+        let end = journal
+            .blocks()
+            .filter(|record| matches!(record.content, JournalContent::Commit { .. }))
+            .last()
+            .map_or(start, |commit| commit.sequence.wrapping_add(1));
+        let mut stats = ReplayStats {
+            transactions: end.wrapping_sub(start),
+            ..ReplayStats::default()
+        };
+        log::trace!("Replaying transactions {} to {}", start, end);
+
+        for record in journal.blocks().take_while(|record| record.sequence != end) {
+            let logged = match record.content {
+                JournalContent::Descriptor(logged) => logged,
+                _ => continue,
+            };
+            for logged in logged {
+                if journal.is_revoked(logged.block, record.sequence, end) {
+                    log::trace!("Block {} is revoked", logged.block);
+                    stats.revoked_blocks += 1;
+                    continue;
+                }
+                let copy = block_ptr(self, &journal.inode, logged.position)
+                    .ok_or(Error::Corrupt("journal block out of range"))?;
+                unsafe {
+                    let block = self.checked_block(logged.block)?;
+                    access::copy_within(copy, block, self.block_size);
+                    if logged.escaped {
+                        access::write(block as *mut [u8; 4], JOURNAL_MAGIC.to_be_bytes());
+                    }
+                }
+                stats.replayed_blocks += 1;
+            }
+        }
+
+        let superblock =
+            block_ptr(self, &journal.inode, 0).ok_or(Error::Corrupt("journal superblock"))?;
+        unsafe {
+            // Skip a sequence number like jbd2, the log may hold blocks of the discarded one
+            let sequence = end.wrapping_add(1);
+            access::write(superblock.add(24) as *mut [u8; 4], sequence.to_be_bytes());
+            access::write(superblock.add(28) as *mut [u8; 4], [0; 4]);
+        }
+        // The superblock may have been replayed, its features are read again
+        self.update_extended_superblock(|extended| {
+            extended.required_features =
+                { extended.required_features } - RequiredFeatures::REPLAY_JOURNAL
+        });
+        self.replay_pending.set(false);
+        Ok(stats)
+    }
+}
+
+/// The block position of the journal in inode
+fn read_block<'fs>(
+    fs: &'fs FileSystem<'_>,
+    inode: &Inode<'_, '_>,
+    position: u32,
+) -> Option<&'fs [u8]> {
+    let data = block_ptr(fs, inode, position)?;
+    Some(unsafe { core::slice::from_raw_parts(data, fs.block_size) })
+}
+
+fn block_ptr(fs: &FileSystem<'_>, inode: &Inode<'_, '_>, position: u32) -> Option<*mut u8> {
+    let block = inode.block_at(position)?;
+    unsafe { fs.checked_block(block) }.ok()
+}
+
+/// Whether the sequence number a is after b, they wrap around
+fn after(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) > 0
+}
+
+/// The big endian u32 at offset of data
+fn be32(data: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&data[offset..offset + 4]);
+    u32::from_be_bytes(bytes)
+}
+
+/// The log goes back to first after its last block
+fn wrap(position: u32, first: u32, len: u32) -> u32 {
+    if position >= len {
+        position - len + first
+    } else {
+        position
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use std::vec::Vec;
+
+    use super::{JournalContent, JournalIncompatFeatures, LoggedBlock, ReplayStats, JOURNAL_MAGIC};
+    use crate::inode::MappedBlock;
+    use crate::metadata::RequiredFeatures;
+    use crate::tests::load_image;
+    use crate::{Error, Ext2Device};
+
+    /// The transactions written by debugfs: blocks 300 and 301, a revoke of 301, then block 400
+    #[test]
+    fn transactions() {
+        let mut image = load_image("test_fs_journal");
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        // The journal must be replayed before writing
+        let fs = device.try_open().unwrap();
+        assert!(fs.is_read_only());
+        let journal = fs.journal().unwrap();
+        let superblock = journal.superblock();
+        assert_eq!(superblock.version, 2);
+        assert_eq!(superblock.block_size, 1024);
+        assert_eq!(superblock.len, 1024);
+        assert_eq!((superblock.first, superblock.start), (1, 1));
+        assert_eq!(superblock.sequence, 1);
+        assert_eq!(
+            superblock.incompat_features,
+            JournalIncompatFeatures::REVOKE
+        );
+
+        // The journal spans indirect and doubly indirect blocks
+        let blocks: Vec<_> = journal
+            .inode()
+            .mapped_blocks()
+            .filter_map(|mapped| match mapped {
+                MappedBlock::Data(block) => Some(block),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(blocks.len(), 1024);
+        assert!(
+            (0..1024).all(|index| journal.inode().block_at(index) == Some(blocks[index as usize]))
+        );
+
+        let logged = |position, block| LoggedBlock {
+            block,
+            position,
+            escaped: false,
+        };
+        let mut records = journal.blocks();
+        let mut next = || {
+            let record = records.next().unwrap();
+            (record.sequence, record.position, record.content)
+        };
+        match next() {
+            (1, 1, JournalContent::Descriptor(tags)) => {
+                assert_eq!(tags.collect::<Vec<_>>(), [logged(2, 300), logged(3, 301)])
+            }
+            record => panic!("{:?}", record),
+        }
+        assert!(matches!(next(), (1, 4, JournalContent::Commit { seconds }) if seconds > 0));
+        match next() {
+            (2, 5, JournalContent::Revoke(revoked)) => {
+                assert_eq!(revoked.collect::<Vec<_>>(), [301])
+            }
+            record => panic!("{:?}", record),
+        }
+        assert!(matches!(next(), (2, 6, JournalContent::Commit { .. })));
+        match next() {
+            (3, 7, JournalContent::Descriptor(tags)) => {
+                assert_eq!(tags.collect::<Vec<_>>(), [logged(8, 400)])
+            }
+            record => panic!("{:?}", record),
+        }
+        assert!(matches!(next(), (3, 9, JournalContent::Commit { .. })));
+        assert!(records.next().is_none());
+        // The copies of the data written
+        assert_eq!(journal.block(2).unwrap()[0], 0xAA);
+        assert_eq!(journal.block(3).unwrap()[0], 0xBB);
+        assert_eq!(journal.block(8).unwrap()[0], 0xAA);
+    }
+
+    #[test]
+    fn unfinished_transaction() {
+        let mut image = load_image("test_fs_journal");
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.try_open_read_only().unwrap();
+        let journal = fs.journal().unwrap();
+        let commit = journal.inode().block_at(9).unwrap();
+        let commit = unsafe { fs.get_block(commit) };
+        assert_eq!(
+            unsafe { *(commit as *const [u8; 4]) },
+            JOURNAL_MAGIC.to_be_bytes()
+        );
+        unsafe { commit.write_bytes(0, 4) };
+        let records: Vec<_> = journal.blocks().map(|record| record.position).collect();
+        assert_eq!(records, [1, 4, 5, 6, 7]);
+
+        // An empty journal has no records
+        let superblock = journal.inode().block_at(0).unwrap();
+        unsafe { fs.get_block(superblock).add(28).write_bytes(0, 4) };
+        let journal = fs.journal().unwrap();
+        assert_eq!(journal.superblock().start, 0);
+        assert_eq!(journal.blocks().count(), 0);
+    }
+
+    #[test]
+    fn no_journal() {
+        let mut image = load_image("test_fs_acl");
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.open();
+        assert_eq!(fs.journal().err(), Some(Error::NotFound));
+    }
+
+    #[test]
+    fn replay() {
+        let mut image = load_image("test_fs_journal");
+        let block = |image: &[u8], block: usize| image[block * 1024];
+        assert_eq!((block(&image, 300), block(&image, 400)), (0, 0));
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.try_open().unwrap();
+        // 301 is revoked by the second transaction
+        assert_eq!(
+            fs.replay_journal(),
+            Ok(ReplayStats {
+                transactions: 3,
+                replayed_blocks: 2,
+                revoked_blocks: 1,
+            })
+        );
+        assert_eq!(
+            { fs.get_extended_superblock().required_features },
+            RequiredFeatures::TYPED_DIRECTORY
+        );
+        let journal = fs.journal().unwrap();
+        assert_eq!(journal.superblock().sequence, 5);
+        assert_eq!(journal.superblock().start, 0);
+        assert_eq!(fs.replay_journal(), Ok(ReplayStats::default()));
+        drop(fs);
+        assert_eq!(block(&image, 300), 0xAA);
+        assert_eq!(block(&image, 301), 0);
+        assert_eq!(block(&image, 400), 0xAA);
+
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.try_open().unwrap();
+        assert!(!fs.is_read_only());
+    }
+
+    #[test]
+    fn replay_committed() {
+        let mut image = load_image("test_fs_journal");
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        // Nothing is written to a filesystem opened read-only
+        let fs = device.try_open_read_only().unwrap();
+        assert_eq!(fs.replay_journal(), Err(Error::ReadOnly));
+        drop(fs);
+
+        // The last transaction is not committed
+        let fs = device.try_open().unwrap();
+        let commit = fs.journal().unwrap().inode().block_at(9).unwrap();
+        unsafe { fs.get_block(commit).write_bytes(0, 4) };
+        assert_eq!(
+            fs.replay_journal(),
+            Ok(ReplayStats {
+                transactions: 2,
+                replayed_blocks: 1,
+                revoked_blocks: 1,
+            })
+        );
+        assert_eq!(fs.journal().unwrap().superblock().sequence, 4);
+        assert!(!{ fs.get_extended_superblock().required_features }
+            .contains(RequiredFeatures::REPLAY_JOURNAL));
+        drop(fs);
+        assert_eq!(image[400 * 1024], 0);
+    }
+}
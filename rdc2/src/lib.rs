@@ -1,51 +1,590 @@
 #![no_std]
+#[cfg(feature = "alloc")]
+extern crate alloc;
 extern crate core;
+#[cfg(feature = "std")]
+extern crate std;
 
+#[macro_use]
+mod access;
+pub mod acl;
+#[cfg(feature = "alloc")]
+pub mod cache;
+#[cfg(feature = "alloc")]
+pub mod check;
+pub mod device;
+pub mod diff;
+pub mod dir;
+#[cfg(feature = "alloc")]
+mod dirty;
+pub mod error;
+pub mod file;
 pub mod inode;
+pub mod journal;
 pub mod metadata;
+pub mod mkfs;
+#[cfg(feature = "async")]
+pub mod nonblocking;
+pub mod partition;
+#[cfg(feature = "alloc")]
+mod pool;
+pub mod readonly;
+#[cfg(feature = "exclusive")]
+pub mod registry;
+pub mod report;
+mod resize;
+#[cfg(feature = "sync")]
+pub mod shared;
+mod tar;
+#[cfg(feature = "alloc")]
+pub mod transaction;
+pub mod walk;
+pub mod xattr;
+pub use diff::diff;
+pub use dir::Dir;
+pub use error::{CreateError, Error, OpenError, UnsupportedFeatures};
+pub use file::{File, OpenOptions};
 pub use inode::{Inode, InodeRef};
 
-use inode::InodeData;
-use metadata::{BlockGroupDescriptor, ExtendedSuperblock, Superblock};
+use core::cell::Cell;
+use core::cmp::min;
+use core::convert::TryFrom;
+use core::marker::PhantomData;
+
+use inode::{root_inode, EntryKind, InodeData, Permission};
+use metadata::{
+    BlockGroupDescriptor, ExtendedSuperblock, FsState, OnError, OptionalFeatures, RequiredFeatures,
+    Superblock, WriteFeatures, BLOCK_GROUP_DESCRITPOR_SIZE, SUPERBLOCK_SIZE,
+};
+
+/// The required features implemented, a filesystem with others can't be opened
+const SUPPORTED_REQUIRED_FEATURES: RequiredFeatures = RequiredFeatures::TYPED_DIRECTORY;
+/// The write features implemented, a filesystem with others is opened read-only
+const SUPPORTED_WRITE_FEATURES: WriteFeatures = WriteFeatures::from_bits_truncate(
+    WriteFeatures::SPARSE_SUPERBLOCK_GROUP_DESCRIPTOR_TABLE.bits()
+        | WriteFeatures::FILE_SIZE_64.bits(),
+);
+/// The blocks of a lost+found directory created by `FileSystem::ensure_lost_and_found`
+const LOST_FOUND_BLOCKS: u32 = 12;
+/// The symlinks `FileSystem::read_file` follows before giving up, like ELOOP
+pub const MAX_SYMLINKS: u32 = 8;
 
 /// A device partionned in ext2
-pub struct Ext2Device {
+pub struct Ext2Device<'device> {
     device: *mut u8,
+    /// The size of the region, unknown for the devices made with `from_ptr`
+    len: Option<usize>,
+    region: PhantomData<&'device mut [u8]>,
 }
 
-impl Ext2Device {
-    /// You give ownership of the fs to this. The pointer must be valid for as long as the
-    /// Ext2Device exists
+impl<'device> Ext2Device<'device> {
+    /// The filesystem in device. InvalidSuperblock if the region is too short to hold a
+    /// superblock.
+    ///
+    /// The filesystem must fit in the region to be opened, so that every block it references is
+    /// in it
+    pub fn from_slice(device: &'device mut [u8]) -> Result<Self, Error> {
+        if device.len() < 2048 {
+            return Err(Error::InvalidSuperblock);
+        }
+        Ok(Ext2Device {
+            device: device.as_mut_ptr(),
+            len: Some(device.len()),
+            region: PhantomData,
+        })
+    }
+
+    /// The filesystem starting offset bytes into device, like a partition found with
+    /// `partition::partitions`. The blocks are addressed from offset, the filesystem must fit
+    /// between offset and the end of device
+    pub fn from_slice_at(device: &'device mut [u8], offset: usize) -> Result<Self, Error> {
+        Self::from_slice(device.get_mut(offset..).ok_or(Error::InvalidSuperblock)?)
+    }
+
+    /// The filesystem at device, for the regions that can't be borrowed as a slice like memory
+    /// mapped devices. The size of the region is not known, the blocks are not checked against
+    /// it.
+    ///
+    /// # Safety
+    ///
+    /// The pointer must be valid for as long as the Ext2Device exists, and the whole filesystem
+    /// described by its superblock must be readable and writable from it
     pub unsafe fn from_ptr(device: *mut u8) -> Self {
-        Ext2Device { device }
+        Ext2Device {
+            device,
+            len: None,
+            region: PhantomData,
+        }
     }
 
-    /// Open the filesystem
+    /// Open the filesystem, panics if the region does not hold a supported ext2 filesystem
     pub fn open(&mut self) -> FileSystem<'_> {
-        let (superblock, extended) = unsafe { Superblock::from_ptr(self.device.offset(1024)) };
-        let extended = extended.expect("only support for version >= 1");
+        self.try_open().expect("not a supported ext2 filesystem")
+    }
+
+    /// Open the filesystem, fails if the superblock is not one of a supported ext2 filesystem.
+    /// Only the superblock and the group descriptors are read, the bitmaps and inode tables of the
+    /// descriptors must be in the filesystem. With `from_slice`, the filesystem must fit in the
+    /// region.
+    ///
+    /// The filesystem is opened read-only if it has write features that are not implemented, or
+    /// if its journal must be replayed, see `FileSystem::replay_journal`.
+    /// If its state records errors, the `on_error` policy of the superblock is followed: the
+    /// filesystem is read-only for `RemountReadOnly` or an unknown policy, the open fails with
+    /// `OpenError::Errored` for `KernelPanic` and `Ignore` only reports it in `needs_check`
+    pub fn try_open(&mut self) -> Result<FileSystem<'_>, OpenError> {
+        unsafe { self.open_at(1024, false) }
+    }
+
+    /// Like try_open, but the filesystem is read-only: the operations that would modify it fail
+    /// with `Error::ReadOnly` and nothing is written to the device
+    pub fn try_open_read_only(&mut self) -> Result<FileSystem<'_>, OpenError> {
+        unsafe { self.open_at(1024, true) }
+    }
+
+    /// Like try_open, but if the primary superblock is damaged the filesystem is opened with one
+    /// of its backups. `len` is the size of the device, the backups are only searched in it.
+    ///
+    /// The backups are at the start of group 1 and of the groups that are powers of 3, 5 and 7.
+    /// Their block size is guessed, 1, 2 then 4KiB, with the default number of blocks per group.
+    /// A filesystem opened with a backup is read-only, as the primary superblock and group
+    /// descriptors must be repaired before writing to it. Returns the error of the primary
+    /// superblock if no backup is valid
+    pub fn open_with_recovery(
+        &mut self,
+        len: usize,
+    ) -> Result<(FileSystem<'_>, SuperblockCopy), OpenError> {
+        let len = self.len.map_or(len, |region| min(len, region));
+        if len < 2048 {
+            return Err(OpenError::BadSignature);
+        }
+        let primary = unsafe { Superblock::from_ptr(self.device.add(1024)) };
+        let (offset, copy) = match primary {
+            Ok(_) => (1024, SuperblockCopy::Primary),
+            Err(error) => {
+                log::trace!("Primary superblock is invalid: {:?}", error);
+                self.find_backup(len).ok_or(error)?
+            }
+        };
+        let fs = unsafe { self.open_at(offset, copy != SuperblockCopy::Primary)? };
+        Ok((fs, copy))
+    }
+
+    /// A valid backup superblock in the first len bytes of the device and its offset
+    fn find_backup(&self, len: usize) -> Option<(usize, SuperblockCopy)> {
+        for &block_size in &[1024u64, 2048, 4096] {
+            let blocks_per_group = 8 * block_size;
+            let first_block = if block_size == 1024 { 1 } else { 0 };
+            for group in backup_groups() {
+                let offset = (first_block + group * blocks_per_group) * block_size;
+                if offset + 1024 > len as u64 {
+                    break;
+                }
+                log::trace!("Probing superblock of group {} at {}", group, offset);
+                let (superblock, extended) =
+                    match unsafe { Superblock::from_ptr(self.device.add(offset as usize)) } {
+                        Ok(found) => found,
+                        Err(_) => continue,
+                    };
+                let (superblock, extended) =
+                    unsafe { (&*superblock, ExtendedSuperblock::or_revision_0(extended)) };
+                // Revision 0 does not record the group of the copy
+                let copy_group = u64::from(extended.part_of_block);
+                if superblock.block_size() as u64 == block_size
+                    && u64::from(superblock.block_count_in_group) == blocks_per_group
+                    && u64::from(superblock.index_of_superblock) == first_block
+                    && group < u64::from(superblock.group_count())
+                    && (superblock.major_version == 0 || copy_group == group)
+                    && u64::from(superblock.block_count) * block_size <= len as u64
+                {
+                    let copy = SuperblockCopy::Backup {
+                        group: group as u32,
+                        block_size: block_size as usize,
+                    };
+                    return Some((offset as usize, copy));
+                }
+            }
+        }
+        None
+    }
+
+    /// Open the filesystem with the superblock at offset, the group descriptors are in the
+    /// block after the one holding it
+    unsafe fn open_at(
+        &mut self,
+        offset: usize,
+        read_only: bool,
+    ) -> Result<FileSystem<'_>, OpenError> {
+        open_region(self.device, self.len, offset, read_only)
+    }
+}
+
+/// Open the filesystem of the region at device, of len bytes if it is known, with the superblock
+/// at offset, like `Ext2Device::try_open`
+unsafe fn open_region<'device>(
+    device: *mut u8,
+    len: Option<usize>,
+    offset: usize,
+    read_only: bool,
+) -> Result<FileSystem<'device>, OpenError> {
+    if len.is_some_and(|len| offset + 1024 > len) {
+        return Err(OpenError::BadSignature);
+    }
+    let (superblock, extended) = Superblock::from_ptr(device.add(offset))?;
+
+    let (block_size, number_of_groups, errored, on_error) = {
+        let superblock = &*superblock;
+        (
+            superblock.block_size(),
+            superblock.group_count() as usize,
+            superblock.state & FsState::Errored as u16 != 0,
+            superblock.on_error(),
+        )
+    };
+    let (required_features, write_features) = {
+        let extended = ExtendedSuperblock::or_revision_0(extended);
+        (extended.required_features, extended.write_features)
+    };
+
+    // The journal is not replayed when opening, the metadata is read as it was before
+    let needs_replay = required_features.contains(RequiredFeatures::REPLAY_JOURNAL);
+    check_required_features(required_features)?;
+    let replay_pending = needs_replay && !read_only;
+    let mut read_only =
+        read_only || needs_replay || write_features.bits() & !SUPPORTED_WRITE_FEATURES.bits() != 0;
+    if errored {
+        log::trace!("Filesystem has errors, policy is {:?}", on_error);
+        match on_error {
+            Ok(OnError::Ignore) => (),
+            Ok(OnError::KernelPanic) => return Err(OpenError::Errored),
+            Ok(OnError::RemountReadOnly) | Err(_) => read_only = true,
+        }
+    }
+
+    let block_table = offset / block_size + 1;
+    let (block_count, descriptor_table_end) = {
+        let superblock = &*superblock;
+        let table_len = number_of_groups * BLOCK_GROUP_DESCRITPOR_SIZE;
+        (
+            superblock.block_count,
+            (block_table * block_size).saturating_add(table_len),
+        )
+    };
+    let region_len = len.unwrap_or(usize::MAX);
+    if u64::from(block_count) * block_size as u64 > region_len as u64
+        || descriptor_table_end > region_len
+    {
+        return Err(OpenError::InvalidGeometry(
+            "filesystem larger than the device",
+        ));
+    }
+    let descriptors = core::slice::from_raw_parts(
+        device.add(block_table * block_size) as *const BlockGroupDescriptor,
+        number_of_groups,
+    );
+    check_descriptors(
+        &*superblock,
+        ExtendedSuperblock::or_revision_0(extended),
+        descriptors,
+    )?;
+
+    Ok(FileSystem {
+        fs: device,
+        device: PhantomData,
+        block_size,
+        superblock,
+        extended,
+        block_group_descriptor_table: device.add(block_size * block_table)
+            as *mut BlockGroupDescriptor,
+        block_group_descriptor_table_len: number_of_groups,
+        clock: None,
+        group_policy: GroupPolicy::Spread,
+        readahead: None,
+        read_only,
+        mounted: Cell::new(false),
+        mount_state: Cell::new(0),
+        replay_pending: Cell::new(replay_pending),
+        #[cfg(feature = "alloc")]
+        dir_cache: None,
+        #[cfg(feature = "exclusive")]
+        open_inodes: registry::OpenInodes::new(),
+        #[cfg(feature = "alloc")]
+        staged: None,
+        #[cfg(feature = "alloc")]
+        dirty: None,
+        #[cfg(feature = "alloc")]
+        pool: None,
+    })
+}
+
+impl Drop for FileSystem<'_> {
+    fn drop(&mut self) {
+        // Nothing can be reported from here, unmount can't fail on a writable filesystem
+        let _ = self.unmount();
+        #[cfg(feature = "alloc")]
+        if self.pool.is_some() {
+            let _ = self.flush_device();
+        }
+    }
+}
+
+/// Which superblock a filesystem was opened with, see `Ext2Device::open_with_recovery`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SuperblockCopy {
+    Primary,
+    Backup { group: u32, block_size: usize },
+}
+
+/// Why a filesystem should be checked, see `FileSystem::needs_check`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheckReason {
+    /// The state records errors
+    Errored,
+    /// It was mounted more times than the superblock allows between checks
+    MountCount,
+    /// The time the superblock allows between checks has passed since the last one
+    Interval,
+}
+
+/// The groups of a filesystem of group_count groups that hold a copy of the superblock, see
+/// `FileSystem::superblock_backup_groups`
+fn superblock_groups(group_count: u32, sparse: bool) -> impl Iterator<Item = u32> {
+    let is_power = |mut group: u32, base: u32| {
+        while group.is_multiple_of(base) {
+            group /= base;
+        }
+        group == 1
+    };
+    (0..group_count).filter(move |&group| {
+        !sparse || group <= 1 || is_power(group, 3) || is_power(group, 5) || is_power(group, 7)
+    })
+}
+
+/// The groups that hold a backup of the superblock with sparse_super, in order: 1 and the
+/// powers of 3, 5 and 7. Without sparse_super every group has one
+fn backup_groups() -> impl Iterator<Item = u64> {
+    let bases = [3u64, 5, 7];
+    let mut powers = bases;
+    core::iter::once(1).chain(core::iter::from_fn(move || {
+        let next = *powers.iter().min()?;
+        for (power, base) in powers.iter_mut().zip(bases.iter()) {
+            if *power == next {
+                *power = (*power).saturating_mul(*base);
+            }
+        }
+        Some(next)
+    }))
+}
+
+/// The offset of block from the start of the device, OffsetOverflow if it can't be addressed with
+/// the pointer width of the target
+pub(crate) fn block_offset(block_size: usize, block: u32) -> Result<isize, Error> {
+    // At most 2^16 * 2^32, it can't overflow
+    let offset = u64::from(block) * block_size as u64;
+    isize::try_from(offset).map_err(|_| Error::OffsetOverflow)
+}
+
+/// Whether the bitmaps and the inode table of each group are blocks of the filesystem, they
+/// are accessed without checking their block numbers
+fn check_descriptors(
+    superblock: &Superblock,
+    extended: &ExtendedSuperblock,
+    descriptors: &[BlockGroupDescriptor],
+) -> Result<(), OpenError> {
+    let inode_table_blocks = (u64::from(superblock.inode_count_in_group)
+        * u64::from(extended.inode_struct_size))
+    .div_ceil(superblock.block_size() as u64);
+    let in_filesystem = |first: u32, len: u64| {
+        first >= superblock.index_of_superblock
+            && first != 0
+            && u64::from(first) + len <= u64::from(superblock.block_count)
+    };
+    for descriptor in descriptors {
+        if !in_filesystem(descriptor.block_address_of_block_bitmap, 1)
+            || !in_filesystem(descriptor.block_address_of_inode_bitmap, 1)
+            || !in_filesystem(descriptor.starting_block_of_inode_table, inode_table_blocks)
+        {
+            return Err(OpenError::InvalidGeometry(
+                "group descriptor out of the filesystem",
+            ));
+        }
+    }
+    Ok(())
+}
 
-        let block_size = superblock.block_size();
+/// The number of cleared bits in a bitmap of `len` bits
+fn count_free_bits(bitmap: *const u8, len: u32) -> u32 {
+    let full_bytes = (len / 8) as usize;
+    let mut count = (0..full_bytes)
+        .map(|i| unsafe { access::read(bitmap.add(i)) }.count_zeros())
+        .sum();
+    if !len.is_multiple_of(8) {
+        let byte = unsafe { access::read(bitmap.add(full_bytes)) } | (u8::MAX << (len % 8));
+        count += byte.count_zeros();
+    }
+    count
+}
+
+/// The index of the first cleared bit at or after `first` in a bitmap of `len` bits
+fn find_free_bit(bitmap: *const u8, first: u32, len: u32) -> Option<u32> {
+    find_bit(bitmap, first, len, false)
+}
 
-        let mut number_of_groups = superblock.block_count / superblock.block_count_in_group;
-        // If it does not divide evenly round up
-        if superblock.block_count % superblock.block_count_in_group != 0 {
-            number_of_groups += 1;
+/// The index of the first bit equal to `set` at or after `first` in a bitmap of `len` bits.
+///
+/// The bitmap is read a word at a time, bit i being the bit i % 8 of the byte i / 8
+fn find_bit(bitmap: *const u8, first: u32, len: u32, set: bool) -> Option<u32> {
+    const WORD_BYTES: usize = core::mem::size_of::<usize>();
+    let byte_len = len.div_ceil(8) as usize;
+    let mut bit = first - first % usize::BITS;
+    while bit < len {
+        let byte = (bit / 8) as usize;
+        let mut word = if byte + WORD_BYTES <= byte_len {
+            usize::from_le(unsafe { access::read(bitmap.add(byte).cast::<usize>()) })
+        } else {
+            // Do not read past the bitmap, the missing bytes are masked below
+            let mut bytes = [0; WORD_BYTES];
+            for (i, b) in bytes.iter_mut().enumerate().take(byte_len - byte) {
+                *b = unsafe { access::read(bitmap.add(byte + i)) };
+            }
+            usize::from_le_bytes(bytes)
+        };
+        // Look for a cleared bit in both cases
+        if set {
+            word = !word;
+        }
+        // The bits before first are skipped
+        if bit < first {
+            word |= (1 << (first - bit)) - 1;
+        }
+        // The bits past the end of the bitmap may be garbage
+        if len - bit < usize::BITS {
+            word |= usize::MAX << (len - bit);
+        }
+        if word != usize::MAX {
+            return Some(bit + word.trailing_ones());
         }
-        let number_of_groups = number_of_groups as usize;
+        bit += usize::BITS;
+    }
+    None
+}
+
+/// Returns the current time in seconds since the unix epoch
+pub type Clock = extern "C" fn() -> u32;
+
+/// Chooses the group in which to look for a new inode first.
+///
+/// Called with the group of the parent directory and the kind of the new inode
+pub type GroupSelector = extern "C" fn(&FileSystem<'_>, u32, EntryKind) -> u32;
+
+/// Asks the storage behind the filesystem to read count blocks from block ahead, see
+/// `Cursor::set_readahead`. For the filesystems in memory, the ones opened with
+/// `FileSystem::open_device` call `BlockDevice::prefetch` themselves
+pub type Readahead = extern "C" fn(&FileSystem<'_>, u32, u32);
+
+/// How `create_inode_in_dir` places new inodes, see `FileSystem::set_group_policy`
+#[repr(C, u8)]
+#[derive(Debug, Clone, Copy)]
+pub enum GroupPolicy {
+    /// Always start in the group of the parent directory
+    SameGroup,
+    /// Keep files near their parent and spread directories over the emptiest groups
+    Spread,
+    Custom(GroupSelector),
+}
+
+/// Usage of the filesystem, see `FileSystem::statistics`
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Statistics {
+    pub block_size: u32,
+    pub total_blocks: u32,
+    pub free_blocks: u32,
+    /// The free blocks minus the ones reserved for the superuser
+    pub available_blocks: u32,
+    pub total_inodes: u32,
+    pub free_inodes: u32,
+    pub max_name_length: u32,
+}
+
+/// The settings of the superblock that can be changed like with tune2fs, see
+/// `FileSystem::tuning`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Tuning {
+    /// How many mounts are allowed between checks, None if there is no limit
+    pub max_mount_count: Option<u16>,
+    /// How many seconds are allowed between checks, None if there is no limit
+    pub check_interval: Option<u32>,
+    /// The blocks only the superuser and the reserved user and group can use
+    pub reserved_blocks: u32,
+    pub reserved_uid: u16,
+    pub reserved_gid: u16,
+    /// What to do when an error is found, or the raw value if it is not known
+    pub on_error: Result<OnError, u16>,
+}
 
-        let block_table = if superblock.log_block_size == 0 { 2 } else { 1 };
+/// Usage and layout of a block group, see `FileSystem::group_statistics`.
+///
+/// The free counts are both read from the group descriptor and counted in the bitmaps, they
+/// only differ on an inconsistent filesystem
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GroupStatistics {
+    pub group: u32,
+    pub first_block: u32,
+    pub block_count: u32,
+    pub first_inode: InodeRef,
+    pub inode_count: u32,
 
-        FileSystem {
-            fs: self.device,
-            block_size,
-            superblock,
-            extended,
-            block_group_descriptor_table: unsafe {
-                self.device.offset((block_size * block_table) as isize)
-            } as *mut BlockGroupDescriptor,
-            block_group_descriptor_table_len: number_of_groups,
+    pub block_bitmap: u32,
+    pub inode_bitmap: u32,
+    pub inode_table: u32,
+    pub inode_table_blocks: u32,
+
+    pub free_blocks: u16,
+    pub free_inodes: u16,
+    pub directories: u16,
+    pub counted_free_blocks: u32,
+    pub counted_free_inodes: u32,
+}
+
+/// Formatted like the groups in dumpe2fs
+impl core::fmt::Display for GroupStatistics {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        writeln!(
+            f,
+            "Group {}: (Blocks {}-{}) (Inodes {}-{})",
+            self.group,
+            self.first_block,
+            self.first_block + self.block_count - 1,
+            self.first_inode.0,
+            self.first_inode.0 + self.inode_count - 1
+        )?;
+        writeln!(
+            f,
+            "  Block bitmap at {}, Inode bitmap at {}",
+            self.block_bitmap, self.inode_bitmap
+        )?;
+        writeln!(
+            f,
+            "  Inode table at {}-{}",
+            self.inode_table,
+            self.inode_table + self.inode_table_blocks - 1
+        )?;
+        write!(
+            f,
+            "  {} free blocks, {} free inodes, {} directories",
+            self.free_blocks, self.free_inodes, self.directories
+        )?;
+        if self.counted_free_blocks != self.free_blocks as u32
+            || self.counted_free_inodes != self.free_inodes as u32
+        {
+            write!(
+                f,
+                " ({} free blocks, {} free inodes in the bitmaps)",
+                self.counted_free_blocks, self.counted_free_inodes
+            )?;
         }
+        Ok(())
     }
 }
 
@@ -53,20 +592,439 @@ impl Ext2Device {
 #[repr(C)]
 pub struct FileSystem<'device> {
     fs: *mut u8,
-    superblock: &'device mut Superblock,
-    extended: &'device mut ExtendedSuperblock,
+    /// The metadata is only accessed through raw pointers and short lived borrows, as inodes and
+    /// blocks alias the same device
+    device: PhantomData<&'device mut u8>,
+    /// Only written by update_superblock, the FileSystem methods only take &self
+    superblock: *mut Superblock,
+    /// Null for revision 0, only written by update_extended_superblock
+    extended: *mut ExtendedSuperblock,
 
+    /// Only written by update_group_descriptor
     block_group_descriptor_table: *mut BlockGroupDescriptor,
     block_group_descriptor_table_len: usize,
     block_size: usize,
+
+    clock: Option<Clock>,
+    group_policy: GroupPolicy,
+    readahead: Option<Readahead>,
+    read_only: bool,
+    /// Set by mount_writable, with the state it found
+    mounted: Cell<bool>,
+    mount_state: Cell<u16>,
+    /// Read-only until the journal is replayed, only `replay_journal` writes
+    replay_pending: Cell<bool>,
+    /// Not part of the C layout, the binding is built without alloc
+    #[cfg(feature = "alloc")]
+    dir_cache: Option<core::cell::RefCell<cache::DirCache>>,
+    /// Not part of the C layout either
+    #[cfg(feature = "exclusive")]
+    open_inodes: registry::OpenInodes,
+    /// The copies of the blocks of the current `Transaction`, not part of the C layout
+    #[cfg(feature = "alloc")]
+    staged: Option<core::cell::RefCell<transaction::Staged>>,
+    /// See `enable_dirty_tracking`, not part of the C layout
+    #[cfg(feature = "alloc")]
+    dirty: Option<core::cell::RefCell<dirty::DirtyTracker>>,
+    /// The buffers of the blocks of a filesystem opened with `open_device`, not part of the C
+    /// layout
+    #[cfg(feature = "alloc")]
+    pool: Option<core::cell::RefCell<pool::Pool>>,
 }
 
 impl<'device> FileSystem<'device> {
     pub fn get_superblock(&self) -> &Superblock {
-        self.superblock
+        unsafe { &*self.superblock }
+    }
+    /// Change the superblock.
+    ///
+    /// The superblock, its extension and the group descriptors are only written by the update
+    /// methods: f gets a copy that is written back through the raw pointer, no `&mut` to the
+    /// device is ever made. The references returned by the getters must not be held across an
+    /// update, they are only read right away
+    fn update_superblock(&self, f: impl FnOnce(&mut Superblock)) {
+        unsafe { access::modify(self.superblock, f) }
+    }
+    /// Change the extended superblock, see `update_superblock`. The filesystem must not be
+    /// revision 0
+    fn update_extended_superblock(&self, f: impl FnOnce(&mut ExtendedSuperblock)) {
+        assert!(!self.extended.is_null());
+        self.note_write();
+        unsafe { access::modify(self.extended, f) }
+    }
+    /// Change the descriptor of group, see `update_superblock`
+    fn update_group_descriptor(&self, group: u32, f: impl FnOnce(&mut BlockGroupDescriptor)) {
+        assert!((group as usize) < self.block_group_descriptor_table_len);
+        self.note_write();
+        unsafe { access::modify(self.block_group_descriptor_table.add(group as usize), f) }
+    }
+    /// Record a modification in the write time of the superblock if there is a clock. The
+    /// superblock is only written when the second changed, this is called for every write
+    pub(crate) fn note_write(&self) {
+        let now = match self.now() {
+            Some(now) if !self.read_only => now,
+            _ => return,
+        };
+        if unsafe { read_field!(self.superblock, last_written) } != now {
+            self.update_superblock(|superblock| superblock.last_written = now);
+        }
+    }
+    /// Mark the filesystem as in use like a mount does: the clean bit of the state is cleared
+    /// until `unmount`, the mount count is incremented and the mount time set if there is a
+    /// clock. An image left with the clean bit cleared was not unmounted and must be checked.
+    ///
+    /// Does nothing on a read-only filesystem or if it is already mounted
+    pub fn mount_writable(&self) {
+        if self.read_only || self.mounted.get() {
+            return;
+        }
+        let state = self.get_superblock().state;
+        log::trace!("Mounting, state was {:#x}", state);
+        self.mount_state.set(state);
+        self.mounted.set(true);
+        let now = self.now();
+        self.update_superblock(|superblock| {
+            superblock.state = state & !(FsState::Clean as u16);
+            superblock.number_of_times_mounted_since_last_consitency_check = superblock
+                .number_of_times_mounted_since_last_consitency_check
+                .wrapping_add(1);
+            if let Some(now) = now {
+                superblock.last_mounted = now;
+            }
+        });
+    }
+    /// Like `mount_writable`, and the path is recorded as the last mount point of the
+    /// filesystem, see `set_last_mounted_path`
+    pub fn mount_writable_at(&self, path: &[u8]) -> Result<(), Error> {
+        if self.read_only || self.mounted.get() {
+            return Ok(());
+        }
+        if !self.extended.is_null() {
+            self.set_last_mounted_path(path)?;
+        }
+        self.mount_writable();
+        Ok(())
+    }
+    /// Undo `mount_writable`: the state it found is restored, then the filesystem is synced with
+    /// `sync`, which sets the write time. This is done when the FileSystem is dropped.
+    ///
+    /// Does nothing if the filesystem is not mounted
+    pub fn unmount(&self) -> Result<(), Error> {
+        if !self.mounted.replace(false) {
+            return Ok(());
+        }
+        let state = self.mount_state.get();
+        log::trace!("Unmounting, restoring state {:#x}", state);
+        self.update_superblock(|superblock| superblock.state = state);
+        self.sync()
+    }
+    /// Make the metadata of the filesystem consistent: the free counts of the superblock are
+    /// recomputed from the group descriptors, its write time is set if there is a clock and the
+    /// backups are updated with `sync_metadata`.
+    ///
+    /// The device is written directly, or the blocks that changed are written back to the device
+    /// of `open_device`: this is the point where it can be saved or powered off. Open files must
+    /// be synced by `File::sync` first. Does nothing on a read-only filesystem
+    pub fn sync(&self) -> Result<(), Error> {
+        if self.read_only {
+            return Ok(());
+        }
+        let (free_blocks, free_inodes) = self.get_block_group_descriptor_table().iter().fold(
+            (0, 0),
+            |(blocks, inodes), descriptor| {
+                (
+                    blocks + u32::from(descriptor.unallocated_blocks_in_group),
+                    inodes + u32::from(descriptor.unallocated_inodes_in_group),
+                )
+            },
+        );
+        let now = self.now();
+        self.update_superblock(|superblock| {
+            superblock.unallocated_blocks = free_blocks;
+            superblock.unallocated_inodes = free_inodes;
+            if let Some(now) = now {
+                superblock.last_written = now;
+            }
+        });
+        self.sync_metadata()?;
+        #[cfg(feature = "alloc")]
+        self.write_back_pool()?;
+        Ok(())
+    }
+    /// Copy the superblock and the group descriptors to their backups, in the groups given by
+    /// `superblock_backup_groups`.
+    ///
+    /// The modifications only change the primary copies, the backups are stale until this is
+    /// called. Each copy of the superblock records the group holding it
+    pub fn sync_metadata(&self) -> Result<(), Error> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+        let descriptors_len =
+            self.block_group_descriptor_table_len * core::mem::size_of::<BlockGroupDescriptor>();
+        // Group 0 holds the primary copies
+        for group in self.superblock_backup_groups().skip(1) {
+            let first_block = self.first_block_of_group(group);
+            log::trace!("Writing the backup superblock of group {}", group);
+            unsafe {
+                let superblock = self.get_block(first_block);
+                access::copy_within(self.superblock as *const u8, superblock, 1024);
+                if !self.extended.is_null() {
+                    let extended = superblock.add(SUPERBLOCK_SIZE) as *mut ExtendedSuperblock;
+                    write_field!(extended, part_of_block, group as u16);
+                }
+                let table = self.block_group_descriptor_table as *const u8;
+                for (block, offset) in (0..descriptors_len).step_by(self.block_size).enumerate() {
+                    access::copy_within(
+                        table.add(offset),
+                        self.get_block(first_block + 1 + block as u32),
+                        min(self.block_size, descriptors_len - offset),
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+    /// The current usage of the filesystem, like statfs.
+    ///
+    /// The free counts come from the superblock, or from the bitmaps with recount
+    pub fn statistics(&self, recount: bool) -> Statistics {
+        let superblock = self.get_superblock();
+        let (free_blocks, free_inodes) = if recount {
+            (0..self.block_group_descriptor_table_len as u32)
+                .filter_map(|group| self.group_statistics(group))
+                .fold((0, 0), |(blocks, inodes), group| {
+                    (
+                        blocks + group.counted_free_blocks,
+                        inodes + group.counted_free_inodes,
+                    )
+                })
+        } else {
+            (superblock.unallocated_blocks, superblock.unallocated_inodes)
+        };
+        Statistics {
+            block_size: self.block_size as u32,
+            total_blocks: superblock.block_count,
+            free_blocks,
+            available_blocks: free_blocks.saturating_sub(superblock.block_superuser),
+            total_inodes: superblock.inode_count,
+            free_inodes,
+            max_name_length: 255,
+        }
+    }
+    /// The current usage of a block group, None if the group does not exist
+    pub fn group_statistics(&self, group: u32) -> Option<GroupStatistics> {
+        let descriptor = self
+            .get_block_group_descriptor_table()
+            .get(group as usize)?;
+        let superblock = self.get_superblock();
+        let block_count = self.block_count_of_group(group);
+        let inode_count = superblock.inode_count_in_group;
+        let inode_table_bytes =
+            inode_count * self.get_extended_superblock().inode_struct_size as u32;
+        Some(GroupStatistics {
+            group,
+            first_block: self.first_block_of_group(group),
+            block_count,
+            first_inode: InodeRef(group * inode_count + 1),
+            inode_count,
+
+            block_bitmap: descriptor.block_address_of_block_bitmap,
+            inode_bitmap: descriptor.block_address_of_inode_bitmap,
+            inode_table: descriptor.starting_block_of_inode_table,
+            inode_table_blocks: inode_table_bytes.div_ceil(self.block_size as u32),
+
+            free_blocks: descriptor.unallocated_blocks_in_group,
+            free_inodes: descriptor.unallocated_inodes_in_group,
+            directories: descriptor.number_of_directories_in_group,
+            counted_free_blocks: count_free_bits(
+                unsafe { self.get_block(descriptor.block_address_of_block_bitmap) },
+                block_count,
+            ),
+            counted_free_inodes: count_free_bits(
+                unsafe { self.get_block(descriptor.block_address_of_inode_bitmap) },
+                inode_count,
+            ),
+        })
+    }
+    /// The groups holding a copy of the superblock and of the group descriptors, in order. The
+    /// primary is in group 0.
+    ///
+    /// With sparse_super only groups 0, 1 and the powers of 3, 5 and 7 have one, otherwise every
+    /// group does
+    pub fn superblock_backup_groups(&self) -> impl Iterator<Item = u32> {
+        let sparse = { self.get_extended_superblock().write_features }
+            .contains(WriteFeatures::SPARSE_SUPERBLOCK_GROUP_DESCRIPTOR_TABLE);
+        superblock_groups(self.block_group_descriptor_table_len as u32, sparse)
+    }
+    /// Whether the modifications are refused, because it was opened with
+    /// `Ext2Device::try_open_read_only` or a backup superblock, because of unsupported write
+    /// features or because it has errors and its policy is `OnError::RemountReadOnly`.
+    ///
+    /// Every operation that would write to the device then fails with ReadOnly, or does nothing
+    /// when it can't fail like `mount_writable`. Only the raw pointers given by `get_block` and
+    /// `Inode::get_data` are not checked
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+    /// Why the filesystem should be checked before being trusted, None if nothing asks for it.
+    ///
+    /// Errors recorded in the state come first, then the limits of the superblock on the mounts
+    /// and on the time between checks. The time is only checked if there is a clock
+    pub fn needs_check(&self) -> Option<CheckReason> {
+        let superblock = self.get_superblock();
+        if superblock.state & FsState::Errored as u16 != 0 {
+            return Some(CheckReason::Errored);
+        }
+        // A negative maximum disables the limit
+        let max_mounts = superblock.number_of_mounts_until_consistency_check as i16;
+        if max_mounts > 0
+            && superblock.number_of_times_mounted_since_last_consitency_check >= max_mounts as u16
+        {
+            return Some(CheckReason::MountCount);
+        }
+        let interval = superblock.time_between_forced_consistency_check;
+        if let Some(now) = self.now() {
+            let last_check = superblock.time_since_last_constiency_check;
+            if interval != 0 && now >= last_check.saturating_add(interval) {
+                return Some(CheckReason::Interval);
+            }
+        }
+        None
+    }
+    /// The current values of the settings changed by the `set_` functions below
+    pub fn tuning(&self) -> Tuning {
+        let superblock = self.get_superblock();
+        let max_mount_count = superblock.number_of_mounts_until_consistency_check;
+        let check_interval = superblock.time_between_forced_consistency_check;
+        Tuning {
+            max_mount_count: if max_mount_count as i16 > 0 {
+                Some(max_mount_count)
+            } else {
+                None
+            },
+            check_interval: if check_interval != 0 {
+                Some(check_interval)
+            } else {
+                None
+            },
+            reserved_blocks: superblock.block_superuser,
+            reserved_uid: superblock.user_id_allowed_to_reserve,
+            reserved_gid: superblock.group_id_allowed_to_reserve,
+            on_error: superblock.on_error(),
+        }
+    }
+    /// Change a setting of the superblock, fails on a read-only filesystem
+    fn tune(&self, f: impl FnOnce(&mut Superblock)) -> Result<(), Error> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+        self.note_write();
+        self.update_superblock(f);
+        Ok(())
+    }
+    /// Limit the number of mounts between checks, None removes the limit. The limit must be
+    /// between 1 and `i16::MAX`, the negative values disable it on the disk
+    pub fn set_max_mount_count(&self, count: Option<u16>) -> Result<(), Error> {
+        let count = match count {
+            None => u16::MAX,
+            Some(count) if count as i16 > 0 => count,
+            Some(_) => return Err(Error::InvalidArgument),
+        };
+        self.tune(|superblock| superblock.number_of_mounts_until_consistency_check = count)
+    }
+    /// Limit the time in seconds between checks, None removes the limit. 0 is not a valid limit
+    pub fn set_check_interval(&self, interval: Option<u32>) -> Result<(), Error> {
+        let interval = match interval {
+            None => 0,
+            Some(0) => return Err(Error::InvalidArgument),
+            Some(interval) => interval,
+        };
+        self.tune(|superblock| superblock.time_between_forced_consistency_check = interval)
+    }
+    /// Set the number of blocks kept for the superuser, it can't be more than the blocks of the
+    /// filesystem
+    pub fn set_reserved_block_count(&self, count: u32) -> Result<(), Error> {
+        if count > self.get_superblock().block_count {
+            return Err(Error::InvalidArgument);
+        }
+        self.tune(|superblock| superblock.block_superuser = count)
+    }
+    /// Set the user that can use the blocks reserved for the superuser, see `is_privileged`
+    pub fn set_reserved_uid(&self, user_id: u16) -> Result<(), Error> {
+        self.tune(|superblock| superblock.user_id_allowed_to_reserve = user_id)
+    }
+    /// Set the group that can use the blocks reserved for the superuser, see `is_privileged`
+    pub fn set_reserved_gid(&self, group_id: u16) -> Result<(), Error> {
+        self.tune(|superblock| superblock.group_id_allowed_to_reserve = group_id)
+    }
+    /// Set what should be done when an error is found, see `Ext2Device::try_open`
+    pub fn set_default_mount_behavior(&self, on_error: OnError) -> Result<(), Error> {
+        self.tune(|superblock| superblock.on_error = on_error as u16)
+    }
+    /// Set the name of the volume, at most 16 bytes without NUL. It is only terminated by a NUL
+    /// if it is shorter. Revision 0 filesystems have no name
+    pub fn set_volume_name(&self, name: &[u8]) -> Result<(), Error> {
+        self.set_name(name, |extended| &mut extended.volume_name)
+    }
+    /// Set the path the filesystem was last mounted at, at most 64 bytes without NUL, see
+    /// `mount_writable_at`
+    pub fn set_last_mounted_path(&self, path: &[u8]) -> Result<(), Error> {
+        self.set_name(path, |extended| &mut extended.path_last_mounted_at)
+    }
+    /// Write name in the array of the extended superblock given by field, padded with NULs
+    fn set_name<const N: usize>(
+        &self,
+        name: &[u8],
+        field: impl FnOnce(&mut ExtendedSuperblock) -> &mut [i8; N],
+    ) -> Result<(), Error> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+        if self.extended.is_null() {
+            return Err(Error::UnsupportedFeature(
+                "names in a revision 0 superblock",
+            ));
+        }
+        if name.len() > N || name.contains(&0) {
+            return Err(Error::InvalidArgument);
+        }
+        self.update_extended_superblock(|extended| {
+            let array = field(extended);
+            *array = [0; N];
+            for (byte, &name) in array.iter_mut().zip(name) {
+                *byte = name as i8;
+            }
+        });
+        Ok(())
     }
+    /// The write features of the superblock that are not implemented, they make the filesystem
+    /// read-only
+    pub fn unsupported_features(&self) -> UnsupportedFeatures {
+        let write_features = self.get_extended_superblock().write_features;
+        UnsupportedFeatures(write_features.bits() & !SUPPORTED_WRITE_FEATURES.bits())
+    }
+    /// The defaults of the specification for revision 0 filesystems, see `is_revision_0`
     pub fn get_extended_superblock(&self) -> &ExtendedSuperblock {
-        self.extended
+        unsafe { ExtendedSuperblock::or_revision_0(self.extended) }
+    }
+    /// Revision 0 filesystems have no extended superblock and no features
+    pub fn is_revision_0(&self) -> bool {
+        self.extended.is_null()
+    }
+    /// Whether directory entries hold the type of their inode, otherwise the byte of the type
+    /// is the high byte of the name length
+    pub(crate) fn typed_directories(&self) -> bool {
+        { self.get_extended_superblock().required_features }
+            .contains(RequiredFeatures::TYPED_DIRECTORY)
+    }
+    /// The type byte of a directory entry
+    pub(crate) fn entry_kind_byte(&self, kind: EntryKind) -> u8 {
+        if self.typed_directories() {
+            kind as u8
+        } else {
+            0
+        }
     }
     pub fn get_block_group_descriptor_table(&self) -> &[BlockGroupDescriptor] {
         unsafe {
@@ -77,101 +1035,3973 @@ impl<'device> FileSystem<'device> {
         }
     }
 
+    /// Set the function used to timestamp inodes and the writes of the superblock.
+    /// Without a clock timestamps are left untouched
+    pub fn set_clock(&mut self, clock: Clock) {
+        self.clock = Some(clock)
+    }
+    /// Set the function the cursors with a readahead call before reading a block. Without it
+    /// the readahead does nothing on memory, a filesystem opened with `open_device` prefetches
+    /// the blocks from its device in any case
+    pub fn set_readahead_hook(&mut self, readahead: Readahead) {
+        self.readahead = Some(readahead)
+    }
+    /// Whether the readahead of the cursors does something
+    pub(crate) fn reads_ahead(&self) -> bool {
+        #[cfg(feature = "alloc")]
+        if self.pool.is_some() {
+            return true;
+        }
+        self.readahead.is_some()
+    }
+    /// Announce that count blocks from block will be read: they are prefetched from the device
+    /// of the pool and given to the hook
+    pub(crate) fn read_ahead(&self, block: u32, count: u32) {
+        #[cfg(feature = "alloc")]
+        if let Some(pool) = &self.pool {
+            pool.borrow_mut().prefetch(block, count);
+        }
+        if let Some(hook) = self.readahead {
+            hook(self, block, count);
+        }
+    }
+    /// The size of an inode in the inode table, at least 128 bytes
+    pub(crate) fn inode_size(&self) -> usize {
+        usize::from(self.get_extended_superblock().inode_struct_size)
+    }
+    pub(crate) fn now(&self) -> Option<u32> {
+        self.clock.map(|clock| clock())
+    }
+
+    /// How many blocks a file (or a directory) reserves at once, 1 unless the filesystem asks
+    /// for preallocation
+    pub(crate) fn preallocation_hint(&self, directory: bool) -> u32 {
+        let extended = self.get_extended_superblock();
+        if !{ extended.optional_features }.contains(OptionalFeatures::PREALLOCATE) {
+            return 1;
+        }
+        let hint = if directory {
+            extended.number_of_blocks_to_preallocate_dirs
+        } else {
+            extended.number_of_blocks_to_preallocate_files
+        };
+        core::cmp::max(hint as u32, 1)
+    }
+
+    /// Set how the groups of new inodes are chosen, the default is `GroupPolicy::Spread`
+    pub fn set_group_policy(&mut self, policy: GroupPolicy) {
+        self.group_policy = policy
+    }
+    /// The group in which to look for the inode of a new `kind` child of a directory in
+    /// `parent_group`. The inode may still end up elsewhere if that group is full
+    pub(crate) fn group_for_new_inode(&self, parent_group: u32, kind: EntryKind) -> u32 {
+        match self.group_policy {
+            GroupPolicy::SameGroup => parent_group,
+            GroupPolicy::Spread => match kind {
+                EntryKind::Directory => self.group_for_directory(parent_group),
+                _ => self.group_for_file(parent_group),
+            },
+            GroupPolicy::Custom(selector) => selector(self, parent_group, kind),
+        }
+    }
+    /// The first group from parent_group with both free inodes and free blocks
+    fn group_for_file(&self, parent_group: u32) -> u32 {
+        let table = self.get_block_group_descriptor_table();
+        let group_count = table.len() as u32;
+        (0..group_count)
+            .map(|i| (parent_group + i) % group_count)
+            .find(|&group| {
+                let descriptor = &table[group as usize];
+                descriptor.unallocated_inodes_in_group != 0
+                    && descriptor.unallocated_blocks_in_group != 0
+            })
+            .unwrap_or(parent_group)
+    }
+    /// Among the groups with at least the average number of free inodes, the one with the fewest
+    /// directories, then the most free blocks
+    fn group_for_directory(&self, parent_group: u32) -> u32 {
+        let table = self.get_block_group_descriptor_table();
+        let average_free_inodes = self.get_superblock().unallocated_inodes / table.len() as u32;
+        table
+            .iter()
+            .enumerate()
+            .filter(|(_, descriptor)| {
+                descriptor.unallocated_inodes_in_group != 0
+                    && descriptor.unallocated_inodes_in_group as u32 >= average_free_inodes
+            })
+            .min_by_key(|(_, descriptor)| {
+                (
+                    descriptor.number_of_directories_in_group,
+                    core::cmp::Reverse(descriptor.unallocated_blocks_in_group),
+                )
+            })
+            .map(|(group, _)| group as u32)
+            .unwrap_or(parent_group)
+    }
+
     #[inline(always)]
     pub fn get_root(&self) -> Inode<'_, 'device> {
-        self.get_inode(InodeRef(2))
+        self.load_inode(InodeRef(2))
     }
 
-    fn reserve_bitmap(&self, start: *mut u8) -> u32 {
-        let mut bitmap_block = start;
-        let mut index = 0;
-
-        while index < 1024 && unsafe { *bitmap_block } == 255 {
-            index += 1;
-            bitmap_block = unsafe { bitmap_block.offset(1) };
+    /// Find the inode at path. The path is always taken from the root, its components are
+    /// separated by `/`
+    pub fn lookup_path(&self, path: &[u8]) -> Result<InodeRef, Error> {
+        self.lookup_from(root_inode(), path)
+    }
+    /// Find the inode at path from directory, a leading `/` is ignored
+    fn lookup_from(&self, mut current: InodeRef, path: &[u8]) -> Result<InodeRef, Error> {
+        for component in path.split(|&c| c == b'/').filter(|c| !c.is_empty()) {
+            if let Some(child) = self.cached_entry(current, component) {
+                current = child;
+                continue;
+            }
+            let inode = self.get_inode(current)?;
+            current = inode
+                .find_entry(component)
+                .ok_or(if inode.is_dir() {
+                    Error::NotFound
+                } else {
+                    Error::NotADirectory
+                })?
+                .inode;
         }
-        let byte = unsafe { *bitmap_block };
-        log::trace!("Found space in bitmap at index {}: {:08b}", index, byte);
+        Ok(current)
+    }
 
-        let mut reserved_in_current = None;
-        for i in 0..8 {
-            if byte & (1 << i) == 0 {
-                reserved_in_current = Some(i);
-                break;
+    /// Call sink with the content of the regular file at path, see `Inode::read_all`. Returns
+    /// the size of the file.
+    ///
+    /// When follow_symlink is set and path names a symlink, the file it targets is read instead.
+    /// The targets are taken from the directory holding the symlink, or from the root when they
+    /// start with `/`, the symlinks in the middle of the paths are not followed
+    pub fn read_file<E>(
+        &self,
+        path: &[u8],
+        follow_symlink: bool,
+        sink: impl FnMut(&[u8]) -> Result<(), E>,
+    ) -> Result<u64, Error> {
+        let mut directory = root_inode();
+        let mut path = path;
+        for _ in 0..=MAX_SYMLINKS {
+            if path.first() == Some(&b'/') {
+                directory = root_inode();
+            }
+            let (parent, name) = match path.iter().rposition(|&c| c == b'/') {
+                Some(slash) => (&path[..slash], &path[slash + 1..]),
+                None => (&[][..], path),
+            };
+            directory = self.lookup_from(directory, parent)?;
+            let inode = self.get_inode(self.lookup_from(directory, name)?)?;
+            match inode.file_type() {
+                EntryKind::Symlink if follow_symlink => path = &**inode.read_link()?,
+                _ => return inode.read_all(sink),
             }
         }
-        let reserved_in_current = reserved_in_current.unwrap();
+        Err(Error::TooManySymlinks)
+    }
 
-        log::trace!("Space is at index {}", reserved_in_current);
-        unsafe { *bitmap_block |= 1 << reserved_in_current }
+    /// Remember up to capacity directory entries to speed up lookup_path
+    #[cfg(feature = "alloc")]
+    pub fn enable_dir_cache(&mut self, capacity: usize) {
+        self.dir_cache = Some(core::cell::RefCell::new(cache::DirCache::new(capacity)))
+    }
+    #[cfg(feature = "alloc")]
+    pub fn disable_dir_cache(&mut self) {
+        self.dir_cache = None
+    }
+    fn cached_entry(&self, directory: InodeRef, name: &[u8]) -> Option<InodeRef> {
+        #[cfg(feature = "alloc")]
+        if let Some(cache) = &self.dir_cache {
+            return cache.borrow().get(directory, name);
+        }
+        let _ = (directory, name);
+        None
+    }
+    pub(crate) fn cache_entry(&self, directory: InodeRef, name: &[u8], child: InodeRef) {
+        #[cfg(feature = "alloc")]
+        if let Some(cache) = &self.dir_cache {
+            cache.borrow_mut().insert(directory, name, child);
+        }
+        let _ = (directory, name, child);
+    }
+    /// Called whenever the entries of directory change
+    pub(crate) fn invalidate_cached_entries(&self, directory: InodeRef) {
+        #[cfg(feature = "alloc")]
+        if let Some(cache) = &self.dir_cache {
+            cache.borrow_mut().invalidate(directory);
+        }
+        let _ = directory;
+    }
 
-        let index = index * 8 + reserved_in_current;
-        log::trace!("total index is {}", index);
-        index
+    /// Walk the whole tree from the root, yielding the regular files. See `walk::Walk` for the
+    /// options
+    pub fn iter_files(&self) -> walk::Walk<'_, 'device> {
+        walk::Walk::files(self)
     }
-    fn reserve_block(&self, group: u32) -> u32 {
-        log::trace!("reserving new block in group {}", group);
-        let bitmap =
-            self.get_block_group_descriptor_table()[group as usize].block_address_of_block_bitmap;
-        self.reserve_bitmap(unsafe { self.get_block(bitmap) })
+    /// Walk the whole tree from the root, yielding the entries whose name matches pattern (see
+    /// `walk::glob_match`)
+    pub fn find<'fs>(&'fs self, pattern: &'fs [u8]) -> walk::Walk<'fs, 'device> {
+        walk::Walk::glob(self, pattern)
     }
-    fn reserve_inode(&self, group: u32) -> InodeRef {
-        log::trace!("reserving new inode in group {}", group);
-        let bitmap =
-            self.get_block_group_descriptor_table()[group as usize].block_address_of_inode_bitmap;
-        // Inodes start at 0
-        InodeRef(self.reserve_bitmap(unsafe { self.get_block(bitmap) }) + 1)
+
+    /// Open the file at path, see OpenOptions for the available behaviours
+    pub fn open(&self, path: &[u8], options: OpenOptions) -> Result<File<'_, 'device>, Error> {
+        options.check()?;
+        if self.read_only && (options.write || options.append) {
+            return Err(Error::ReadOnly);
+        }
+
+        let inode = match self.lookup_path(path) {
+            Ok(_) if options.create_new => return Err(Error::AlreadyExists),
+            Ok(inode) => self.get_inode(inode)?,
+            Err(Error::NotFound) if options.create || options.create_new => self.load_inode(
+                self.create_file(path, options.permissions, options.user_id, options.group_id)?,
+            ),
+            Err(e) => return Err(e),
+        };
+
+        let mut file = inode.open_file(options.write || options.append)?;
+        file.set_privileged(self.is_privileged(options.user_id, options.group_id));
+        if options.truncate {
+            file.set_len(0)?;
+        }
+        if options.append {
+            file.seek(file::SeekFrom::End(0));
+        }
+        Ok(file)
     }
 
-    pub fn get_inode(&self, inode: InodeRef) -> Inode<'_, 'device> {
-        // I think it is safe because Inodes use *mut InodeData, you
-        // can give multiple of them
-        unsafe { Inode::from_fs(self, inode.0, self.get_inode_in_table(inode.0)) }
+    /// Create a regular file at path, its parent must exist
+    pub fn create_file(
+        &self,
+        path: &[u8],
+        perms: Permission,
+        user_id: u16,
+        group_id: u16,
+    ) -> Result<InodeRef, Error> {
+        self.create(path, EntryKind::RegularFile, perms, user_id, group_id)
     }
-    pub(crate) fn group_of_inode(&self, inode: InodeRef) -> u32 {
-        (inode.0 - 1) / self.superblock.inode_count_in_group
+
+    /// Create a directory at path, its parent must exist
+    pub fn create_dir(
+        &self,
+        path: &[u8],
+        perms: Permission,
+        user_id: u16,
+        group_id: u16,
+    ) -> Result<InodeRef, Error> {
+        self.create(path, EntryKind::Directory, perms, user_id, group_id)
     }
 
-    /// This function assumes that you have exclusive access to that part of memory
-    unsafe fn get_inode_in_table<'a>(&self, inode: u32) -> *mut InodeData {
-        let block_group = self.group_of_inode(InodeRef(inode));
-        let index = (inode - 1) % self.superblock.inode_count_in_group;
+    /// The inode of /lost+found, the directory is created if the root has no entry with that
+    /// name, NotADirectory if the entry is not a directory.
+    ///
+    /// Like mke2fs, the new directory has LOST_FOUND_BLOCKS blocks of empty records so that the
+    /// inodes can be linked in it without allocating blocks. They are a single run in the group
+    /// of the inode, if there is no such run the directory only has its first block
+    pub fn ensure_lost_and_found(&self) -> Result<InodeRef, Error> {
+        if let Some(entry) = self.get_root().find_entry(b"lost+found") {
+            if !self.get_inode(entry.inode)?.is_dir() {
+                return Err(Error::NotADirectory);
+            }
+            return Ok(entry.inode);
+        }
+        let lost_found = self.create_dir(
+            b"/lost+found",
+            Permission::USER_READ | Permission::USER_WRITE | Permission::USER_EXECUTE,
+            0,
+            0,
+        )?;
+        let inode = self.load_inode(lost_found);
+        let count = LOST_FOUND_BLOCKS - 1;
+        match self.reserve_contiguous(self.group_of_inode(lost_found), count, true) {
+            Ok(first) => inode.append_empty_dir_blocks(first, count)?,
+            Err(Error::NoFreeBlocks) => log::trace!("No room to preallocate lost+found"),
+            Err(error) => return Err(error),
+        }
+        Ok(lost_found)
+    }
 
-        let inode_table = self.get_block_group_descriptor_table()[block_group as usize]
-            .starting_block_of_inode_table;
+    /// Create a directory at path and all of its missing parents.
+    /// It is not an error if the directory already exists
+    pub fn create_dir_all(
+        &self,
+        path: &[u8],
+        perms: Permission,
+        user_id: u16,
+        group_id: u16,
+    ) -> Result<InodeRef, Error> {
+        let mut current = root_inode();
+        for component in path.split(|&c| c == b'/').filter(|c| !c.is_empty()) {
+            let inode = self.get_inode(current)?;
+            current = match inode.find_entry(component) {
+                Some(entry) => entry.inode,
+                None => inode.create_inode_in_dir(
+                    EntryKind::Directory,
+                    perms,
+                    user_id,
+                    group_id,
+                    component,
+                )?,
+            };
+        }
+        if self.get_inode(current)?.is_dir() {
+            Ok(current)
+        } else {
+            Err(Error::NotADirectory)
+        }
+    }
 
-        let inode_table_offset = self
-            .get_block(inode_table)
-            .offset((self.extended.inode_struct_size as u32 * index) as isize);
+    /// Remove the entry at path, the inode is freed if it was its last link.
+    /// Directories can't be unlinked
+    pub fn unlink(&self, path: &[u8]) -> Result<(), Error> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+        let (parent, name) = split_parent(path);
+        let parent = self.get_inode(self.lookup_path(parent)?)?;
+        let entry = parent.find_entry(name).ok_or(Error::NotFound)?;
+        let inode = self.get_inode(entry.inode)?;
+        if inode.is_dir() {
+            return Err(Error::IsADirectory);
+        }
+        parent.remove_entry(name);
+        let links = inode.link_count().saturating_sub(1);
+        inode.set_link_count(links);
+        if links == 0 {
+            inode.truncate_blocks(0)?;
+            self.release_inode(entry.inode)?;
+        }
+        Ok(())
+    }
 
-        inode::InodeData::from_ptr(inode_table_offset)
+    /// Remove the empty directory at path, like rmdir. The parent loses the link of '..' and
+    /// the inode is freed with its blocks.
+    ///
+    /// DirectoryNotEmpty if it has entries other than '.' and '..', the root and paths ending
+    /// in '.' or '..' are InvalidArgument
+    pub fn rmdir(&self, path: &[u8]) -> Result<(), Error> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+        let (parent, name) = split_parent(path);
+        if let b"" | b"." | b".." = name {
+            return Err(Error::InvalidArgument);
+        }
+        let parent = self.get_inode(self.lookup_path(parent)?)?;
+        let entry = parent.find_entry(name).ok_or(Error::NotFound)?;
+        let inode = self.get_inode(entry.inode)?;
+        if !inode.as_dir().ok_or(Error::NotADirectory)?.is_empty() {
+            return Err(Error::DirectoryNotEmpty);
+        }
+        parent.remove_entry(name);
+        parent.set_link_count(parent.link_count().saturating_sub(1));
+        inode.set_link_count(0);
+        inode.truncate_blocks(0)?;
+        self.release_inode(entry.inode)
     }
 
-    /// Safety: Don't have two handles on the same block !
-    unsafe fn get_block(&self, index: u32) -> *mut u8 {
-        self.fs.offset((self.block_size * index as usize) as isize)
+    /// Copy the regular file src to a new file called dst_name in dst_dir, returns the new
+    /// inode. The copy has the owner and the timestamps of src, and the permissions of src that
+    /// are in perms. The holes of src stay holes in the copy.
+    ///
+    /// If the content does not fit, with NoFreeBlocks or FileTooLarge, the partial copy is
+    /// removed
+    pub fn copy_file(
+        &self,
+        src: InodeRef,
+        dst_dir: &Inode<'_, 'device>,
+        dst_name: &[u8],
+        perms: Permission,
+    ) -> Result<InodeRef, Error> {
+        let src = self.get_inode(src)?;
+        src.cursor()?;
+        let metadata = src.metadata();
+        let copy = dst_dir.create_inode_in_dir(
+            EntryKind::RegularFile,
+            metadata.permissions & perms,
+            metadata.user_id,
+            metadata.group_id,
+            dst_name,
+        )?;
+        let dst = self.load_inode(copy);
+        if let Err(error) = self.copy_content(&src, &dst) {
+            dst_dir.remove_entry(dst_name);
+            dst.truncate_blocks(0)?;
+            self.release_inode(copy)?;
+            return Err(error);
+        }
+        dst.copy_times(&src);
+        Ok(copy)
     }
-}
 
-#[cfg(test)]
-mod tests {
-    extern crate std;
-    use std::io::Read;
+    /// Write the blocks of src to the same places of dst, skipping the holes
+    fn copy_content(
+        &self,
+        src: &Inode<'_, 'device>,
+        dst: &Inode<'_, 'device>,
+    ) -> Result<(), Error> {
+        let size = src.size();
+        let block_size = self.block_size as u32;
+        let metadata = src.metadata();
+        let privileged = self.is_privileged(metadata.user_id, metadata.group_id);
+        // Set first so that truncating dst releases what was written
+        dst.set_size(size);
+        for index in 0..size.div_ceil(block_size) {
+            let block = match src.block_at(index) {
+                Some(block) => block,
+                None => continue,
+            };
+            let len = min(block_size, size - index * block_size) as usize;
+            let data = unsafe { core::slice::from_raw_parts(self.checked_block(block)?, len) };
+            inode::Cursor::at(dst, index * block_size)
+                .privileged(privileged)
+                .write(data)?;
+        }
+        Ok(())
+    }
 
-    use super::Superblock;
+    /// Copy len bytes at src_off in src to dst_off in dst, like copy_file_range, returns the
+    /// number of bytes copied: the range stops at the end of src. The blocks of dst are
+    /// allocated as needed and dst grows when the range ends past its end.
+    ///
+    /// The whole blocks at the same place in the blocks of src and dst are copied directly,
+    /// the blocks that are holes in both stay holes. The ragged edges go through a small
+    /// buffer. Overlapping ranges of the same inode are refused with InvalidArgument. If a
+    /// block can't be allocated, what was copied before it is kept
+    pub fn copy_range(
+        &self,
+        src: &Inode<'_, 'device>,
+        src_off: u32,
+        dst: &Inode<'_, 'device>,
+        dst_off: u32,
+        len: u32,
+    ) -> Result<u32, Error> {
+        src.cursor()?;
+        dst.cursor()?;
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+        let len = min(len, src.size().saturating_sub(src_off));
+        let end = dst_off.checked_add(len).ok_or(Error::FileTooLarge)?;
+        if src.inode_ref() == dst.inode_ref() && src_off < end && dst_off < src_off + len {
+            return Err(Error::InvalidArgument);
+        }
+        if len == 0 {
+            return Ok(0);
+        }
+        self.note_write();
+        let metadata = dst.metadata();
+        let privileged = self.is_privileged(metadata.user_id, metadata.group_id);
+        let block_size = self.block_size as u32;
+        let size = dst.size();
+        if dst_off > size && !size.is_multiple_of(block_size) {
+            // The end of the last block may hold data that was truncated away
+            if let Some(block) = dst.block_at(size / block_size) {
+                let start = (size % block_size) as usize;
+                unsafe {
+                    access::fill(
+                        self.checked_block(block)?.add(start),
+                        0,
+                        self.block_size - start,
+                    )
+                };
+            }
+        }
 
-    #[test]
-    fn map_test_file() {
-        let mut file = std::fs::OpenOptions::new()
-            .read(true)
-            .write(true)
-            .open("test_fs")
-            .unwrap();
-        let mut backing = std::vec::Vec::with_capacity(500_000);
-        file.read_to_end(&mut backing).unwrap();
-        let ptr = backing.as_mut_ptr();
+        let mut bounce = [0; 512];
+        let mut done = 0;
+        let mut result = Ok(());
+        while done < len {
+            let (from, to) = (src_off + done, dst_off + done);
+            let copied = if from.is_multiple_of(block_size)
+                && to.is_multiple_of(block_size)
+                && len - done >= block_size
+            {
+                self.copy_block(src, from / block_size, dst, to / block_size, privileged)
+                    .map(|()| block_size)
+            } else {
+                let chunk = min(
+                    min(bounce.len() as u32, len - done),
+                    min(block_size - from % block_size, block_size - to % block_size),
+                );
+                let buffer = &mut bounce[..chunk as usize];
+                self.read_range(src, from, buffer).and_then(|()| {
+                    inode::Cursor::at(dst, to)
+                        .privileged(privileged)
+                        .write(buffer)
+                        .map(|()| chunk)
+                })
+            };
+            match copied {
+                Ok(copied) => done += copied,
+                Err(error) => {
+                    result = Err(error);
+                    break;
+                }
+            }
+        }
+        if dst_off + done > dst.size() {
+            dst.set_size(dst_off + done);
+        }
+        if let (true, Some(now)) = (done != 0, self.now()) {
+            dst.set_modification_time(now);
+        }
+        result.map(|()| done)
+    }
+
+    /// Copy the block index of src over the block dst_index of dst
+    fn copy_block(
+        &self,
+        src: &Inode<'_, 'device>,
+        index: u32,
+        dst: &Inode<'_, 'device>,
+        dst_index: u32,
+        privileged: bool,
+    ) -> Result<(), Error> {
+        let data = match src.block_at(index) {
+            Some(block) => Some(unsafe { self.checked_block(block)? }),
+            None => None,
+        };
+        match (data, dst.block_at(dst_index)) {
+            (None, None) => (),
+            (None, Some(block)) => unsafe {
+                access::fill(self.checked_block(block)?, 0, self.block_size)
+            },
+            (Some(data), Some(block)) => unsafe {
+                access::copy_within(data, self.checked_block(block)?, self.block_size)
+            },
+            (Some(data), None) => {
+                let data = unsafe { core::slice::from_raw_parts(data, self.block_size) };
+                inode::Cursor::at(dst, dst_index * self.block_size as u32)
+                    .privileged(privileged)
+                    .write(data)?
+            }
+        }
+        Ok(())
+    }
+
+    /// Fill buffer with the content of inode at position, which must stay in one block
+    fn read_range(
+        &self,
+        inode: &Inode<'_, 'device>,
+        position: u32,
+        buffer: &mut [u8],
+    ) -> Result<(), Error> {
+        let block_size = self.block_size as u32;
+        match inode.block_at(position / block_size) {
+            Some(block) => unsafe {
+                let start = self
+                    .checked_block(block)?
+                    .add((position % block_size) as usize);
+                access::copy_from_device(start, buffer.as_mut_ptr(), buffer.len());
+            },
+            None => buffer.fill(0),
+        }
+        Ok(())
+    }
+
+    fn create(
+        &self,
+        path: &[u8],
+        kind: EntryKind,
+        perms: Permission,
+        user_id: u16,
+        group_id: u16,
+    ) -> Result<InodeRef, Error> {
+        let (parent, name) = split_parent(path);
+        if name.is_empty() {
+            return Err(Error::AlreadyExists);
+        }
+        if name.len() > 255 {
+            return Err(Error::NameTooLong);
+        }
+        let parent = self.get_inode(self.lookup_path(parent)?)?;
+        Ok(parent.create_inode_in_dir(kind, perms, user_id, group_id, name)?)
+    }
 
-        let (superblock, _extended) = unsafe { Superblock::from_ptr(ptr.offset(1024)) };
-        assert_eq!(superblock.inode_count, 56);
+    /// Reserve the first free bit at or after `first` in a bitmap of `len` bits, returns None if
+    /// it is full
+    fn reserve_bitmap(&self, start: *mut u8, first: u32, len: u32) -> Option<u32> {
+        let index = match find_free_bit(start, first, len) {
+            Some(index) => index,
+            None => {
+                log::trace!("Bitmap is full");
+                return None;
+            }
+        };
+        log::trace!("Reserving index {} in bitmap", index);
+        unsafe {
+            access::modify(start.add(index as usize / 8), |byte| {
+                *byte |= 1 << (index % 8)
+            })
+        };
+        Some(index)
+    }
+    /// Clear a bit of the bitmap, returns false if it was already cleared
+    fn release_bitmap(&self, start: *mut u8, index: u32) -> bool {
+        log::trace!("Releasing index {} in bitmap", index);
+        let byte = unsafe { start.add(index as usize / 8) };
+        let was_set = self.bitmap_bit(start, index);
+        unsafe { access::modify(byte, |byte| *byte &= !(1 << (index % 8))) };
+        was_set
+    }
+    fn bitmap_bit(&self, start: *const u8, index: u32) -> bool {
+        unsafe { access::read(start.add(index as usize / 8)) & (1 << (index % 8)) != 0 }
+    }
+    /// Whether block is marked as used in the bitmap of its group, InvalidArgument if it is not
+    /// in a group
+    pub fn is_block_allocated(&self, block: u32) -> Result<bool, Error> {
+        let superblock = self.get_superblock();
+        if block < superblock.index_of_superblock || block >= superblock.block_count {
+            return Err(Error::InvalidArgument);
+        }
+        let group = self.group_of_block(block);
+        let bitmap =
+            self.get_block_group_descriptor_table()[group as usize].block_address_of_block_bitmap;
+        Ok(self.bitmap_bit(
+            unsafe { self.get_block(bitmap) },
+            block - self.first_block_of_group(group),
+        ))
+    }
+    /// Whether inode is marked as used in the bitmap of its group, InvalidArgument if it does
+    /// not exist
+    pub fn is_inode_allocated(&self, inode: InodeRef) -> Result<bool, Error> {
+        if inode.0 == 0 || inode.0 > self.get_superblock().inode_count {
+            return Err(Error::InvalidArgument);
+        }
+        let group = self.group_of_inode(inode);
+        let bitmap =
+            self.get_block_group_descriptor_table()[group as usize].block_address_of_inode_bitmap;
+        Ok(self.bitmap_bit(
+            unsafe { self.get_block(bitmap) },
+            (inode.0 - 1) % self.get_superblock().inode_count_in_group,
+        ))
+    }
+    /// The inodes marked as used in the bitmaps, including the reserved ones and those that can't
+    /// be reached from the root
+    pub fn allocated_inodes(&self) -> impl Iterator<Item = InodeRef> + '_ {
+        let superblock = self.get_superblock();
+        let inode_count = superblock.inode_count;
+        let inode_count_in_group = superblock.inode_count_in_group;
+        self.get_block_group_descriptor_table()
+            .iter()
+            .enumerate()
+            .flat_map(move |(group, descriptor)| {
+                let first = group as u32 * inode_count_in_group;
+                // The inode count may end before the last group
+                let len = core::cmp::min(inode_count.saturating_sub(first), inode_count_in_group);
+                let bitmap = unsafe { self.get_block(descriptor.block_address_of_inode_bitmap) }
+                    as *const u8;
+                let mut next = 0;
+                core::iter::from_fn(move || {
+                    let index = find_bit(bitmap, next, len, true)?;
+                    next = index + 1;
+                    Some(InodeRef(first + index + 1))
+                })
+            })
+    }
+    /// Whether the user can use the blocks reserved for the superuser
+    pub fn is_privileged(&self, user_id: u16, group_id: u16) -> bool {
+        let superblock = self.get_superblock();
+        user_id == 0
+            || user_id == superblock.user_id_allowed_to_reserve
+            || group_id == superblock.group_id_allowed_to_reserve
+    }
+    /// Reserve a block as close as possible after goal: first in the group of goal, then in the
+    /// following groups. Returns its absolute block number.
+    /// Only privileged allocations can use the blocks reserved for the superuser
+    fn reserve_block(&self, goal: u32, privileged: bool) -> Option<u32> {
+        let superblock = self.get_superblock();
+        if !privileged && superblock.unallocated_blocks <= superblock.block_superuser {
+            log::trace!("Only the reserved blocks are left");
+            return None;
+        }
+        // The goal may be just after the last block
+        let goal = if goal < superblock.block_count {
+            goal
+        } else {
+            superblock.index_of_superblock
+        };
+        let goal_group = self.group_of_block(goal);
+        let goal_index = goal - self.first_block_of_group(goal_group);
+        log::trace!("reserving new block near {} in group {}", goal, goal_group);
+        let has_free_blocks = |group: u32| {
+            self.get_block_group_descriptor_table()[group as usize].unallocated_blocks_in_group != 0
+        };
+        if has_free_blocks(goal_group) {
+            if let Some(block) = self.reserve_block_in_group(
+                goal_group,
+                goal_index,
+                self.block_count_of_group(goal_group),
+            ) {
+                return Some(block);
+            }
+        }
+        let group_count = self.block_group_descriptor_table_len as u32;
+        (0..group_count)
+            .map(|i| (goal_group + i) % group_count)
+            .filter(|&group| has_free_blocks(group))
+            .find_map(|group| {
+                self.reserve_block_in_group(group, 0, self.block_count_of_group(group))
+            })
+    }
+    /// Reserve exactly block, returns false if it is already used or does not exist
+    fn reserve_block_at(&self, block: u32, privileged: bool) -> bool {
+        let superblock = self.get_superblock();
+        if !privileged && superblock.unallocated_blocks <= superblock.block_superuser {
+            return false;
+        }
+        if block >= superblock.block_count {
+            return false;
+        }
+        let group = self.group_of_block(block);
+        let index = block - self.first_block_of_group(group);
+        self.reserve_block_in_group(group, index, index + 1)
+            .is_some()
+    }
+    /// Reserve the first run of count free blocks of group, returns its first block.
+    ///
+    /// NoFreeBlocks if there is no such run, the blocks can still be reserved one at a time.
+    /// Only privileged reservations can use the blocks reserved for the superuser, see
+    /// `is_privileged`. InvalidArgument if count is 0 or does not fit in the free count of a
+    /// group
+    pub fn reserve_contiguous(
+        &self,
+        group: u32,
+        count: u32,
+        privileged: bool,
+    ) -> Result<u32, Error> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+        if count == 0
+            || count > u32::from(u16::MAX)
+            || group as usize >= self.block_group_descriptor_table_len
+        {
+            return Err(Error::InvalidArgument);
+        }
+        let superblock = self.get_superblock();
+        if !privileged
+            && superblock
+                .unallocated_blocks
+                .saturating_sub(superblock.block_superuser)
+                < count
+        {
+            log::trace!("Only the reserved blocks are left");
+            return Err(Error::NoFreeBlocks);
+        }
+        let (start, _) = self
+            .free_extents(group)
+            .find(|&(_, length)| length >= count)
+            .ok_or(Error::NoFreeBlocks)?;
+        log::trace!("reserving {} blocks from {}", count, start);
+        let bitmap = unsafe {
+            self.get_block(
+                self.get_block_group_descriptor_table()[group as usize]
+                    .block_address_of_block_bitmap,
+            )
+        };
+        let first_index = start - self.first_block_of_group(group);
+        for index in first_index..first_index + count {
+            unsafe {
+                access::modify(bitmap.add(index as usize / 8), |byte| {
+                    *byte |= 1 << (index % 8)
+                })
+            };
+        }
+        self.take_free_blocks(group, count);
+        Ok(start)
+    }
+    /// Reserve the first free block of group at or after the index first in the group, and
+    /// before the index end
+    fn reserve_block_in_group(&self, group: u32, first: u32, end: u32) -> Option<u32> {
+        let bitmap =
+            self.get_block_group_descriptor_table()[group as usize].block_address_of_block_bitmap;
+        let index = self.reserve_bitmap(unsafe { self.get_block(bitmap) }, first, end)?;
+        self.take_free_blocks(group, 1);
+        Some(self.first_block_of_group(group) + index)
+    }
+    /// Remove count blocks that were just marked as used in the bitmap of group from the free
+    /// blocks of the superblock and of the group. The bitmaps are trusted: a smaller count is
+    /// only reported and clamped to 0, `sync` recomputes the one of the superblock
+    fn take_free_blocks(&self, group: u32, count: u32) {
+        self.update_superblock(|superblock| {
+            if superblock.unallocated_blocks < count {
+                log::warn!(
+                    "The superblock counted {} free blocks, but the bitmap had {}",
+                    { superblock.unallocated_blocks },
+                    count
+                );
+            }
+            superblock.unallocated_blocks = superblock.unallocated_blocks.saturating_sub(count)
+        });
+        self.update_group_descriptor(group, |descriptor| {
+            if u32::from(descriptor.unallocated_blocks_in_group) < count {
+                log::warn!(
+                    "Group {} counted {} free blocks, but its bitmap had {}",
+                    group,
+                    { descriptor.unallocated_blocks_in_group },
+                    count
+                );
+            }
+            descriptor.unallocated_blocks_in_group = descriptor
+                .unallocated_blocks_in_group
+                .saturating_sub(count as u16)
+        });
+    }
+    /// The block where group starts, the bitmap of the group starts at this block
+    pub(crate) fn first_block_of_group(&self, group: u32) -> u32 {
+        let superblock = self.get_superblock();
+        superblock.index_of_superblock + group * superblock.block_count_in_group
+    }
+    pub(crate) fn group_of_block(&self, block: u32) -> u32 {
+        let superblock = self.get_superblock();
+        (block - superblock.index_of_superblock) / superblock.block_count_in_group
+    }
+    /// The number of blocks in group, the last group may be smaller than the others
+    pub(crate) fn block_count_of_group(&self, group: u32) -> u32 {
+        core::cmp::min(
+            self.get_superblock().block_count - self.first_block_of_group(group),
+            self.get_superblock().block_count_in_group,
+        )
+    }
+    /// The runs of free blocks of group, as (first block, length).
+    ///
+    /// Panics if the group does not exist
+    pub fn free_extents(&self, group: u32) -> impl Iterator<Item = (u32, u32)> + '_ {
+        let bitmap = unsafe {
+            self.get_block(
+                self.get_block_group_descriptor_table()[group as usize]
+                    .block_address_of_block_bitmap,
+            )
+        } as *const u8;
+        let first_block = self.first_block_of_group(group);
+        let len = self.block_count_of_group(group);
+        let mut next = 0;
+        core::iter::from_fn(move || {
+            let start = find_bit(bitmap, next, len, false)?;
+            next = find_bit(bitmap, start, len, true).unwrap_or(len);
+            Some((first_block + start, next - start))
+        })
+    }
+    /// Give back a block to the group owning it.
+    ///
+    /// The block must not be used by an inode anymore. Releasing a free block leaves the
+    /// bitmap and the counters alone and returns Corrupt. Blocks out of the filesystem are
+    /// ignored
+    pub fn release_block(&self, block: u32) -> Result<(), Error> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+        if !self.free_block(block) {
+            return Err(Error::Corrupt("block released twice"));
+        }
+        Ok(())
+    }
+    /// Release a block without checking that the filesystem is writable, for the callers that
+    /// did. Returns false if the block was already free, which is logged
+    fn free_block(&self, block: u32) -> bool {
+        if !self.is_valid_block(block) {
+            return true;
+        }
+        let group = self.group_of_block(block);
+        log::trace!("releasing block {} in group {}", block, group);
+        let bitmap =
+            self.get_block_group_descriptor_table()[group as usize].block_address_of_block_bitmap;
+        let was_used = self.release_bitmap(
+            unsafe { self.get_block(bitmap) },
+            block - self.first_block_of_group(group),
+        );
+        if was_used {
+            self.update_superblock(|superblock| superblock.unallocated_blocks += 1);
+            self.update_group_descriptor(group, |descriptor| {
+                descriptor.unallocated_blocks_in_group += 1
+            });
+        } else {
+            log::warn!("Block {} was released twice", block);
+        }
+        was_used
+    }
+    /// Like release_block, but the content of the block is overwritten with zeros first
+    pub fn release_block_erasing(&self, block: u32) -> Result<(), Error> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+        if let Ok(data) = unsafe { self.checked_block(block) } {
+            unsafe { access::fill(data, 0, self.block_size) };
+            if !self.free_block(block) {
+                return Err(Error::Corrupt("block released twice"));
+            }
+        }
+        Ok(())
+    }
+    /// Reserve an inode, trying group first then the following groups
+    fn reserve_inode(&self, group: u32) -> Option<InodeRef> {
+        let group_count = self.block_group_descriptor_table_len as u32;
+        (0..group_count)
+            .map(|i| (group + i) % group_count)
+            .filter(|&group| {
+                self.get_block_group_descriptor_table()[group as usize].unallocated_inodes_in_group
+                    != 0
+            })
+            .find_map(|group| self.reserve_inode_in_group(group))
+    }
+    fn reserve_inode_in_group(&self, group: u32) -> Option<InodeRef> {
+        log::trace!("reserving new inode in group {}", group);
+        let inode_count_in_group = self.get_superblock().inode_count_in_group;
+        let bitmap =
+            self.get_block_group_descriptor_table()[group as usize].block_address_of_inode_bitmap;
+        // The first inodes are reserved for the bad blocks, the journal...
+        let first = if group == 0 {
+            self.get_extended_superblock().first_non_reserved_inode - 1
+        } else {
+            0
+        };
+        let index = self.reserve_bitmap(
+            unsafe { self.get_block(bitmap) },
+            first,
+            inode_count_in_group,
+        )?;
+        self.update_superblock(|superblock| {
+            if superblock.unallocated_inodes == 0 {
+                log::warn!("The superblock counted no free inode, but the bitmap had one");
+            }
+            superblock.unallocated_inodes = superblock.unallocated_inodes.saturating_sub(1)
+        });
+        self.update_group_descriptor(group, |descriptor| {
+            if descriptor.unallocated_inodes_in_group == 0 {
+                log::warn!(
+                    "Group {} counted no free inode, but its bitmap had one",
+                    group
+                );
+            }
+            descriptor.unallocated_inodes_in_group =
+                descriptor.unallocated_inodes_in_group.saturating_sub(1)
+        });
+        // Inodes start at 1
+        Some(InodeRef(group * inode_count_in_group + index + 1))
+    }
+    /// Free an inode whose link count dropped to 0, its blocks must already have been released.
+    /// The block of its extended attributes is released with it.
+    ///
+    /// The reserved inodes, including the root, and the inodes that are not allocated can't be
+    /// released
+    pub fn release_inode(&self, inode: InodeRef) -> Result<(), Error> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+        if inode.0 < self.get_extended_superblock().first_non_reserved_inode
+            || inode == root_inode()
+        {
+            return Err(Error::InvalidArgument);
+        }
+        let released = self.get_inode(inode)?;
+        if !self.is_inode_allocated(inode)? {
+            return Err(Error::InvalidArgument);
+        }
+        released.release_xattr_block();
+        released.set_link_count(0);
+        // A deletion time of 0 means the inode is in use, even without a clock it must be set.
+        // Small values are links of the orphan list, the last write time is a safe fallback
+        released.set_deletion_time(
+            self.now()
+                .unwrap_or_else(|| self.get_superblock().last_written),
+        );
+        self.release_inode_bit(inode);
+        Ok(())
+    }
+    /// Give back an inode to the group owning it
+    fn release_inode_bit(&self, inode: InodeRef) {
+        let group = self.group_of_inode(inode);
+        log::trace!("releasing inode {:?} in group {}", inode, group);
+        let is_dir = self.load_inode(inode).is_dir();
+        if is_dir {
+            self.invalidate_cached_entries(inode);
+        }
+        let bitmap =
+            self.get_block_group_descriptor_table()[group as usize].block_address_of_inode_bitmap;
+        // An inode that was already free is not counted twice
+        if self.release_bitmap(
+            unsafe { self.get_block(bitmap) },
+            (inode.0 - 1) % self.get_superblock().inode_count_in_group,
+        ) {
+            self.update_superblock(|superblock| superblock.unallocated_inodes += 1);
+            self.update_group_descriptor(group, |descriptor| {
+                descriptor.unallocated_inodes_in_group += 1;
+                if is_dir {
+                    descriptor.number_of_directories_in_group -= 1
+                }
+            });
+        }
+    }
+
+    /// The inode referenced by inode, BadInodeRef if it is out of the range of the superblock.
+    /// References read from the image or given by a caller are only checked here
+    pub fn get_inode(&self, inode: InodeRef) -> Result<Inode<'_, 'device>, Error> {
+        if inode.0 == 0
+            || inode.0 > self.get_superblock().inode_count
+            || self.group_of_inode(inode) as usize >= self.block_group_descriptor_table_len
+        {
+            return Err(Error::BadInodeRef);
+        }
+        Ok(self.load_inode(inode))
+    }
+    /// Like get_inode for the references that are known to be valid, like the root or a newly
+    /// reserved inode
+    pub(crate) fn load_inode(&self, inode: InodeRef) -> Inode<'_, 'device> {
+        // I think it is safe because Inodes use *mut InodeData, you
+        // can give multiple of them
+        unsafe { Inode::from_fs(self, inode.0, self.get_inode_in_table(inode.0)) }
+    }
+    pub(crate) fn group_of_inode(&self, inode: InodeRef) -> u32 {
+        (inode.0 - 1) / self.get_superblock().inode_count_in_group
+    }
+
+    /// This function assumes that you have exclusive access to that part of memory
+    unsafe fn get_inode_in_table(&self, inode: u32) -> *mut InodeData {
+        let block_group = self.group_of_inode(InodeRef(inode));
+        let index = (inode - 1) % self.get_superblock().inode_count_in_group;
+
+        let inode_table = self.get_block_group_descriptor_table()[block_group as usize]
+            .starting_block_of_inode_table;
+
+        // The inode table is in the device, that open checked to be addressable
+        // The inodes don't cross the blocks, a transaction or the pool of `open_device` hold each
+        // block on its own
+        let offset_in_table =
+            u64::from(self.get_extended_superblock().inode_struct_size) * u64::from(index);
+        let block_size = self.block_size as u64;
+        let inode_table_offset = self
+            .get_block(inode_table + (offset_in_table / block_size) as u32)
+            .add((offset_in_table % block_size) as usize);
+
+        inode::InodeData::from_ptr(inode_table_offset)
+    }
+
+    /// Safety: Don't have two handles on the same block !
+    ///
+    /// The blocks before the block count are addressable, open refuses the filesystems that are
+    /// not
+    ///
+    /// The pointer is only valid for the block: during a `Transaction` it points to the staged
+    /// copy of the block, and to its buffer for a filesystem opened with `open_device`
+    unsafe fn get_block(&self, index: u32) -> *mut u8 {
+        self.hand_out(index, self.block_address(index))
+    }
+    /// Give block, where the block index is outside of a transaction, to the dirty tracking
+    /// and to the transaction, returns the pointer to use
+    unsafe fn hand_out(&self, index: u32, block: *mut u8) -> *mut u8 {
+        #[cfg(feature = "alloc")]
+        if let Some(dirty) = &self.dirty {
+            dirty.borrow_mut().hand_out(index, block);
+        }
+        #[cfg(feature = "alloc")]
+        if let Some(staged) = &self.staged {
+            return staged.borrow_mut().block(index, block);
+        }
+        let _ = index;
+        block
+    }
+    /// Where the block index is outside of a transaction: in the region of the filesystem, or
+    /// in the pool of its device
+    unsafe fn block_address(&self, index: u32) -> *mut u8 {
+        #[cfg(feature = "alloc")]
+        if let Some(pool) = &self.pool {
+            return pool.borrow_mut().block_or_zeros(index);
+        }
+        self.fs
+            .add((u64::from(index) * self.block_size as u64) as usize)
+    }
+    /// Like block_address, but the pool of a `BlockDevice` reports a failed read and its limit
+    unsafe fn try_block_address(&self, index: u32) -> Result<*mut u8, Error> {
+        #[cfg(feature = "alloc")]
+        if let Some(pool) = &self.pool {
+            return pool.borrow_mut().block(index, true);
+        }
+        Ok(self.block_address(index))
+    }
+    /// Whether get_block gives the blocks where they are on the device, one after the other.
+    /// They are copies during a `Transaction`, and buffers on a `BlockDevice`
+    pub(crate) fn blocks_in_place(&self) -> bool {
+        #[cfg(feature = "alloc")]
+        if self.staged.is_some() || self.pool.is_some() {
+            return false;
+        }
+        true
+    }
+    /// The blocks holding the superblock and the group descriptors, the filesystem points into
+    /// them
+    #[cfg(feature = "alloc")]
+    fn metadata_blocks(&self) -> core::ops::Range<u32> {
+        // They were found by open, in the device
+        let (superblock, table) = unsafe {
+            (
+                (self.superblock as *mut u8).offset_from(self.fs) as usize,
+                (self.block_group_descriptor_table as *mut u8).offset_from(self.fs) as usize,
+            )
+        };
+        let end = table + self.block_group_descriptor_table_len * BLOCK_GROUP_DESCRITPOR_SIZE;
+        (superblock / self.block_size) as u32..end.div_ceil(self.block_size) as u32
+    }
+    /// Like get_block for the blocks referenced by inodes, Corrupt if index is not a block of
+    /// the filesystem. On a `BlockDevice`, DeviceFailed if the block can't be read and PoolFull
+    /// past the limit of the pool
+    unsafe fn checked_block(&self, index: u32) -> Result<*mut u8, Error> {
+        if !self.is_valid_block(index) {
+            log::trace!("block {} is out of the filesystem", index);
+            return Err(Error::Corrupt("block number out of range"));
+        }
+        block_offset(self.block_size, index)?;
+        Ok(self.hand_out(index, self.try_block_address(index)?))
+    }
+    /// Call f with the content of block, like reading it from checked_block. The content of
+    /// a `BlockDevice` that is not in its pool is read without being kept
+    pub(crate) fn with_block<T>(&self, block: u32, f: impl FnOnce(&[u8]) -> T) -> Result<T, Error> {
+        #[cfg(feature = "alloc")]
+        if let (Some(pool), None) = (&self.pool, &self.staged) {
+            if !self.is_valid_block(block) {
+                return Err(Error::Corrupt("block number out of range"));
+            }
+            // The pool is not borrowed while f runs, it may use the filesystem
+            let unpooled = pool.borrow_mut().read_unpooled(block);
+            if let Some(scratch) = unpooled {
+                let scratch = scratch?;
+                let result = f(&scratch);
+                pool.borrow_mut().recycle(scratch);
+                return Ok(result);
+            }
+        }
+        let data = unsafe { self.checked_block(block)? };
+        Ok(f(unsafe {
+            core::slice::from_raw_parts(data, self.block_size)
+        }))
+    }
+    /// Copy the bytes of block from offset into buffer, with `with_block`. When
+    /// `blocks_in_place`, they run into the next blocks if buffer goes past the end of block
+    pub(crate) fn read_block(
+        &self,
+        block: u32,
+        offset: usize,
+        buffer: &mut [u8],
+    ) -> Result<(), Error> {
+        if !self.blocks_in_place() {
+            return self.with_block(block, |data| {
+                buffer.copy_from_slice(&data[offset..offset + buffer.len()])
+            });
+        }
+        unsafe {
+            let data = self.checked_block(block)?;
+            access::copy_from_device(data.add(offset), buffer.as_mut_ptr(), buffer.len());
+        }
+        Ok(())
+    }
+    /// Whether block is in the range of blocks that can be allocated
+    pub(crate) fn is_valid_block(&self, block: u32) -> bool {
+        let superblock = self.get_superblock();
+        block != 0 && block >= superblock.index_of_superblock && block < superblock.block_count
+    }
+}
+
+/// Refuse the filesystems that can't be read without a feature that is not implemented. A
+/// journal to replay is not one of them, the metadata is read as it was before
+fn check_required_features(required: RequiredFeatures) -> Result<(), OpenError> {
+    let unsupported = required.bits()
+        & !SUPPORTED_REQUIRED_FEATURES.bits()
+        & !RequiredFeatures::REPLAY_JOURNAL.bits();
+    if unsupported != 0 {
+        return Err(OpenError::Unsupported(UnsupportedFeatures(unsupported)));
+    }
+    Ok(())
+}
+
+/// Split a path between the path of its parent and its last component
+fn split_parent(path: &[u8]) -> (&[u8], &[u8]) {
+    let path = match path.iter().rposition(|&c| c != b'/') {
+        Some(end) => &path[..=end],
+        None => return (b"/", b""),
+    };
+    match path.iter().rposition(|&c| c == b'/') {
+        Some(separator) => (&path[..separator], &path[separator + 1..]),
+        None => (b"", path),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use std::io::Read;
+
+    use super::{
+        CheckReason, CreateError, EntryKind, Error, Ext2Device, FileSystem, GroupPolicy, InodeRef,
+        OpenError, Permission, Statistics, Superblock, SuperblockCopy, Tuning, UnsupportedFeatures,
+    };
+    use crate::access;
+    use crate::inode::{
+        root_inode, BlockMapping, Cursor, InodeExtra, InodeFlags, MappedBlock, PhysicalBlock,
+        Timestamp,
+    };
+    use crate::metadata::{FsState, OnError};
+    use bstr::ByteSlice;
+
+    /// Load one of the test images at the root of the repository into memory
+    pub(crate) fn load_image(name: &str) -> std::vec::Vec<u8> {
+        std::fs::read(
+            std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+                .join("..")
+                .join(name),
+        )
+        .unwrap()
+    }
+
+    /// An empty filesystem of len bytes made by `mkfs::format` with the default options
+    pub(crate) fn formatted(len: usize) -> std::vec::Vec<u8> {
+        let mut image = std::vec![0; len];
+        crate::mkfs::format(&mut image, crate::mkfs::MkfsOptions::new()).unwrap();
+        image
+    }
+
+    #[test]
+    fn map_test_file() {
+        let mut file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(concat!(env!("CARGO_MANIFEST_DIR"), "/../test_fs"))
+            .unwrap();
+        let mut backing = std::vec::Vec::with_capacity(500_000);
+        file.read_to_end(&mut backing).unwrap();
+        let ptr = backing.as_mut_ptr();
+
+        let (superblock, _extended) = unsafe { Superblock::from_ptr(ptr.offset(1024)).unwrap() };
+        assert_eq!(unsafe { (*superblock).inode_count }, 56);
+    }
+
+    #[test]
+    fn block_offsets() {
+        use super::block_offset;
+        assert_eq!(block_offset(1024, 0), Ok(0));
+        assert_eq!(block_offset(1024, 3), Ok(3072));
+        // Past 4GiB
+        let far = block_offset(4096, 1 << 20);
+        let last = block_offset(65536, u32::MAX);
+        if cfg!(target_pointer_width = "64") {
+            assert_eq!(far, Ok(1 << 32));
+            assert_eq!(last, Ok((u32::MAX as isize) << 16));
+        } else {
+            assert_eq!(far, Err(Error::OffsetOverflow));
+            assert_eq!(last, Err(Error::OffsetOverflow));
+        }
+    }
+
+    #[test]
+    fn open_invalid() {
+        let image = formatted(400 * 1024);
+        let open = |modify: &dyn Fn(&mut [u8])| {
+            let mut image = image.clone();
+            modify(&mut image);
+            let mut device = unsafe { Ext2Device::from_ptr(image.as_mut_ptr()) };
+            device.try_open().err()
+        };
+        assert_eq!(open(&|_| ()), None);
+        assert_eq!(
+            open(&|image| image.iter_mut().for_each(|byte| *byte = 0)),
+            Some(OpenError::BadSignature)
+        );
+        assert_eq!(open(&|image| image[1024 + 76..1024 + 80].fill(0)), None);
+        assert_eq!(
+            open(&|image| image[1024 + 76] = 2),
+            Some(OpenError::UnsupportedRevision(2))
+        );
+        // Compression in the required features
+        assert_eq!(
+            open(&|image| image[1024 + 96] |= 1),
+            Some(OpenError::Unsupported(UnsupportedFeatures(1)))
+        );
+        // The blocks per group
+        assert_eq!(
+            open(&|image| image[1024 + 32..1024 + 36].fill(0)),
+            Some(OpenError::InvalidGeometry("blocks per group out of range"))
+        );
+        assert_eq!(
+            open(&|image| image[1024 + 24] = 7),
+            Some(OpenError::InvalidGeometry("block size out of range"))
+        );
+        assert_eq!(
+            open(&|image| image[1024 + 24] = 6),
+            Some(OpenError::UnsupportedBlockSize(65536))
+        );
+        // The block count, one group would not be enough for the inodes
+        assert_eq!(
+            open(&|image| image[1024 + 4..1024 + 8].copy_from_slice(&u32::MAX.to_le_bytes())),
+            Some(OpenError::InvalidGeometry(
+                "inode count does not match the groups"
+            ))
+        );
+        assert_eq!(
+            open(&|image| image[1024 + 88] = 100),
+            Some(OpenError::InvalidGeometry("inode size out of range"))
+        );
+        assert_eq!(
+            Error::from(OpenError::BadSignature),
+            Error::InvalidSuperblock
+        );
+    }
+
+    #[test]
+    fn backup_groups() {
+        let groups: std::vec::Vec<_> = super::backup_groups().take(10).collect();
+        assert_eq!(groups, [1, 3, 5, 7, 9, 25, 27, 49, 81, 125]);
+    }
+
+    #[test]
+    fn superblock_backup_groups() {
+        // As listed by dumpe2fs for 50 groups
+        let groups: std::vec::Vec<_> = super::superblock_groups(50, true).collect();
+        assert_eq!(groups, [0, 1, 3, 5, 7, 9, 25, 27, 49]);
+        assert_eq!(super::superblock_groups(1, true).count(), 1);
+        assert_eq!(super::superblock_groups(4, false).count(), 4);
+
+        let mut image = load_image("test_fs_groups");
+        let mut device = unsafe { Ext2Device::from_ptr(image.as_mut_ptr()) };
+        let fs = device.open();
+        let groups: std::vec::Vec<_> = fs.superblock_backup_groups().collect();
+        assert_eq!(groups, [0, 1]);
+
+        // Without sparse_super in the write features
+        image[1024 + 100] &= !1;
+        let mut device = unsafe { Ext2Device::from_ptr(image.as_mut_ptr()) };
+        let fs = device.open();
+        let groups: std::vec::Vec<_> = fs.superblock_backup_groups().collect();
+        assert_eq!(groups, [0, 1, 2]);
+    }
+
+    #[test]
+    fn sync_metadata() {
+        let mut image = load_image("test_fs_groups");
+        let mut device = unsafe { Ext2Device::from_ptr(image.as_mut_ptr()) };
+        let fs = device.open();
+        let free = fs.get_superblock().unallocated_blocks;
+        fs.update_superblock(|superblock| superblock.unallocated_blocks -= 1);
+        fs.update_group_descriptor(1, |descriptor| descriptor.unallocated_blocks_in_group -= 1);
+        fs.sync_metadata().unwrap();
+
+        // The superblock and the descriptors of group 1 are in its first two blocks
+        let backup = 257 * 1024;
+        assert_eq!(image[backup + 12..backup + 16], (free - 1).to_le_bytes());
+        assert_eq!(image[backup..backup + 90], image[1024..1024 + 90]);
+        // The group of the copy
+        assert_eq!(image[backup + 90..backup + 92], [1, 0]);
+        assert_eq!(image[1024 + 90..1024 + 92], [0, 0]);
+        assert_eq!(
+            image[backup + 1024..backup + 1024 + 96],
+            image[2048..2048 + 96]
+        );
+        assert_eq!(image[backup + 92..backup + 1024], image[1024 + 92..2048]);
+    }
+
+    #[test]
+    fn sync() {
+        let mut image = load_image("test_fs_groups");
+        let mut device = unsafe { Ext2Device::from_ptr(image.as_mut_ptr()) };
+        let mut fs = device.open();
+        extern "C" fn clock() -> u32 {
+            1_000_000
+        }
+        fs.set_clock(clock);
+        let file = fs.create_file(b"/synced", Permission::all(), 0, 0).unwrap();
+        let mut file = fs.get_inode(file).unwrap().as_file().unwrap();
+        file.write(&[0xaa; 3000]).unwrap();
+        file.sync();
+        drop(file);
+        let statistics = fs.statistics(true);
+        fs.update_superblock(|superblock| superblock.unallocated_blocks = 0);
+        fs.sync().unwrap();
+        assert_eq!(fs.statistics(false), statistics);
+
+        let mut device = unsafe { Ext2Device::from_ptr(image.as_mut_ptr()) };
+        let fs = device.open();
+        assert_eq!(fs.statistics(false), statistics);
+        assert_eq!({ fs.get_superblock().last_written }, 1_000_000);
+        let mut content = [0; 3000];
+        let mut file = fs
+            .open(b"/synced", super::OpenOptions::new().read(true))
+            .unwrap();
+        assert_eq!(file.read(&mut content), 3000);
+        assert!(content.iter().all(|&byte| byte == 0xaa));
+        // The backup of group 1
+        assert_eq!(image[257 * 1024..257 * 1024 + 90], image[1024..1024 + 90]);
+    }
+
+    #[test]
+    fn note_write() {
+        use core::sync::atomic::{AtomicU32, Ordering};
+        static TIME: AtomicU32 = AtomicU32::new(1_000_000);
+        extern "C" fn clock() -> u32 {
+            TIME.load(Ordering::Relaxed)
+        }
+        let mut image = load_image("test_fs_groups");
+        let mut device = unsafe { Ext2Device::from_ptr(image.as_mut_ptr()) };
+        let mut fs = device.open();
+        let last_written = |fs: &FileSystem| fs.get_superblock().last_written;
+        let written = last_written(&fs);
+        let file = fs.create_file(b"/noted", Permission::all(), 0, 0).unwrap();
+        assert_eq!(last_written(&fs), written);
+
+        fs.set_clock(clock);
+        let file = fs.get_inode(file).unwrap();
+        file.as_file().unwrap().write(b"a").unwrap();
+        assert_eq!(last_written(&fs), 1_000_000);
+        TIME.store(1_000_001, Ordering::Relaxed);
+        file.set_flags(InodeFlags::SYNCHRONOUS_UPDATES).unwrap();
+        assert_eq!(last_written(&fs), 1_000_001);
+        TIME.store(1_000_002, Ordering::Relaxed);
+        let root = fs.get_inode(fs.lookup_path(b"/").unwrap()).unwrap();
+        root.remove_entry(b"noted").unwrap();
+        assert_eq!(last_written(&fs), 1_000_002);
+        TIME.store(1_000_003, Ordering::Relaxed);
+        fs.create_dir(b"/other", Permission::all(), 0, 0).unwrap();
+        assert_eq!(last_written(&fs), 1_000_003);
+        // Reading does not count
+        TIME.store(1_000_004, Ordering::Relaxed);
+        fs.lookup_path(b"/other").unwrap();
+        assert_eq!(last_written(&fs), 1_000_003);
+    }
+
+    #[test]
+    fn mount() {
+        let mut image = load_image("test_fs_groups");
+        let state = |image: &[u8]| u16::from_le_bytes([image[1024 + 58], image[1024 + 59]]);
+        let mounts = |image: &[u8]| u16::from_le_bytes([image[1024 + 52], image[1024 + 53]]);
+        let initial_mounts = mounts(&image);
+        assert_eq!(state(&image), FsState::Clean as u16);
+        let mut device = unsafe { Ext2Device::from_ptr(image.as_mut_ptr()) };
+        let mut fs = device.open();
+        extern "C" fn clock() -> u32 {
+            1_000_000
+        }
+        fs.set_clock(clock);
+        fs.mount_writable();
+        fs.mount_writable();
+        assert_eq!(fs.get_superblock().state(), Err(0));
+        assert_eq!(
+            {
+                fs.get_superblock()
+                    .number_of_times_mounted_since_last_consitency_check
+            },
+            initial_mounts + 1
+        );
+        assert_eq!({ fs.get_superblock().last_mounted }, 1_000_000);
+        fs.create_dir(b"/mounted", Permission::all(), 0, 0).unwrap();
+        fs.unmount().unwrap();
+        assert_eq!(fs.get_superblock().state(), Ok(FsState::Clean));
+        assert_eq!({ fs.get_superblock().last_written }, 1_000_000);
+        fs.unmount().unwrap();
+
+        // Dropping the FileSystem unmounts it, an errored state is kept
+        fs.update_superblock(|superblock| superblock.state = FsState::Errored as u16);
+        fs.mount_writable();
+        assert_eq!(fs.get_superblock().state(), Ok(FsState::Errored));
+        drop(fs);
+        assert_eq!(state(&image), FsState::Errored as u16);
+        assert_eq!(mounts(&image), initial_mounts + 2);
+
+        // A crash leaves the filesystem not clean
+        image[1024 + 58] = FsState::Clean as u8;
+        let mut device = unsafe { Ext2Device::from_ptr(image.as_mut_ptr()) };
+        let fs = device.open();
+        fs.mount_writable();
+        core::mem::forget(fs);
+        assert_eq!(state(&image), 0);
+
+        // Nothing is changed on a read-only filesystem
+        image[1024 + 58] = FsState::Clean as u8;
+        image[1024 + 100] |= 0x80;
+        let mut device = unsafe { Ext2Device::from_ptr(image.as_mut_ptr()) };
+        let fs = device.open();
+        assert!(fs.is_read_only());
+        fs.mount_writable();
+        assert_eq!(fs.get_superblock().state(), Ok(FsState::Clean));
+        assert_eq!(mounts(&image), initial_mounts + 3);
+    }
+
+    #[test]
+    fn errors_policy() {
+        let mut image = load_image("test_fs_groups");
+        let open_errored = |image: &mut [u8], policy: u8| {
+            image[1024 + 58] = FsState::Errored as u8;
+            image[1024 + 60] = policy;
+            let mut device = Ext2Device::from_slice(image).unwrap();
+            device.try_open().map(|fs| {
+                let created = fs.create_dir(b"/errored", Permission::all(), 0, 0);
+                (fs.is_read_only(), fs.needs_check(), created.is_ok())
+            })
+        };
+        assert_eq!(
+            open_errored(&mut image, OnError::RemountReadOnly as u8),
+            Ok((true, Some(CheckReason::Errored), false))
+        );
+        assert_eq!(
+            open_errored(&mut image, 9),
+            Ok((true, Some(CheckReason::Errored), false))
+        );
+        assert_eq!(
+            open_errored(&mut image, OnError::KernelPanic as u8),
+            Err(OpenError::Errored)
+        );
+        assert_eq!(
+            open_errored(&mut image, OnError::Ignore as u8),
+            Ok((false, Some(CheckReason::Errored), true))
+        );
+    }
+
+    #[test]
+    fn read_only() {
+        let mut image = load_image("test_fs_xattr");
+        let pristine = image.clone();
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let mut fs = device.try_open_read_only().unwrap();
+        extern "C" fn clock() -> u32 {
+            1_000_000
+        }
+        fs.set_clock(clock);
+        assert!(fs.is_read_only());
+        let read_only = Some(Error::ReadOnly);
+        let labeled_ref = fs.lookup_path(b"/labeled.txt").unwrap();
+        let labeled = fs.get_inode(labeled_ref).unwrap();
+        let block = unsafe { (*labeled.get_data()).direct_block_pointers[0] };
+        let root = fs.get_inode(root_inode()).unwrap();
+
+        fs.mount_writable();
+        let new = b"/new";
+        let permissions = Permission::all();
+        assert_eq!(fs.create_file(new, permissions, 0, 0).err(), read_only);
+        assert_eq!(fs.create_dir(new, permissions, 0, 0).err(), read_only);
+        assert_eq!(
+            fs.create_dir_all(b"/a/b", permissions, 0, 0).err(),
+            read_only
+        );
+        assert_eq!(
+            root.create_inode_in_dir(EntryKind::RegularFile, permissions, 0, 0, b"new"),
+            Err(CreateError::ReadOnly)
+        );
+        assert_eq!(fs.unlink(b"/labeled.txt"), Err(Error::ReadOnly));
+        let options = super::OpenOptions::new().write(true);
+        assert_eq!(fs.open(b"/labeled.txt", options).err(), read_only);
+        assert_eq!(labeled.cursor().unwrap().write(b"new").err(), read_only);
+        assert_eq!(labeled.as_file().unwrap().set_len(0).err(), read_only);
+        assert_eq!(labeled.truncate(0).err(), read_only);
+        assert_eq!(labeled.set_flags(InodeFlags::empty()).err(), read_only);
+        assert_eq!(labeled.set_xattr(b"user.new", b"new").err(), read_only);
+        assert_eq!(labeled.remove_xattr(b"user.comment").err(), read_only);
+        assert_eq!(fs.reserve_contiguous(0, 1, true).err(), read_only);
+        assert_eq!(fs.release_block(block).err(), read_only);
+        assert_eq!(fs.release_block_erasing(block).err(), read_only);
+        assert_eq!(fs.release_inode(labeled_ref).err(), read_only);
+        assert_eq!(fs.sync_metadata().err(), read_only);
+        assert_eq!(fs.sync(), Ok(()));
+        assert_eq!(fs.unmount(), Ok(()));
+        drop(fs);
+        assert!(image == pristine);
+    }
+
+    #[test]
+    fn tuning() {
+        let mut image = load_image("test_fs_groups");
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.open();
+        let initial = fs.tuning();
+        assert_eq!(initial.max_mount_count, None);
+        assert_eq!(initial.check_interval, None);
+        assert_eq!(initial.on_error, Ok(OnError::Ignore));
+
+        let invalid = Err(Error::InvalidArgument);
+        assert_eq!(fs.set_max_mount_count(Some(0)), invalid);
+        assert_eq!(fs.set_max_mount_count(Some(40_000)), invalid);
+        assert_eq!(fs.set_check_interval(Some(0)), invalid);
+        let blocks = fs.get_superblock().block_count;
+        assert_eq!(fs.set_reserved_block_count(blocks + 1), invalid);
+        assert_eq!(fs.tuning(), initial);
+
+        fs.set_max_mount_count(Some(25)).unwrap();
+        fs.set_check_interval(Some(86400)).unwrap();
+        fs.set_reserved_block_count(100).unwrap();
+        fs.set_reserved_uid(1000).unwrap();
+        fs.set_reserved_gid(100).unwrap();
+        fs.set_default_mount_behavior(OnError::RemountReadOnly)
+            .unwrap();
+        let tuned = Tuning {
+            max_mount_count: Some(25),
+            check_interval: Some(86400),
+            reserved_blocks: 100,
+            reserved_uid: 1000,
+            reserved_gid: 100,
+            on_error: Ok(OnError::RemountReadOnly),
+        };
+        assert_eq!(fs.tuning(), tuned);
+        assert!(fs.is_privileged(1000, 0));
+        drop(fs);
+
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.open();
+        assert_eq!(fs.tuning(), tuned);
+        fs.set_max_mount_count(None).unwrap();
+        fs.set_check_interval(None).unwrap();
+        assert_eq!(
+            { fs.get_superblock().number_of_mounts_until_consistency_check },
+            u16::MAX
+        );
+        assert_eq!(fs.tuning().max_mount_count, None);
+        assert_eq!(fs.tuning().check_interval, None);
+        drop(fs);
+
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.try_open_read_only().unwrap();
+        assert_eq!(fs.set_reserved_uid(0), Err(Error::ReadOnly));
+    }
+
+    #[test]
+    fn names() {
+        let mut image = load_image("test_fs_groups");
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.open();
+        let extended = || fs.get_extended_superblock();
+        fs.set_volume_name(b"sixteen bytes!!!").unwrap();
+        assert_eq!(extended().volume_name(), "sixteen bytes!!!");
+        fs.set_volume_name(b"short").unwrap();
+        assert_eq!(extended().volume_name(), "short");
+        assert_eq!(
+            fs.set_volume_name(b"seventeen bytes!!"),
+            Err(Error::InvalidArgument)
+        );
+        assert_eq!(
+            fs.set_volume_name(b"embedded\0nul"),
+            Err(Error::InvalidArgument)
+        );
+        assert_eq!(extended().volume_name(), "short");
+
+        let path = [b'p'; 64];
+        fs.set_last_mounted_path(&path).unwrap();
+        assert_eq!(extended().last_mounted_path(), &path[..]);
+        assert_eq!(
+            fs.set_last_mounted_path(&[b'p'; 65]),
+            Err(Error::InvalidArgument)
+        );
+        fs.mount_writable_at(b"/mnt").unwrap();
+        assert_eq!(extended().last_mounted_path(), "/mnt");
+        // Mounting again changes nothing
+        fs.mount_writable_at(b"/other").unwrap();
+        assert_eq!(extended().last_mounted_path(), "/mnt");
+        fs.unmount().unwrap();
+        drop(fs);
+        // Written by hand, the NUL ends the name
+        image[1024 + 120 + 4] = 0;
+        let mut device = unsafe { Ext2Device::from_ptr(image.as_mut_ptr()) };
+        let fs = device.open();
+        assert_eq!(fs.get_extended_superblock().volume_name(), "shor");
+        assert_eq!(fs.get_extended_superblock().last_mounted_path(), "/mnt");
+    }
+
+    #[test]
+    fn check_limits() {
+        let mut image = load_image("test_fs_groups");
+        let mut device = unsafe { Ext2Device::from_ptr(image.as_mut_ptr()) };
+        let mut fs = device.open();
+        assert_eq!(fs.needs_check(), None);
+
+        fs.update_superblock(|superblock| {
+            superblock.number_of_times_mounted_since_last_consitency_check = 20;
+            superblock.number_of_mounts_until_consistency_check = 20;
+        });
+        assert_eq!(fs.needs_check(), Some(CheckReason::MountCount));
+        fs.update_superblock(|superblock| {
+            superblock.number_of_mounts_until_consistency_check = u16::MAX
+        });
+        assert_eq!(fs.needs_check(), None);
+
+        extern "C" fn clock() -> u32 {
+            1_000_000
+        }
+        fs.update_superblock(|superblock| {
+            superblock.time_since_last_constiency_check = 900_000;
+            superblock.time_between_forced_consistency_check = 200_000;
+        });
+        // The time is unknown without a clock
+        assert_eq!(fs.needs_check(), None);
+        fs.set_clock(clock);
+        assert_eq!(fs.needs_check(), None);
+        fs.update_superblock(|superblock| {
+            superblock.time_between_forced_consistency_check = 100_000
+        });
+        assert_eq!(fs.needs_check(), Some(CheckReason::Interval));
+    }
+
+    #[test]
+    fn recover_synced_metadata() {
+        let mut image = load_image("test_fs_backup");
+        let len = image.len();
+        let mut device = unsafe { Ext2Device::from_ptr(image.as_mut_ptr()) };
+        let fs = device.open();
+        fs.create_dir(b"/synced", Permission::all(), 0, 0).unwrap();
+        let statistics = fs.statistics(false);
+        fs.sync_metadata().unwrap();
+
+        image[1024..2048].fill(0);
+        let mut device = unsafe { Ext2Device::from_ptr(image.as_mut_ptr()) };
+        let (fs, _) = device.open_with_recovery(len).unwrap();
+        assert_eq!(fs.statistics(false), statistics);
+        assert!(fs.lookup_path(b"/synced").is_ok());
+        assert_eq!(fs.sync_metadata(), Err(Error::ReadOnly));
+    }
+
+    #[test]
+    fn truncated_image() {
+        let mut image = load_image("test_fs");
+        assert_eq!(
+            Ext2Device::from_slice(&mut image[..2047]).err(),
+            Some(Error::InvalidSuperblock)
+        );
+        let mut device = Ext2Device::from_slice(&mut image[..200 * 1024]).unwrap();
+        assert_eq!(
+            device.try_open().err(),
+            Some(OpenError::InvalidGeometry(
+                "filesystem larger than the device"
+            ))
+        );
+        assert_eq!(
+            device.open_with_recovery(400 * 1024).err(),
+            Some(OpenError::InvalidGeometry(
+                "filesystem larger than the device"
+            ))
+        );
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        assert!(device.try_open().is_ok());
+
+        // The inode table of group 0 past the last block
+        image[2048 + 8..2048 + 12].copy_from_slice(&400u32.to_le_bytes());
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        assert_eq!(
+            device.try_open().err(),
+            Some(OpenError::InvalidGeometry(
+                "group descriptor out of the filesystem"
+            ))
+        );
+    }
+
+    #[test]
+    fn open_with_recovery() {
+        let mut image = load_image("test_fs_backup");
+        let len = image.len();
+        let mut device = unsafe { Ext2Device::from_ptr(image.as_mut_ptr()) };
+        let (fs, copy) = device.open_with_recovery(len).unwrap();
+        assert_eq!(copy, SuperblockCopy::Primary);
+        assert!(!fs.is_read_only());
+
+        image[1024..2048].fill(0);
+        let mut device = unsafe { Ext2Device::from_ptr(image.as_mut_ptr()) };
+        assert_eq!(device.try_open().err(), Some(OpenError::BadSignature));
+        let (fs, copy) = device.open_with_recovery(len).unwrap();
+        assert_eq!(
+            copy,
+            SuperblockCopy::Backup {
+                group: 1,
+                block_size: 1024
+            }
+        );
+        assert!(fs.is_read_only());
+        assert_eq!({ fs.get_superblock().block_count }, 8400);
+        let mut content = [0; 16];
+        let mut file = fs
+            .open(b"/backup.txt", super::OpenOptions::new().read(true))
+            .unwrap();
+        assert_eq!(file.read(&mut content), 11);
+        assert_eq!(&content[..11], b"still here\n");
+
+        // The backup must be in the device
+        let mut device = unsafe { Ext2Device::from_ptr(image.as_mut_ptr()) };
+        assert_eq!(
+            device.open_with_recovery(8193 * 1024).err(),
+            Some(OpenError::BadSignature)
+        );
+        // A damaged backup is skipped
+        image[8193 * 1024 + 32..8193 * 1024 + 36].fill(0);
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        assert_eq!(
+            device.open_with_recovery(len).err(),
+            Some(OpenError::BadSignature)
+        );
+    }
+
+    #[test]
+    fn unsupported_features() {
+        let mut image = load_image("test_fs_back");
+        // An unknown required feature
+        image[1024 + 97] |= 1;
+        let mut device = unsafe { Ext2Device::from_ptr(image.as_mut_ptr()) };
+        assert_eq!(
+            device.try_open().err(),
+            Some(OpenError::Unsupported(UnsupportedFeatures(0x100)))
+        );
+
+        let mut image = load_image("test_fs_back");
+        let mut device = unsafe { Ext2Device::from_ptr(image.as_mut_ptr()) };
+        let fs = device.open();
+        assert!(!fs.is_read_only());
+        assert_eq!(fs.unsupported_features(), UnsupportedFeatures(0));
+
+        // The directory b-trees and an unknown write feature
+        image[1024 + 100] |= 0x84;
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.open();
+        assert!(fs.is_read_only());
+        assert_eq!(fs.unsupported_features(), UnsupportedFeatures(0x84));
+        let before = fs.statistics(true);
+
+        let read = super::OpenOptions::new().read(true);
+        let mut file = fs.open(b"/foo.txt", read).unwrap();
+        let mut content = [0; 64];
+        assert!(file.read(&mut content) > 0);
+        assert_eq!(file.write(b"nope"), Err(Error::ReadOnly));
+        assert_eq!(file.set_len(0), Err(Error::ReadOnly));
+        assert_eq!(
+            file.inode().cursor().unwrap().write(b"nope"),
+            Err(Error::ReadOnly)
+        );
+        assert_eq!(
+            fs.open(b"/foo.txt", read.write(true)).err(),
+            Some(Error::ReadOnly)
+        );
+        assert_eq!(
+            fs.create_file(b"/new", Permission::all(), 0, 0),
+            Err(Error::ReadOnly)
+        );
+        assert_eq!(
+            fs.create_dir_all(b"/a/b", Permission::all(), 0, 0),
+            Err(Error::ReadOnly)
+        );
+        assert_eq!(fs.unlink(b"/foo.txt"), Err(Error::ReadOnly));
+        assert!(fs.lookup_path(b"/foo.txt").is_ok());
+        assert_eq!(fs.statistics(true), before);
+    }
+
+    #[test]
+    fn open_fuzzed() {
+        let image = load_image("test_fs_back");
+        // xorshift, the test must be reproducible
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let mut random = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        let mut opened = 0;
+        for round in 0..5000 {
+            let mut image = image.clone();
+            if round % 100 == 0 {
+                image[..4096]
+                    .iter_mut()
+                    .for_each(|byte| *byte = random() as u8);
+            } else {
+                for _ in 0..1 + random() % 4 {
+                    // Mostly in the fields that are checked
+                    let index = 1024 + (random() % 256) as usize;
+                    image[index] = random() as u8;
+                }
+            }
+            let mut device = Ext2Device::from_slice(&mut image).unwrap();
+            if device.try_open().is_ok() {
+                opened += 1;
+            }
+        }
+        // Most mutations land in fields that are not checked
+        assert!(opened > 0 && opened < 5000);
+    }
+
+    #[test]
+    fn create_unknown_kind() {
+        let mut image = formatted(400 * 1024);
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.open();
+        let free = fs.statistics(false);
+        assert_eq!(
+            fs.get_root()
+                .create_inode_in_dir(EntryKind::Unkown, Permission::all(), 0, 0, b"what"),
+            Err(CreateError::UnknownKind)
+        );
+        assert_eq!(fs.statistics(false), free);
+        assert_eq!(fs.lookup_path(b"/what"), Err(Error::NotFound));
+    }
+
+    #[test]
+    fn create_special_kinds() {
+        let mut image = formatted(400 * 1024);
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.open();
+        let root = fs.get_root();
+        let free = fs.statistics(false);
+        for kind in [
+            EntryKind::CharDevice,
+            EntryKind::BlockDevice,
+            EntryKind::Symlink,
+        ]
+        .iter()
+        {
+            assert_eq!(
+                root.create_inode_in_dir(*kind, Permission::all(), 0, 0, b"special"),
+                Err(CreateError::UnsupportedKind)
+            );
+        }
+        assert_eq!(fs.statistics(false), free);
+        assert_eq!(fs.lookup_path(b"/special"), Err(Error::NotFound));
+
+        for (kind, name) in [(EntryKind::Fifo, b"fifo"), (EntryKind::Socket, b"sock")].iter() {
+            let inode = root
+                .create_inode_in_dir(*kind, Permission::all(), 0, 0, &name[..])
+                .unwrap();
+            let inode = fs.get_inode(inode).unwrap();
+            assert_eq!(inode.file_type(), *kind);
+            assert_eq!(inode.size(), 0);
+            assert_eq!(inode.cursor().err(), Some(Error::NotAFile));
+            assert_eq!(root.find_entry(&name[..]).unwrap().kind, *kind);
+        }
+    }
+
+    #[test]
+    fn split_parent() {
+        assert_eq!(super::split_parent(b"/a/b/c"), (&b"/a/b"[..], &b"c"[..]));
+        assert_eq!(super::split_parent(b"/a/b/"), (&b"/a"[..], &b"b"[..]));
+        assert_eq!(super::split_parent(b"a"), (&b""[..], &b"a"[..]));
+        assert_eq!(super::split_parent(b"/"), (&b"/"[..], &b""[..]));
+    }
+
+    #[test]
+    fn lookup_path() {
+        let mut image = load_image("test_fs_back");
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.open();
+
+        let never = fs.lookup_path(b"/thing/more/never.txt").unwrap();
+        assert_eq!(fs.get_inode(never).unwrap().size(), 11);
+        assert_eq!(
+            fs.lookup_path(b"/thing/../foo.txt"),
+            fs.lookup_path(b"foo.txt")
+        );
+        assert_eq!(fs.lookup_path(b"/"), Ok(InodeRef(2)));
+        assert_eq!(fs.lookup_path(b"/thing/nope"), Err(Error::NotFound));
+        assert_eq!(fs.lookup_path(b"/foo.txt/nope"), Err(Error::NotADirectory));
+    }
+
+    /// The image is not read from a file so that Miri can run it with isolation, see the Readme
+    #[test]
+    fn in_memory_image() {
+        let mut image = include_bytes!("../../test_fs_special").to_vec();
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.open();
+        let read = super::OpenOptions::new().read(true);
+
+        let mut content = [0; 16];
+        let mut hello = fs.open(b"/hello.txt", read).unwrap();
+        assert_eq!(hello.read(&mut content), 6);
+        assert_eq!(&content[..6], b"hello\n");
+
+        fs.create_dir(b"/dir", Permission::all(), 0, 0).unwrap();
+        let mut file = fs
+            .open(b"/dir/file", read.write(true).create(true))
+            .unwrap();
+        file.write(&[7; 1500]).unwrap();
+        file.sync();
+        file.discard_preallocation();
+        drop(file);
+
+        let mut file = fs.open(b"/dir/file", read).unwrap();
+        let mut content = [0; 2048];
+        assert_eq!(file.read(&mut content), 1500);
+        assert!(content[..1500].iter().all(|&byte| byte == 7));
+        let names: std::vec::Vec<_> = fs
+            .get_inode(fs.lookup_path(b"/dir").unwrap())
+            .unwrap()
+            .get_dir_entries()
+            .unwrap()
+            .map(|entry| entry.name)
+            .collect();
+        assert_eq!(names, [".", "..", "file"]);
+    }
+
+    #[test]
+    fn misaligned_image() {
+        let image = load_image("test_fs_back");
+        let mut buffer = std::vec![0; image.len() + 8];
+        // The device starts at an odd address
+        let shift = (9 - buffer.as_ptr() as usize % 8) % 8;
+        buffer[shift..shift + image.len()].copy_from_slice(&image);
+        let mut device = unsafe { Ext2Device::from_ptr(buffer.as_mut_ptr().add(shift)) };
+        let fs = device.open();
+        assert_eq!({ fs.get_superblock().inode_count }, 56);
+
+        let mut content = [0; 64];
+        let mut never = fs
+            .open(
+                b"/thing/more/never.txt",
+                super::OpenOptions::new().read(true),
+            )
+            .unwrap();
+        assert_eq!(never.read(&mut content), 11);
+
+        fs.create_dir(b"/dir", Permission::all(), 0, 0).unwrap();
+        let mut file = fs
+            .open(
+                b"/dir/file",
+                super::OpenOptions::new().write(true).create(true),
+            )
+            .unwrap();
+        file.write(b"misaligned").unwrap();
+        file.sync();
+        let names: std::vec::Vec<_> = fs.iter_files().map(|file| file.name).collect();
+        assert!(names.iter().any(|name| *name == "file"));
+        fs.unlink(b"/dir/file").unwrap();
+        assert_eq!(fs.lookup_path(b"/dir/file"), Err(Error::NotFound));
+    }
+
+    #[test]
+    fn revision_0() {
+        let mut image = load_image("test_fs_rev0");
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.open();
+        assert!(fs.is_revision_0());
+        assert!(!fs.is_read_only());
+        let extended = fs.get_extended_superblock();
+        assert_eq!({ extended.inode_struct_size }, 128);
+        assert_eq!({ extended.first_non_reserved_inode }, 11);
+        assert!({ extended.required_features }.is_empty());
+
+        // The types of the entries come from the inodes
+        let kinds: std::vec::Vec<_> = fs
+            .get_root()
+            .get_dir_entries()
+            .unwrap()
+            .map(|entry| entry.kind)
+            .collect();
+        assert_eq!(
+            kinds,
+            [
+                EntryKind::Directory,
+                EntryKind::Directory,
+                EntryKind::Directory,
+                EntryKind::Directory,
+                EntryKind::Symlink,
+            ]
+        );
+        let mut content = [0; 16];
+        let mut file = fs
+            .open(b"/dir/file.txt", super::OpenOptions::new().read(true))
+            .unwrap();
+        assert_eq!(file.read(&mut content), 11);
+        assert_eq!(&content[..11], b"revision 0\n");
+
+        let dir = fs.create_dir(b"/new", Permission::all(), 0, 0).unwrap();
+        let file = fs
+            .create_file(b"/new/file", Permission::all(), 0, 0)
+            .unwrap();
+        assert_eq!(fs.lookup_path(b"/new/file"), Ok(file));
+        let dir = fs.get_inode(dir).unwrap();
+        let kinds: std::vec::Vec<_> = dir
+            .get_dir_entries()
+            .unwrap()
+            .map(|entry| (entry.name.to_str().unwrap(), entry.kind))
+            .collect();
+        assert_eq!(
+            kinds,
+            [
+                (".", EntryKind::Directory),
+                ("..", EntryKind::Directory),
+                ("file", EntryKind::RegularFile),
+            ]
+        );
+        // The byte after the name length of '.', '..' and 'file' is the high byte of the length
+        let mut block = [0; 36];
+        Cursor::at(&dir, 0).read(&mut block);
+        assert_eq!((block[7], block[19], block[31]), (0, 0, 0));
+    }
+
+    #[test]
+    fn large_inodes() {
+        let mut image = load_image("test_fs_large_inodes");
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let mut fs = device.open();
+        assert_eq!(fs.inode_size(), 256);
+
+        let file = fs.lookup_path(b"/file.txt").unwrap();
+        let inode = fs.get_inode(file).unwrap();
+        let metadata = inode.metadata();
+        assert_eq!(metadata.kind, EntryKind::RegularFile);
+        assert_eq!(metadata.size, 13);
+        assert_eq!(metadata.link_count, 1);
+        let time = |seconds, nanoseconds| Timestamp {
+            seconds,
+            nanoseconds,
+        };
+        assert_eq!(metadata.accessed, time(1_600_000_000, 0));
+        assert_eq!(metadata.modified, time(1_600_000_000, 125_000_000));
+        assert_eq!(metadata.created, Some(time(1_500_000_000, 1)));
+        let extra = inode.get_extra_data().unwrap() as *mut InodeExtra;
+        assert_eq!(
+            unsafe {
+                {
+                    (*extra).extra_isize
+                }
+            },
+            32
+        );
+
+        // The extra area of a released inode is not reused
+        unsafe { (*extra).project_id = 7 };
+        fs.unlink(b"/file.txt").unwrap();
+        extern "C" fn clock() -> u32 {
+            1_000_000
+        }
+        fs.set_clock(clock);
+        let new = fs.create_file(b"/new", Permission::all(), 0, 0).unwrap();
+        assert_eq!(new, file);
+        let inode = fs.get_inode(new).unwrap();
+        let metadata = inode.metadata();
+        assert_eq!(metadata.modified, time(1_000_000, 0));
+        assert_eq!(metadata.changed, time(1_000_000, 0));
+        assert_eq!(metadata.created, Some(time(1_000_000, 0)));
+        let extra = unsafe { &*inode.get_extra_data().unwrap() };
+        assert_eq!({ extra.extra_isize }, 32);
+        assert_eq!({ extra.project_id }, 0);
+        let rest = unsafe {
+            core::slice::from_raw_parts(
+                (extra as *const InodeExtra).add(1) as *const u8,
+                256 - 128 - 32,
+            )
+        };
+        assert!(rest.iter().all(|&byte| byte == 0));
+
+        let mut back = load_image("test_fs_back");
+        let mut device = Ext2Device::from_slice(&mut back).unwrap();
+        let fs = device.open();
+        assert!(fs.get_root().get_extra_data().is_none());
+        assert_eq!(fs.get_root().metadata().created, None);
+        assert_eq!(fs.get_root().metadata().modified.nanoseconds, 0);
+    }
+
+    #[test]
+    fn special_files() {
+        let mut image = load_image("test_fs_special");
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.open();
+        let get = |path: &[u8]| fs.get_inode(fs.lookup_path(path).unwrap()).unwrap();
+
+        let kinds: std::vec::Vec<_> = fs
+            .get_root()
+            .get_dir_entries()
+            .unwrap()
+            .skip(3)
+            .map(|entry| (entry.kind, fs.get_inode(entry.inode).unwrap().file_type()))
+            .collect();
+        assert_eq!(
+            kinds,
+            [
+                (EntryKind::RegularFile, EntryKind::RegularFile),
+                (EntryKind::Symlink, EntryKind::Symlink),
+                (EntryKind::Fifo, EntryKind::Fifo),
+                (EntryKind::CharDevice, EntryKind::CharDevice),
+            ]
+        );
+
+        let hello = get(b"/hello.txt");
+        assert!(hello.cursor().is_ok());
+        let mut content = [0; 16];
+        assert_eq!(hello.as_file().unwrap().read(&mut content), 6);
+        assert_eq!(&content[..6], b"hello\n");
+        for path in [&b"/link"[..], b"/fifo", b"/chr"].iter() {
+            let inode = get(path);
+            assert_eq!(inode.cursor().err(), Some(Error::NotAFile));
+            assert_eq!(inode.end().err(), Some(Error::NotAFile));
+            assert!(inode.as_file().is_none());
+            assert!(!inode.is_dir());
+            assert!(inode.get_dir_entries().is_none());
+            assert_eq!(
+                fs.open(path, super::OpenOptions::new().read(true)).err(),
+                Some(Error::NotAFile)
+            );
+        }
+        assert_eq!(fs.get_root().cursor().err(), Some(Error::IsADirectory));
+        assert_eq!(fs.lookup_path(b"/link/x"), Err(Error::NotADirectory));
+    }
+
+    #[test]
+    fn read_file() {
+        let mut image = load_image("test_fs_special");
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.open();
+        let link = fs.lookup_path(b"/link").unwrap();
+        assert_eq!(
+            &**fs.get_inode(link).unwrap().read_link().unwrap(),
+            b"hello.txt"
+        );
+        assert_eq!(
+            fs.get_root().read_link().err(),
+            Some(Error::InvalidArgument)
+        );
+
+        let read = |path: &[u8], follow: bool| {
+            let mut content = std::vec::Vec::new();
+            fs.read_file(path, follow, |chunk| {
+                content.extend_from_slice(chunk);
+                Ok::<_, ()>(())
+            })
+            .map(|size| {
+                assert_eq!(size, content.len() as u64);
+                content
+            })
+        };
+        assert_eq!(read(b"/hello.txt", false).unwrap(), b"hello\n");
+        assert_eq!(read(b"/link", true).unwrap(), b"hello\n");
+        assert_eq!(read(b"/link", false), Err(Error::NotAFile));
+        assert_eq!(read(b"/", true), Err(Error::IsADirectory));
+        assert_eq!(read(b"/lost+found/x", true), Err(Error::NotFound));
+        assert_eq!(
+            fs.read_file(b"/hello.txt", false, |_| Err(())),
+            Err(Error::WriteFailed)
+        );
+
+        // A relative target taken from the root is the link itself
+        unsafe {
+            let data = fs.get_inode_in_table(link.0);
+            write_field!(data, direct_block_pointers[0], u32::from_le_bytes(*b"link"));
+            write_field!(data, size_lower_32_bits, 4);
+        }
+        assert_eq!(read(b"/link", true), Err(Error::TooManySymlinks));
+        drop(fs);
+
+        // Given block by block, past the direct blocks
+        let mut image = load_image("test_fs_indirect");
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.open();
+        let mut chunks = 0;
+        let size = fs.read_file(b"/big", false, |chunk| {
+            assert_eq!(chunk.len(), 1024);
+            chunks += 1;
+            Ok::<_, ()>(())
+        });
+        assert_eq!((size, chunks), (Ok(307200), 300));
+    }
+
+    #[test]
+    fn mapped_blocks() {
+        let mut image = load_image("test_fs_indirect");
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.open();
+        let big = fs.get_inode(fs.lookup_path(b"/big").unwrap()).unwrap();
+        let blocks: std::vec::Vec<_> = big.mapped_blocks().collect();
+        // As listed by debugfs
+        let expected: std::vec::Vec<_> = (54..66)
+            .map(MappedBlock::Data)
+            .chain(Some(MappedBlock::Indirect {
+                block: 66,
+                level: 1,
+            }))
+            .chain((67..323).map(MappedBlock::Data))
+            .chain(Some(MappedBlock::Indirect {
+                block: 323,
+                level: 2,
+            }))
+            .chain(Some(MappedBlock::Indirect {
+                block: 324,
+                level: 1,
+            }))
+            .chain((325..357).map(MappedBlock::Data))
+            .collect();
+        assert_eq!(blocks, expected);
+    }
+
+    #[test]
+    fn blocks() {
+        let mut image = load_image("test_fs_indirect");
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.open();
+        let big = fs.get_inode(fs.lookup_path(b"/big").unwrap()).unwrap();
+        let mapping = |index, block, level| BlockMapping {
+            index,
+            block,
+            level,
+        };
+        let blocks: std::vec::Vec<_> = big.blocks().collect();
+        // (0-11):54-65, (IND):66, (12-267):67-322, (DIND):323, (IND):324, (268-299):325-356
+        let expected: std::vec::Vec<_> = (0..12)
+            .map(|index| mapping(index, PhysicalBlock::Block(54 + index), 0))
+            .chain((12..268).map(|index| mapping(index, PhysicalBlock::Block(55 + index), 1)))
+            .chain((268..300).map(|index| mapping(index, PhysicalBlock::Block(57 + index), 2)))
+            .collect();
+        assert_eq!(blocks, expected);
+        assert_eq!(big.blocks().len(), 300);
+
+        // A pointer cleared in the indirect block, and the indirect block under the doubly
+        // indirect one
+        unsafe {
+            access::write(fs.get_block(66).add(4 * 4) as *mut u32, 0);
+            access::write(fs.get_block(323) as *mut u32, 0);
+        }
+        let blocks: std::vec::Vec<_> = big.blocks().collect();
+        assert_eq!(blocks[15], mapping(15, PhysicalBlock::Block(70), 1));
+        assert_eq!(blocks[16], mapping(16, PhysicalBlock::Hole, 1));
+        assert!(blocks[268..]
+            .iter()
+            .all(|mapping| mapping.block == PhysicalBlock::Hole && mapping.level == 2));
+
+        assert_eq!(fs.get_root().blocks().count(), 1);
+        let empty = fs.create_file(b"/empty", Permission::all(), 0, 0).unwrap();
+        assert_eq!(fs.get_inode(empty).unwrap().blocks().count(), 0);
+    }
+
+    #[test]
+    fn bmap() {
+        let mut image = load_image("test_fs_indirect");
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.open();
+        let big = fs.get_inode(fs.lookup_path(b"/big").unwrap()).unwrap();
+        // (0-11):54-65, (12-267):67-322, (268-299):325-356
+        for (index, block) in [
+            (0, 54),
+            (11, 65),
+            (12, 67),
+            (267, 322),
+            (268, 325),
+            (299, 356),
+        ] {
+            assert_eq!(big.bmap(index), Ok(Some(block)), "block {}", index);
+        }
+        assert_eq!(big.bmap(300), Err(Error::InvalidArgument));
+        assert_eq!(big.bmap(u32::MAX), Err(Error::InvalidArgument));
+
+        // The cursors resolve the blocks the same way
+        let mut cursor = big.cursor().unwrap();
+        cursor.advance(268 * 1024);
+        let mut data = [0; 4];
+        assert_eq!(cursor.read(&mut data), 4);
+        assert_eq!(data, unsafe { *(fs.get_block(325) as *const [u8; 4]) });
+
+        unsafe {
+            access::write(fs.get_block(66) as *mut u32, 0);
+            access::write(fs.get_block(66).add(4) as *mut u32, u32::MAX);
+        }
+        assert_eq!(big.bmap(12), Ok(None));
+        assert_eq!(
+            big.bmap(13),
+            Err(Error::Corrupt("block number out of range"))
+        );
+    }
+
+    #[test]
+    fn extent_at() {
+        let mut image = formatted(400 * 1024);
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.open();
+        let file = fs.create_file(b"/file", Permission::all(), 0, 0).unwrap();
+        let inode = fs.get_inode(file).unwrap();
+
+        // Blocks 0-2 and 4-5, the second run starts right after the first on the device
+        inode.allocate(0, 3 * 1024, false).unwrap();
+        inode.allocate(4 * 1024, 2 * 1024 - 100, false).unwrap();
+        let data: std::vec::Vec<u8> = (0..6 * 1024 - 100).map(|i| (i % 253) as u8).collect();
+        Cursor::at(&inode, 0).write(&data[..3 * 1024]).unwrap();
+        Cursor::at(&inode, 4 * 1024)
+            .write(&data[4 * 1024..])
+            .unwrap();
+        let first = u64::from(inode.bmap(0).unwrap().unwrap()) * 1024;
+        assert_eq!(inode.bmap(4), Ok(Some(inode.bmap(2).unwrap().unwrap() + 1)));
+
+        let extent = |start, len, hole| super::inode::Extent { start, len, hole };
+        assert_eq!(inode.extent_at(0), Ok(extent(first, 3 * 1024, false)));
+        assert_eq!(
+            inode.extent_at(1500),
+            Ok(extent(first + 1500, 3 * 1024 - 1500, false))
+        );
+        assert_eq!(inode.extent_at(3 * 1024 + 5), Ok(extent(0, 1019, true)));
+        assert_eq!(
+            inode.extent_at(4 * 1024),
+            Ok(extent(first + 3 * 1024, 2 * 1024 - 100, false))
+        );
+        assert_eq!(
+            inode.extent_at(6 * 1024 - 101),
+            Ok(extent(first + 5 * 1024 - 101, 1, false))
+        );
+        assert_eq!(inode.extent_at(6 * 1024 - 100), Err(Error::InvalidArgument));
+
+        // The cursor reads across the runs and the hole
+        let mut read = std::vec![0xff; data.len()];
+        assert_eq!(
+            Cursor::at(&inode, 0).read(&mut read[..3 * 1024 + 10]),
+            3 * 1024
+        );
+        assert_eq!(
+            Cursor::at(&inode, 4 * 1024).read(&mut read[4 * 1024..]),
+            1948
+        );
+        read[3 * 1024..4 * 1024].fill(0);
+        let mut expected = data.clone();
+        expected[3 * 1024..4 * 1024].fill(0);
+        assert!(read == expected);
+    }
+
+    #[test]
+    fn readahead() {
+        std::thread_local! {
+            static REQUESTS: core::cell::RefCell<std::vec::Vec<(u32, u32)>> =
+                const { core::cell::RefCell::new(std::vec::Vec::new()) };
+        }
+        extern "C" fn record(_: &FileSystem<'_>, block: u32, count: u32) {
+            REQUESTS.with(|requests| requests.borrow_mut().push((block, count)));
+        }
+        let take = || REQUESTS.with(|requests| requests.take());
+
+        let mut image = load_image("test_fs_indirect");
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let mut fs = device.open();
+        let big = fs.lookup_path(b"/big").unwrap();
+        // Without a hook there is nothing to ask
+        {
+            let inode = fs.get_inode(big).unwrap();
+            let mut cursor = inode.cursor().unwrap();
+            cursor.set_readahead(4);
+            cursor.read(&mut [0; 1024]);
+        }
+
+        fs.set_readahead_hook(record);
+        let big = fs.get_inode(big).unwrap();
+        let mut data = [0; 1024];
+        let mut cursor = big.cursor().unwrap();
+        cursor.read(&mut data);
+        assert_eq!(take(), []);
+
+        // (0-11):54-65, (12-267):67-322, (268-299):325-356
+        let mut cursor = big.cursor().unwrap();
+        cursor.set_readahead(4);
+        cursor.read(&mut data);
+        assert_eq!(take(), [(55, 4)]);
+        cursor.read(&mut data[..100]);
+        assert_eq!(take(), [(56, 4)]);
+        // Inside a block
+        cursor.read(&mut data[..100]);
+        assert_eq!(take(), []);
+
+        let mut cursor = Cursor::at(&big, 10 * 1024);
+        cursor.set_readahead(4);
+        cursor.read(&mut data);
+        assert_eq!(take(), [(65, 1), (67, 3)]);
+        cursor.read(&mut data);
+        assert_eq!(take(), [(67, 4)]);
+
+        let mut cursor = Cursor::at(&big, 266 * 1024);
+        cursor.set_readahead(3);
+        cursor.read(&mut data);
+        assert_eq!(take(), [(322, 1), (325, 2)]);
+        let mut cursor = Cursor::at(&big, 298 * 1024);
+        cursor.set_readahead(3);
+        cursor.read(&mut data);
+        assert_eq!(take(), [(356, 1)]);
+    }
+
+    #[test]
+    fn inode_ref_new() {
+        let mut image = load_image("test_fs_back");
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.open();
+        assert_eq!(InodeRef::new(0), None);
+        assert_eq!(InodeRef::new(2), Some(InodeRef::root()));
+        let first = InodeRef::new(1).unwrap();
+        assert_eq!(first.get(), 1);
+        assert!(fs.get_inode(first).is_ok());
+        let max = InodeRef::new(fs.get_superblock().inode_count).unwrap();
+        assert!(fs.get_inode(max).is_ok());
+        let past = InodeRef::new(max.get() + 1).unwrap();
+        assert_eq!(fs.get_inode(past).err(), Some(Error::BadInodeRef));
+    }
+
+    #[test]
+    fn bad_inode_refs() {
+        let mut image = load_image("test_fs_back");
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.open();
+        assert_eq!(fs.get_inode(InodeRef(0)).err(), Some(Error::BadInodeRef));
+        assert_eq!(fs.get_inode(InodeRef(57)).err(), Some(Error::BadInodeRef));
+        assert_eq!(
+            fs.get_inode(InodeRef(u32::MAX)).err(),
+            Some(Error::BadInodeRef)
+        );
+        assert!(fs.get_inode(InodeRef(56)).is_ok());
+        assert_eq!(fs.release_inode(InodeRef(57)), Err(Error::BadInodeRef));
+
+        // Make the entries of /thing/more and /other/niche.txt reference inodes that don't exist
+        let thing = fs.get_inode(fs.lookup_path(b"/thing").unwrap()).unwrap();
+        let other = fs.get_inode(fs.lookup_path(b"/other").unwrap()).unwrap();
+        Cursor::at(&thing, 24)
+            .write(&1000u32.to_le_bytes())
+            .unwrap();
+        Cursor::at(&other, 40)
+            .write(&u32::MAX.to_le_bytes())
+            .unwrap();
+        assert_eq!(fs.lookup_path(b"/thing/more"), Ok(InodeRef(1000)));
+        assert_eq!(
+            fs.lookup_path(b"/thing/more/never.txt"),
+            Err(Error::BadInodeRef)
+        );
+        assert_eq!(
+            fs.create_file(b"/thing/more/new", Permission::all(), 0, 0),
+            Err(Error::BadInodeRef)
+        );
+        assert_eq!(fs.unlink(b"/other/niche.txt"), Err(Error::BadInodeRef));
+        let files: std::vec::Vec<_> = fs.iter_files().map(|file| file.name).collect();
+        assert_eq!(files, ["foo.txt"]);
+    }
+
+    #[test]
+    fn create_tree() {
+        let mut image = formatted(400 * 1024);
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.open();
+        let perms = Permission::USER_READ | Permission::USER_WRITE | Permission::USER_EXECUTE;
+        let root_links = fs.get_root().link_count();
+
+        let a = fs.create_dir(b"/a", perms, 1000, 100).unwrap();
+        let b = fs.create_dir(b"/a/b", perms, 1000, 100).unwrap();
+        let c = fs.create_file(b"/a/b/c.txt", perms, 1000, 100).unwrap();
+        let z = fs.create_dir_all(b"/x/y/z", perms, 0, 0).unwrap();
+        assert_eq!(fs.create_dir_all(b"/x/y/z", perms, 0, 0), Ok(z));
+        assert_eq!(
+            fs.create_dir_all(b"/x/y", perms, 0, 0),
+            fs.lookup_path(b"/x/y")
+        );
+
+        assert_eq!(
+            fs.create_file(b"/a/b/c.txt", perms, 0, 0),
+            Err(Error::AlreadyExists)
+        );
+        assert_eq!(
+            fs.create_dir(b"/a/nope/d", perms, 0, 0),
+            Err(Error::NotFound)
+        );
+        assert_eq!(
+            fs.create_dir(b"/a/b/c.txt/d", perms, 0, 0),
+            Err(Error::NotADirectory)
+        );
+        assert_eq!(
+            fs.create_dir_all(b"/a/b/c.txt/d", perms, 0, 0),
+            Err(Error::NotADirectory)
+        );
+
+        assert_eq!(fs.lookup_path(b"/a"), Ok(a));
+        assert_eq!(fs.lookup_path(b"/a/b"), Ok(b));
+        assert_eq!(fs.lookup_path(b"/a/b/c.txt"), Ok(c));
+        assert_eq!(fs.lookup_path(b"/a/b/.."), Ok(a));
+        assert_eq!(fs.lookup_path(b"/a/b/."), Ok(b));
+        assert_eq!(fs.lookup_path(b"/x/y/z/../../.."), Ok(InodeRef(2)));
+
+        let names: std::vec::Vec<_> = fs
+            .get_inode(b)
+            .unwrap()
+            .get_dir_entries()
+            .unwrap()
+            .map(|entry| entry.name.as_bytes().to_vec())
+            .collect();
+        assert_eq!(names, [&b"."[..], b"..", b"c.txt"]);
+
+        assert_eq!(fs.get_root().link_count(), root_links + 2);
+        assert_eq!(fs.get_inode(a).unwrap().link_count(), 3);
+        assert_eq!(fs.get_inode(b).unwrap().link_count(), 2);
+        assert_eq!(fs.get_inode(c).unwrap().link_count(), 1);
+        assert!(fs.get_inode(z).unwrap().is_dir());
+        assert_eq!(fs.get_inode(z).unwrap().size(), 1024);
+    }
+
+    /// Delete an inode without cleaning the inode table or the directory entry
+    fn forget(fs: &FileSystem<'_>, inode: InodeRef) {
+        let blocks = unsafe { (*fs.get_inode(inode).unwrap().get_data()).direct_block_pointers };
+        for &block in &blocks {
+            if block != 0 {
+                fs.release_block(block).unwrap();
+            }
+        }
+        fs.release_inode(inode).unwrap();
+    }
+
+    #[test]
+    fn reuse_inode() {
+        let mut image = load_image("test_fs_back");
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let mut fs = device.open();
+        extern "C" fn clock() -> u32 {
+            1_000_000
+        }
+
+        let big = fs.create_file(b"/big", Permission::all(), 0, 0).unwrap();
+        let mut file = fs.get_inode(big).unwrap().as_file().unwrap();
+        file.write(&[0xaa; 5000]).unwrap();
+        drop(file);
+
+        assert_ne!(
+            unsafe { (*fs.get_inode(big).unwrap().get_data()).direct_block_pointers },
+            [0; 12]
+        );
+        forget(&fs, big);
+
+        fs.set_clock(clock);
+        let new = fs
+            .create_file(b"/other/new", Permission::all(), 0, 0)
+            .unwrap();
+        assert_eq!(new, big);
+        let data = unsafe { &*fs.get_inode(new).unwrap().get_data() };
+        assert_eq!({ data.direct_block_pointers }, [0; 12]);
+        assert_eq!({ data.size_lower_32_bits }, 0);
+        assert_eq!({ data.disk_sectors_used }, 0);
+        assert_eq!({ data.creation_time }, 1_000_000);
+        assert_eq!({ data.last_modification_time }, 1_000_000);
+        assert_eq!({ data.deletion_time }, 0);
+    }
+
+    #[test]
+    fn reuse_block() {
+        let mut image = load_image("test_fs_back");
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.open();
+
+        let old = fs.create_file(b"/old", Permission::all(), 0, 0).unwrap();
+        fs.get_inode(old)
+            .unwrap()
+            .as_file()
+            .unwrap()
+            .write(&[0xcc; 2048])
+            .unwrap();
+        let block = unsafe { (*fs.get_inode(old).unwrap().get_data()).direct_block_pointers[0] };
+        forget(&fs, old);
+
+        let new = fs.create_file(b"/new", Permission::all(), 0, 0).unwrap();
+        let new = fs.get_inode(new).unwrap();
+        new.as_file().unwrap().write(b"new").unwrap();
+        assert_eq!(unsafe { (*new.get_data()).direct_block_pointers[0] }, block);
+        let raw = unsafe { core::slice::from_raw_parts(fs.get_block(block), 1024) };
+        assert_eq!(&raw[..3], b"new");
+        assert!(raw[3..].iter().all(|&b| b == 0));
+
+        // A directory growing in a reused block must not see the old entries
+        forget(&fs, new.inode_ref());
+        let dir = fs.create_dir(b"/dir", Permission::all(), 0, 0).unwrap();
+        assert_eq!(
+            unsafe { (*fs.get_inode(dir).unwrap().get_data()).direct_block_pointers[0] },
+            block
+        );
+        assert_eq!(fs.get_inode(dir).unwrap().as_dir().unwrap().len(), 0);
+    }
+
+    #[test]
+    fn generation() {
+        let mut image = load_image("test_fs_back");
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.open();
+
+        let first = fs.create_file(b"/first", Permission::all(), 0, 0).unwrap();
+        let mut generation = fs.get_inode(first).unwrap().generation();
+        for name in [
+            &b"/other/second"[..],
+            b"/thing/third",
+            b"/thing/more/fourth",
+        ] {
+            forget(&fs, first);
+            let inode = fs.create_file(name, Permission::all(), 0, 0).unwrap();
+            assert_eq!(inode, first);
+            assert!(fs.get_inode(inode).unwrap().generation() > generation);
+            generation = fs.get_inode(inode).unwrap().generation();
+        }
+    }
+
+    #[test]
+    fn reserved_inodes() {
+        let mut image = load_image("test_fs_back");
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.open();
+        assert_eq!(
+            { fs.get_extended_superblock().first_non_reserved_inode },
+            11
+        );
+
+        // Mark the inodes 1 to 10 as free, only 11 to 17 are used
+        let bitmap = fs.get_block_group_descriptor_table()[0].block_address_of_inode_bitmap;
+        unsafe {
+            let bitmap = fs.get_block(bitmap);
+            *bitmap = 0;
+            *bitmap.add(1) &= 0b1111_1100;
+        }
+        assert_eq!(fs.reserve_inode(0), Some(InodeRef(18)));
+        let inode = fs.create_file(b"/file", Permission::all(), 0, 0).unwrap();
+        assert_eq!(inode, InodeRef(19));
+    }
+
+    #[test]
+    fn bitmap_length() {
+        let mut image = load_image("test_fs_back");
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.open();
+
+        // A 4KiB bitmap where only the bits after the first 1024 bytes are free
+        let mut bitmap = std::vec![255; 4096];
+        bitmap[1024..].fill(0);
+        assert_eq!(fs.reserve_bitmap(bitmap.as_mut_ptr(), 0, 32768), Some(8192));
+        assert_eq!(fs.reserve_bitmap(bitmap.as_mut_ptr(), 0, 32768), Some(8193));
+        // Only 8195 bits are meaningful
+        assert_eq!(fs.reserve_bitmap(bitmap.as_mut_ptr(), 0, 8195), Some(8194));
+        assert_eq!(fs.reserve_bitmap(bitmap.as_mut_ptr(), 0, 8195), None);
+        assert_eq!(bitmap[1024], 0b0000_0111);
+    }
+
+    /// The byte at a time scan find_free_bit replaced
+    fn find_free_bit_bytewise(bitmap: &[u8], first: u32, len: u32) -> Option<u32> {
+        let byte_len = len.div_ceil(8);
+        let mut index = first / 8;
+        let mut skipped = ((1u16 << (first % 8)) - 1) as u8;
+        while index < byte_len {
+            let mut byte = bitmap[index as usize] | skipped;
+            skipped = 0;
+            if index == byte_len - 1 && !len.is_multiple_of(8) {
+                byte |= !((1u8 << (len % 8)) - 1);
+            }
+            if byte != 255 {
+                return Some(index * 8 + (!byte).trailing_zeros());
+            }
+            index += 1;
+        }
+        None
+    }
+
+    #[test]
+    fn word_bitmap_scan() {
+        // xorshift, good enough to make bitmaps
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let mut random = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        for _ in 0..2000 {
+            // Mostly full bitmaps, so the free bits are far apart
+            let density = random() % 4;
+            let bitmap: std::vec::Vec<u8> = (0..128)
+                .map(|_| (0..density).fold(random() as u8, |byte, _| byte | random() as u8))
+                .collect();
+            let len = (random() % 1025) as u32;
+            let first = (random() % (len as u64 + 2)) as u32;
+            // Only the bytes covering len are given, to catch reads past the bitmap
+            let bitmap = &bitmap[..len.div_ceil(8) as usize];
+            assert_eq!(
+                super::find_free_bit(bitmap.as_ptr(), first, len),
+                find_free_bit_bytewise(bitmap, first, len),
+                "{:?} from {} in {} bits",
+                bitmap,
+                first,
+                len
+            );
+        }
+    }
+
+    /// The free extents of group, bit by bit
+    fn free_extents_bitwise(fs: &FileSystem<'_>, group: u32) -> std::vec::Vec<(u32, u32)> {
+        let bitmap =
+            fs.get_block_group_descriptor_table()[group as usize].block_address_of_block_bitmap;
+        let bitmap = unsafe { fs.get_block(bitmap) };
+        let mut extents: std::vec::Vec<(u32, u32)> = std::vec::Vec::new();
+        for index in 0..fs.block_count_of_group(group) {
+            if unsafe { *bitmap.add(index as usize / 8) } & (1 << (index % 8)) != 0 {
+                continue;
+            }
+            let block = fs.first_block_of_group(group) + index;
+            match extents.last_mut() {
+                Some((start, len)) if *start + *len == block => *len += 1,
+                _ => extents.push((block, 1)),
+            }
+        }
+        extents
+    }
+
+    #[test]
+    fn free_extents() {
+        let mut image = load_image("test_fs_groups");
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.open();
+        assert_eq!(
+            fs.free_extents(0).collect::<std::vec::Vec<_>>(),
+            [(95, 162)]
+        );
+        for group in 0..3 {
+            assert_eq!(
+                fs.free_extents(group).collect::<std::vec::Vec<_>>(),
+                free_extents_bitwise(&fs, group)
+            );
+        }
+
+        // The blocks past the end of the last group are not free extents
+        let bitmap = fs.get_block_group_descriptor_table()[2].block_address_of_block_bitmap;
+        unsafe { fs.get_block(bitmap).write_bytes(0, 1024) };
+        assert_eq!(
+            fs.free_extents(2).collect::<std::vec::Vec<_>>(),
+            [(513, 87)]
+        );
+
+        let mut state = 0x9e37_79b9_7f4a_7c15u64;
+        for _ in 0..50 {
+            for byte in 0..1024 {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                // Long runs of used and free blocks
+                let value = if state % 8 < 3 {
+                    state as u8
+                } else {
+                    (state % 2 * 255) as u8
+                };
+                unsafe { *fs.get_block(bitmap).add(byte) = value };
+            }
+            assert_eq!(
+                fs.free_extents(2).collect::<std::vec::Vec<_>>(),
+                free_extents_bitwise(&fs, 2)
+            );
+        }
+    }
+
+    #[test]
+    fn allocated() {
+        let mut image = load_image("test_fs_groups");
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.open();
+
+        // Block 0 is before the first group on 1KiB images
+        assert_eq!(fs.is_block_allocated(0), Err(Error::InvalidArgument));
+        assert_eq!(fs.is_block_allocated(1), Ok(true));
+        assert_eq!(fs.is_block_allocated(94), Ok(true));
+        assert_eq!(fs.is_block_allocated(95), Ok(false));
+        assert_eq!(fs.is_block_allocated(337), Ok(false));
+        // The last group is short
+        assert_eq!(fs.is_block_allocated(599), Ok(false));
+        assert_eq!(fs.is_block_allocated(600), Err(Error::InvalidArgument));
+
+        let block = fs.reserve_block(599, true).unwrap();
+        assert_eq!(block, 599);
+        assert_eq!(fs.is_block_allocated(599), Ok(true));
+        fs.release_block(599).unwrap();
+        assert_eq!(fs.is_block_allocated(599), Ok(false));
+
+        assert_eq!(
+            fs.is_inode_allocated(InodeRef(0)),
+            Err(Error::InvalidArgument)
+        );
+        assert_eq!(fs.is_inode_allocated(InodeRef(1)), Ok(true));
+        assert_eq!(fs.is_inode_allocated(InodeRef(11)), Ok(true));
+        assert_eq!(fs.is_inode_allocated(InodeRef(12)), Ok(false));
+        assert_eq!(fs.is_inode_allocated(InodeRef(48)), Ok(false));
+        assert_eq!(
+            fs.is_inode_allocated(InodeRef(49)),
+            Err(Error::InvalidArgument)
+        );
+        let inode = fs.create_dir(b"/dir", Permission::all(), 0, 0).unwrap();
+        assert_eq!(fs.is_inode_allocated(inode), Ok(true));
+        assert!(fs.group_of_inode(inode) > 0);
+    }
+
+    #[test]
+    fn group_statistics() {
+        let mut image = load_image("test_fs_groups");
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.open();
+
+        let statistics = fs.group_statistics(1).unwrap();
+        assert_eq!(statistics.first_block, 257);
+        assert_eq!(statistics.block_count, 256);
+        assert_eq!(statistics.first_inode, InodeRef(17));
+        assert_eq!(statistics.inode_count, 16);
+        assert_eq!(
+            (
+                statistics.block_bitmap,
+                statistics.inode_bitmap,
+                statistics.inode_table,
+                statistics.inode_table_blocks
+            ),
+            (333, 334, 335, 2)
+        );
+        assert_eq!(statistics.free_blocks, 176);
+        assert_eq!(statistics.counted_free_blocks, 176);
+        assert_eq!(
+            std::format!("{}", statistics),
+            "Group 1: (Blocks 257-512) (Inodes 17-32)\n  \
+             Block bitmap at 333, Inode bitmap at 334\n  \
+             Inode table at 335-336\n  \
+             176 free blocks, 16 free inodes, 0 directories"
+        );
+
+        // The last group is short
+        let statistics = fs.group_statistics(2).unwrap();
+        assert_eq!(statistics.block_count, 87);
+        assert_eq!(statistics.counted_free_blocks, recount_group(&fs, 2).0);
+
+        // A discrepancy between the descriptor and the bitmap is shown
+        fs.update_group_descriptor(0, |descriptor| descriptor.unallocated_inodes_in_group = 4);
+        let statistics = fs.group_statistics(0).unwrap();
+        assert_eq!(statistics.counted_free_inodes, 5);
+        assert!(std::format!("{}", statistics).ends_with(
+            "4 free inodes, 2 directories (162 free blocks, 5 free inodes in the bitmaps)"
+        ));
+    }
+
+    #[test]
+    fn statistics() {
+        let mut image = load_image("test_fs_groups");
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.open();
+        fs.update_superblock(|superblock| superblock.block_superuser = 100);
+
+        let statistics = fs.statistics(false);
+        assert_eq!(statistics.block_size, 1024);
+        assert_eq!(statistics.total_blocks, 600);
+        assert_eq!(statistics.total_inodes, 48);
+        assert_eq!(statistics.max_name_length, 255);
+        assert_eq!(statistics.available_blocks, statistics.free_blocks - 100);
+
+        // Only the recount sees what the superblock misses
+        fs.update_superblock(|superblock| superblock.unallocated_inodes -= 1);
+        assert_eq!(fs.statistics(true).free_inodes, statistics.free_inodes);
+        assert_eq!(fs.statistics(false).free_inodes, statistics.free_inodes - 1);
+    }
+
+    #[test]
+    fn reserve_contiguous() {
+        let mut image = load_image("test_fs_groups");
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.open();
+        let bitmap = fs.get_block_group_descriptor_table()[1].block_address_of_block_bitmap;
+        let bitmap = unsafe { fs.get_block(bitmap) };
+
+        // Free runs of 5 (from index 80) and 12 (from index 100) blocks in group 1
+        unsafe { bitmap.write_bytes(255, 256 / 8) };
+        for index in (80..85).chain(100..112) {
+            unsafe { *bitmap.add(index / 8) &= !(1 << (index % 8)) };
+        }
+        fs.update_superblock(|superblock| superblock.unallocated_blocks = 17 + 162 + 83);
+        fs.update_group_descriptor(1, |descriptor| descriptor.unallocated_blocks_in_group = 17);
+
+        assert_eq!(fs.reserve_contiguous(1, 13, true), Err(Error::NoFreeBlocks));
+        // Too long for the first run, straddles the bytes 12 and 13 of the bitmap
+        assert_eq!(fs.reserve_contiguous(1, 6, true), Ok(257 + 100));
+        assert_eq!(unsafe { *bitmap.add(12) }, 0b1111_1111);
+        assert_eq!(unsafe { *bitmap.add(13) }, 0b0000_0011);
+        assert_eq!(fs.reserve_contiguous(1, 5, true), Ok(257 + 80));
+        assert_eq!(fs.reserve_contiguous(1, 6, true), Ok(257 + 106));
+        assert_eq!(fs.reserve_contiguous(1, 1, true), Err(Error::NoFreeBlocks));
+        assert_eq!(fs.group_statistics(1).unwrap().free_blocks, 0);
+        check_group_counters(&fs);
+
+        assert_eq!(
+            fs.reserve_contiguous(2, 0, true),
+            Err(Error::InvalidArgument)
+        );
+        assert_eq!(
+            fs.reserve_contiguous(3, 1, true),
+            Err(Error::InvalidArgument)
+        );
+        assert_eq!(
+            fs.reserve_contiguous(2, 1 << 16, true),
+            Err(Error::InvalidArgument)
+        );
+        // The free blocks left are all reserved for the superuser
+        fs.update_superblock(|superblock| {
+            superblock.block_superuser = superblock.unallocated_blocks
+        });
+        assert_eq!(
+            fs.reserve_contiguous(2, 83, false),
+            Err(Error::NoFreeBlocks)
+        );
+        assert_eq!(fs.reserve_contiguous(2, 83, true), Ok(517));
+        assert_eq!(fs.statistics(false), recount(&fs));
+    }
+
+    #[test]
+    fn stale_free_counters() {
+        let mut image = load_image("test_fs_groups");
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.open();
+        // The bitmaps still have free bits, they are trusted over the counters
+        fs.update_superblock(|superblock| {
+            superblock.unallocated_blocks = 0;
+            superblock.unallocated_inodes = 0;
+        });
+        let file = fs.create_file(b"/stale", Permission::all(), 0, 0).unwrap();
+        let mut file = fs.get_inode(file).unwrap().as_file().unwrap();
+        file.write(&[0xaa; 3000]).unwrap();
+        drop(file);
+        assert!(fs.reserve_contiguous(2, 4, true).is_ok());
+        assert_eq!({ fs.get_superblock().unallocated_blocks }, 0);
+        assert_eq!({ fs.get_superblock().unallocated_inodes }, 0);
+
+        fs.sync().unwrap();
+        assert_eq!(fs.statistics(false), recount(&fs));
+    }
+
+    #[test]
+    fn stale_group_counters() {
+        let mut image = load_image("test_fs_groups");
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.open();
+        fs.update_group_descriptor(1, |descriptor| descriptor.unallocated_blocks_in_group = 0);
+        // The goal group is skipped like the others that count no free block
+        let block = fs.reserve_block(300, true).unwrap();
+        assert_ne!(fs.group_of_block(block), 1);
+        // Reserving from the bitmap does not underflow the counter
+        assert!(fs.reserve_contiguous(1, 4, true).is_ok());
+        assert_eq!(fs.group_statistics(1).unwrap().free_blocks, 0);
+    }
+
+    #[test]
+    fn ensure_lost_and_found() {
+        let mut image = formatted(400 * 1024);
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.open();
+        let lost_found = fs.ensure_lost_and_found().unwrap();
+        assert_eq!(lost_found, InodeRef(11));
+        let inode = fs.load_inode(lost_found);
+        assert_eq!(inode.size(), 12 * 1024);
+        assert_eq!(inode.blocks_used(), 12 * 2);
+        assert_eq!(inode.link_count(), 2);
+        assert_eq!(
+            inode.metadata().permissions,
+            Permission::from_bits_truncate(0o700)
+        );
+        // Empty records in a single run after the first block
+        let blocks: std::vec::Vec<_> = inode.mapped_blocks().map(|block| block.block()).collect();
+        assert_eq!(blocks.len(), 12);
+        assert!(blocks[1..].windows(2).all(|pair| pair[1] == pair[0] + 1));
+        let names: std::vec::Vec<_> = inode.get_dir_entries().unwrap().map(|e| e.name).collect();
+        assert_eq!(names, [&b"."[..], b".."]);
+        assert_eq!(fs.statistics(false), recount(&fs));
+
+        // Created again if the name is missing
+        fs.get_root().remove_entry(b"lost+found").unwrap();
+        let free_blocks = fs.statistics(false).free_blocks;
+        let lost_found = fs.ensure_lost_and_found().unwrap();
+        assert_ne!(lost_found, InodeRef(11));
+        assert_eq!(fs.load_inode(lost_found).size(), 12 * 1024);
+        assert_eq!(fs.statistics(false).free_blocks, free_blocks - 12);
+        assert_eq!(fs.lookup_path(b"/lost+found"), Ok(lost_found));
+
+        fs.get_root().remove_entry(b"lost+found").unwrap();
+        fs.create_file(b"/lost+found", Permission::all(), 0, 0)
+            .unwrap();
+        assert_eq!(fs.ensure_lost_and_found(), Err(Error::NotADirectory));
+    }
+
+    #[test]
+    fn allocated_inodes() {
+        let mut image = load_image("test_fs_back");
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.open();
+
+        let inodes: std::vec::Vec<_> = fs.allocated_inodes().collect();
+        assert_eq!(inodes, (1..=17).map(InodeRef).collect::<std::vec::Vec<_>>());
+        // Mostly the resize inode, as reported by debugfs
+        let total: u64 = inodes
+            .iter()
+            .map(|&inode| u64::from(fs.get_inode(inode).unwrap().size()))
+            .sum();
+        assert_eq!(total, 67_399_696);
+
+        // An inode that is not in the tree is still visited
+        fs.release_inode(InodeRef(14)).unwrap();
+        let orphan = fs.reserve_inode(0).unwrap();
+        assert_eq!(orphan, InodeRef(14));
+        assert_eq!(fs.allocated_inodes().count(), 17);
+
+        let mut image = load_image("test_fs_groups");
+        let mut device = unsafe { Ext2Device::from_ptr(image.as_mut_ptr()) };
+        let fs = device.open();
+        let dir = fs.create_dir(b"/dir", Permission::all(), 0, 0).unwrap();
+        assert_eq!(fs.allocated_inodes().last(), Some(dir));
+        // The bits past the inode count are ignored
+        let bitmap = fs.get_block_group_descriptor_table()[2].block_address_of_inode_bitmap;
+        unsafe { *fs.get_block(bitmap).add(2) = 255 };
+        assert_eq!(fs.allocated_inodes().last(), Some(dir));
+    }
+
+    #[test]
+    fn partial_group() {
+        let mut image = load_image("test_fs_4k");
+        let mut device = unsafe { Ext2Device::from_ptr(image.as_mut_ptr()) };
+        let fs = device.open();
+        assert_eq!({ fs.get_superblock().block_count }, 64);
+        assert_eq!({ fs.get_superblock().block_count_in_group }, 32768);
+
+        // Blocks past the end of the filesystem are not marked as used
+        let bitmap = fs.get_block_group_descriptor_table()[0].block_address_of_block_bitmap;
+        unsafe { core::ptr::write_bytes(fs.get_block(bitmap).add(8), 0, 4096 - 8) };
+
+        // Fill the 53 free blocks
+        for (name, blocks) in [
+            (&b"/a"[..], 12),
+            (b"/b", 12),
+            (b"/c", 12),
+            (b"/d", 12),
+            (b"/e", 5),
+        ] {
+            let file = fs.create_file(name, Permission::all(), 0, 0).unwrap();
+            fs.get_inode(file)
+                .unwrap()
+                .as_file()
+                .unwrap()
+                .write(&[1; 4096][..].repeat(blocks))
+                .unwrap();
+        }
+        assert_eq!(
+            fs.create_dir(b"/dir", Permission::all(), 0, 0),
+            Err(Error::NoFreeBlocks)
+        );
+
+        let mut content = std::vec![0; 4096 * 12];
+        let a = fs.get_inode(fs.lookup_path(b"/a").unwrap()).unwrap();
+        assert_eq!(a.as_file().unwrap().read(&mut content), 4096 * 12);
+        assert!(content.iter().all(|&b| b == 1));
+    }
+
+    #[test]
+    fn absolute_blocks() {
+        let mut image = load_image("test_fs_groups");
+        let mut device = unsafe { Ext2Device::from_ptr(image.as_mut_ptr()) };
+        let fs = device.open();
+        assert_eq!({ fs.get_superblock().block_count_in_group }, 256);
+
+        // The first free blocks of the groups 1 and 2
+        let block = fs.reserve_block(fs.first_block_of_group(1), true).unwrap();
+        assert_eq!(block, 337);
+        assert_eq!(
+            fs.reserve_block(fs.first_block_of_group(2), true),
+            Some(517)
+        );
+        unsafe { fs.get_block(block).write_bytes(0xaa, 1024) };
+        assert!(image[337 * 1024..338 * 1024].iter().all(|&b| b == 0xaa));
+        assert!(image[336 * 1024..337 * 1024].iter().all(|&b| b != 0xaa));
+
+        let mut image = load_image("test_fs_back");
+        let mut device = unsafe { Ext2Device::from_ptr(image.as_mut_ptr()) };
+        let fs = device.open();
+        let file = fs.create_file(b"/file", Permission::all(), 0, 0).unwrap();
+        let file = fs.get_inode(file).unwrap();
+        file.as_file().unwrap().write(&[0xbb; 1024]).unwrap();
+        let block = unsafe { (*file.get_data()).direct_block_pointers[0] } as usize;
+        assert!(image[block * 1024..(block + 1) * 1024]
+            .iter()
+            .all(|&b| b == 0xbb));
+        // The bit of the block in the bitmap, the bitmap starts at block 1
+        let bitmap = 4 * 1024;
+        assert_ne!(
+            image[bitmap + (block - 1) / 8] & (1 << ((block - 1) % 8)),
+            0
+        );
+    }
+
+    #[test]
+    fn inode_in_second_group() {
+        let mut image = load_image("test_fs_groups");
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.open();
+        assert_eq!({ fs.get_superblock().inode_count_in_group }, 16);
+
+        // Fill the inodes of group 0
+        let bitmap = fs.get_block_group_descriptor_table()[0].block_address_of_inode_bitmap;
+        unsafe { fs.get_block(bitmap).write_bytes(255, 2) };
+
+        let inode = fs.create_file(b"/file", Permission::all(), 0, 0).unwrap();
+        assert_eq!(inode, InodeRef(17));
+        assert_eq!(fs.group_of_inode(inode), 1);
+        // The first slot of the inode table of group 1
+        let table = fs.get_block_group_descriptor_table()[1].starting_block_of_inode_table;
+        assert_eq!(table, 335);
+        assert_eq!(
+            fs.get_inode(inode).unwrap().get_data() as *const u8,
+            unsafe { fs.get_block(table) } as *const u8
+        );
+        let bitmap = fs.get_block_group_descriptor_table()[1].block_address_of_inode_bitmap;
+        assert_eq!(unsafe { *fs.get_block(bitmap) }, 1);
+
+        let mut file = fs.get_inode(inode).unwrap().as_file().unwrap();
+        file.write(b"in group 1").unwrap();
+        drop(file);
+        assert_eq!(
+            fs.get_inode(fs.lookup_path(b"/file").unwrap())
+                .unwrap()
+                .size(),
+            10
+        );
+    }
+
+    /// Count the free blocks and inodes in the bitmap of group
+    fn recount_group(fs: &FileSystem<'_>, group: usize) -> (u32, u32) {
+        let count_free = |bitmap: u32, len: u32| {
+            let bitmap = unsafe { fs.get_block(bitmap) };
+            (0..len)
+                .filter(|i| unsafe { *bitmap.add(*i as usize / 8) } & (1 << (i % 8)) == 0)
+                .count() as u32
+        };
+        let descriptor = &fs.get_block_group_descriptor_table()[group];
+        (
+            count_free(
+                descriptor.block_address_of_block_bitmap,
+                fs.block_count_of_group(group as u32),
+            ),
+            count_free(
+                descriptor.block_address_of_inode_bitmap,
+                fs.get_superblock().inode_count_in_group,
+            ),
+        )
+    }
+
+    /// Count the free blocks and inodes in the bitmaps
+    fn recount(fs: &FileSystem<'_>) -> Statistics {
+        let mut statistics = Statistics {
+            free_blocks: 0,
+            free_inodes: 0,
+            ..fs.statistics(false)
+        };
+        for group in 0..fs.get_block_group_descriptor_table().len() {
+            let (free_blocks, free_inodes) = recount_group(fs, group);
+            statistics.free_blocks += free_blocks;
+            statistics.free_inodes += free_inodes;
+        }
+        statistics.available_blocks = statistics
+            .free_blocks
+            .saturating_sub(fs.get_superblock().block_superuser);
+        statistics
+    }
+
+    /// Check that the group descriptors agree with the bitmaps
+    pub(crate) fn check_group_counters(fs: &FileSystem<'_>) {
+        for (group, descriptor) in fs.get_block_group_descriptor_table().iter().enumerate() {
+            let (free_blocks, free_inodes) = recount_group(fs, group);
+            assert_eq!(
+                u32::from(descriptor.unallocated_blocks_in_group),
+                free_blocks,
+                "free blocks of group {}",
+                group
+            );
+            assert_eq!(
+                u32::from(descriptor.unallocated_inodes_in_group),
+                free_inodes,
+                "free inodes of group {}",
+                group
+            );
+        }
+    }
+
+    #[test]
+    fn group_counters() {
+        let mut image = load_image("test_fs_groups");
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.open();
+        check_group_counters(&fs);
+
+        let mut files = std::vec::Vec::new();
+        for i in 0..8u8 {
+            let name = [b'/', b'a' + i];
+            let file = fs.create_file(&name, Permission::all(), 0, 0).unwrap();
+            fs.get_inode(file)
+                .unwrap()
+                .as_file()
+                .unwrap()
+                .write(&[i; 3000])
+                .unwrap();
+            files.push(file);
+        }
+        // Group 0 only has 5 free inodes, the others went to group 1
+        assert_eq!(fs.group_of_inode(files[7]), 1);
+        check_group_counters(&fs);
+
+        for &file in files.iter().step_by(2) {
+            fs.get_inode(file).unwrap().truncate(0).unwrap();
+            forget(&fs, file);
+        }
+        check_group_counters(&fs);
+        fs.create_dir(b"/dir", Permission::all(), 0, 0).unwrap();
+        check_group_counters(&fs);
+    }
+
+    #[test]
+    fn counters() {
+        for name in ["test_fs_back", "test_fs_groups", "test_fs_4k"] {
+            let mut image = load_image(name);
+            let mut device = Ext2Device::from_slice(&mut image).unwrap();
+            let fs = device.open();
+            let initial = fs.statistics(false);
+            assert_eq!(initial, recount(&fs));
+            assert_eq!(fs.statistics(true), recount(&fs));
+
+            fs.create_dir(b"/dir", Permission::all(), 0, 0).unwrap();
+            let file = fs
+                .create_file(b"/dir/file", Permission::all(), 0, 0)
+                .unwrap();
+            fs.get_inode(file)
+                .unwrap()
+                .as_file()
+                .unwrap()
+                .write(&[1; 5000])
+                .unwrap();
+            assert_eq!(fs.statistics(false), recount(&fs));
+            assert_eq!(fs.statistics(true), recount(&fs));
+            assert_eq!(fs.statistics(false).free_inodes, initial.free_inodes - 2);
+
+            fs.unlink(b"/dir/file").unwrap();
+            fs.rmdir(b"/dir").unwrap();
+            assert_eq!(fs.statistics(false), recount(&fs));
+            assert_eq!(fs.statistics(false), initial);
+        }
+    }
+
+    #[test]
+    fn directory_count() {
+        let mut image = load_image("test_fs_groups");
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let mut fs = device.open();
+        fs.set_group_policy(GroupPolicy::SameGroup);
+        // The root and lost+found
+        assert_eq!(fs.group_statistics(0).unwrap().directories, 2);
+        assert_eq!(fs.group_statistics(1).unwrap().directories, 0);
+        assert_eq!(fs.group_statistics(3), None);
+
+        // Leave only 2 free inodes in group 0
+        for name in [&b"/a"[..], b"/b", b"/c"] {
+            fs.create_file(name, Permission::all(), 0, 0).unwrap();
+        }
+        let mut dirs = std::vec::Vec::new();
+        for name in [&b"/d1"[..], b"/d2", b"/d3", b"/d4", b"/d5"] {
+            dirs.push(fs.create_dir(name, Permission::all(), 0, 0).unwrap());
+        }
+        assert_eq!(fs.group_statistics(0).unwrap().directories, 4);
+        assert_eq!(fs.group_statistics(1).unwrap().directories, 3);
+
+        assert_eq!(fs.group_of_inode(dirs[4]), 1);
+        fs.rmdir(b"/d5").unwrap();
+        assert_eq!(fs.group_statistics(1).unwrap().directories, 2);
+    }
+
+    #[test]
+    fn rmdir() {
+        let mut image = load_image("test_fs_back");
+        let mut device = unsafe { Ext2Device::from_ptr(image.as_mut_ptr()) };
+        let fs = device.open();
+        let initial = fs.statistics(false);
+        let links = fs.get_root().link_count();
+
+        fs.create_dir(b"/dir", Permission::all(), 0, 0).unwrap();
+        assert_eq!(fs.get_root().link_count(), links + 1);
+        assert_eq!(fs.group_statistics(0).unwrap().directories, 6);
+        fs.rmdir(b"/dir/").unwrap();
+        assert_eq!(fs.lookup_path(b"/dir"), Err(Error::NotFound));
+        assert_eq!(fs.get_root().link_count(), links);
+        assert_eq!(fs.statistics(false), initial);
+        assert_eq!(fs.group_statistics(0).unwrap().directories, 5);
+
+        assert_eq!(fs.rmdir(b"/thing"), Err(Error::DirectoryNotEmpty));
+        assert_eq!(fs.rmdir(b"/foo.txt"), Err(Error::NotADirectory));
+        assert_eq!(fs.rmdir(b"/nope"), Err(Error::NotFound));
+        assert_eq!(fs.rmdir(b"/"), Err(Error::InvalidArgument));
+        assert_eq!(fs.rmdir(b"/thing/.."), Err(Error::InvalidArgument));
+        check_group_counters(&fs);
+    }
+
+    #[test]
+    fn spread_directories() {
+        let mut image = load_image("test_fs_groups");
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.open();
+
+        // Directories go to the emptiest groups, group 1 has the most free blocks
+        let groups: std::vec::Vec<_> = [&b"/d1"[..], b"/d2", b"/d3", b"/d4"]
+            .iter()
+            .map(|name| fs.group_of_inode(fs.create_dir(name, Permission::all(), 0, 0).unwrap()))
+            .collect();
+        assert_eq!(groups, [1, 2, 1, 2]);
+        // Files stay with their parent
+        let file = fs
+            .create_file(b"/d2/file", Permission::all(), 0, 0)
+            .unwrap();
+        assert_eq!(fs.group_of_inode(file), 2);
+        let file = fs.create_file(b"/file", Permission::all(), 0, 0).unwrap();
+        assert_eq!(fs.group_of_inode(file), 0);
+
+        // Unless the group of the parent has no free blocks
+        fs.update_group_descriptor(0, |descriptor| descriptor.unallocated_blocks_in_group = 0);
+        let file = fs.create_file(b"/full", Permission::all(), 0, 0).unwrap();
+        assert_eq!(fs.group_of_inode(file), 1);
+    }
+
+    #[test]
+    fn custom_group_policy() {
+        let mut image = load_image("test_fs_groups");
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let mut fs = device.open();
+
+        extern "C" fn last_group(fs: &FileSystem<'_>, _: u32, _: EntryKind) -> u32 {
+            fs.get_block_group_descriptor_table().len() as u32 - 1
+        }
+        fs.set_group_policy(GroupPolicy::Custom(last_group));
+        let file = fs.create_file(b"/file", Permission::all(), 0, 0).unwrap();
+        assert_eq!(fs.group_of_inode(file), 2);
+
+        fs.set_group_policy(GroupPolicy::SameGroup);
+        let dir = fs.create_dir(b"/dir", Permission::all(), 0, 0).unwrap();
+        assert_eq!(fs.group_of_inode(dir), 0);
+    }
+
+    #[test]
+    fn release_block() {
+        let mut image = load_image("test_fs_groups");
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.open();
+        let initial = fs.statistics(false);
+
+        for group in 0..3 {
+            let block = fs
+                .reserve_block(fs.first_block_of_group(group), true)
+                .unwrap();
+            assert_eq!(fs.statistics(false).free_blocks, initial.free_blocks - 1);
+            fs.release_block(block).unwrap();
+            assert_eq!(fs.statistics(false), initial);
+            assert_eq!(
+                fs.reserve_block(fs.first_block_of_group(group), true),
+                Some(block)
+            );
+            fs.release_block(block).unwrap();
+        }
+        check_group_counters(&fs);
+    }
+
+    #[test]
+    fn double_release() {
+        let mut image = load_image("test_fs_groups");
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.open();
+        let block = fs.reserve_block(fs.first_block_of_group(1), true).unwrap();
+        fs.release_block(block).unwrap();
+        let statistics = fs.statistics(false);
+        assert_eq!(
+            fs.release_block(block),
+            Err(Error::Corrupt("block released twice"))
+        );
+        assert_eq!(fs.statistics(false), statistics);
+        check_group_counters(&fs);
+    }
+
+    #[test]
+    fn unlink() {
+        let mut image = load_image("test_fs_back");
+        let mut device = unsafe { Ext2Device::from_ptr(image.as_mut_ptr()) };
+        let mut fs = device.open();
+        extern "C" fn clock() -> u32 {
+            1_000_000
+        }
+        fs.set_clock(clock);
+        let initial = fs.statistics(false);
+
+        let file = fs.create_file(b"/file", Permission::all(), 0, 0).unwrap();
+        fs.get_inode(file)
+            .unwrap()
+            .as_file()
+            .unwrap()
+            .write(&[1; 3000])
+            .unwrap();
+        fs.unlink(b"/file").unwrap();
+        assert_eq!(fs.lookup_path(b"/file"), Err(Error::NotFound));
+        assert_eq!(fs.statistics(false), initial);
+        assert_eq!(
+            unsafe { (*fs.get_inode(file).unwrap().get_data()).deletion_time },
+            1_000_000
+        );
+        assert_eq!(fs.reserve_inode(0), Some(file));
+
+        // Entries that are not the first of their block are merged in the previous one
+        fs.unlink(b"/foo.txt").unwrap();
+        fs.unlink(b"/other/niche.txt").unwrap();
+        fs.unlink(b"/thing/more/never.txt").unwrap();
+        let names: std::vec::Vec<_> = fs
+            .get_root()
+            .get_dir_entries()
+            .unwrap()
+            .map(|entry| entry.name.as_bytes().to_vec())
+            .collect();
+        assert_eq!(names, [&b"."[..], b"..", b"lost+found", b"thing", b"other"]);
+        assert!(fs
+            .get_inode(fs.lookup_path(b"/thing/more").unwrap())
+            .unwrap()
+            .as_dir()
+            .unwrap()
+            .is_empty());
+
+        assert_eq!(fs.unlink(b"/thing"), Err(Error::IsADirectory));
+        assert_eq!(fs.unlink(b"/nope"), Err(Error::NotFound));
+        assert_eq!(fs.release_inode(InodeRef(2)), Err(Error::InvalidArgument));
+        assert_eq!(fs.release_inode(InodeRef(10)), Err(Error::InvalidArgument));
+        check_group_counters(&fs);
+    }
+
+    #[test]
+    fn release_free_inode() {
+        let mut image = load_image("test_fs_groups");
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.open();
+        let dir = fs.create_dir(b"/gone", Permission::all(), 0, 0).unwrap();
+        fs.rmdir(b"/gone").unwrap();
+        let statistics = fs.group_statistics(fs.group_of_inode(dir)).unwrap();
+        assert_eq!(fs.release_inode(dir), Err(Error::InvalidArgument));
+        // The inode is still a directory, an already free bit does not change the counters
+        fs.release_inode_bit(dir);
+        assert_eq!(
+            fs.group_statistics(fs.group_of_inode(dir)).unwrap(),
+            statistics
+        );
+        check_group_counters(&fs);
+    }
+
+    #[test]
+    #[cfg(feature = "alloc")]
+    fn unlink_indirect() {
+        let mut image = load_image("test_fs_indirect");
+        let mut device = unsafe { Ext2Device::from_ptr(image.as_mut_ptr()) };
+        let fs = device.open();
+        let initial = fs.statistics(false);
+
+        // The 300 blocks of the content and the 3 indirect ones
+        fs.unlink(b"/big").unwrap();
+        let counts = crate::check::check_all(&fs, &mut |finding| panic!("{}", finding));
+        assert_eq!(counts.total(), 0);
+        let statistics = fs.statistics(false);
+        assert_eq!(statistics, fs.statistics(true));
+        assert_eq!(
+            (statistics.free_blocks, statistics.free_inodes),
+            (initial.free_blocks + 303, initial.free_inodes + 1)
+        );
+        check_group_counters(&fs);
+    }
+
+    #[test]
+    fn copy_file() {
+        let mut image = load_image("test_fs_large_inodes");
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let mut fs = device.open();
+        extern "C" fn clock() -> u32 {
+            1_000_000
+        }
+        fs.set_clock(clock);
+
+        // Blocks 1 and 2 are holes
+        let perms = Permission::USER_READ | Permission::USER_WRITE | Permission::GROUP_READ;
+        let sparse = fs.create_file(b"/sparse", perms, 12, 34).unwrap();
+        {
+            let inode = fs.get_inode(sparse).unwrap();
+            Cursor::at(&inode, 0).write(&[b'a'; 1024]).unwrap();
+            Cursor::at(&inode, 3 * 1024).write(b"end").unwrap();
+            inode.set_size(3 * 1024 + 3);
+        }
+        extern "C" fn later() -> u32 {
+            2_000_000
+        }
+        fs.set_clock(later);
+
+        let root = fs.get_root();
+        let copy = fs
+            .copy_file(
+                sparse,
+                &root,
+                b"copy",
+                Permission::all() - Permission::GROUP_READ,
+            )
+            .unwrap();
+        assert_eq!(fs.lookup_path(b"/copy"), Ok(copy));
+        let copy = fs.get_inode(copy).unwrap();
+        let pointers = unsafe { (*copy.get_data()).direct_block_pointers };
+        assert!(pointers[0] != 0 && pointers[3] != 0);
+        assert_eq!(pointers[1..3], [0, 0]);
+        assert_eq!(copy.blocks_used(), 4);
+        let mut content = std::vec::Vec::new();
+        copy.read_all(|data| {
+            content.extend_from_slice(data);
+            Ok::<_, ()>(())
+        })
+        .unwrap();
+        let mut expected = [b'a'; 1024].to_vec();
+        expected.extend_from_slice(&[0; 2048]);
+        expected.extend_from_slice(b"end");
+        assert!(content == expected);
+
+        let source = fs.get_inode(sparse).unwrap().metadata();
+        let copied = copy.metadata();
+        assert_eq!(
+            copied.permissions,
+            Permission::USER_READ | Permission::USER_WRITE
+        );
+        assert_eq!((copied.user_id, copied.group_id), (12, 34));
+        assert_eq!(copied.modified, source.modified);
+        assert_eq!(copied.accessed, source.accessed);
+        assert_eq!(copied.changed, source.changed);
+        assert_eq!(copied.modified.seconds, 1_000_000);
+
+        assert_eq!(
+            fs.copy_file(sparse, &root, b"copy", Permission::all()),
+            Err(Error::AlreadyExists)
+        );
+        assert_eq!(
+            fs.copy_file(root_inode(), &root, b"dir", Permission::all()),
+            Err(Error::IsADirectory)
+        );
+        check_group_counters(&fs);
+    }
+
+    #[test]
+    fn truncate_indirect() {
+        let mut image = load_image("test_fs_indirect");
+        let mut device = unsafe { Ext2Device::from_ptr(image.as_mut_ptr()) };
+        let fs = device.open();
+        let big = fs.get_inode(fs.lookup_path(b"/big").unwrap()).unwrap();
+        let free = fs.statistics(false).free_blocks;
+
+        // The doubly indirect block and the indirect one below it are released with the blocks
+        big.truncate(13 * 1024).unwrap();
+        let expected: std::vec::Vec<_> = (54..66)
+            .map(MappedBlock::Data)
+            .chain(Some(MappedBlock::Indirect {
+                block: 66,
+                level: 1,
+            }))
+            .chain(Some(MappedBlock::Data(67)))
+            .collect();
+        assert_eq!(big.mapped_blocks().collect::<std::vec::Vec<_>>(), expected);
+        assert_eq!(big.blocks_used(), 14 * 2);
+        assert_eq!(fs.statistics(false).free_blocks, free + 287 + 2);
+
+        big.truncate(5 * 1024).unwrap();
+        let expected: std::vec::Vec<_> = (54..59).map(MappedBlock::Data).collect();
+        assert_eq!(big.mapped_blocks().collect::<std::vec::Vec<_>>(), expected);
+        assert_eq!(big.blocks_used(), 5 * 2);
+        assert_eq!(fs.statistics(false).free_blocks, free + 289 + 8 + 1);
+        check_group_counters(&fs);
+    }
+
+    #[test]
+    fn copy_file_no_space() {
+        let mut image = load_image("test_fs_tiny");
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.open();
+
+        // 43 free blocks, the second copy only finds 7 of its 12
+        let file = fs.create_file(b"/a", Permission::all(), 0, 0).unwrap();
+        fs.get_inode(file)
+            .unwrap()
+            .as_file()
+            .unwrap()
+            .write(&[1; 1024][..].repeat(12))
+            .unwrap();
+        fs.copy_file(file, &fs.get_root(), b"b", Permission::all())
+            .unwrap();
+        fs.copy_file(file, &fs.get_root(), b"c", Permission::all())
+            .unwrap();
+        let before = fs.statistics(false);
+        assert_eq!(
+            fs.copy_file(file, &fs.get_root(), b"d", Permission::all()),
+            Err(Error::NoFreeBlocks)
+        );
+        assert_eq!(fs.lookup_path(b"/d"), Err(Error::NotFound));
+        assert_eq!(fs.statistics(false), before);
+        check_group_counters(&fs);
+    }
+
+    #[test]
+    fn copy_range() {
+        let mut image = formatted(400 * 1024);
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.open();
+        let content = |inode: &super::Inode<'_, '_>| {
+            let mut content = std::vec::Vec::new();
+            inode
+                .read_all(|data| {
+                    content.extend_from_slice(data);
+                    Ok::<_, ()>(())
+                })
+                .unwrap();
+            content
+        };
+
+        // Five blocks, the third is a hole
+        let src = fs.create_file(b"/src", Permission::all(), 0, 0).unwrap();
+        let src = fs.get_inode(src).unwrap();
+        let data: std::vec::Vec<u8> = (0..5 * 1024).map(|i| (i % 251) as u8 + 1).collect();
+        Cursor::at(&src, 0).write(&data[..2048]).unwrap();
+        Cursor::at(&src, 3 * 1024).write(&data[3 * 1024..]).unwrap();
+        src.set_size(5 * 1024);
+        let mut expected = data.clone();
+        expected[2048..3072].fill(0);
+        assert!(content(&src) == expected);
+
+        let dst = fs.create_file(b"/dst", Permission::all(), 0, 0).unwrap();
+        let dst = fs.get_inode(dst).unwrap();
+        assert_eq!(fs.copy_range(&src, 0, &dst, 0, u32::MAX), Ok(5 * 1024));
+        assert!(content(&dst) == expected);
+        let pointers = unsafe { (*dst.get_data()).direct_block_pointers };
+        assert_eq!(pointers[2], 0);
+        assert_eq!(dst.blocks_used(), 8);
+
+        // Ragged edges, over existing blocks and past the end
+        assert_eq!(fs.copy_range(&src, 100, &dst, 4000, 3000), Ok(3000));
+        expected[4000..].copy_from_slice(&data[100..100 + 1120]);
+        expected.extend_from_slice(&data[1220..3100]);
+        expected[4000 + 1948..4000 + 2972].fill(0);
+        assert_eq!(dst.size(), 7000);
+        assert!(content(&dst) == expected);
+
+        // The truncated bytes of the last block don't come back in the gap
+        dst.truncate(6200).unwrap();
+        assert_eq!(fs.copy_range(&src, 0, &dst, 9000, 10), Ok(10));
+        expected.truncate(6200);
+        expected.resize(9000, 0);
+        expected.extend_from_slice(&data[..10]);
+        assert!(content(&dst) == expected);
+
+        // The same inode, without overlap
+        assert_eq!(fs.copy_range(&dst, 0, &dst, 9010, 1000), Ok(1000));
+        assert_eq!(
+            fs.copy_range(&dst, 0, &dst, 500, 1000),
+            Err(Error::InvalidArgument)
+        );
+        assert_eq!(
+            fs.copy_range(&dst, 1000, &dst, 500, 1000),
+            Err(Error::InvalidArgument)
+        );
+        assert_eq!(fs.copy_range(&src, 6000, &dst, 0, 10), Ok(0));
+        assert_eq!(
+            fs.copy_range(&src, 0, &fs.get_root(), 0, 10),
+            Err(Error::IsADirectory)
+        );
+        check_group_counters(&fs);
+    }
+
+    #[test]
+    fn allocate() {
+        let mut image = formatted(400 * 1024);
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.open();
+
+        let file = fs.create_file(b"/file", Permission::all(), 0, 0).unwrap();
+        let inode = fs.get_inode(file).unwrap();
+        inode.as_file().unwrap().write(&[1; 100]).unwrap();
+        let first = unsafe { (*inode.get_data()).direct_block_pointers[0] };
+        let free = fs.statistics(false).free_blocks;
+
+        inode.allocate(50, 5000, true).unwrap();
+        assert_eq!(inode.size(), 100);
+        assert_eq!(inode.blocks_used(), 10);
+        assert_eq!(fs.statistics(false).free_blocks, free - 4);
+        let pointers = unsafe { (*inode.get_data()).direct_block_pointers };
+        assert_eq!(pointers[0], first);
+        assert!(pointers[1..5].windows(2).all(|w| w[1] == w[0] + 1));
+        assert_eq!(pointers[5], 0);
+
+        inode.allocate(6000, 10, false).unwrap();
+        assert_eq!(inode.size(), 6010);
+        assert_eq!(inode.blocks_used(), 12);
+        let mut content = std::vec::Vec::new();
+        inode
+            .read_all(|data| {
+                content.extend_from_slice(data);
+                Ok::<_, ()>(())
+            })
+            .unwrap();
+        assert!(content[..100].iter().all(|&byte| byte == 1));
+        assert!(content[100..].iter().all(|&byte| byte == 0));
+
+        // Nothing is allocated when the range does not fit
+        assert_eq!(
+            inode.allocate(0, 13 * 1024, false),
+            Err(Error::FileTooLarge)
+        );
+        assert_eq!(inode.allocate(u32::MAX, 2, false), Err(Error::FileTooLarge));
+        assert_eq!(
+            fs.get_root().allocate(0, 10, false),
+            Err(Error::IsADirectory)
+        );
+        assert_eq!(inode.blocks_used(), 12);
+        check_group_counters(&fs);
+    }
+
+    #[test]
+    fn allocate_no_space() {
+        let mut image = load_image("test_fs_tiny");
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.open();
+
+        // Leave 7 of the 43 free blocks
+        for name in [&b"/a"[..], b"/b", b"/c"] {
+            let file = fs.create_file(name, Permission::all(), 0, 0).unwrap();
+            fs.get_inode(file)
+                .unwrap()
+                .allocate(0, 12 * 1024, false)
+                .unwrap();
+        }
+        let file = fs.create_file(b"/d", Permission::all(), 0, 0).unwrap();
+        let inode = fs.get_inode(file).unwrap();
+        let before = fs.statistics(false);
+        assert_eq!(before.free_blocks, 7);
+        assert_eq!(inode.allocate(0, 8 * 1024, false), Err(Error::NoFreeBlocks));
+        assert_eq!(fs.statistics(false), before);
+        assert_eq!((inode.size(), inode.blocks_used()), (0, 0));
+        inode.allocate(0, 7 * 1024, true).unwrap();
+        assert_eq!(fs.statistics(false).free_blocks, 0);
+        check_group_counters(&fs);
+    }
+
+    #[test]
+    fn secure_deletion() {
+        let mut image = formatted(400 * 1024);
+        let secure = b"This should not be found after deletion";
+        let normal = b"This can be found after deletion";
+        let contains =
+            |image: &[u8], data: &[u8]| image.windows(data.len()).any(|window| window == data);
+
+        let mut device = unsafe { Ext2Device::from_ptr(image.as_mut_ptr()) };
+        let fs = device.open();
+        for (name, content) in [(&b"/secure"[..], &secure[..]), (b"/normal", normal)] {
+            let file = fs.create_file(name, Permission::all(), 0, 0).unwrap();
+            let file = fs.get_inode(file).unwrap();
+            if name == b"/secure" {
+                file.set_flags(InodeFlags::SECURE_DELETION).unwrap();
+            }
+            let mut file = file.as_file().unwrap();
+            for _ in 0..100 {
+                file.write(content).unwrap();
+            }
+        }
+        fs.unlink(b"/secure").unwrap();
+        fs.unlink(b"/normal").unwrap();
+        assert!(!contains(&image, secure));
+        assert!(contains(&image, normal));
+    }
+
+    #[test]
+    fn secure_deletion_indirect() {
+        let mut image = load_image("test_fs_indirect");
+        // The content of /big repeats, each of its windows is somewhere in its first block
+        let content = image[54 * 1024..55 * 1024].to_vec();
+        let windows: std::collections::HashSet<_> = content.windows(32).collect();
+        let found = |image: &[u8]| image.windows(32).any(|window| windows.contains(window));
+        assert!(found(&image));
+
+        let mut device = unsafe { Ext2Device::from_ptr(image.as_mut_ptr()) };
+        let fs = device.open();
+        fs.get_inode(fs.lookup_path(b"/big").unwrap())
+            .unwrap()
+            .set_flags(InodeFlags::SECURE_DELETION)
+            .unwrap();
+        fs.unlink(b"/big").unwrap();
+        assert!(!found(&image));
+    }
+
+    #[test]
+    fn contiguous_files() {
+        let mut image = formatted(400 * 1024);
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.open();
+
+        let a = fs.create_file(b"/a", Permission::all(), 0, 0).unwrap();
+        let b = fs.create_file(b"/b", Permission::all(), 0, 0).unwrap();
+        let mut file_a = fs.get_inode(a).unwrap().as_file().unwrap();
+        let mut file_b = fs.get_inode(b).unwrap().as_file().unwrap();
+        for _ in 0..12 {
+            file_a.write(&[b'a'; 1024]).unwrap();
+            file_b.write(&[b'b'; 1024]).unwrap();
+        }
+        for inode in [a, b] {
+            let blocks =
+                unsafe { (*fs.get_inode(inode).unwrap().get_data()).direct_block_pointers };
+            assert!(
+                blocks.windows(2).all(|w| w[1] == w[0] + 1),
+                "{:?} is fragmented",
+                blocks
+            );
+        }
+
+        // A goal just past the last block wraps around to the start
+        let end = fs.get_superblock().block_count;
+        assert!(fs.reserve_block(end, true).unwrap() < end);
+    }
+
+    #[test]
+    fn exhaustion() {
+        let mut image = load_image("test_fs_tiny");
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.open();
+
+        // Fill the 43 free blocks with files of at most 12 blocks
+        for (name, blocks) in [(&b"/a"[..], 12), (b"/b", 12), (b"/c", 12), (b"/d", 7)] {
+            let file = fs.create_file(name, Permission::all(), 0, 0).unwrap();
+            fs.get_inode(file)
+                .unwrap()
+                .as_file()
+                .unwrap()
+                .write(&[1; 1024][..].repeat(blocks))
+                .unwrap();
+        }
+        assert_eq!(
+            fs.create_dir(b"/dir", Permission::all(), 0, 0),
+            Err(Error::NoFreeBlocks)
+        );
+        assert_eq!(fs.lookup_path(b"/dir"), Err(Error::NotFound));
+
+        // The inode of the failed directory was given back
+        fs.create_file(b"/e", Permission::all(), 0, 0).unwrap();
+        assert_eq!(
+            fs.create_file(b"/f", Permission::all(), 0, 0),
+            Err(Error::NoFreeInodes)
+        );
+        assert_eq!(
+            fs.get_root().create_inode_in_dir(
+                EntryKind::RegularFile,
+                Permission::all(),
+                0,
+                0,
+                b"f"
+            ),
+            Err(CreateError::NoFreeInodes)
+        );
+    }
+
+    #[test]
+    fn grow_directory() {
+        let mut image = formatted(400 * 1024);
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.open();
+
+        let dir = fs.create_dir(b"/dir", Permission::all(), 0, 0).unwrap();
+        let mut path = *b"/dir/________________________________________________________________________________________________xx";
+        for i in 0..12 {
+            let len = path.len();
+            path[len - 2] = b'a' + i;
+            fs.create_file(&path, Permission::all(), 0, 0).unwrap();
+        }
+        assert_eq!(fs.get_inode(dir).unwrap().size(), 2048);
+        for i in 0..12 {
+            let len = path.len();
+            path[len - 2] = b'a' + i;
+            fs.lookup_path(&path).unwrap();
+        }
+        assert_eq!(
+            fs.get_inode(dir)
+                .unwrap()
+                .get_dir_entries()
+                .unwrap()
+                .count(),
+            14
+        );
     }
 }
@@ -3,8 +3,13 @@ pub const BLOCK_GROUP_DESCRITPOR_SIZE: usize = 32;
 pub const EXTENDED_SUPERBLOCK_SIZE: usize = 1023 - SUPERBLOCK_SIZE;
 
 use bitflags::bitflags;
+use bstr::{BStr, ByteSlice};
 
-#[repr(C)]
+use super::{Error, OpenError};
+
+/// The structures read from the device are packed, the device may start at any address. Their
+/// fields must be copied out instead of borrowed
+#[repr(C, packed)]
 pub struct BlockGroupDescriptor {
     pub block_address_of_block_bitmap: u32,
     pub block_address_of_inode_bitmap: u32,
@@ -15,39 +20,47 @@ pub struct BlockGroupDescriptor {
     _unused: [u8; 14],
 }
 
+impl BlockGroupDescriptor {
+    /// The descriptor of a group without bitmaps nor inode table, all zeros
+    pub(crate) const fn empty() -> Self {
+        BlockGroupDescriptor {
+            block_address_of_block_bitmap: 0,
+            block_address_of_inode_bitmap: 0,
+            starting_block_of_inode_table: 0,
+            unallocated_blocks_in_group: 0,
+            unallocated_inodes_in_group: 0,
+            number_of_directories_in_group: 0,
+            _unused: [0; 14],
+        }
+    }
+}
+
 impl core::fmt::Debug for BlockGroupDescriptor {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         f.debug_struct("BlockGroupDescriptor")
-            .field(
-                "block_address_of_block_bitmap",
-                &self.block_address_of_block_bitmap,
-            )
-            .field(
-                "block_address_of_inode_bitmap",
-                &self.block_address_of_inode_bitmap,
-            )
-            .field(
-                "starting_block_of_inode_table",
-                &self.starting_block_of_inode_table,
-            )
-            .field(
-                "unallocated_blocks_in_group",
-                &self.unallocated_blocks_in_group,
-            )
-            .field(
-                "unallocated_inodes_in_group",
-                &self.unallocated_inodes_in_group,
-            )
-            .field(
-                "number_of_directories_in_group",
-                &self.number_of_directories_in_group,
-            )
+            .field("block_address_of_block_bitmap", &{
+                self.block_address_of_block_bitmap
+            })
+            .field("block_address_of_inode_bitmap", &{
+                self.block_address_of_inode_bitmap
+            })
+            .field("starting_block_of_inode_table", &{
+                self.starting_block_of_inode_table
+            })
+            .field("unallocated_blocks_in_group", &{
+                self.unallocated_blocks_in_group
+            })
+            .field("unallocated_inodes_in_group", &{
+                self.unallocated_inodes_in_group
+            })
+            .field("number_of_directories_in_group", &{
+                self.number_of_directories_in_group
+            })
             .finish()
     }
 }
 
-#[repr(C)]
-#[derive(Debug)]
+#[repr(C, packed)]
 pub struct Superblock {
     pub inode_count: u32,
     pub block_count: u32,
@@ -65,12 +78,15 @@ pub struct Superblock {
     pub number_of_times_mounted_since_last_consitency_check: u16,
     pub number_of_mounts_until_consistency_check: u16,
     pub ext2sig: u16,
-    pub state: FsState,
-    pub on_error: OnError,
+    /// See `Superblock::state`
+    pub state: u16,
+    /// See `Superblock::on_error`
+    pub on_error: u16,
     pub minor_version: u16,
     pub time_since_last_constiency_check: u32,
     pub time_between_forced_consistency_check: u32,
-    pub creator_system_id: OsId,
+    /// See `Superblock::creator_system_id`
+    pub creator_system_id: u32,
     pub major_version: u32,
     pub user_id_allowed_to_reserve: u16,
     pub group_id_allowed_to_reserve: u16,
@@ -78,53 +94,186 @@ pub struct Superblock {
 
 impl Superblock {
     /// You must provide a valid superblock start.
-    /// You must *NOT* use the locations from start upto start + 1023
-    /// The lifetime 'a is the lifetime of the pointer
-    pub(crate) unsafe fn from_ptr<'a>(
+    /// The superblocks are only borrowed for the checks, the returned pointers must be accessed
+    /// through short lived borrows as they alias the rest of the device
+    ///
+    /// Only the contents of the superblock are checked, any 1024 bytes can be given. The extended
+    /// superblock is null for revision 0, which does not have one
+    pub(crate) unsafe fn from_ptr(
         start: *mut u8,
-    ) -> (&'a mut Superblock, Option<&'a mut ExtendedSuperblock>) {
-        let superblock = (start as *mut Superblock)
-            .as_mut()
-            .expect("Superblock was null");
-
-        assert_eq!(superblock.ext2sig, 0xef53, "Ext2 is not valid");
-
-        let extended = if superblock.major_version > 1 {
-            None
-        } else {
-            Some(
-                match (start.offset(SUPERBLOCK_SIZE as isize) as *mut ExtendedSuperblock).as_mut() {
-                    Some(p) => p,
-                    None => core::hint::unreachable_unchecked(),
-                },
-            )
+    ) -> Result<(*mut Superblock, *mut ExtendedSuperblock), OpenError> {
+        let superblock = start as *mut Superblock;
+        if (*superblock).ext2sig != 0xef53 {
+            return Err(OpenError::BadSignature);
+        }
+
+        // The extended superblock only exists from revision 1
+        let extended = match (*superblock).major_version {
+            0 => core::ptr::null_mut(),
+            1 => start.add(SUPERBLOCK_SIZE) as *mut ExtendedSuperblock,
+            revision => return Err(OpenError::UnsupportedRevision(revision)),
         };
+        (*superblock).check_geometry(ExtendedSuperblock::or_revision_0(extended))?;
+
+        Ok((superblock, extended))
+    }
+
+    fn check_geometry(&self, extended: &ExtendedSuperblock) -> Result<(), OpenError> {
+        // Blocks are at most 64KiB
+        if self.log_block_size > 6 {
+            return Err(OpenError::InvalidGeometry("block size out of range"));
+        }
+        // The record length of a directory entry spanning a whole 64KiB block does not fit in
+        // its 16 bits
+        if self.log_block_size == 6 {
+            return Err(OpenError::UnsupportedBlockSize(self.block_size() as u32));
+        }
+        let bits_in_block = 8 << (10 + self.log_block_size);
+        // Each group has one block of bitmap for its blocks and one for its inodes
+        if self.block_count_in_group == 0 || self.block_count_in_group > bits_in_block {
+            return Err(OpenError::InvalidGeometry("blocks per group out of range"));
+        }
+        if self.inode_count_in_group == 0 || self.inode_count_in_group > bits_in_block {
+            return Err(OpenError::InvalidGeometry("inodes per group out of range"));
+        }
+        if self.index_of_superblock >= self.block_count {
+            return Err(OpenError::InvalidGeometry("no blocks after the superblock"));
+        }
+        // Every block must be addressable with the pointer width of the target
+        if crate::block_offset(self.block_size(), self.block_count).is_err() {
+            return Err(OpenError::InvalidGeometry(
+                "filesystem larger than the address space",
+            ));
+        }
+        let groups = self.group_count();
+        if groups.checked_mul(self.inode_count_in_group) != Some(self.inode_count) {
+            return Err(OpenError::InvalidGeometry(
+                "inode count does not match the groups",
+            ));
+        }
+        let inode_size = u32::from(extended.inode_struct_size);
+        if inode_size < 128
+            || !inode_size.is_power_of_two()
+            || inode_size > self.block_size() as u32
+        {
+            return Err(OpenError::InvalidGeometry("inode size out of range"));
+        }
+        Ok(())
+    }
+
+    /// The number of block groups, the last one can be shorter
+    pub(crate) fn group_count(&self) -> u32 {
+        // The blocks before the superblock are not in a group
+        (self.block_count - self.index_of_superblock).div_ceil(self.block_count_in_group)
+    }
 
-        (superblock, extended)
+    /// The state of the filesystem, or the raw value if it is not known
+    pub fn state(&self) -> Result<FsState, u16> {
+        match self.state {
+            1 => Ok(FsState::Clean),
+            2 => Ok(FsState::Errored),
+            raw => Err(raw),
+        }
+    }
+    /// What to do when an error is detected, or the raw value if it is not known
+    pub fn on_error(&self) -> Result<OnError, u16> {
+        match self.on_error {
+            1 => Ok(OnError::Ignore),
+            2 => Ok(OnError::RemountReadOnly),
+            3 => Ok(OnError::KernelPanic),
+            raw => Err(raw),
+        }
+    }
+    /// The system that created the filesystem, or the raw value if it is not known
+    pub fn creator_system_id(&self) -> Result<OsId, u32> {
+        match self.creator_system_id {
+            0 => Ok(OsId::Linux),
+            1 => Ok(OsId::GnuHurd),
+            2 => Ok(OsId::Masix),
+            3 => Ok(OsId::FreeBSD),
+            4 => Ok(OsId::OtherLite),
+            raw => Err(raw),
+        }
     }
 
-    pub fn block_size(&mut self) -> usize {
+    pub fn block_size(&self) -> usize {
         1024 << self.log_block_size
     }
 }
 
+/// Debug of a decoded field, showing the raw value when it is not known
+struct Decoded<T, R>(Result<T, R>);
+
+impl<T: core::fmt::Debug, R: core::fmt::Debug> core::fmt::Debug for Decoded<T, R> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match &self.0 {
+            Ok(value) => value.fmt(f),
+            Err(raw) => write!(f, "Unknown({:?})", raw),
+        }
+    }
+}
+
+impl core::fmt::Debug for Superblock {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("Superblock")
+            .field("inode_count", &{ self.inode_count })
+            .field("block_count", &{ self.block_count })
+            .field("block_superuser", &{ self.block_superuser })
+            .field("unallocated_blocks", &{ self.unallocated_blocks })
+            .field("unallocated_inodes", &{ self.unallocated_inodes })
+            .field("index_of_superblock", &{ self.index_of_superblock })
+            .field("log_block_size", &{ self.log_block_size })
+            .field("log_fragment_size", &{ self.log_fragment_size })
+            .field("block_count_in_group", &{ self.block_count_in_group })
+            .field("fragment_count_in_group", &{ self.fragment_count_in_group })
+            .field("inode_count_in_group", &{ self.inode_count_in_group })
+            .field("last_mounted", &{ self.last_mounted })
+            .field("last_written", &{ self.last_written })
+            .field("number_of_times_mounted_since_last_consitency_check", &{
+                self.number_of_times_mounted_since_last_consitency_check
+            })
+            .field("number_of_mounts_until_consistency_check", &{
+                self.number_of_mounts_until_consistency_check
+            })
+            .field("ext2sig", &{ self.ext2sig })
+            .field("state", &Decoded(self.state()))
+            .field("on_error", &Decoded(self.on_error()))
+            .field("minor_version", &{ self.minor_version })
+            .field("time_since_last_constiency_check", &{
+                self.time_since_last_constiency_check
+            })
+            .field("time_between_forced_consistency_check", &{
+                self.time_between_forced_consistency_check
+            })
+            .field("creator_system_id", &Decoded(self.creator_system_id()))
+            .field("major_version", &{ self.major_version })
+            .field("user_id_allowed_to_reserve", &{
+                self.user_id_allowed_to_reserve
+            })
+            .field("group_id_allowed_to_reserve", &{
+                self.group_id_allowed_to_reserve
+            })
+            .finish()
+    }
+}
+
 #[repr(u16)]
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum FsState {
     Clean = 1,
     Errored = 2,
 }
 
 #[repr(u16)]
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum OnError {
     Ignore = 1,
     RemountReadOnly = 2,
     KernelPanic = 3,
 }
 
-#[repr(u16)]
-#[derive(Debug)]
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum OsId {
     Linux = 0,
     GnuHurd = 1,
@@ -134,7 +283,7 @@ pub enum OsId {
 }
 
 /// bytes 236 to 1023 are not counted
-#[repr(C)]
+#[repr(C, packed)]
 pub struct ExtendedSuperblock {
     pub first_non_reserved_inode: u32,
     pub inode_struct_size: u16,
@@ -148,49 +297,148 @@ pub struct ExtendedSuperblock {
     pub compression_algorithm: u32,
     pub number_of_blocks_to_preallocate_files: u8,
     pub number_of_blocks_to_preallocate_dirs: u8,
-    pub unused: u16,
+    /// The blocks kept after the group descriptors to grow the filesystem, see
+    /// `OptionalFeatures::RESIZEABLE`
+    pub reserved_gdt_blocks: u16,
     pub journal_id: Id,
     pub journal_inode: u32,
     pub journal_device: u32,
     pub head_of_orphan_list: u32,
 }
 
+/// What revision 0 filesystems use in place of the extended superblock
+static REVISION_0: ExtendedSuperblock = ExtendedSuperblock {
+    first_non_reserved_inode: 11,
+    inode_struct_size: 128,
+    part_of_block: 0,
+    optional_features: OptionalFeatures::empty(),
+    required_features: RequiredFeatures::empty(),
+    write_features: WriteFeatures::empty(),
+    fs_id: Id([0; 16]),
+    volume_name: [0; 16],
+    path_last_mounted_at: [0; 64],
+    compression_algorithm: 0,
+    number_of_blocks_to_preallocate_files: 0,
+    number_of_blocks_to_preallocate_dirs: 0,
+    reserved_gdt_blocks: 0,
+    journal_id: Id([0; 16]),
+    journal_inode: 0,
+    journal_device: 0,
+    head_of_orphan_list: 0,
+};
+
+impl ExtendedSuperblock {
+    /// The extended superblock at extended, or the defaults of revision 0 if it is null
+    ///
+    /// # Safety
+    ///
+    /// extended must be null or valid for the returned lifetime
+    pub(crate) unsafe fn or_revision_0<'a>(extended: *const ExtendedSuperblock) -> &'a Self {
+        extended.as_ref().unwrap_or(&REVISION_0)
+    }
+    /// The name of the volume, up to its first NUL
+    pub fn volume_name(&self) -> &BStr {
+        name_of(&self.volume_name)
+    }
+    /// The path the filesystem was last mounted at, up to its first NUL
+    pub fn last_mounted_path(&self) -> &BStr {
+        name_of(&self.path_last_mounted_at)
+    }
+}
+
+/// The bytes of the array before the first NUL, all of them if it has none
+fn name_of(array: &[i8]) -> &BStr {
+    // i8 and u8 have the same layout
+    let bytes = unsafe { core::slice::from_raw_parts(array.as_ptr() as *const u8, array.len()) };
+    let end = bytes
+        .iter()
+        .position(|&byte| byte == 0)
+        .unwrap_or(bytes.len());
+    bytes[..end].as_bstr()
+}
+
 impl core::fmt::Debug for ExtendedSuperblock {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         f.debug_struct("ExtendedSuperblock")
-            .field("first_non_reserved_inode", &self.first_non_reserved_inode)
-            .field("inode_struct_size", &self.inode_struct_size)
-            .field("part_of_block", &self.part_of_block)
-            .field("optional_features", &self.optional_features)
-            .field("required_features", &self.required_features)
-            .field("write_features", &self.write_features)
+            .field("first_non_reserved_inode", &{
+                self.first_non_reserved_inode
+            })
+            .field("inode_struct_size", &{ self.inode_struct_size })
+            .field("part_of_block", &{ self.part_of_block })
+            .field("optional_features", &{ self.optional_features })
+            .field("required_features", &{ self.required_features })
+            .field("write_features", &{ self.write_features })
             .field("fs_id", &self.fs_id)
-            .field("volume_name", unsafe {
-                &cstr_core::CStr::from_ptr(&self.volume_name as *const cstr_core::c_char)
+            .field("volume_name", &self.volume_name())
+            .field("path_last_mounted_at", &self.last_mounted_path())
+            .field("compression_algorithm", &{ self.compression_algorithm })
+            .field("number_of_blocks_to_preallocate_files", &{
+                self.number_of_blocks_to_preallocate_files
             })
-            .field("path_last_mounted_at", unsafe {
-                &cstr_core::CStr::from_ptr(&self.path_last_mounted_at as *const cstr_core::c_char)
+            .field("number_of_blocks_to_preallocate_dirs", &{
+                self.number_of_blocks_to_preallocate_dirs
             })
-            .field("compression_algorithm", &self.compression_algorithm)
-            .field(
-                "number_of_blocks_to_preallocate_files",
-                &self.number_of_blocks_to_preallocate_files,
-            )
-            .field(
-                "number_of_blocks_to_preallocate_dirs",
-                &self.number_of_blocks_to_preallocate_dirs,
-            )
             .field("journal_id", &self.journal_id)
-            .field("journal_inode", &self.journal_inode)
-            .field("journal_device", &self.journal_device)
-            .field("head_of_orphan_list", &self.head_of_orphan_list)
+            .field("journal_inode", &{ self.journal_inode })
+            .field("journal_device", &{ self.journal_device })
+            .field("head_of_orphan_list", &{ self.head_of_orphan_list })
             .finish()
     }
 }
 
+/// A UUID, formatted like `f81d4fae-7dec-41d0-a765-00a0c91e6bf6` by Display and FromStr
 #[repr(transparent)]
+#[derive(Clone, Copy, PartialEq, Eq)]
 pub struct Id(pub [u8; 16]);
 
+impl Id {
+    /// A random UUID (version 4), random must fill its argument with random bytes
+    pub fn new_v4(random: impl FnOnce(&mut [u8])) -> Self {
+        let mut bytes = [0; 16];
+        random(&mut bytes);
+        bytes[6] = bytes[6] & 0x0f | 0x40;
+        bytes[8] = bytes[8] & 0x3f | 0x80;
+        Id(bytes)
+    }
+}
+
+impl core::fmt::Display for Id {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        for (index, byte) in self.0.iter().enumerate() {
+            if let 4 | 6 | 8 | 10 = index {
+                f.write_str("-")?;
+            }
+            write!(f, "{:02x}", byte)?
+        }
+        Ok(())
+    }
+}
+
+impl core::str::FromStr for Id {
+    type Err = Error;
+
+    /// The 32 hexadecimal digits grouped by 8, 4, 4, 4 and 12 with dashes, in any case
+    fn from_str(text: &str) -> Result<Self, Error> {
+        let text = text.as_bytes();
+        if text.len() != 36 || [8, 13, 18, 23].iter().any(|&dash| text[dash] != b'-') {
+            return Err(Error::InvalidArgument);
+        }
+        let mut digits = text.iter().filter(|&&c| c != b'-').map(|&c| match c {
+            b'0'..=b'9' => Ok(c - b'0'),
+            b'a'..=b'f' => Ok(c - b'a' + 10),
+            b'A'..=b'F' => Ok(c - b'A' + 10),
+            _ => Err(Error::InvalidArgument),
+        });
+        let mut id = [0; 16];
+        for byte in &mut id {
+            let high = digits.next().ok_or(Error::InvalidArgument)??;
+            let low = digits.next().ok_or(Error::InvalidArgument)??;
+            *byte = high << 4 | low;
+        }
+        Ok(Id(id))
+    }
+}
+
 impl core::fmt::Debug for Id {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         for byte in &self.0 {
@@ -233,12 +481,18 @@ bitflags! {
 
 #[cfg(test)]
 mod tests {
+    extern crate std;
+
     use super::BlockGroupDescriptor;
     use super::ExtendedSuperblock;
     use super::Superblock;
     use super::BLOCK_GROUP_DESCRITPOR_SIZE;
     use super::EXTENDED_SUPERBLOCK_SIZE;
     use super::SUPERBLOCK_SIZE;
+    use super::{FsState, Id, OnError, OsId};
+    use crate::tests::formatted;
+    use crate::{Error, Ext2Device};
+    use std::string::ToString;
 
     #[test]
     fn block_descriptor_size() {
@@ -248,6 +502,32 @@ mod tests {
         )
     }
 
+    #[test]
+    fn unknown_enum_values() {
+        let mut image = formatted(400 * 1024);
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        {
+            let fs = device.open();
+            let superblock = fs.get_superblock();
+            assert_eq!(superblock.state(), Ok(FsState::Clean));
+            assert_eq!(superblock.on_error(), Ok(OnError::Ignore));
+            assert_eq!(superblock.creator_system_id(), Ok(OsId::Linux));
+        }
+
+        image[1024 + 58] = 9;
+        image[1024 + 60] = 0;
+        image[1024 + 72..1024 + 76].copy_from_slice(&0x1234u32.to_le_bytes());
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.open();
+        let superblock = fs.get_superblock();
+        assert_eq!(superblock.state(), Err(9));
+        assert_eq!(superblock.on_error(), Err(0));
+        assert_eq!(superblock.creator_system_id(), Err(0x1234));
+        let debug = std::format!("{:?}", superblock);
+        assert!(debug.contains("state: Unknown(9), on_error: Unknown(0)"));
+        assert!(debug.contains("creator_system_id: Unknown(4660)"));
+    }
+
     #[test]
     fn superblock_size() {
         assert_eq!(core::mem::size_of::<Superblock>(), SUPERBLOCK_SIZE)
@@ -259,4 +539,27 @@ mod tests {
             EXTENDED_SUPERBLOCK_SIZE
         )
     }
+
+    #[test]
+    fn uuid() {
+        let id = Id::new_v4(|bytes| bytes.fill(0xff));
+        assert_eq!(id.0[6] >> 4, 4);
+        assert_eq!(id.0[8] >> 6, 0b10);
+        let text = id.to_string();
+        assert_eq!(text, "ffffffff-ffff-4fff-bfff-ffffffffffff");
+        assert_eq!(text.parse(), Ok(id));
+
+        let id: Id = "F81D4FAE-7dec-11d0-a765-00a0c91e6bf6".parse().unwrap();
+        assert_eq!(id.0[..4], [0xf8, 0x1d, 0x4f, 0xae]);
+        assert_eq!(id.to_string(), "f81d4fae-7dec-11d0-a765-00a0c91e6bf6");
+        for invalid in &[
+            "",
+            "f81d4fae7dec11d0a76500a0c91e6bf6",
+            "f81d4fae-7dec-11d0-a765-00a0c91e6bf",
+            "f81d4fae-7dec-11d0-a765-00a0c91e6bfg",
+            "f81d4fae-7dec-11d0-a765-00a0c91e6b-f",
+        ] {
+            assert_eq!(invalid.parse::<Id>(), Err(Error::InvalidArgument));
+        }
+    }
 }
@@ -0,0 +1,453 @@
+//! Creating an empty ext2 filesystem in a region of memory, like mke2fs.
+//!
+//! The filesystem is revision 1 with 128 bytes inodes, sparse superblock backups and the type
+//! of the inodes in the directory entries. Its root and lost+found directories are owned by root.
+
+use super::inode::{root_inode, Permission, TypePermission};
+use super::metadata::{
+    BlockGroupDescriptor, ExtendedSuperblock, FsState, Id, OnError, OsId, RequiredFeatures,
+    Superblock, WriteFeatures, BLOCK_GROUP_DESCRITPOR_SIZE, SUPERBLOCK_SIZE,
+};
+use super::{access, superblock_groups, Clock, Error, Ext2Device, GroupPolicy};
+
+const INODE_SIZE: u32 = 128;
+/// Inodes 1 to 10 are reserved, lost+found is the first one that is not
+const RESERVED_INODES: u32 = 10;
+/// A last group with less blocks than this beyond its metadata is not worth keeping
+pub(crate) const MIN_LAST_GROUP_BLOCKS: u32 = 50;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum InodeCount {
+    PerGroup(u32),
+    BytesPerInode(u32),
+}
+
+/// Options to configure the filesystem created by `format`, the defaults are those of mke2fs
+/// for small filesystems
+#[derive(Debug, Clone, Copy)]
+pub struct MkfsOptions<'a> {
+    block_size: usize,
+    inodes: InodeCount,
+    volume_name: &'a [u8],
+    reserved_percent: u8,
+    clock: Option<Clock>,
+    fill_random: Option<fn(&mut [u8])>,
+}
+
+impl Default for MkfsOptions<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a> MkfsOptions<'a> {
+    /// 1KiB blocks, an inode for every 4KiB, no name and 5% of the blocks reserved
+    pub fn new() -> Self {
+        MkfsOptions {
+            block_size: 1024,
+            inodes: InodeCount::BytesPerInode(4096),
+            volume_name: b"",
+            reserved_percent: 5,
+            clock: None,
+            fill_random: None,
+        }
+    }
+    /// 1024, 2048 or 4096 bytes
+    pub fn block_size(mut self, block_size: usize) -> Self {
+        self.block_size = block_size;
+        self
+    }
+    /// The number of inodes of each group, rounded up to fill the blocks of the inode table
+    pub fn inodes_per_group(mut self, inodes: u32) -> Self {
+        self.inodes = InodeCount::PerGroup(inodes);
+        self
+    }
+    /// Create an inode for every `bytes` bytes of the region, replaces `inodes_per_group`
+    pub fn bytes_per_inode(mut self, bytes: u32) -> Self {
+        self.inodes = InodeCount::BytesPerInode(bytes);
+        self
+    }
+    /// At most 16 bytes, see `FileSystem::set_volume_name`
+    pub fn volume_name(mut self, name: &'a [u8]) -> Self {
+        self.volume_name = name;
+        self
+    }
+    /// The percentage of the blocks kept for the superuser, at most 50
+    pub fn reserved_percent(mut self, percent: u8) -> Self {
+        self.reserved_percent = percent;
+        self
+    }
+    /// The clock used to timestamp the filesystem and its directories, they are 0 without one
+    pub fn clock(mut self, clock: Clock) -> Self {
+        self.clock = Some(clock);
+        self
+    }
+    /// The source of the random bytes of the UUID of the filesystem, see `Id::new_v4`.
+    ///
+    /// Without one the UUID is `00000000-0000-4000-8000-000000000000`, so that the images
+    /// built with the same options are identical
+    pub fn fill_random(mut self, fill_random: fn(&mut [u8])) -> Self {
+        self.fill_random = Some(fill_random);
+        self
+    }
+}
+
+/// Where the metadata of the filesystem goes
+#[derive(Debug)]
+struct Layout {
+    block_size: u32,
+    first_block: u32,
+    block_count: u32,
+    group_count: u32,
+    inodes_per_group: u32,
+    descriptor_blocks: u32,
+    inode_table_blocks: u32,
+}
+
+impl Layout {
+    fn new(len: usize, options: &MkfsOptions<'_>) -> Result<Self, Error> {
+        let block_size = options.block_size as u32;
+        if ![1024, 2048, 4096].contains(&block_size) {
+            return Err(Error::InvalidArgument);
+        }
+        let bits_in_block = 8 * block_size;
+        let first_block = u32::from(block_size == 1024);
+        let mut block_count = core::cmp::min(len / block_size as usize, u32::MAX as usize) as u32;
+        if block_count <= first_block {
+            return Err(Error::InvalidArgument);
+        }
+        let mut group_count = (block_count - first_block).div_ceil(bits_in_block);
+        let inodes_per_block = block_size / INODE_SIZE;
+        let inodes_per_group = match options.inodes {
+            InodeCount::PerGroup(inodes) => inodes,
+            InodeCount::BytesPerInode(0) => return Err(Error::InvalidArgument),
+            InodeCount::BytesPerInode(bytes) => {
+                let inodes = u64::from(block_count) * u64::from(block_size) / u64::from(bytes);
+                inodes.div_ceil(u64::from(group_count)) as u32
+            }
+        };
+        // The first group must have an inode for lost+found, and a bitmap block holds them all
+        let inodes_per_group = core::cmp::max(inodes_per_group, RESERVED_INODES + 1)
+            .next_multiple_of(core::cmp::max(inodes_per_block, 8));
+        if inodes_per_group > bits_in_block {
+            return Err(Error::InvalidArgument);
+        }
+
+        let mut layout = Layout {
+            block_size,
+            first_block,
+            block_count,
+            group_count,
+            inodes_per_group,
+            descriptor_blocks: 0,
+            inode_table_blocks: inodes_per_group / inodes_per_block,
+        };
+        loop {
+            layout.descriptor_blocks =
+                (group_count * BLOCK_GROUP_DESCRITPOR_SIZE as u32).div_ceil(block_size);
+            let last = group_count - 1;
+            if group_count == 1
+                || layout.blocks_in_group(last) >= layout.overhead(last) + MIN_LAST_GROUP_BLOCKS
+            {
+                break;
+            }
+            group_count -= 1;
+            block_count = first_block + group_count * bits_in_block;
+            layout.group_count = group_count;
+            layout.block_count = block_count;
+        }
+        // The root directory and lost+found take a block each
+        if layout.blocks_in_group(0) < layout.overhead(0) + 2 {
+            return Err(Error::InvalidArgument);
+        }
+        Ok(layout)
+    }
+    fn first_block_of_group(&self, group: u32) -> u32 {
+        self.first_block + group * 8 * self.block_size
+    }
+    fn blocks_in_group(&self, group: u32) -> u32 {
+        core::cmp::min(
+            self.block_count - self.first_block_of_group(group),
+            8 * self.block_size,
+        )
+    }
+    fn has_superblock(&self, group: u32) -> bool {
+        superblock_groups(self.group_count, true).any(|backup| backup == group)
+    }
+    /// The blocks of the group used by the superblock, the group descriptors, the bitmaps and
+    /// the inode table, they are at its start in this order
+    fn overhead(&self, group: u32) -> u32 {
+        let superblock = if self.has_superblock(group) {
+            1 + self.descriptor_blocks
+        } else {
+            0
+        };
+        superblock + 2 + self.inode_table_blocks
+    }
+}
+
+/// Mark the entries of the bitmap in range as used
+pub(crate) unsafe fn set_bits(bitmap: *mut u8, range: core::ops::Range<u32>) {
+    for index in range {
+        access::modify(bitmap.add(index as usize / 8), |byte| {
+            *byte |= 1 << (index % 8)
+        });
+    }
+}
+
+/// Create an empty filesystem covering as much of region as possible.
+///
+/// Only the metadata is written, the blocks are zeroed when they are allocated. InvalidArgument
+/// if the options are invalid or the region is too small for a group with its root directory
+pub fn format(region: &mut [u8], options: MkfsOptions<'_>) -> Result<(), Error> {
+    let layout = Layout::new(region.len(), &options)?;
+    let name = options.volume_name;
+    if options.reserved_percent > 50 || name.len() > 16 || name.contains(&0) {
+        return Err(Error::InvalidArgument);
+    }
+    log::trace!("Formatting {:?}", layout);
+    let block_size = layout.block_size as usize;
+    let device = region.as_mut_ptr();
+    let block = |index: u32| unsafe { device.add(index as usize * block_size) };
+    let now = options.clock.map(|clock| clock()).unwrap_or(0);
+
+    let inode_count = layout.group_count * layout.inodes_per_group;
+    let mut free_blocks = 0;
+    unsafe {
+        // The boot sector and the superblock, the other metadata is at the start of its group
+        access::fill(device, 0, 2048);
+        let descriptors = block(layout.first_block + 1) as *mut BlockGroupDescriptor;
+        for group in 0..layout.group_count {
+            let first = layout.first_block_of_group(group);
+            let overhead = layout.overhead(group);
+            access::fill(block(first), 0, (overhead * layout.block_size) as usize);
+            // The bits past the end of the group are set, like in the bitmaps of mke2fs
+            let bits_in_block = 8 * layout.block_size;
+            let bitmap = first + overhead - 2 - layout.inode_table_blocks;
+            set_bits(block(bitmap), 0..overhead);
+            set_bits(block(bitmap), layout.blocks_in_group(group)..bits_in_block);
+            let used_inodes = if group == 0 { RESERVED_INODES } else { 0 };
+            set_bits(block(bitmap + 1), 0..used_inodes);
+            set_bits(block(bitmap + 1), layout.inodes_per_group..bits_in_block);
+
+            let descriptor = descriptors.add(group as usize);
+            let group_free_blocks = layout.blocks_in_group(group) - overhead;
+            free_blocks += group_free_blocks;
+            write_field!(descriptor, block_address_of_block_bitmap, bitmap);
+            write_field!(descriptor, block_address_of_inode_bitmap, bitmap + 1);
+            write_field!(descriptor, starting_block_of_inode_table, bitmap + 2);
+            write_field!(
+                descriptor,
+                unallocated_blocks_in_group,
+                group_free_blocks as u16
+            );
+            write_field!(
+                descriptor,
+                unallocated_inodes_in_group,
+                (layout.inodes_per_group - used_inodes) as u16
+            );
+        }
+
+        access::write(
+            device.add(1024) as *mut Superblock,
+            Superblock {
+                inode_count,
+                block_count: layout.block_count,
+                block_superuser: (u64::from(layout.block_count)
+                    * u64::from(options.reserved_percent)
+                    / 100) as u32,
+                unallocated_blocks: free_blocks,
+                unallocated_inodes: inode_count - RESERVED_INODES,
+                index_of_superblock: layout.first_block,
+                log_block_size: layout.block_size.trailing_zeros() - 10,
+                log_fragment_size: layout.block_size.trailing_zeros() - 10,
+                block_count_in_group: 8 * layout.block_size,
+                fragment_count_in_group: 8 * layout.block_size,
+                inode_count_in_group: layout.inodes_per_group,
+                last_mounted: 0,
+                last_written: now,
+                number_of_times_mounted_since_last_consitency_check: 0,
+                // Negative, the mount count does not force checks
+                number_of_mounts_until_consistency_check: u16::MAX,
+                ext2sig: 0xef53,
+                state: FsState::Clean as u16,
+                on_error: OnError::Ignore as u16,
+                minor_version: 0,
+                time_since_last_constiency_check: now,
+                time_between_forced_consistency_check: 0,
+                creator_system_id: OsId::Linux as u32,
+                major_version: 1,
+                user_id_allowed_to_reserve: 0,
+                group_id_allowed_to_reserve: 0,
+            },
+        );
+        let extended = device.add(1024 + SUPERBLOCK_SIZE) as *mut ExtendedSuperblock;
+        write_field!(extended, first_non_reserved_inode, RESERVED_INODES + 1);
+        write_field!(extended, inode_struct_size, INODE_SIZE as u16);
+        let id = match options.fill_random {
+            Some(fill_random) => Id::new_v4(fill_random),
+            None => Id::new_v4(|bytes| bytes.fill(0)),
+        };
+        write_field!(extended, fs_id, id);
+        write_field!(
+            extended,
+            required_features,
+            RequiredFeatures::TYPED_DIRECTORY
+        );
+        write_field!(
+            extended,
+            write_features,
+            WriteFeatures::SPARSE_SUPERBLOCK_GROUP_DESCRIPTOR_TABLE
+        );
+    }
+
+    let mut device = Ext2Device::from_slice(region)?;
+    let mut fs = device.try_open()?;
+    if let Some(clock) = options.clock {
+        fs.set_clock(clock);
+    }
+    fs.set_volume_name(options.volume_name)?;
+    // lost+found is the first inode after the reserved ones, like mke2fs does
+    fs.set_group_policy(GroupPolicy::SameGroup);
+
+    let root = unsafe { fs.get_inode_in_table(root_inode().0) };
+    let permissions = Permission::USER_READ
+        | Permission::USER_WRITE
+        | Permission::USER_EXECUTE
+        | Permission::GROUP_READ
+        | Permission::GROUP_EXECUTE
+        | Permission::OTHER_READ
+        | Permission::OTHER_EXECUTE;
+    unsafe {
+        write_field!(
+            root,
+            type_permission,
+            TypePermission::DIR | TypePermission::from_bits_truncate(permissions.bits())
+        );
+        write_field!(root, last_access_time, now);
+        write_field!(root, creation_time, now);
+        write_field!(root, last_modification_time, now);
+    }
+    fs.update_group_descriptor(0, |descriptor| {
+        descriptor.number_of_directories_in_group += 1
+    });
+    fs.load_inode(root_inode()).init_dir(root_inode(), true)?;
+    fs.ensure_lost_and_found()?;
+    fs.sync()
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use std::string::ToString;
+    use std::vec;
+    use std::vec::Vec;
+
+    use super::{format, MkfsOptions};
+    use crate::inode::{EntryKind, Permission};
+    use crate::tests::check_group_counters;
+    use crate::{Error, Ext2Device, FileSystem};
+
+    fn check(fs: &FileSystem<'_>) {
+        let root = fs.get_root();
+        let entries: Vec<_> = root
+            .get_dir_entries()
+            .unwrap()
+            .map(|entry| (entry.name.to_vec(), entry.inode.0, entry.kind))
+            .collect();
+        assert_eq!(
+            entries,
+            [
+                (b".".to_vec(), 2, EntryKind::Directory),
+                (b"..".to_vec(), 2, EntryKind::Directory),
+                (b"lost+found".to_vec(), 11, EntryKind::Directory),
+            ]
+        );
+        assert_eq!(root.link_count(), 3);
+        assert_eq!(fs.statistics(false), fs.statistics(true));
+        check_group_counters(fs);
+    }
+
+    #[test]
+    fn format_sizes() {
+        for &(len, block_size) in &[
+            (64 * 1024, 1024),
+            (3 << 20, 1024),
+            (9 << 20, 2048),
+            (40 << 20, 4096),
+        ] {
+            let mut region = vec![0xa5; len];
+            let options = MkfsOptions::new()
+                .block_size(block_size)
+                .volume_name(b"formatted");
+            format(&mut region, options).unwrap();
+            let mut device = Ext2Device::from_slice(&mut region).unwrap();
+            let fs = device.try_open().unwrap();
+            assert_eq!(fs.get_extended_superblock().volume_name(), "formatted");
+            assert!(fs.needs_check().is_none());
+            check(&fs);
+
+            let file = fs.create_file(b"/file", Permission::all(), 0, 0).unwrap();
+            let mut file = fs.get_inode(file).unwrap().as_file().unwrap();
+            file.write(&[0x42; 5000]).unwrap();
+        }
+    }
+
+    #[test]
+    fn format_uuid() {
+        fn counting(bytes: &mut [u8]) {
+            for (index, byte) in bytes.iter_mut().enumerate() {
+                *byte = index as u8;
+            }
+        }
+        let uuid = |options| {
+            let mut region = vec![0; 64 * 1024];
+            format(&mut region, options).unwrap();
+            let mut device = Ext2Device::from_slice(&mut region).unwrap();
+            let fs = device.try_open().unwrap();
+            let id = { fs.get_extended_superblock().fs_id };
+            id.to_string()
+        };
+        assert_eq!(
+            uuid(MkfsOptions::new()),
+            "00000000-0000-4000-8000-000000000000"
+        );
+        assert_eq!(
+            uuid(MkfsOptions::new().fill_random(counting)),
+            "00010203-0405-4607-8809-0a0b0c0d0e0f"
+        );
+    }
+
+    #[test]
+    fn format_options() {
+        let mut region = vec![0; 2 << 20];
+        let options = MkfsOptions::new()
+            .inodes_per_group(100)
+            .reserved_percent(10);
+        format(&mut region, options).unwrap();
+        let mut device = Ext2Device::from_slice(&mut region).unwrap();
+        let fs = device.try_open().unwrap();
+        let superblock = fs.get_superblock();
+        // Rounded up to fill the blocks of the inode table
+        assert_eq!({ superblock.inode_count_in_group }, 104);
+        assert_eq!(
+            { superblock.block_superuser },
+            { superblock.block_count } / 10
+        );
+        check(&fs);
+        drop(fs);
+
+        let invalid = Err(Error::InvalidArgument);
+        let mut region = vec![0; 2 << 20];
+        for options in &[
+            MkfsOptions::new().block_size(512),
+            MkfsOptions::new().reserved_percent(51),
+            MkfsOptions::new().volume_name(b"seventeen bytes!!"),
+            MkfsOptions::new().bytes_per_inode(0),
+            MkfsOptions::new().inodes_per_group(9000),
+        ] {
+            assert_eq!(format(&mut region, *options), invalid);
+        }
+        assert_eq!(format(&mut region[..6 * 1024], MkfsOptions::new()), invalid);
+    }
+}
@@ -0,0 +1,571 @@
+//! Reading a filesystem through an asynchronous device, for the executors that must not block
+//! on storage.
+//!
+//! `AsyncFileSystem` is read-only: it opens the filesystem, looks up paths and reads files. It
+//! keeps nothing but the superblock, every inode, pointer and directory record is read from
+//! the device when it is needed, a cache belongs in the device. The decisions that don't do IO,
+//! the checks of the superblock, the indirection of the blocks and the validation of the
+//! directory records, are the ones of `FileSystem`.
+
+use core::cmp::min;
+use core::future::Future;
+
+use super::device::BlockDevice;
+use super::inode::{
+    block_path, record_fits, EntryKind, InodeData, InodeRef, RawDirectoryEntry,
+    DIRECTORY_ENTRY_HEADER,
+};
+use super::metadata::{
+    ExtendedSuperblock, Superblock, BLOCK_GROUP_DESCRITPOR_SIZE, SUPERBLOCK_SIZE,
+};
+use super::{check_required_features, Error};
+
+/// A device read and written by sectors, without blocking, see `BlockDevice`
+pub trait AsyncBlockDevice {
+    type Error;
+
+    /// The unit of the reads and writes, the offsets and the lengths of the buffers are
+    /// multiples of it
+    fn sector_size(&self) -> usize;
+    /// Fill buffer with the bytes at offset
+    fn read(
+        &mut self,
+        offset: u64,
+        buffer: &mut [u8],
+    ) -> impl Future<Output = Result<(), Self::Error>>;
+    /// Write data at offset, it may only reach the device on `flush`
+    fn write(&mut self, offset: u64, data: &[u8]) -> impl Future<Output = Result<(), Self::Error>>;
+    /// Wait for the writes to reach the device
+    fn flush(&mut self) -> impl Future<Output = Result<(), Self::Error>>;
+}
+
+/// A `BlockDevice` used as an `AsyncBlockDevice`, its futures are ready when they are first
+/// polled. For the devices that never wait, like `MemoryDevice`
+pub struct Blocking<D>(pub D);
+
+impl<D: BlockDevice> AsyncBlockDevice for Blocking<D> {
+    type Error = D::Error;
+
+    fn sector_size(&self) -> usize {
+        self.0.sector_size()
+    }
+    async fn read(&mut self, offset: u64, buffer: &mut [u8]) -> Result<(), D::Error> {
+        self.0.read(offset, buffer)
+    }
+    async fn write(&mut self, offset: u64, data: &[u8]) -> Result<(), D::Error> {
+        self.0.write(offset, data)
+    }
+    async fn flush(&mut self) -> Result<(), D::Error> {
+        self.0.flush()
+    }
+}
+
+/// The failures of an `AsyncFileSystem`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AsyncError<E> {
+    Device(E),
+    Fs(Error),
+}
+
+impl<E> From<Error> for AsyncError<E> {
+    fn from(error: Error) -> Self {
+        AsyncError::Fs(error)
+    }
+}
+
+/// The size of the inodes of revision 0, the fields past it are not read
+const INODE_SIZE: usize = core::mem::size_of::<InodeData>();
+
+/// The largest sectors of the devices an `AsyncFileSystem` opens, they are read one at a time
+/// into a buffer of this size when a read does not cover them
+const MAX_SECTOR_SIZE: usize = 4096;
+
+/// Read the bytes at offset into buffer by whole sectors of device, the sectors that buffer only
+/// partly covers go through bounce
+async fn read_sectors<D: AsyncBlockDevice>(
+    device: &mut D,
+    bounce: &mut [u8; MAX_SECTOR_SIZE],
+    offset: u64,
+    buffer: &mut [u8],
+) -> Result<(), D::Error> {
+    let sector = device.sector_size() as u64;
+    let end = offset + buffer.len() as u64;
+    let mut position = offset;
+    while position < end {
+        let done = (position - offset) as usize;
+        let start = position - position % sector;
+        if position == start && end - position >= sector {
+            let whole = ((end - position) / sector * sector) as usize;
+            device
+                .read(position, &mut buffer[done..done + whole])
+                .await?;
+            position += whole as u64;
+            continue;
+        }
+        device.read(start, &mut bounce[..sector as usize]).await?;
+        let from = (position - start) as usize;
+        let len = min(sector - from as u64, end - position) as usize;
+        buffer[done..done + len].copy_from_slice(&bounce[from..from + len]);
+        position += len as u64;
+    }
+    Ok(())
+}
+
+/// An inode read by an `AsyncFileSystem`, it is a copy: it does not see the later changes to
+/// the device
+pub struct AsyncInode {
+    id: InodeRef,
+    data: [u8; INODE_SIZE],
+}
+
+impl AsyncInode {
+    fn data(&self) -> *const InodeData {
+        self.data.as_ptr() as *const InodeData
+    }
+    pub fn inode_ref(&self) -> InodeRef {
+        self.id
+    }
+    pub fn size(&self) -> u32 {
+        unsafe { read_field!(self.data(), size_lower_32_bits) }
+    }
+    pub fn file_type(&self) -> EntryKind {
+        EntryKind::from_typeperm(unsafe { read_field!(self.data(), type_permission) })
+    }
+    pub fn is_dir(&self) -> bool {
+        self.file_type() == EntryKind::Directory
+    }
+}
+
+/// A filesystem opened read-only on an `AsyncBlockDevice`
+pub struct AsyncFileSystem<D> {
+    device: D,
+    /// The superblock and the extended one
+    superblock: [u8; 1024],
+    block_size: u64,
+    /// The sector holding the bytes of a read that does not cover it
+    bounce: [u8; MAX_SECTOR_SIZE],
+}
+
+impl<D: AsyncBlockDevice> AsyncFileSystem<D> {
+    /// Read and check the superblock of the filesystem on device, see `Ext2Device::try_open`.
+    /// The filesystem is not marked as mounted, nothing is written to the device.
+    ///
+    /// The sectors of device are at most 4KiB, UnsupportedFeature otherwise
+    pub async fn open(mut device: D) -> Result<Self, AsyncError<D::Error>> {
+        let sector = device.sector_size();
+        if sector == 0 || sector > MAX_SECTOR_SIZE {
+            return Err(Error::UnsupportedFeature("sectors larger than 4KiB").into());
+        }
+        let mut bounce = [0; MAX_SECTOR_SIZE];
+        let mut superblock = [0; 1024];
+        read_sectors(&mut device, &mut bounce, 1024, &mut superblock)
+            .await
+            .map_err(AsyncError::Device)?;
+        let (block_size, required) = unsafe {
+            let (superblock, extended) =
+                Superblock::from_ptr(superblock.as_mut_ptr()).map_err(Error::from)?;
+            (
+                (*superblock).block_size() as u64,
+                ExtendedSuperblock::or_revision_0(extended).required_features,
+            )
+        };
+        check_required_features(required).map_err(Error::from)?;
+        Ok(AsyncFileSystem {
+            device,
+            superblock,
+            block_size,
+            bounce,
+        })
+    }
+
+    pub fn get_superblock(&self) -> &Superblock {
+        // The superblock is packed, it can be at any address
+        unsafe { &*(self.superblock.as_ptr() as *const Superblock) }
+    }
+    pub fn get_extended_superblock(&self) -> &ExtendedSuperblock {
+        let extended = match self.get_superblock().major_version {
+            0 => core::ptr::null(),
+            _ => self.superblock[SUPERBLOCK_SIZE..].as_ptr() as *const ExtendedSuperblock,
+        };
+        // The extended superblock is in the copy, open checked the revision
+        unsafe { ExtendedSuperblock::or_revision_0(extended) }
+    }
+
+    /// The device, to release it
+    pub fn into_device(self) -> D {
+        self.device
+    }
+
+    async fn read_device(
+        &mut self,
+        offset: u64,
+        buffer: &mut [u8],
+    ) -> Result<(), AsyncError<D::Error>> {
+        read_sectors(&mut self.device, &mut self.bounce, offset, buffer)
+            .await
+            .map_err(AsyncError::Device)
+    }
+
+    fn is_valid_block(&self, block: u32) -> bool {
+        let superblock = self.get_superblock();
+        block != 0 && block >= superblock.index_of_superblock && block < superblock.block_count
+    }
+
+    /// Read inode, see `FileSystem::get_inode`
+    pub async fn get_inode(&mut self, inode: InodeRef) -> Result<AsyncInode, AsyncError<D::Error>> {
+        let (inode_count, per_group, first_block) = {
+            let superblock = self.get_superblock();
+            (
+                superblock.inode_count,
+                superblock.inode_count_in_group,
+                superblock.index_of_superblock,
+            )
+        };
+        if inode.0 == 0 || inode.0 > inode_count {
+            return Err(Error::BadInodeRef.into());
+        }
+        let (group, index) = ((inode.0 - 1) / per_group, (inode.0 - 1) % per_group);
+
+        // The descriptors follow the block of the superblock
+        let descriptor = (u64::from(first_block) + 1) * self.block_size
+            + u64::from(group) * BLOCK_GROUP_DESCRITPOR_SIZE as u64;
+        let mut table = [0; 4];
+        // The inode table is the third field of the descriptor
+        self.read_device(descriptor + 8, &mut table).await?;
+        let table = u32::from_le_bytes(table);
+        if !self.is_valid_block(table) {
+            return Err(Error::Corrupt("inode table out of the filesystem").into());
+        }
+
+        let inode_size = u64::from(self.get_extended_superblock().inode_struct_size);
+        let mut data = [0; INODE_SIZE];
+        let offset = u64::from(table) * self.block_size + u64::from(index) * inode_size;
+        self.read_device(offset, &mut data).await?;
+        Ok(AsyncInode { id: inode, data })
+    }
+
+    /// The block holding the content at index of inode, None for a hole or a pointer out of
+    /// the filesystem
+    async fn block_at(
+        &mut self,
+        inode: &AsyncInode,
+        index: u32,
+    ) -> Result<Option<u32>, AsyncError<D::Error>> {
+        let per_block = (self.block_size / 4) as u32;
+        let (pointer, mut index, levels) = block_path(index, per_block);
+        let mut block = unsafe { InodeData::pointer(inode.data(), pointer) };
+        for level in (0..levels).rev() {
+            if !self.is_valid_block(block) {
+                return Ok(None);
+            }
+            let covered = per_block.pow(level);
+            let mut pointer = [0; 4];
+            let offset = u64::from(block) * self.block_size + u64::from(index / covered) * 4;
+            self.read_device(offset, &mut pointer).await?;
+            block = u32::from_le_bytes(pointer);
+            index %= covered;
+        }
+        Ok(Some(block).filter(|&block| self.is_valid_block(block)))
+    }
+
+    /// Read the content of inode at offset into buffer, returns the number of bytes read, 0
+    /// past the end. Holes are read as zeros
+    pub async fn read(
+        &mut self,
+        inode: &AsyncInode,
+        offset: u64,
+        buffer: &mut [u8],
+    ) -> Result<usize, AsyncError<D::Error>> {
+        let end = min(
+            u64::from(inode.size()),
+            offset.saturating_add(buffer.len() as u64),
+        );
+        let mut position = offset;
+        while position < end {
+            let start = position % self.block_size;
+            let len = min(self.block_size - start, end - position) as usize;
+            let done = (position - offset) as usize;
+            let chunk = &mut buffer[done..done + len];
+            match self
+                .block_at(inode, (position / self.block_size) as u32)
+                .await?
+            {
+                Some(block) => {
+                    self.read_device(u64::from(block) * self.block_size + start, chunk)
+                        .await?
+                }
+                None => chunk.iter_mut().for_each(|byte| *byte = 0),
+            }
+            position += len as u64;
+        }
+        Ok(position.saturating_sub(offset) as usize)
+    }
+
+    /// The entry called name in directory, see `Inode::find_entry`. A corrupted record ends the
+    /// search
+    pub async fn find_entry(
+        &mut self,
+        directory: &AsyncInode,
+        name: &[u8],
+    ) -> Result<Option<InodeRef>, AsyncError<D::Error>> {
+        if !directory.is_dir() {
+            return Err(Error::NotADirectory.into());
+        }
+        let mut position = 0;
+        let mut header = [0; DIRECTORY_ENTRY_HEADER as usize];
+        let mut entry_name = [0; 255];
+        while position + u64::from(DIRECTORY_ENTRY_HEADER) <= u64::from(directory.size()) {
+            let remain = (self.block_size - position % self.block_size) as u32;
+            if remain < DIRECTORY_ENTRY_HEADER {
+                return Ok(None);
+            }
+            self.read(directory, position, &mut header).await?;
+            let raw = header.as_ptr() as *const RawDirectoryEntry;
+            let (inode, size, name_len) = unsafe {
+                (
+                    read_field!(raw, inode),
+                    u32::from(read_field!(raw, size)),
+                    u32::from(read_field!(raw, name_len)),
+                )
+            };
+            if !record_fits(size, name_len, remain) {
+                log::trace!("Corrupted directory entry of {} bytes", size);
+                return Ok(None);
+            }
+            // Inode 0 marks a deleted entry
+            if inode.0 != 0 && name_len as usize == name.len() {
+                let entry_name = &mut entry_name[..name.len()];
+                let start = position + u64::from(DIRECTORY_ENTRY_HEADER);
+                self.read(directory, start, entry_name).await?;
+                if entry_name == name {
+                    return Ok(Some(inode));
+                }
+            }
+            position += u64::from(size);
+        }
+        Ok(None)
+    }
+
+    /// The inode at path, from the root, see `FileSystem::lookup_path`. Symlinks are not
+    /// followed
+    pub async fn lookup_path(&mut self, path: &[u8]) -> Result<InodeRef, AsyncError<D::Error>> {
+        let mut current = InodeRef::root();
+        for component in path.split(|&c| c == b'/').filter(|c| !c.is_empty()) {
+            let inode = self.get_inode(current).await?;
+            current = self
+                .find_entry(&inode, component)
+                .await?
+                .ok_or(Error::NotFound)?;
+        }
+        Ok(current)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use core::future::Future;
+    use core::pin::pin;
+    use core::task::{Context, Poll, Waker};
+    use std::vec::Vec;
+
+    use super::{AsyncBlockDevice, AsyncError, AsyncFileSystem, Blocking};
+    use crate::device::{BlockDevice, MemoryDevice, OutOfRange};
+    use crate::inode::EntryKind;
+    use crate::tests::load_image;
+    use crate::{Error, Ext2Device};
+
+    /// Poll future until it is ready
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = pin!(future);
+        let mut context = Context::from_waker(Waker::noop());
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut context) {
+                return output;
+            }
+        }
+    }
+
+    /// A device whose reads are pending once before being made
+    struct Yielding<'memory> {
+        device: MemoryDevice<'memory>,
+        reads: usize,
+    }
+
+    struct YieldOnce(bool);
+
+    impl Future for YieldOnce {
+        type Output = ();
+        fn poll(mut self: core::pin::Pin<&mut Self>, context: &mut Context<'_>) -> Poll<()> {
+            if self.0 {
+                return Poll::Ready(());
+            }
+            self.0 = true;
+            context.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+
+    impl AsyncBlockDevice for Yielding<'_> {
+        type Error = OutOfRange;
+        fn sector_size(&self) -> usize {
+            1
+        }
+        async fn read(&mut self, offset: u64, buffer: &mut [u8]) -> Result<(), OutOfRange> {
+            YieldOnce(false).await;
+            self.reads += 1;
+            self.device.read(offset, buffer)
+        }
+        async fn write(&mut self, offset: u64, data: &[u8]) -> Result<(), OutOfRange> {
+            YieldOnce(false).await;
+            self.device.write(offset, data)
+        }
+        async fn flush(&mut self) -> Result<(), OutOfRange> {
+            Ok(())
+        }
+    }
+
+    /// A device that only takes whole sectors
+    struct Sectors<'memory> {
+        device: MemoryDevice<'memory>,
+        size: usize,
+    }
+
+    impl AsyncBlockDevice for Sectors<'_> {
+        type Error = OutOfRange;
+        fn sector_size(&self) -> usize {
+            self.size
+        }
+        async fn read(&mut self, offset: u64, buffer: &mut [u8]) -> Result<(), OutOfRange> {
+            assert_eq!(offset % self.size as u64, 0, "read at {}", offset);
+            assert_eq!(buffer.len() % self.size, 0, "read of {}", buffer.len());
+            self.device.read(offset, buffer)
+        }
+        async fn write(&mut self, offset: u64, data: &[u8]) -> Result<(), OutOfRange> {
+            self.device.write(offset, data)
+        }
+        async fn flush(&mut self) -> Result<(), OutOfRange> {
+            Ok(())
+        }
+    }
+
+    /// The content of the file at path, read with the blocking filesystem
+    fn read_blocking(image: &mut [u8], path: &[u8]) -> Vec<u8> {
+        let mut device = Ext2Device::from_slice(image).unwrap();
+        let fs = device.open();
+        let mut content = Vec::new();
+        fs.read_file(path, false, |data| {
+            content.extend_from_slice(data);
+            Ok::<_, ()>(())
+        })
+        .unwrap();
+        content
+    }
+
+    #[test]
+    fn lookup_and_read() {
+        let mut image = load_image("test_fs_indirect");
+        let expected = read_blocking(&mut image, b"/big");
+        let mut copy = image.clone();
+        block_on(async {
+            let mut fs = AsyncFileSystem::open(Blocking(MemoryDevice::new(&mut copy)))
+                .await
+                .unwrap();
+            let big = fs.lookup_path(b"/big").await.unwrap();
+            let big = fs.get_inode(big).await.unwrap();
+            assert_eq!(big.file_type(), EntryKind::RegularFile);
+            let mut content = std::vec![0; big.size() as usize + 100];
+            assert_eq!(
+                fs.read(&big, 0, &mut content).await.unwrap(),
+                expected.len()
+            );
+            assert!(content[..expected.len()] == expected[..]);
+            // Across the end of the direct blocks
+            let mut chunk = [0; 100];
+            assert_eq!(
+                fs.read(&big, 12 * 1024 - 50, &mut chunk).await.unwrap(),
+                100
+            );
+            assert_eq!(chunk[..], expected[12 * 1024 - 50..12 * 1024 + 50]);
+            assert_eq!(fs.read(&big, 1 << 40, &mut chunk).await.unwrap(), 0);
+
+            assert_eq!(
+                fs.lookup_path(b"/missing").await,
+                Err(AsyncError::Fs(Error::NotFound))
+            );
+            assert_eq!(
+                fs.lookup_path(b"/big/file").await,
+                Err(AsyncError::Fs(Error::NotADirectory))
+            );
+        });
+    }
+
+    #[test]
+    fn whole_sectors() {
+        let mut image = load_image("test_fs_indirect");
+        let expected = read_blocking(&mut image, b"/big");
+        for size in [512, 4096] {
+            let device = Sectors {
+                device: MemoryDevice::new(&mut image),
+                size,
+            };
+            block_on(async {
+                let mut fs = AsyncFileSystem::open(device).await.unwrap();
+                let big = fs.lookup_path(b"/big").await.unwrap();
+                let big = fs.get_inode(big).await.unwrap();
+                let mut content = std::vec![0; expected.len()];
+                // Starts and ends inside sectors
+                assert_eq!(
+                    fs.read(&big, 100, &mut content[100..]).await.unwrap(),
+                    expected.len() - 100
+                );
+                fs.read(&big, 0, &mut content[..100]).await.unwrap();
+                assert!(content == expected);
+            });
+        }
+
+        let device = Sectors {
+            device: MemoryDevice::new(&mut image),
+            size: 8192,
+        };
+        assert!(matches!(
+            block_on(AsyncFileSystem::open(device)),
+            Err(AsyncError::Fs(Error::UnsupportedFeature(_)))
+        ));
+    }
+
+    #[test]
+    fn pending_device() {
+        let mut image = load_image("test_fs");
+        let expected = read_blocking(&mut image, b"/thing/more/never.txt");
+        let device = Yielding {
+            device: MemoryDevice::new(&mut image),
+            reads: 0,
+        };
+        let device = block_on(async {
+            let mut fs = AsyncFileSystem::open(device).await.unwrap();
+            assert_eq!({ fs.get_superblock().ext2sig }, 0xef53);
+            let inode = fs.lookup_path(b"/thing/more/never.txt").await.unwrap();
+            let inode = fs.get_inode(inode).await.unwrap();
+            let mut content = [0; 64];
+            let len = fs.read(&inode, 0, &mut content).await.unwrap();
+            assert_eq!(content[..len], expected[..]);
+            fs.into_device()
+        });
+        assert!(device.reads > 5);
+
+        // Not a filesystem
+        let mut zeros = [0; 4096];
+        let device = Blocking(MemoryDevice::new(&mut zeros));
+        assert!(matches!(
+            block_on(AsyncFileSystem::open(device)),
+            Err(AsyncError::Fs(Error::InvalidSuperblock))
+        ));
+        let mut short = [0; 100];
+        let device = Blocking(MemoryDevice::new(&mut short));
+        assert!(matches!(
+            block_on(AsyncFileSystem::open(device)),
+            Err(AsyncError::Device(OutOfRange))
+        ));
+    }
+}
@@ -0,0 +1,136 @@
+//! The partitions of a disk with a master boot record, to open the filesystem of one of them
+//! with `Ext2Device::from_slice_at`.
+//!
+//! Only the 4 primary partitions are listed, the logical partitions in an extended partition
+//! are not followed. A GPT disk shows its protective partition, of type `GPT_PROTECTIVE`.
+
+use core::convert::TryInto;
+
+/// The size of the sectors the partitions are counted in
+pub const SECTOR_SIZE: u64 = 512;
+/// The type of the Linux filesystems
+pub const LINUX: u8 = 0x83;
+/// The types of the extended partitions, holding logical ones
+pub const EXTENDED: [u8; 3] = [0x05, 0x0f, 0x85];
+/// The partition covering a GPT disk
+pub const GPT_PROTECTIVE: u8 = 0xee;
+
+const TABLE: usize = 446;
+const ENTRY_SIZE: usize = 16;
+
+/// A partition of the master boot record
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Partition {
+    /// The position of the entry in the table, from 0 to 3
+    pub index: u8,
+    /// In bytes from the start of the disk
+    pub offset: u64,
+    /// In bytes
+    pub len: u64,
+    /// The system ID, see `LINUX`
+    pub kind: u8,
+    /// The active flag
+    pub bootable: bool,
+}
+
+/// The used entries of the partition table in the first sector of device, nothing when it
+/// does not end with the boot signature
+pub fn partitions(device: &[u8]) -> impl Iterator<Item = Partition> + '_ {
+    let has_table = device.get(510..512) == Some(&[0x55, 0xaa][..]);
+    let table = if has_table {
+        &device[TABLE..TABLE + 4 * ENTRY_SIZE]
+    } else {
+        &[]
+    };
+    table
+        .chunks_exact(ENTRY_SIZE)
+        .enumerate()
+        .filter_map(|(index, entry)| {
+            let field =
+                |start: usize| u32::from_le_bytes(entry[start..start + 4].try_into().unwrap());
+            let (kind, first, sectors) = (entry[4], field(8), field(12));
+            if kind == 0 || sectors == 0 {
+                return None;
+            }
+            Some(Partition {
+                index: index as u8,
+                offset: u64::from(first) * SECTOR_SIZE,
+                len: u64::from(sectors) * SECTOR_SIZE,
+                kind,
+                bootable: entry[0] & 0x80 != 0,
+            })
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use std::vec::Vec;
+
+    use super::{partitions, Partition, LINUX};
+    use crate::tests::load_image;
+    use crate::{Error, Ext2Device, OpenOptions};
+
+    #[test]
+    fn mbr() {
+        let mut disk = load_image("test_fs_mbr");
+        let found: Vec<_> = partitions(&disk).collect();
+        assert_eq!(
+            found,
+            [
+                Partition {
+                    index: 0,
+                    offset: 1 << 20,
+                    len: 512 * 1024,
+                    kind: LINUX,
+                    bootable: false,
+                },
+                Partition {
+                    index: 1,
+                    offset: 1536 * 1024,
+                    len: 256 * 1024,
+                    kind: 0x0c,
+                    bootable: false,
+                },
+            ]
+        );
+
+        let linux = found
+            .iter()
+            .find(|partition| partition.kind == LINUX)
+            .unwrap();
+        let mut device = Ext2Device::from_slice_at(&mut disk, linux.offset as usize).unwrap();
+        let fs = device.open();
+        assert_eq!(fs.get_extended_superblock().volume_name(), "part");
+        let mut content = [0; 32];
+        let mut file = fs
+            .open(b"/hello.txt", OpenOptions::new().read(true).write(true))
+            .unwrap();
+        assert_eq!(file.read(&mut content), 17);
+        assert_eq!(&content[..17], b"in the partition\n");
+        file.write(b"!").unwrap();
+        drop(file);
+        drop(fs);
+        // The blocks are addressed from the start of the partition
+        assert!(disk[..1 << 20]
+            .iter()
+            .enumerate()
+            .all(|(offset, &byte)| byte == 0 || (440..512).contains(&offset)));
+        assert!(disk[1536 * 1024..].iter().all(|&byte| byte == 0));
+
+        // The filesystem of the other partition is past the end of the disk
+        assert_eq!(
+            Ext2Device::from_slice_at(&mut disk, 1792 * 1024).err(),
+            Some(Error::InvalidSuperblock)
+        );
+        assert_eq!(
+            Ext2Device::from_slice_at(&mut disk, usize::MAX).err(),
+            Some(Error::InvalidSuperblock)
+        );
+
+        // Without the boot signature
+        disk[511] = 0;
+        assert_eq!(partitions(&disk).count(), 0);
+        assert_eq!(partitions(&disk[..100]).count(), 0);
+    }
+}
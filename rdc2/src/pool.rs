@@ -0,0 +1,600 @@
+//! A filesystem on a `BlockDevice`, see `FileSystem::open_device`.
+//!
+//! The blocks from the start of the device to the end of the group descriptors are read when
+//! opening and stay in memory, in one region like on the device: the filesystem points into it
+//! as it does into a `MemoryDevice`. Every other block `get_block` reaches is read into a buffer
+//! of a pool on its first access, and the filesystem uses the buffer.
+//!
+//! The buffers are borrowed along the filesystem: the pointers to them, held by the inodes, the
+//! cursors or the allocators, can't outlive the `&FileSystem` they came from, and a buffer is
+//! never moved nor dropped while the filesystem is shared. `FileSystem::flush_device` takes the
+//! filesystem exclusively, when nothing can point into the pool anymore, to write the blocks
+//! that changed and give the buffers back: only the capacity of the pool is kept, the blocks
+//! used last. In between, the pool grows with the blocks accessed like a `Transaction` does, up
+//! to its limit: past it the blocks of the inodes fail with PoolFull until the next flush. The
+//! bitmaps and the inode tables are still read, the allocators can't fail on them. The content
+//! read by the cursors does not stay in the pool, it is copied straight from the device when its
+//! block is not there.
+//!
+//! A block changed when its content no longer has the hash it had when it was read, like with
+//! the dirty tracking. `FileSystem::sync` writes them too, without giving the buffers back. A
+//! failed read of a block of an inode is returned as DeviceFailed. A bitmap or an inode table
+//! whose read failed holds zeros, what the filesystem did with it can't be written: nothing
+//! reaches the device until `flush_device` drops the pool and reads the region again, the
+//! changes made since the last write are lost.
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use core::ptr::NonNull;
+
+use super::device::BlockDevice;
+use super::dirty::hash;
+use super::metadata::{Superblock, BLOCK_GROUP_DESCRITPOR_SIZE};
+use super::{open_region, Error, FileSystem, OpenError};
+
+/// The accesses of the pool to a `BlockDevice`, whatever its error type
+pub(crate) trait Device {
+    fn read(&mut self, offset: u64, buffer: &mut [u8]) -> Result<(), Error>;
+    fn write(&mut self, offset: u64, data: &[u8]) -> Result<(), Error>;
+    fn flush(&mut self) -> Result<(), Error>;
+    fn prefetch(&mut self, offset: u64, len: usize);
+}
+
+impl<D: BlockDevice> Device for D {
+    fn read(&mut self, offset: u64, buffer: &mut [u8]) -> Result<(), Error> {
+        BlockDevice::read(self, offset, buffer).map_err(|_| {
+            log::trace!("Reading {} bytes at {} failed", buffer.len(), offset);
+            Error::DeviceFailed
+        })
+    }
+    fn write(&mut self, offset: u64, data: &[u8]) -> Result<(), Error> {
+        BlockDevice::write(self, offset, data).map_err(|_| {
+            log::trace!("Writing {} bytes at {} failed", data.len(), offset);
+            Error::DeviceFailed
+        })
+    }
+    fn flush(&mut self) -> Result<(), Error> {
+        BlockDevice::flush(self).map_err(|_| Error::DeviceFailed)
+    }
+    /// Only a hint, its failures are not reported
+    fn prefetch(&mut self, offset: u64, len: usize) {
+        let _ = BlockDevice::prefetch(self, offset, len);
+    }
+}
+
+/// A block of the device read in the pool
+struct Buffer {
+    data: Box<[u8]>,
+    /// The hash of the content written on the device
+    hash: u64,
+    /// The value of `Pool::uses` when the block was last handed out
+    last_use: u64,
+}
+
+/// The blocks of a filesystem on a device, see the module documentation
+pub(crate) struct Pool {
+    /// Borrowed exclusively for the 'device of the filesystem, that holds the pool. The
+    /// lifetime is erased: in the cell of the pool it would make the filesystem invariant in
+    /// 'device
+    device: NonNull<dyn Device>,
+    block_size: usize,
+    /// The blocks from the first one to the end of the group descriptors, the filesystem points
+    /// into them
+    region: Vec<u8>,
+    region_hashes: Vec<u64>,
+    buffers: BTreeMap<u32, Buffer>,
+    /// The blocks kept by `FileSystem::flush_device`
+    capacity: usize,
+    /// The blocks past which `block` refuses the bounded accesses
+    limit: usize,
+    uses: u64,
+    /// A buffer holds zeros instead of the content of its block
+    failed: bool,
+    /// The content of the block given by `read_unpooled`
+    scratch: Vec<u8>,
+}
+
+impl Pool {
+    fn device(&mut self) -> &mut dyn Device {
+        // The pool has the only access to the device
+        unsafe { self.device.as_mut() }
+    }
+
+    fn region_blocks(&self) -> u32 {
+        (self.region.len() / self.block_size) as u32
+    }
+
+    /// The buffer of the block index, read from the device on the first access. The buffers
+    /// don't move until `release`.
+    ///
+    /// DeviceFailed if the read failed, and PoolFull if bounded and the pool holds its limit of
+    /// blocks, nothing is kept in both cases
+    pub(crate) fn block(&mut self, index: u32, bounded: bool) -> Result<*mut u8, Error> {
+        let block_size = self.block_size;
+        if index < self.region_blocks() {
+            return Ok(unsafe { self.region.as_mut_ptr().add(index as usize * block_size) });
+        }
+        self.uses += 1;
+        let uses = self.uses;
+        if let Some(buffer) = self.buffers.get_mut(&index) {
+            buffer.last_use = uses;
+            return Ok(buffer.data.as_mut_ptr());
+        }
+        if bounded && self.buffers.len() >= self.limit {
+            log::trace!("The pool is full, block {} is not read", index);
+            return Err(Error::PoolFull);
+        }
+        let mut data = vec![0; block_size].into_boxed_slice();
+        self.device()
+            .read(u64::from(index) * block_size as u64, &mut data)?;
+        Ok(self.insert(index, data))
+    }
+
+    /// Like block without bound, a block that can't be read is given as zeros and nothing is
+    /// written until the next `release`
+    pub(crate) fn block_or_zeros(&mut self, index: u32) -> *mut u8 {
+        match self.block(index, false) {
+            Ok(block) => block,
+            Err(_) => {
+                self.failed = true;
+                let data = vec![0; self.block_size].into_boxed_slice();
+                self.insert(index, data)
+            }
+        }
+    }
+
+    fn insert(&mut self, index: u32, data: Box<[u8]>) -> *mut u8 {
+        let block_size = self.block_size;
+        let uses = self.uses;
+        let hash = unsafe { hash(data.as_ptr(), block_size) };
+        let buffer = self.buffers.entry(index).or_insert(Buffer {
+            data,
+            hash,
+            last_use: uses,
+        });
+        buffer.data.as_mut_ptr()
+    }
+
+    /// The content of the block index read in a buffer that is not kept, None if the block is
+    /// in the pool. The buffer is given back with `recycle`
+    pub(crate) fn read_unpooled(&mut self, index: u32) -> Option<Result<Vec<u8>, Error>> {
+        if index < self.region_blocks() || self.buffers.contains_key(&index) {
+            return None;
+        }
+        let mut scratch = core::mem::take(&mut self.scratch);
+        scratch.resize(self.block_size, 0);
+        let offset = u64::from(index) * self.block_size as u64;
+        Some(self.device().read(offset, &mut scratch).map(|()| scratch))
+    }
+    pub(crate) fn recycle(&mut self, scratch: Vec<u8>) {
+        self.scratch = scratch;
+    }
+
+    /// Ask the device to read count blocks from first ahead
+    pub(crate) fn prefetch(&mut self, first: u32, count: u32) {
+        let block_size = self.block_size;
+        self.device().prefetch(
+            u64::from(first) * block_size as u64,
+            count as usize * block_size,
+        );
+    }
+
+    /// Write the blocks that changed to the device and flush it, the pool first and the region
+    /// from its end, so that the superblock comes last. Returns the number of blocks written.
+    /// DeviceFailed without writing anything if a read failed
+    fn write_back(&mut self) -> Result<usize, Error> {
+        if self.failed {
+            return Err(Error::DeviceFailed);
+        }
+        let block_size = self.block_size;
+        let device = unsafe { self.device.as_mut() };
+        let mut written = 0;
+        for (&index, buffer) in &mut self.buffers {
+            let hash = unsafe { hash(buffer.data.as_ptr(), block_size) };
+            if hash != buffer.hash {
+                device.write(u64::from(index) * block_size as u64, &buffer.data)?;
+                buffer.hash = hash;
+                written += 1;
+            }
+        }
+        let blocks = self.region.chunks(block_size).zip(&mut self.region_hashes);
+        for (index, (block, written_hash)) in blocks.enumerate().rev() {
+            let hash = unsafe { hash(block.as_ptr(), block_size) };
+            if hash != *written_hash {
+                device.write(index as u64 * block_size as u64, block)?;
+                *written_hash = hash;
+                written += 1;
+            }
+        }
+        device.flush()?;
+        Ok(written)
+    }
+
+    /// Give the buffers back, keeping the capacity of the pool. After a failed read the whole
+    /// pool is dropped and the region is read again
+    ///
+    /// # Safety
+    ///
+    /// Nothing may point into the buffers, the filesystem must be borrowed exclusively
+    unsafe fn release(&mut self) -> Result<(), Error> {
+        if self.failed {
+            self.buffers.clear();
+            let mut region = core::mem::take(&mut self.region);
+            let read = self.device().read(0, &mut region);
+            self.region = region;
+            read?;
+            self.hash_region();
+            self.failed = false;
+            return Ok(());
+        }
+        if self.buffers.len() > self.capacity {
+            // Each handing out has its own use, the capacity last ones are kept
+            let mut uses: Vec<u64> = self
+                .buffers
+                .values()
+                .map(|buffer| buffer.last_use)
+                .collect();
+            uses.sort_unstable();
+            let oldest_kept = uses
+                .get(uses.len() - self.capacity)
+                .copied()
+                .unwrap_or(u64::MAX);
+            self.buffers
+                .retain(|_, buffer| buffer.last_use >= oldest_kept);
+        }
+        Ok(())
+    }
+
+    fn hash_region(&mut self) {
+        let block_size = self.block_size;
+        self.region_hashes = self
+            .region
+            .chunks(block_size)
+            .map(|block| unsafe { hash(block.as_ptr(), block_size) })
+            .collect();
+    }
+}
+
+impl<'device> FileSystem<'device> {
+    /// Open the filesystem of device, through a pool of buffers that keeps capacity blocks
+    /// between the flushes and reads at most limit blocks for the inodes, see the module
+    /// documentation. Fails like `Ext2Device::try_open`, or with DeviceFailed if the superblock
+    /// or the group descriptors can't be read.
+    ///
+    /// The changes reach device with `sync`, `flush_device` or when the filesystem is dropped
+    pub fn open_device<D: BlockDevice>(
+        device: &'device mut D,
+        capacity: usize,
+        limit: usize,
+    ) -> Result<Self, OpenError> {
+        Self::open_pool(device, capacity, limit, false)
+    }
+
+    /// Like open_device, but the filesystem is read-only, see
+    /// `Ext2Device::try_open_read_only`
+    pub fn open_device_read_only<D: BlockDevice>(
+        device: &'device mut D,
+        capacity: usize,
+        limit: usize,
+    ) -> Result<Self, OpenError> {
+        Self::open_pool(device, capacity, limit, true)
+    }
+
+    fn open_pool<D: BlockDevice>(
+        device: &'device mut D,
+        capacity: usize,
+        limit: usize,
+        read_only: bool,
+    ) -> Result<Self, OpenError> {
+        let sector = device.sector_size();
+        let mut head = vec![0; 2048usize.div_ceil(sector) * sector];
+        Device::read(device, 0, &mut head).map_err(|_| OpenError::DeviceFailed)?;
+        let (block_size, group_count) = unsafe {
+            let (superblock, _) = Superblock::from_ptr(head.as_mut_ptr().add(1024))?;
+            (
+                (*superblock).block_size(),
+                (*superblock).group_count() as usize,
+            )
+        };
+        if !block_size.is_multiple_of(sector) {
+            return Err(OpenError::InvalidGeometry("blocks are not made of sectors"));
+        }
+        // The descriptors are in the block after the superblock
+        let descriptors_end =
+            (1024 / block_size + 1) * block_size + group_count * BLOCK_GROUP_DESCRITPOR_SIZE;
+        let mut region = vec![0; descriptors_end.div_ceil(block_size) * block_size];
+        Device::read(device, 0, &mut region).map_err(|_| OpenError::DeviceFailed)?;
+
+        // The region is moved into the pool, its content stays where it is
+        let mut fs = unsafe { open_region(region.as_mut_ptr(), None, 1024, read_only)? };
+        let device = NonNull::from(device as &mut (dyn Device + 'device));
+        let mut pool = Pool {
+            // The filesystem keeps the borrow of 'device
+            device: unsafe {
+                core::mem::transmute::<NonNull<dyn Device + 'device>, NonNull<dyn Device>>(device)
+            },
+            block_size,
+            region,
+            region_hashes: Vec::new(),
+            buffers: BTreeMap::new(),
+            capacity,
+            limit,
+            uses: 0,
+            failed: false,
+            scratch: Vec::new(),
+        };
+        pool.hash_region();
+        fs.pool = Some(RefCell::new(pool));
+        Ok(fs)
+    }
+
+    /// Write the blocks that changed to the device of a filesystem opened with `open_device`
+    /// and flush it, then give back the buffers of the pool past its capacity. Returns the
+    /// number of blocks written, 0 for the other filesystems.
+    ///
+    /// DeviceFailed if the device failed: after a failed read the pool is dropped without
+    /// writing anything and the group descriptors are read again
+    pub fn flush_device(&mut self) -> Result<usize, Error> {
+        let pool = match &mut self.pool {
+            Some(pool) => pool.get_mut(),
+            None => return Ok(0),
+        };
+        let written = pool.write_back();
+        // The buffers that were not written are kept, unless they can't be
+        if written.is_ok() || pool.failed {
+            // The filesystem is borrowed exclusively, nothing points into the buffers
+            unsafe { pool.release()? };
+        }
+        written
+    }
+
+    /// The blocks held by the pool of a filesystem opened with `open_device`, without the
+    /// superblock and the group descriptors
+    pub fn pooled_blocks(&self) -> usize {
+        self.pool
+            .as_ref()
+            .map_or(0, |pool| pool.borrow().buffers.len())
+    }
+
+    /// Write the blocks of the pool that changed, for `sync`
+    pub(crate) fn write_back_pool(&self) -> Result<(), Error> {
+        match &self.pool {
+            Some(pool) => pool.borrow_mut().write_back().map(|_| ()),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use std::vec::Vec;
+
+    use crate::check::check_all;
+    use crate::device::{BlockDevice, CachedDevice, MemoryDevice, OutOfRange};
+    use crate::inode::Permission;
+    use crate::tests::load_image;
+    use crate::{Error, Ext2Device, FileSystem, OpenError};
+
+    /// A MemoryDevice whose reads fail past an offset
+    struct Failing<'memory> {
+        device: MemoryDevice<'memory>,
+        readable: u64,
+    }
+
+    impl BlockDevice for Failing<'_> {
+        type Error = OutOfRange;
+        fn sector_size(&self) -> usize {
+            512
+        }
+        fn read(&mut self, offset: u64, buffer: &mut [u8]) -> Result<(), OutOfRange> {
+            if offset + buffer.len() as u64 > self.readable {
+                return Err(OutOfRange);
+            }
+            self.device.read(offset, buffer)
+        }
+        fn write(&mut self, offset: u64, data: &[u8]) -> Result<(), OutOfRange> {
+            self.device.write(offset, data)
+        }
+        fn flush(&mut self) -> Result<(), OutOfRange> {
+            Ok(())
+        }
+    }
+
+    fn read(fs: &FileSystem<'_>, path: &[u8]) -> Vec<u8> {
+        let mut content = Vec::new();
+        fs.read_file(path, false, |chunk| {
+            content.extend_from_slice(chunk);
+            Ok::<_, ()>(())
+        })
+        .unwrap();
+        content
+    }
+
+    #[test]
+    fn open_device() {
+        let mut image = load_image("test_fs_indirect");
+        let mut expected = image.clone();
+        let big = read(
+            &Ext2Device::from_slice(&mut expected).unwrap().open(),
+            b"/big",
+        );
+
+        let mut memory = MemoryDevice::new(&mut image);
+        let mut fs = FileSystem::open_device(&mut memory, 4, 64).unwrap();
+        // The content of the files is not kept, only the inode table and the indirect blocks
+        assert_eq!(read(&fs, b"/big"), big);
+        assert!(fs.pooled_blocks() < 10);
+        assert_eq!(fs.flush_device(), Ok(0));
+        assert_eq!(fs.pooled_blocks(), 4);
+
+        let file = fs.create_file(b"/file", Permission::all(), 0, 0).unwrap();
+        fs.get_inode(file)
+            .unwrap()
+            .as_file()
+            .unwrap()
+            .write(&[7; 5000])
+            .unwrap();
+        fs.unlink(b"/big").unwrap();
+        assert_eq!(read(&fs, b"/file"), [7; 5000]);
+        // The new content is in the pool, the bitmaps, the inode table, the root directory
+        assert!(fs.flush_device().unwrap() > 5);
+        assert_eq!(fs.pooled_blocks(), 4);
+        assert_eq!(fs.flush_device(), Ok(0));
+        drop(fs);
+
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.open();
+        assert_eq!(read(&fs, b"/file"), [7; 5000]);
+        assert_eq!(fs.lookup_path(b"/big"), Err(Error::NotFound));
+        assert_eq!(
+            check_all(&fs, &mut |finding| panic!("{}", finding)).total(),
+            0
+        );
+    }
+
+    #[test]
+    fn cached_device() {
+        let mut image = load_image("test_fs_back");
+        let mut buffers = [0; 8 * 1024];
+        let write_backs = {
+            let mut cache =
+                CachedDevice::<_, 8>::new(MemoryDevice::new(&mut image), 1024, &mut buffers);
+            let fs = FileSystem::open_device(&mut cache, 0, 64).unwrap();
+            fs.create_dir(b"/dir", Permission::all(), 0, 0).unwrap();
+            let file = fs
+                .create_file(b"/dir/file", Permission::all(), 0, 0)
+                .unwrap();
+            let mut file = fs.get_inode(file).unwrap().as_file().unwrap();
+            file.write(b"through the cache").unwrap();
+            assert_eq!(read(&fs, b"/foo.txt"), b"ZING\n");
+            drop(file);
+            drop(fs);
+            cache.stats().write_backs
+        };
+        // Dropping the cache loses its dirty blocks, the filesystem flushed it when it was
+        // dropped
+        assert!(write_backs > 0);
+
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.open();
+        assert_eq!(read(&fs, b"/dir/file"), b"through the cache");
+        assert_eq!(
+            check_all(&fs, &mut |finding| panic!("{}", finding)).total(),
+            0
+        );
+    }
+
+    #[test]
+    fn readahead() {
+        // The stats of the cache after reading /big with a cursor with readahead
+        let read_big = |readahead| {
+            let mut image = load_image("test_fs_indirect");
+            let mut buffers = [0; 16 * 1024];
+            let mut cache =
+                CachedDevice::<_, 16>::new(MemoryDevice::new(&mut image), 1024, &mut buffers);
+            let fs = FileSystem::open_device(&mut cache, 0, 64).unwrap();
+            let big = fs.get_inode(fs.lookup_path(b"/big").unwrap()).unwrap();
+            let mut cursor = big.cursor().unwrap();
+            cursor.set_readahead(readahead);
+            let mut block = [0; 1024];
+            while cursor.read(&mut block) != 0 {}
+            drop(fs);
+            cache.stats()
+        };
+        let without = read_big(0);
+        // Less than half of the cache, the least recently used blocks are the prefetched ones
+        let with = read_big(4);
+        // The 300 blocks of content are read from the device in both cases, with the readahead
+        // they are prefetched and the reads of the cursor find them in the cache, but the first
+        assert_eq!(with.misses, without.misses);
+        assert_eq!(with.hits, without.hits + 300 - 1);
+    }
+
+    #[test]
+    fn transaction() {
+        let mut image = load_image("test_fs_back");
+        let mut memory = MemoryDevice::new(&mut image);
+        let mut fs = FileSystem::open_device(&mut memory, 16, 64).unwrap();
+
+        let transaction = fs.begin().unwrap();
+        transaction
+            .create_file(b"/aborted", Permission::all(), 0, 0)
+            .unwrap();
+        transaction.abort();
+        let transaction = fs.begin().unwrap();
+        transaction
+            .create_file(b"/committed", Permission::all(), 0, 0)
+            .unwrap();
+        assert!(transaction.commit() > 0);
+        fs.sync().unwrap();
+        drop(fs);
+
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.open();
+        assert!(fs.lookup_path(b"/committed").is_ok());
+        assert_eq!(fs.lookup_path(b"/aborted"), Err(Error::NotFound));
+        assert_eq!(
+            check_all(&fs, &mut |finding| panic!("{}", finding)).total(),
+            0
+        );
+    }
+
+    #[test]
+    fn limit() {
+        let mut image = load_image("test_fs_back");
+        let mut memory = MemoryDevice::new(&mut image);
+        let mut fs = FileSystem::open_device(&mut memory, 0, 1).unwrap();
+        // The block of the inode table is the only one the pool can hold for the inodes
+        let root = fs.get_root().block_at(0).unwrap();
+        assert_eq!(fs.pooled_blocks(), 1);
+        assert_eq!(unsafe { fs.checked_block(root) }, Err(Error::PoolFull));
+        // The bitmaps are still read
+        assert!(fs.reserve_block(root, true).is_some());
+        assert_eq!(fs.pooled_blocks(), 2);
+
+        assert!(fs.flush_device().unwrap() > 0);
+        assert_eq!(fs.pooled_blocks(), 0);
+        assert!(unsafe { fs.checked_block(root) }.is_ok());
+    }
+
+    #[test]
+    fn device_failure() {
+        let mut image = load_image("test_fs_back");
+        let len = image.len() as u64;
+        let mut failing = Failing {
+            device: MemoryDevice::new(&mut image),
+            readable: 1024,
+        };
+        assert_eq!(
+            FileSystem::open_device(&mut failing, 4, 64).err(),
+            Some(OpenError::DeviceFailed)
+        );
+
+        // The inode table of group 0 can be read, the root directory can't
+        let inode_table = 5 * 1024;
+        failing.readable = inode_table + 1024;
+        let mut fs = FileSystem::open_device(&mut failing, 4, 64).unwrap();
+        let before = fs.statistics(false);
+        // The first block of the root directory is not given as zeros
+        assert_eq!(unsafe { fs.checked_block(13) }, Err(Error::DeviceFailed));
+        assert!(fs.create_file(b"/file", Permission::all(), 0, 0).is_err());
+        assert_eq!(fs.sync(), Err(Error::DeviceFailed));
+        // Nothing was written, the descriptors are read again
+        assert_eq!(fs.flush_device(), Err(Error::DeviceFailed));
+        assert_eq!(fs.pooled_blocks(), 0);
+        assert_eq!(fs.statistics(false), before);
+        drop(fs);
+
+        failing.readable = len;
+        let fs = FileSystem::open_device(&mut failing, 4, 64).unwrap();
+        assert_eq!(fs.lookup_path(b"/file"), Err(Error::NotFound));
+        assert_eq!(
+            check_all(&fs, &mut |finding| panic!("{}", finding)).total(),
+            0
+        );
+    }
+}
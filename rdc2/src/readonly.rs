@@ -0,0 +1,263 @@
+//! A view of a filesystem that can only read it, see `FileSystem::into_read_only`.
+//!
+//! Nothing reachable from a `ReadOnlyFileSystem` writes to the device or to the cells of the
+//! filesystem: it is opened read-only, its directory cache and its dirty tracking are dropped
+//! and the methods of its inodes are the ones that only load. It is Send and Sync, its inodes
+//! and directory iterators too, so it can serve files from several threads without a lock.
+
+use core::cmp::min;
+
+use bstr::BStr;
+
+use super::inode::{DirectoryEntries, DirectoryEntry, EntryKind, Inode, InodeRef, Metadata};
+use super::{access, Error, FileSystem};
+
+/// A filesystem whose writes are unreachable, see the module documentation
+#[repr(transparent)]
+pub struct ReadOnlyFileSystem<'device> {
+    fs: FileSystem<'device>,
+}
+
+/// The device is borrowed exclusively by the filesystem for 'device, and only read
+unsafe impl Send for ReadOnlyFileSystem<'_> {}
+unsafe impl Sync for ReadOnlyFileSystem<'_> {}
+
+impl<'device> FileSystem<'device> {
+    /// Unmount the filesystem and keep a view of it that only reads, see `ReadOnlyFileSystem`.
+    ///
+    /// A filesystem opened with `open_device` fills its pool as it reads, it can't be shared:
+    /// UnsupportedFeature. Busy if a `Transaction` was leaked, the readers would use its staged
+    /// blocks
+    pub fn into_read_only(self) -> Result<ReadOnlyFileSystem<'device>, Error> {
+        #[cfg(feature = "alloc")]
+        if self.pool.is_some() {
+            return Err(Error::UnsupportedFeature(
+                "sharing a filesystem on a BlockDevice",
+            ));
+        }
+        #[cfg(feature = "alloc")]
+        if self.staged.is_some() {
+            return Err(Error::Busy);
+        }
+        self.unmount()?;
+        let mut fs = self;
+        fs.read_only = true;
+        fs.clock = None;
+        #[cfg(feature = "alloc")]
+        {
+            fs.dir_cache = None;
+            fs.dirty = None;
+        }
+        Ok(ReadOnlyFileSystem { fs })
+    }
+}
+
+impl<'device> ReadOnlyFileSystem<'device> {
+    /// The view of fs, for the holders of a lock that keeps the writers out. fs must not have
+    /// a directory cache nor dirty tracking
+    #[cfg(feature = "sync")]
+    pub(crate) fn view<'fs>(fs: &'fs FileSystem<'device>) -> &'fs Self {
+        // The view is transparent, and only reads
+        unsafe { &*(fs as *const FileSystem<'device> as *const Self) }
+    }
+
+    /// See `FileSystem::lookup_path`
+    pub fn lookup_path(&self, path: &[u8]) -> Result<InodeRef, Error> {
+        self.fs.lookup_path(path)
+    }
+    /// See `FileSystem::get_inode`
+    pub fn get_inode(&self, inode: InodeRef) -> Result<ReadOnlyInode<'_, 'device>, Error> {
+        Ok(ReadOnlyInode {
+            inode: self.fs.get_inode(inode)?,
+        })
+    }
+    /// See `FileSystem::read_file`
+    pub fn read_file<E>(
+        &self,
+        path: &[u8],
+        follow_symlink: bool,
+        sink: impl FnMut(&[u8]) -> Result<(), E>,
+    ) -> Result<u64, Error> {
+        self.fs.read_file(path, follow_symlink, sink)
+    }
+}
+
+/// An inode of a `ReadOnlyFileSystem`, see `Inode`
+pub struct ReadOnlyInode<'fs, 'device> {
+    inode: Inode<'fs, 'device>,
+}
+
+/// Like the filesystem it comes from
+unsafe impl Send for ReadOnlyInode<'_, '_> {}
+unsafe impl Sync for ReadOnlyInode<'_, '_> {}
+
+impl<'fs, 'device> ReadOnlyInode<'fs, 'device> {
+    pub fn inode_ref(&self) -> InodeRef {
+        self.inode.inode_ref()
+    }
+    pub fn file_type(&self) -> EntryKind {
+        self.inode.file_type()
+    }
+    pub fn is_dir(&self) -> bool {
+        self.inode.is_dir()
+    }
+    pub fn size(&self) -> u32 {
+        self.inode.size()
+    }
+    pub fn metadata(&self) -> Metadata {
+        self.inode.metadata()
+    }
+    /// Read the content at offset into buffer, returns the number of bytes read, 0 past the
+    /// end. Holes, and pointers out of the filesystem, are read as zeros. Unlike a `Cursor` it
+    /// keeps no position, every indirect block is followed
+    pub fn read_at(&self, offset: u32, buffer: &mut [u8]) -> usize {
+        let fs = self.inode.fs;
+        let end = min(
+            u64::from(self.size()),
+            u64::from(offset) + buffer.len() as u64,
+        ) as u32;
+        let block_size = fs.block_size as u32;
+        let mut position = offset;
+        while position < end {
+            let start = position % block_size;
+            let len = min(block_size - start, end - position) as usize;
+            let chunk = &mut buffer[(position - offset) as usize..][..len];
+            let data = self
+                .inode
+                .block_at(position / block_size)
+                .and_then(|block| unsafe { fs.checked_block(block) }.ok());
+            match data {
+                Some(data) => unsafe {
+                    access::copy_from_device(data.add(start as usize), chunk.as_mut_ptr(), len)
+                },
+                None => chunk.iter_mut().for_each(|byte| *byte = 0),
+            }
+            position += len as u32;
+        }
+        position.saturating_sub(offset) as usize
+    }
+    /// See `Inode::read_all`
+    pub fn read_all<E>(&self, sink: impl FnMut(&[u8]) -> Result<(), E>) -> Result<u64, Error> {
+        self.inode.read_all(sink)
+    }
+    /// See `Inode::read_link`
+    pub fn read_link(&self) -> Result<&'fs BStr, Error> {
+        self.inode.read_link()
+    }
+    /// The entries of the directory, None if this is not one
+    pub fn entries(&self) -> Option<ReadOnlyEntries<'_, 'fs, 'device>> {
+        Some(ReadOnlyEntries {
+            entries: self.inode.get_dir_entries()?,
+        })
+    }
+    /// See `Inode::find_entry`
+    pub fn find_entry(&self, name: &[u8]) -> Option<DirectoryEntry<'fs>> {
+        self.inode.find_entry(name)
+    }
+    /// See `Inode::get_xattr`
+    pub fn get_xattr(&self, name: &[u8]) -> Result<Option<&'fs [u8]>, Error> {
+        self.inode.get_xattr(name)
+    }
+}
+
+/// The entries of a directory of a `ReadOnlyFileSystem`, see `DirectoryEntries`
+pub struct ReadOnlyEntries<'inode, 'fs, 'device> {
+    entries: DirectoryEntries<'inode, 'fs, 'device>,
+}
+
+/// Like the filesystem it comes from
+unsafe impl Send for ReadOnlyEntries<'_, '_, '_> {}
+
+impl<'fs> Iterator for ReadOnlyEntries<'_, 'fs, '_> {
+    type Item = DirectoryEntry<'fs>;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.entries.next()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use std::vec::Vec;
+
+    use core::cmp::min;
+
+    use super::ReadOnlyFileSystem;
+    use crate::inode::{InodeRef, Permission};
+    use crate::tests::load_image;
+    use crate::{Ext2Device, OpenOptions};
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    #[test]
+    fn concurrent_readers() {
+        assert_send_sync::<ReadOnlyFileSystem<'_>>();
+        assert_send_sync::<super::ReadOnlyInode<'_, '_>>();
+
+        let mut image = load_image("test_fs_indirect");
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.open();
+        let mut big = Vec::new();
+        fs.read_file(b"/big", false, |data| {
+            big.extend_from_slice(data);
+            Ok::<_, ()>(())
+        })
+        .unwrap();
+        for file in 0..4u8 {
+            let path = [b'/', b'a' + file];
+            fs.create_file(&path, Permission::all(), 0, 0).unwrap();
+            fs.open(&path, OpenOptions::new().write(true))
+                .unwrap()
+                .write(&[file; 5000])
+                .unwrap();
+        }
+        let fs = fs.into_read_only().unwrap();
+        assert!(fs.fs.is_read_only());
+
+        std::thread::scope(|scope| {
+            for thread in 0..8u8 {
+                let (fs, big) = (&fs, &big);
+                scope.spawn(move || {
+                    for round in 0..20u32 {
+                        let root = fs.get_inode(InodeRef::root()).unwrap();
+                        assert_eq!(root.entries().unwrap().count(), 2 + 2 + 4);
+                        if thread % 2 == 0 {
+                            // Through the indirect blocks
+                            let inode = fs.get_inode(fs.lookup_path(b"/big").unwrap()).unwrap();
+                            let offset = (u32::from(thread) * 20 + round) * 1531;
+                            let mut chunk = [0; 3000];
+                            let len = inode.read_at(offset, &mut chunk);
+                            let expected = &big[offset as usize..][..len];
+                            assert_eq!(len, min(3000, big.len() - offset as usize));
+                            assert!(chunk[..len] == *expected);
+                        } else {
+                            let file = thread / 2;
+                            let path = [b'/', b'a' + file];
+                            let inode = root.find_entry(&path[1..]).unwrap().inode;
+                            let inode = fs.get_inode(inode).unwrap();
+                            let mut content = [0; 6000];
+                            assert_eq!(inode.read_at(0, &mut content), 5000);
+                            assert!(content[..5000].iter().all(|&byte| byte == file));
+                            assert_eq!(inode.read_at(4990, &mut content), 10);
+                            assert_eq!(inode.read_at(1 << 30, &mut content), 0);
+                        }
+                    }
+                });
+            }
+        });
+
+        let inode = fs.get_inode(fs.lookup_path(b"/a").unwrap()).unwrap();
+        assert!(inode.entries().is_none());
+        assert_eq!(inode.metadata().size, 5000);
+    }
+
+    #[test]
+    #[cfg(feature = "alloc")]
+    fn leaked_transaction() {
+        let mut image = load_image("test_fs");
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let mut fs = device.open();
+        core::mem::forget(fs.begin().unwrap());
+        assert!(matches!(fs.into_read_only(), Err(crate::Error::Busy)));
+    }
+}
@@ -0,0 +1,95 @@
+//! The inodes open as files, with the `exclusive` feature.
+//!
+//! Every `File` holds a pointer to the data of its inode, two files writing the same inode would
+//! race their updates of the size, the pointers and the times. The registry counts the files of
+//! each inode: an inode is open by any number of readers, or by one writer. The changes made to
+//! an inode outside of its files, like `Inode::truncate`, need it closed.
+
+use core::cell::Cell;
+
+use super::Error;
+
+/// The number of inodes that can be open at the same time
+pub const MAX_OPEN_INODES: usize = 32;
+
+#[derive(Debug, Clone, Copy)]
+struct Slot {
+    /// 0 for a free slot
+    inode: u32,
+    readers: u16,
+    writer: bool,
+}
+
+impl Slot {
+    const FREE: Slot = Slot {
+        inode: 0,
+        readers: 0,
+        writer: false,
+    };
+}
+
+pub(crate) struct OpenInodes {
+    slots: [Cell<Slot>; MAX_OPEN_INODES],
+}
+
+impl OpenInodes {
+    pub(crate) fn new() -> Self {
+        OpenInodes {
+            slots: core::array::from_fn(|_| Cell::new(Slot::FREE)),
+        }
+    }
+
+    /// Record a file of inode, Busy if the inode is open by a writer, or by anyone for a writer.
+    /// TooManyOpenFiles when `MAX_OPEN_INODES` other inodes are open
+    pub(crate) fn acquire(&self, inode: u32, writer: bool) -> Result<(), Error> {
+        let slot = match self.slots.iter().find(|slot| slot.get().inode == inode) {
+            Some(slot) => slot,
+            None => self
+                .slots
+                .iter()
+                .find(|slot| slot.get().inode == 0)
+                .ok_or(Error::TooManyOpenFiles)?,
+        };
+        let mut open = slot.get();
+        if open.writer || (writer && open.readers != 0) {
+            log::trace!("Inode {} is already open", inode);
+            return Err(Error::Busy);
+        }
+        open.inode = inode;
+        if writer {
+            open.writer = true;
+        } else {
+            open.readers = open.readers.checked_add(1).ok_or(Error::TooManyOpenFiles)?;
+        }
+        slot.set(open);
+        Ok(())
+    }
+
+    /// Busy if inode is open by a file, for the changes made outside of the files
+    pub(crate) fn check_closed(&self, inode: u32) -> Result<(), Error> {
+        if self.slots.iter().any(|slot| slot.get().inode == inode) {
+            log::trace!("Inode {} is open", inode);
+            return Err(Error::Busy);
+        }
+        Ok(())
+    }
+
+    /// Forget a file recorded by acquire
+    pub(crate) fn release(&self, inode: u32, writer: bool) {
+        let slot = self
+            .slots
+            .iter()
+            .find(|slot| slot.get().inode == inode)
+            .expect("releasing an inode that is not open");
+        let mut open = slot.get();
+        if writer {
+            open.writer = false;
+        } else {
+            open.readers -= 1;
+        }
+        if !open.writer && open.readers == 0 {
+            open = Slot::FREE;
+        }
+        slot.set(open);
+    }
+}
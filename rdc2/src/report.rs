@@ -0,0 +1,413 @@
+//! The findings of the consistency checks, see `check::CheckReport::findings`.
+//!
+//! A finding is a single problem with the group, the inode and the block it concerns. They can
+//! be kept in a `FindingBuffer` without an allocator, or streamed to any `FindingSink`.
+
+use core::fmt;
+
+use super::InodeRef;
+
+/// How bad a finding is
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    /// Wrong counters or resources that are lost, the files are intact
+    Warning,
+    /// Metadata that can lose or corrupt the content of files
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Warning => f.write_str("warning"),
+            Severity::Error => f.write_str("error"),
+        }
+    }
+}
+
+/// What is wrong with a record of a directory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DirectoryProblem {
+    /// The block holding the offset is not mapped
+    Hole,
+    /// The record is shorter than its header and name, its size is not a multiple of 4 bytes or
+    /// it crosses the end of its block. The rest of the block can't be read
+    BadRecord,
+    /// The first record is not '.' referencing the directory
+    BadDot,
+    /// The second record is not '..'
+    MissingDotDot,
+    /// '..' references an inode that is not a directory listing this one
+    WrongParent(InodeRef),
+    /// A record references inode 0 in the middle of a block. Deleted entries are merged with the
+    /// previous record, only the first one of a block is kept with inode 0
+    ZeroInode,
+    /// The record references a directory that another record already lists
+    CrossLinked {
+        child: InodeRef,
+        first_parent: InodeRef,
+    },
+}
+
+impl fmt::Display for DirectoryProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DirectoryProblem::Hole => f.write_str("unmapped block"),
+            DirectoryProblem::BadRecord => f.write_str("corrupted record"),
+            DirectoryProblem::BadDot => f.write_str("first record is not '.'"),
+            DirectoryProblem::MissingDotDot => f.write_str("second record is not '..'"),
+            DirectoryProblem::WrongParent(parent) => {
+                write!(f, "'..' is inode {} which does not list it", parent.0)
+            }
+            DirectoryProblem::ZeroInode => f.write_str("record of inode 0"),
+            DirectoryProblem::CrossLinked {
+                child,
+                first_parent,
+            } => write!(
+                f,
+                "directory {} is already listed by {}",
+                child.0, first_parent.0
+            ),
+        }
+    }
+}
+
+/// The kinds of findings, with the values that don't identify where the problem is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FindingKind {
+    /// The block is marked in the bitmap but not in use
+    LeakedBlock,
+    /// The block is in use but free in the bitmap
+    UnmarkedBlock,
+    /// The free blocks of the group, or of the superblock without a group
+    FreeBlockCount {
+        recorded: u32,
+        expected: u32,
+    },
+    /// The inode references a block out of the filesystem
+    BlockOutOfRange,
+    LinkCount {
+        recorded: u16,
+        expected: u32,
+    },
+    /// The inode is referenced by an entry but free in the bitmap
+    UnmarkedInode,
+    /// The inode is in use but no entry references it
+    Orphan,
+    /// The directory has an entry referencing an inode out of the filesystem
+    BadEntry {
+        entry: u32,
+    },
+    /// The free inodes of the group, or of the superblock without a group
+    FreeInodeCount {
+        recorded: u32,
+        expected: u32,
+    },
+    /// The block is referenced more than once, there is a finding for each reference
+    DuplicateBlock,
+    Directory {
+        offset: u32,
+        problem: DirectoryProblem,
+    },
+}
+
+impl FindingKind {
+    pub fn severity(&self) -> Severity {
+        match self {
+            FindingKind::LeakedBlock
+            | FindingKind::FreeBlockCount { .. }
+            | FindingKind::LinkCount { .. }
+            | FindingKind::Orphan
+            | FindingKind::FreeInodeCount { .. } => Severity::Warning,
+            FindingKind::UnmarkedBlock
+            | FindingKind::BlockOutOfRange
+            | FindingKind::UnmarkedInode
+            | FindingKind::BadEntry { .. }
+            | FindingKind::DuplicateBlock
+            | FindingKind::Directory { .. } => Severity::Error,
+        }
+    }
+}
+
+impl fmt::Display for FindingKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FindingKind::LeakedBlock => f.write_str("marked as used but not in use"),
+            FindingKind::UnmarkedBlock => f.write_str("in use but marked as free"),
+            FindingKind::FreeBlockCount { recorded, expected } => {
+                write!(
+                    f,
+                    "{} free blocks recorded, {} expected",
+                    recorded, expected
+                )
+            }
+            FindingKind::BlockOutOfRange => f.write_str("referenced but out of the filesystem"),
+            FindingKind::LinkCount { recorded, expected } => {
+                write!(f, "link count is {}, {} expected", recorded, expected)
+            }
+            FindingKind::UnmarkedInode => f.write_str("referenced but marked as free"),
+            FindingKind::Orphan => f.write_str("in use but not referenced"),
+            FindingKind::BadEntry { entry } => {
+                write!(f, "entry of inode {} out of the filesystem", entry)
+            }
+            FindingKind::FreeInodeCount { recorded, expected } => {
+                write!(
+                    f,
+                    "{} free inodes recorded, {} expected",
+                    recorded, expected
+                )
+            }
+            FindingKind::DuplicateBlock => f.write_str("referenced more than once"),
+            FindingKind::Directory { offset, problem } => {
+                write!(f, "at offset {}: {}", offset, problem)
+            }
+        }
+    }
+}
+
+/// A problem found by a check
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Finding {
+    pub severity: Severity,
+    pub group: Option<u32>,
+    pub inode: Option<InodeRef>,
+    pub block: Option<u32>,
+    pub kind: FindingKind,
+}
+
+impl Finding {
+    /// A finding of kind, with its severity, that is not tied to a group, inode or block yet
+    pub fn new(kind: FindingKind) -> Self {
+        Finding {
+            severity: kind.severity(),
+            group: None,
+            inode: None,
+            block: None,
+            kind,
+        }
+    }
+    pub fn group(mut self, group: u32) -> Self {
+        self.group = Some(group);
+        self
+    }
+    pub fn inode(mut self, inode: InodeRef) -> Self {
+        self.inode = Some(inode);
+        self
+    }
+    pub fn block(mut self, block: u32) -> Self {
+        self.block = Some(block);
+        self
+    }
+}
+
+/// `error: group 0, inode 12: link count is 2, 1 expected`, the superblock is named when there
+/// is neither a group, an inode nor a block
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: ", self.severity)?;
+        let mut separator = "";
+        if let Some(group) = self.group {
+            write!(f, "group {}", group)?;
+            separator = ", ";
+        }
+        if let Some(inode) = self.inode {
+            write!(f, "{}inode {}", separator, inode.0)?;
+            separator = ", ";
+        }
+        if let Some(block) = self.block {
+            write!(f, "{}block {}", separator, block)?;
+            separator = ", ";
+        }
+        if separator.is_empty() {
+            f.write_str("superblock")?;
+        }
+        write!(f, ": {}", self.kind)
+    }
+}
+
+/// The number of findings of each severity
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SeverityCounts {
+    pub warnings: u32,
+    pub errors: u32,
+}
+
+impl SeverityCounts {
+    pub fn add(&mut self, severity: Severity) {
+        match severity {
+            Severity::Warning => self.warnings += 1,
+            Severity::Error => self.errors += 1,
+        }
+    }
+    pub fn total(&self) -> u32 {
+        self.warnings + self.errors
+    }
+    pub fn merge(&mut self, other: SeverityCounts) {
+        self.warnings += other.warnings;
+        self.errors += other.errors;
+    }
+}
+
+/// Where the checks send their findings, closures taking a `Finding` are sinks
+pub trait FindingSink {
+    fn report(&mut self, finding: Finding);
+}
+
+impl<F: FnMut(Finding)> FindingSink for F {
+    fn report(&mut self, finding: Finding) {
+        self(finding)
+    }
+}
+
+/// Keeps the first N findings it is given and counts all of them
+#[derive(Debug, Clone)]
+pub struct FindingBuffer<const N: usize> {
+    findings: [Option<Finding>; N],
+    len: usize,
+    counts: SeverityCounts,
+}
+
+impl<const N: usize> FindingBuffer<N> {
+    pub fn new() -> Self {
+        FindingBuffer {
+            findings: [None; N],
+            len: 0,
+            counts: SeverityCounts::default(),
+        }
+    }
+    /// The findings that were kept, in the order they were given
+    pub fn findings(&self) -> impl Iterator<Item = &Finding> + '_ {
+        self.findings[..self.len].iter().flatten()
+    }
+    /// The counts of all the findings given, including the ones that were not kept
+    pub fn counts(&self) -> SeverityCounts {
+        self.counts
+    }
+    /// The findings that were given when the buffer was full
+    pub fn dropped(&self) -> u32 {
+        self.counts.total() - self.len as u32
+    }
+    pub fn is_clean(&self) -> bool {
+        self.counts.total() == 0
+    }
+    /// Add the findings of other after those of this buffer, the ones that don't fit are only
+    /// counted
+    pub fn merge<const M: usize>(&mut self, other: &FindingBuffer<M>) {
+        for finding in other.findings() {
+            self.keep(*finding);
+        }
+        self.counts.merge(other.counts);
+    }
+    fn keep(&mut self, finding: Finding) {
+        if self.len < N {
+            self.findings[self.len] = Some(finding);
+            self.len += 1;
+        }
+    }
+}
+
+impl<const N: usize> Default for FindingBuffer<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> FindingSink for FindingBuffer<N> {
+    fn report(&mut self, finding: Finding) {
+        self.keep(finding);
+        self.counts.add(finding.severity);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use std::string::ToString;
+    use std::vec::Vec;
+
+    use super::{
+        DirectoryProblem, Finding, FindingBuffer, FindingKind, FindingSink, Severity,
+        SeverityCounts,
+    };
+    use crate::InodeRef;
+
+    #[test]
+    fn format() {
+        let finding = Finding::new(FindingKind::LinkCount {
+            recorded: 2,
+            expected: 1,
+        })
+        .group(0)
+        .inode(InodeRef(12));
+        assert_eq!(
+            finding.to_string(),
+            "warning: group 0, inode 12: link count is 2, 1 expected"
+        );
+        let finding = Finding::new(FindingKind::UnmarkedBlock).group(1).block(300);
+        assert_eq!(
+            finding.to_string(),
+            "error: group 1, block 300: in use but marked as free"
+        );
+        let finding = Finding::new(FindingKind::FreeInodeCount {
+            recorded: 3,
+            expected: 4,
+        });
+        assert_eq!(
+            finding.to_string(),
+            "warning: superblock: 3 free inodes recorded, 4 expected"
+        );
+        let finding = Finding::new(FindingKind::Directory {
+            offset: 12,
+            problem: DirectoryProblem::WrongParent(InodeRef(13)),
+        })
+        .inode(InodeRef(14));
+        assert_eq!(
+            finding.to_string(),
+            "error: inode 14: at offset 12: '..' is inode 13 which does not list it"
+        );
+    }
+
+    #[test]
+    fn capacity() {
+        let mut buffer = FindingBuffer::<2>::new();
+        assert!(buffer.is_clean());
+        buffer.report(Finding::new(FindingKind::Orphan).inode(InodeRef(12)));
+        buffer.report(Finding::new(FindingKind::LeakedBlock).block(100));
+        buffer.report(Finding::new(FindingKind::DuplicateBlock).block(101));
+        assert!(!buffer.is_clean());
+        assert_eq!(buffer.dropped(), 1);
+        assert_eq!(
+            buffer.counts(),
+            SeverityCounts {
+                warnings: 2,
+                errors: 1,
+            }
+        );
+        let kinds: Vec<_> = buffer.findings().map(|finding| finding.kind).collect();
+        assert_eq!(kinds, [FindingKind::Orphan, FindingKind::LeakedBlock]);
+
+        // Nothing is kept without capacity, everything is counted
+        let mut empty = FindingBuffer::<0>::new();
+        empty.report(Finding::new(FindingKind::UnmarkedInode));
+        assert_eq!(empty.dropped(), 1);
+        assert_eq!(empty.counts().errors, 1);
+
+        let mut merged = FindingBuffer::<4>::new();
+        merged.report(Finding::new(FindingKind::BlockOutOfRange));
+        merged.merge(&buffer);
+        merged.merge(&empty);
+        assert_eq!(merged.findings().count(), 3);
+        assert_eq!(merged.dropped(), 2);
+        assert_eq!(merged.counts().total(), 5);
+        merged.merge(&buffer);
+        assert_eq!(merged.findings().count(), 4);
+        assert_eq!(merged.dropped(), 4);
+
+        // Closures are sinks
+        let mut severities = Vec::new();
+        let mut sink = |finding: Finding| severities.push(finding.severity);
+        sink.report(Finding::new(FindingKind::LeakedBlock));
+        sink.report(Finding::new(FindingKind::BadEntry { entry: 0 }));
+        assert_eq!(severities, [Severity::Warning, Severity::Error]);
+    }
+}
@@ -0,0 +1,533 @@
+//! Growing and shrinking a filesystem in place, like resize2fs on an unmounted filesystem.
+//!
+//! The groups are laid out like `mkfs::format` and mke2fs do: the superblock and descriptor
+//! backups first, then the bitmaps and the inode table. The descriptors must fit in the blocks
+//! of the group descriptor table: the reserved descriptor blocks of
+//! `OptionalFeatures::RESIZEABLE` are kept for the groups, but not used to extend the table.
+
+use super::inode::InodeRef;
+use super::metadata::{
+    BlockGroupDescriptor, OptionalFeatures, WriteFeatures, BLOCK_GROUP_DESCRITPOR_SIZE,
+};
+use super::mkfs::{set_bits, MIN_LAST_GROUP_BLOCKS};
+use super::{access, block_offset, find_bit, superblock_groups, Error, FileSystem};
+
+/// The inode holding the reserved group descriptor blocks
+const RESIZE_INODE: u32 = 7;
+
+/// The sizes that stay the same when the filesystem is resized
+pub(crate) struct Geometry {
+    block_size: u32,
+    first_block: u32,
+    blocks_per_group: u32,
+    inodes_per_group: u32,
+    sparse: bool,
+    descriptor_blocks: u32,
+    reserved_descriptor_blocks: u32,
+    inode_table_blocks: u32,
+}
+
+impl Geometry {
+    pub(crate) fn first_block_of_group(&self, group: u32) -> u32 {
+        self.first_block + group * self.blocks_per_group
+    }
+    pub(crate) fn blocks_in_group(&self, group: u32, block_count: u32) -> u32 {
+        core::cmp::min(
+            block_count - self.first_block_of_group(group),
+            self.blocks_per_group,
+        )
+    }
+    /// The groups needed for block_count blocks
+    fn group_count(&self, block_count: u32) -> u32 {
+        (block_count - self.first_block).div_ceil(self.blocks_per_group)
+    }
+    /// The blocks at the start of group used by the copy of the superblock and of the group
+    /// descriptors, with the reserved descriptor blocks. 0 if the group has no copy
+    pub(crate) fn backup_blocks(&self, group: u32) -> u32 {
+        if superblock_groups(group + 1, self.sparse).any(|sb| sb == group) {
+            1 + self.descriptor_blocks + self.reserved_descriptor_blocks
+        } else {
+            0
+        }
+    }
+    /// The blocks at the start of group used by its metadata
+    fn overhead(&self, group: u32) -> u32 {
+        self.backup_blocks(group) + 2 + self.inode_table_blocks
+    }
+    /// Whether the last group of a filesystem of block_count blocks is worth keeping
+    fn last_group_fits(&self, block_count: u32) -> bool {
+        let last = self.group_count(block_count) - 1;
+        self.blocks_in_group(last, block_count) >= self.overhead(last) + MIN_LAST_GROUP_BLOCKS
+    }
+}
+
+impl FileSystem<'_> {
+    pub(crate) fn geometry(&self) -> Geometry {
+        let superblock = self.get_superblock();
+        let extended = self.get_extended_superblock();
+        let block_size = self.block_size as u32;
+        let reserved_descriptor_blocks =
+            if { extended.optional_features }.contains(OptionalFeatures::RESIZEABLE) {
+                u32::from(extended.reserved_gdt_blocks)
+            } else {
+                0
+            };
+        Geometry {
+            block_size,
+            first_block: superblock.index_of_superblock,
+            blocks_per_group: superblock.block_count_in_group,
+            inodes_per_group: superblock.inode_count_in_group,
+            sparse: { extended.write_features }
+                .contains(WriteFeatures::SPARSE_SUPERBLOCK_GROUP_DESCRIPTOR_TABLE),
+            descriptor_blocks: (self.block_group_descriptor_table_len as u32
+                * BLOCK_GROUP_DESCRITPOR_SIZE as u32)
+                .div_ceil(block_size),
+            reserved_descriptor_blocks,
+            inode_table_blocks: (superblock.inode_count_in_group
+                * u32::from(extended.inode_struct_size))
+            .div_ceil(block_size),
+        }
+    }
+
+    /// Grow the filesystem to new_block_count blocks, the free blocks and inodes of the new
+    /// groups can be used right away. The superblock and the group descriptors are synced with
+    /// `sync`.
+    ///
+    /// The new last group is dropped if it is too small to hold its metadata and some blocks,
+    /// like resize2fs does, so the filesystem may end up smaller than asked. InvalidArgument if
+    /// the filesystem would shrink and UnsupportedFeature if the group descriptors need more
+    /// blocks than the table has.
+    ///
+    /// # Safety
+    ///
+    /// The device must be valid for new_block_count blocks
+    pub unsafe fn grow(&mut self, new_block_count: u32) -> Result<(), Error> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+        let old_block_count = self.get_superblock().block_count;
+        if new_block_count < old_block_count {
+            return Err(Error::InvalidArgument);
+        }
+        block_offset(self.block_size, new_block_count)?;
+
+        let geometry = self.geometry();
+        let old_group_count = self.block_group_descriptor_table_len as u32;
+        let mut block_count = new_block_count;
+        let mut group_count = geometry.group_count(block_count);
+        if group_count > old_group_count && !geometry.last_group_fits(block_count) {
+            group_count -= 1;
+            block_count = geometry.first_block_of_group(group_count);
+        }
+        if block_count == old_block_count {
+            return Ok(());
+        }
+        if (group_count * BLOCK_GROUP_DESCRITPOR_SIZE as u32).div_ceil(geometry.block_size)
+            > geometry.descriptor_blocks
+        {
+            return Err(Error::UnsupportedFeature(
+                "growing past the group descriptor blocks",
+            ));
+        }
+        let inodes_per_group = geometry.inodes_per_group;
+        let added_inodes = (group_count - old_group_count) * inodes_per_group;
+        let inode_count = { self.get_superblock().inode_count }
+            .checked_add(added_inodes)
+            .ok_or(Error::InvalidArgument)?;
+        log::trace!(
+            "Growing from {} to {} blocks, {} to {} groups",
+            old_block_count,
+            block_count,
+            old_group_count,
+            group_count
+        );
+        self.update_reserved_descriptor_backups(&geometry, old_group_count..group_count, true)?;
+        // The new descriptors fit in the descriptor blocks, nothing fails past this point
+        self.block_group_descriptor_table_len = group_count as usize;
+
+        let blocks_in_group = |group| geometry.blocks_in_group(group, block_count);
+        let mut added_free_blocks = 0;
+
+        // The blocks past the old end of the last group were marked as used in its bitmap
+        let last = old_group_count - 1;
+        let old_end = geometry.blocks_in_group(last, old_block_count);
+        let added = blocks_in_group(last) - old_end;
+        let bitmap = self.get_block(
+            self.get_block_group_descriptor_table()[last as usize].block_address_of_block_bitmap,
+        );
+        for index in old_end..blocks_in_group(last) {
+            access::modify(bitmap.add(index as usize / 8), |byte| {
+                *byte &= !(1 << (index % 8))
+            });
+        }
+        self.update_group_descriptor(last, |descriptor| {
+            descriptor.unallocated_blocks_in_group += added as u16
+        });
+        added_free_blocks += added;
+
+        let bits_in_block = 8 * geometry.block_size;
+        for group in old_group_count..group_count {
+            let first = geometry.first_block_of_group(group);
+            let overhead = geometry.overhead(group);
+            access::fill(
+                self.get_block(first),
+                0,
+                (overhead * geometry.block_size) as usize,
+            );
+            // Like format, the bits past the end of the group are set
+            let bitmap = first + overhead - 2 - geometry.inode_table_blocks;
+            set_bits(self.get_block(bitmap), 0..overhead);
+            set_bits(
+                self.get_block(bitmap),
+                blocks_in_group(group)..bits_in_block,
+            );
+            set_bits(self.get_block(bitmap + 1), inodes_per_group..bits_in_block);
+
+            let free_blocks = blocks_in_group(group) - overhead;
+            added_free_blocks += free_blocks;
+            self.update_group_descriptor(group, |descriptor| {
+                *descriptor = BlockGroupDescriptor::empty();
+                descriptor.block_address_of_block_bitmap = bitmap;
+                descriptor.block_address_of_inode_bitmap = bitmap + 1;
+                descriptor.starting_block_of_inode_table = bitmap + 2;
+                descriptor.unallocated_blocks_in_group = free_blocks as u16;
+                descriptor.unallocated_inodes_in_group = inodes_per_group as u16;
+            });
+        }
+
+        self.update_superblock(|superblock| {
+            // The blocks kept for the superuser stay in the same proportion
+            superblock.block_superuser = (u64::from(superblock.block_superuser)
+                * u64::from(block_count)
+                / u64::from(old_block_count)) as u32;
+            superblock.block_count = block_count;
+            superblock.inode_count = inode_count;
+            superblock.unallocated_blocks += added_free_blocks;
+            superblock.unallocated_inodes += added_inodes;
+        });
+
+        self.sync()
+    }
+
+    /// Shrink the filesystem to new_block_count blocks, the groups past it are removed and the
+    /// last group is cut. The superblock and the group descriptors are synced with `sync`.
+    ///
+    /// Nothing is moved: BlockInUse or InodeInUse if a block past the new end or an inode of a
+    /// removed group is in use. InvalidArgument if the filesystem would grow or if the new last
+    /// group does not hold its metadata and 50 blocks, the end is not rounded.
+    /// UnsupportedFeature if the group descriptor table would need less blocks, as they would be
+    /// left in the middle of group 0
+    pub fn shrink(&mut self, new_block_count: u32) -> Result<(), Error> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+        let geometry = self.geometry();
+        let old_block_count = self.get_superblock().block_count;
+        if new_block_count > old_block_count || new_block_count <= geometry.first_block {
+            return Err(Error::InvalidArgument);
+        }
+        if new_block_count == old_block_count {
+            return Ok(());
+        }
+        if !geometry.last_group_fits(new_block_count) {
+            return Err(Error::InvalidArgument);
+        }
+        let old_group_count = self.block_group_descriptor_table_len as u32;
+        let group_count = geometry.group_count(new_block_count);
+        if (group_count * BLOCK_GROUP_DESCRITPOR_SIZE as u32).div_ceil(geometry.block_size)
+            < geometry.descriptor_blocks
+        {
+            return Err(Error::UnsupportedFeature(
+                "shrinking the group descriptor blocks",
+            ));
+        }
+
+        // The metadata of the removed groups goes with them, everything else must be free
+        let descriptors = self.get_block_group_descriptor_table();
+        for group in group_count..old_group_count {
+            let descriptor = &descriptors[group as usize];
+            let bitmap = unsafe { self.get_block(descriptor.block_address_of_inode_bitmap) };
+            if let Some(index) = find_bit(bitmap, 0, geometry.inodes_per_group, true) {
+                let inode = group * geometry.inodes_per_group + index + 1;
+                log::trace!("Inode {} is in a removed group", inode);
+                return Err(Error::InodeInUse(InodeRef(inode)));
+            }
+        }
+        for group in group_count - 1..old_group_count {
+            let first = if group == group_count - 1 {
+                geometry.blocks_in_group(group, new_block_count)
+            } else {
+                geometry.overhead(group)
+            };
+            let bitmap = unsafe {
+                self.get_block(descriptors[group as usize].block_address_of_block_bitmap)
+            };
+            let len = geometry.blocks_in_group(group, old_block_count);
+            if let Some(index) = find_bit(bitmap, first, len, true) {
+                let block = geometry.first_block_of_group(group) + index;
+                log::trace!("Block {} is past the new end", block);
+                return Err(Error::BlockInUse(block));
+            }
+        }
+        log::trace!(
+            "Shrinking from {} to {} blocks, {} to {} groups",
+            old_block_count,
+            new_block_count,
+            old_group_count,
+            group_count
+        );
+        unsafe {
+            self.update_reserved_descriptor_backups(&geometry, group_count..old_group_count, false)?
+        };
+
+        // The blocks past the end of the last group are marked as used, like format does
+        let last = group_count - 1;
+        let new_end = geometry.blocks_in_group(last, new_block_count);
+        let removed = geometry.blocks_in_group(last, old_block_count) - new_end;
+        unsafe {
+            let bitmap = self.get_block(descriptors[last as usize].block_address_of_block_bitmap);
+            set_bits(bitmap, new_end..8 * geometry.block_size);
+        }
+        for group in group_count..old_group_count {
+            self.update_group_descriptor(group, |descriptor| {
+                *descriptor = BlockGroupDescriptor::empty()
+            });
+        }
+        self.update_group_descriptor(last, |descriptor| {
+            descriptor.unallocated_blocks_in_group -= removed as u16
+        });
+        // The free counts are recomputed by sync
+        self.update_superblock(|superblock| {
+            superblock.block_superuser = (u64::from(superblock.block_superuser)
+                * u64::from(new_block_count)
+                / u64::from(old_block_count)) as u32;
+            superblock.block_count = new_block_count;
+            superblock.inode_count = group_count * geometry.inodes_per_group;
+        });
+        self.block_group_descriptor_table_len = group_count as usize;
+
+        self.sync()
+    }
+
+    /// Reference the reserved descriptor blocks of groups holding a backup of the superblock in
+    /// the resize inode if add, or forget them. Nothing is written if it fails.
+    ///
+    /// Its doubly indirect block references the reserved blocks of group 0, each one references
+    /// its copies in the backup groups, in order. groups must be the last groups
+    unsafe fn update_reserved_descriptor_backups(
+        &self,
+        geometry: &Geometry,
+        groups: core::ops::Range<u32>,
+        add: bool,
+    ) -> Result<(), Error> {
+        if geometry.reserved_descriptor_blocks == 0 {
+            return Ok(());
+        }
+        let inode = self.get_inode_in_table(RESIZE_INODE);
+        let doubly_indirect = read_field!(inode, doubly_indirect_block_pointer);
+        if doubly_indirect == 0 {
+            log::trace!("No resize inode, the reserved blocks are not referenced");
+            return Ok(());
+        }
+        let doubly_indirect = self.checked_block(doubly_indirect)? as *mut u32;
+        let pointers_per_block = geometry.block_size / 4;
+        let first_reserved = geometry.first_block + 1 + geometry.descriptor_blocks;
+        for reserved in 0..geometry.reserved_descriptor_blocks {
+            let pointer = (geometry.descriptor_blocks + reserved) % pointers_per_block;
+            let primary = access::read(doubly_indirect.add(pointer as usize));
+            if primary != first_reserved + reserved || !self.is_valid_block(primary) {
+                return Err(Error::Corrupt("resize inode"));
+            }
+        }
+
+        let mut changed_blocks = 0;
+        // There are less backups than pointers in a block, even with 2^32 blocks of 1KiB
+        for (index, group) in superblock_groups(groups.end, geometry.sparse)
+            .skip(1)
+            .enumerate()
+            .filter(|&(_, group)| groups.contains(&group))
+        {
+            for reserved in 0..geometry.reserved_descriptor_blocks {
+                let primary = first_reserved + reserved;
+                let backups = self.get_block(primary) as *mut u32;
+                let backup = if add {
+                    primary + group * geometry.blocks_per_group
+                } else {
+                    0
+                };
+                access::write(backups.add(index), backup);
+                changed_blocks += 1;
+            }
+        }
+        let sectors = changed_blocks * (geometry.block_size / 512);
+        access::modify(inode, |inode| {
+            if add {
+                inode.disk_sectors_used += sectors
+            } else {
+                inode.disk_sectors_used -= sectors
+            }
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use std::vec;
+
+    use crate::inode::{EntryKind, Permission};
+    use crate::tests::{check_group_counters, load_image};
+    use crate::{Error, Ext2Device, FileSystem, GroupPolicy, OpenOptions};
+
+    extern "C" fn last_group(fs: &FileSystem<'_>, _: u32, _: EntryKind) -> u32 {
+        fs.get_block_group_descriptor_table().len() as u32 - 1
+    }
+
+    #[test]
+    fn grow() {
+        // Two groups, the last one has 207 blocks
+        let mut image = load_image("test_fs_backup");
+        image.resize(30000 * 1024, 0xa5);
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let mut fs = device.open();
+        let (free_blocks, free_inodes) = {
+            let superblock = fs.get_superblock();
+            (superblock.unallocated_blocks, superblock.unallocated_inodes)
+        };
+        unsafe { fs.grow(30000) }.unwrap();
+        let superblock = fs.get_superblock();
+        assert_eq!({ superblock.block_count }, 30000);
+        assert_eq!({ superblock.block_superuser }, 1500);
+        assert_eq!(fs.get_block_group_descriptor_table().len(), 4);
+        assert_eq!(
+            { superblock.inode_count },
+            4 * { superblock.inode_count_in_group }
+        );
+        assert!({ superblock.unallocated_blocks } > free_blocks + 29000 - 8400);
+        assert_eq!(
+            { superblock.unallocated_inodes },
+            free_inodes + 2 * { superblock.inode_count_in_group }
+        );
+        assert_eq!(fs.statistics(false), fs.statistics(true));
+        check_group_counters(&fs);
+        // Group 3 holds a backup, group 2 does not
+        let group_3 = fs.group_statistics(3).unwrap();
+        assert_eq!(group_3.block_count, 30000 - 1 - 3 * 8192);
+        assert_eq!(group_3.block_bitmap, 1 + 3 * 8192 + 1 + 1 + 32);
+        assert_eq!(fs.group_statistics(2).unwrap().block_bitmap, 1 + 2 * 8192);
+
+        fs.set_group_policy(GroupPolicy::Custom(last_group));
+        let file = fs.create_file(b"/file", Permission::all(), 0, 0).unwrap();
+        assert_eq!(fs.group_of_inode(file), 3);
+        let mut file = fs.get_inode(file).unwrap().as_file().unwrap();
+        file.write(&[0x42; 10000]).unwrap();
+        file.sync();
+        drop(file);
+        assert!(fs.reserve_contiguous(3, 5000, true).unwrap() > 3 * 8192);
+        assert_eq!(
+            fs.reserve_contiguous(2, 8000, true).unwrap(),
+            1 + 2 * 8192 + 2 + 8
+        );
+        assert_eq!(fs.statistics(false), fs.statistics(true));
+        check_group_counters(&fs);
+        drop(fs);
+
+        let fs = device.open();
+        assert_eq!(fs.get_block_group_descriptor_table().len(), 4);
+        let mut file = fs.open(b"/file", OpenOptions::new().read(true)).unwrap();
+        let mut content = vec![0; 20000];
+        assert_eq!(file.read(&mut content), 10000);
+        assert!(content[..10000].iter().all(|&byte| byte == 0x42));
+        assert_eq!(fs.statistics(false), fs.statistics(true));
+        check_group_counters(&fs);
+    }
+
+    #[test]
+    fn grow_limits() {
+        let mut image = load_image("test_fs_backup");
+        let len = image.len() / 1024;
+        image.resize((2 * 8192 + 50) * 1024, 0);
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let mut fs = device.open();
+        unsafe {
+            assert_eq!(fs.grow(len as u32 - 1), Err(Error::InvalidArgument));
+            assert_eq!(
+                fs.grow(1 + 33 * 8192),
+                Err(Error::UnsupportedFeature(
+                    "growing past the group descriptor blocks"
+                ))
+            );
+            assert_eq!({ fs.get_superblock().block_count }, len as u32);
+            // The new group would only hold its metadata, the last group is extended instead
+            fs.grow(2 * 8192 + 50).unwrap();
+        }
+        assert_eq!({ fs.get_superblock().block_count }, 1 + 2 * 8192);
+        assert_eq!(fs.get_block_group_descriptor_table().len(), 2);
+        check_group_counters(&fs);
+        drop(fs);
+
+        let mut fs = device.try_open_read_only().unwrap();
+        assert_eq!(unsafe { fs.grow(2 * 8192 + 50) }, Err(Error::ReadOnly));
+    }
+
+    #[test]
+    fn shrink() {
+        let mut image = load_image("test_fs_backup");
+        let len = image.len() as u32 / 1024;
+        image.resize(30000 * 1024, 0);
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let mut fs = device.open();
+        unsafe { fs.grow(30000) }.unwrap();
+        let (free_blocks, free_inodes) = {
+            let superblock = fs.get_superblock();
+            (superblock.unallocated_blocks, superblock.unallocated_inodes)
+        };
+
+        fs.set_group_policy(GroupPolicy::Custom(last_group));
+        let file = fs.create_file(b"/file", Permission::all(), 0, 0).unwrap();
+        assert_eq!(fs.shrink(20000), Err(Error::InodeInUse(file)));
+        fs.unlink(b"/file").unwrap();
+        let block = fs.reserve_contiguous(3, 1, true).unwrap();
+        assert_eq!(fs.shrink(20000), Err(Error::BlockInUse(block)));
+        fs.release_block(block).unwrap();
+        // The first block past the end is reported
+        let start = fs.reserve_contiguous(2, 4000, true).unwrap();
+        assert_eq!(fs.shrink(20000), Err(Error::BlockInUse(20000)));
+        for block in start..start + 4000 {
+            fs.release_block(block).unwrap();
+        }
+        // The last group would not hold its inode table
+        assert_eq!(fs.shrink(2 * 8192 + 5), Err(Error::InvalidArgument));
+        assert_eq!(fs.shrink(30001), Err(Error::InvalidArgument));
+        assert_eq!({ fs.get_superblock().block_count }, 30000);
+        assert_eq!(fs.statistics(false).free_blocks, free_blocks);
+        assert_eq!(fs.statistics(false).free_inodes, free_inodes);
+
+        // Not aligned on a group, the last group is cut
+        fs.shrink(20000).unwrap();
+        let superblock = fs.get_superblock();
+        assert_eq!({ superblock.block_count }, 20000);
+        assert_eq!({ superblock.inode_count }, 3 * 32);
+        assert_eq!({ superblock.block_superuser }, 1000);
+        assert_eq!(fs.get_block_group_descriptor_table().len(), 3);
+        assert_eq!(
+            fs.group_statistics(2).unwrap().block_count,
+            20000 - 1 - 2 * 8192
+        );
+        assert_eq!(fs.statistics(false), fs.statistics(true));
+        check_group_counters(&fs);
+
+        fs.shrink(len).unwrap();
+        assert_eq!({ fs.get_superblock().block_count }, len);
+        assert_eq!(fs.get_block_group_descriptor_table().len(), 2);
+        assert_eq!(fs.statistics(false), fs.statistics(true));
+        check_group_counters(&fs);
+        drop(fs);
+
+        let fs = device.open();
+        assert_eq!({ fs.get_superblock().block_count }, len);
+        assert_eq!(fs.statistics(false), fs.statistics(true));
+        check_group_counters(&fs);
+    }
+}
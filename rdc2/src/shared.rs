@@ -0,0 +1,214 @@
+//! Sharing a filesystem between threads or cores, see `SharedFileSystem`.
+//!
+//! `FileSystem` is neither Send nor Sync: it holds pointers into the device and keeps its
+//! mount state and its directory cache in cells. `SharedFileSystem` puts it behind a
+//! reader-writer lock supplied by the embedder, any `lock_api::RawRwLock`.
+//!
+//! The operations that only read the device run concurrently under the shared lock, whatever
+//! inodes they read: the ones of `ReadOnlyFileSystem`, looking up paths, reading the metadata,
+//! the directories and the contents of the files. They don't write to the device nor to the
+//! cells of the filesystem, no access time is recorded and the directory cache is disabled.
+//!
+//! Everything else takes the exclusive lock and gets the whole `FileSystem`: the writes to the
+//! files, the allocation of blocks and inodes, the changes to the directories, and the updates
+//! of the superblock and the group descriptors, which every allocation makes. A write to one
+//! file can change the bitmaps and counters shared by all the others, so there is no finer
+//! locking.
+
+use lock_api::RawRwLock;
+
+use super::readonly::ReadOnlyFileSystem;
+use super::{Error, FileSystem};
+
+/// A `FileSystem` that can be sent and shared between threads, see the module documentation
+pub struct SharedFileSystem<'device, R: RawRwLock> {
+    lock: R,
+    fs: FileSystem<'device>,
+}
+
+/// The filesystem is only used by the holders of the lock: by one at a time for the writes,
+/// and through the `ReadOnlyFileSystem` of `SharedReader`, that doesn't write, by the others. The device is
+/// borrowed exclusively by the FileSystem for 'device, it can move with it
+unsafe impl<R: RawRwLock + Send> Send for SharedFileSystem<'_, R> {}
+unsafe impl<R: RawRwLock + Sync> Sync for SharedFileSystem<'_, R> {}
+
+impl<'device, R: RawRwLock> SharedFileSystem<'device, R> {
+    /// Share fs, its directory cache and its dirty tracking are dropped: they would be written
+    /// from the readers.
+    ///
+    /// UnsupportedFeature if fs was opened with `FileSystem::open_device`, the readers would
+    /// fill its pool, and Busy if a `Transaction` of fs was leaked, its staged blocks would be
+    /// written from the readers
+    pub fn new(fs: FileSystem<'device>) -> Result<Self, Error> {
+        #[cfg(feature = "alloc")]
+        let fs = {
+            if fs.pool.is_some() {
+                return Err(Error::UnsupportedFeature(
+                    "sharing a filesystem on a BlockDevice",
+                ));
+            }
+            if fs.staged.is_some() {
+                return Err(Error::Busy);
+            }
+            let mut fs = fs;
+            fs.dir_cache = None;
+            fs.dirty = None;
+            fs
+        };
+        Ok(SharedFileSystem { lock: R::INIT, fs })
+    }
+
+    /// Wait for the writer to be done and read, along the other readers
+    pub fn read(&self) -> SharedReader<'_, 'device, R> {
+        self.lock.lock_shared();
+        SharedReader { shared: self }
+    }
+
+    /// Wait for the readers and the writer to be done and use the whole filesystem
+    pub fn write(&self) -> SharedWriter<'_, 'device, R> {
+        self.lock.lock_exclusive();
+        SharedWriter { shared: self }
+    }
+
+    /// The filesystem, to unmount it
+    pub fn into_inner(self) -> FileSystem<'device> {
+        self.fs
+    }
+}
+
+/// The shared lock of a `SharedFileSystem`, it gives the operations that can run concurrently,
+/// the ones of a `ReadOnlyFileSystem`
+pub struct SharedReader<'shared, 'device, R: RawRwLock> {
+    shared: &'shared SharedFileSystem<'device, R>,
+}
+
+impl<'device, R: RawRwLock> core::ops::Deref for SharedReader<'_, 'device, R> {
+    type Target = ReadOnlyFileSystem<'device>;
+    fn deref(&self) -> &ReadOnlyFileSystem<'device> {
+        ReadOnlyFileSystem::view(&self.shared.fs)
+    }
+}
+
+impl<R: RawRwLock> Drop for SharedReader<'_, '_, R> {
+    fn drop(&mut self) {
+        unsafe { self.shared.lock.unlock_shared() }
+    }
+}
+
+/// The exclusive lock of a `SharedFileSystem`, it gives the whole filesystem
+pub struct SharedWriter<'shared, 'device, R: RawRwLock> {
+    shared: &'shared SharedFileSystem<'device, R>,
+}
+
+impl<'device, R: RawRwLock> core::ops::Deref for SharedWriter<'_, 'device, R> {
+    type Target = FileSystem<'device>;
+    fn deref(&self) -> &FileSystem<'device> {
+        &self.shared.fs
+    }
+}
+
+impl<R: RawRwLock> Drop for SharedWriter<'_, '_, R> {
+    fn drop(&mut self) {
+        unsafe { self.shared.lock.unlock_exclusive() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use std::vec::Vec;
+
+    use super::SharedFileSystem;
+    use crate::inode::{InodeRef, Permission};
+    use crate::tests::formatted;
+    use crate::{Error, Ext2Device, OpenOptions};
+
+    /// Readers check that the files are always whole while writers replace them
+    #[test]
+    fn concurrent_reads_and_writes() {
+        let mut image = formatted(4 << 20);
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.open();
+        for file in 0..4u8 {
+            let path = [b'/', b'a' + file];
+            fs.create_file(&path, Permission::all(), 0, 0).unwrap();
+            fs.open(&path, OpenOptions::new().write(true))
+                .unwrap()
+                .write(&[file; 4096])
+                .unwrap();
+        }
+        let shared = SharedFileSystem::<parking_lot::RawRwLock>::new(fs).unwrap();
+
+        std::thread::scope(|scope| {
+            for reader in 0..4u8 {
+                let shared = &shared;
+                scope.spawn(move || {
+                    for round in 0..200 {
+                        let file = (reader + round as u8) % 4;
+                        let path = [b'/', b'a' + file];
+                        let mut content = Vec::new();
+                        let read = shared.read();
+                        let size = read
+                            .read_file(&path, false, |data| {
+                                content.extend_from_slice(data);
+                                Ok::<_, ()>(())
+                            })
+                            .unwrap();
+                        assert_eq!(size, 4096);
+                        // Every write of a file gives it a single value
+                        assert!(content.iter().all(|&byte| byte == content[0]));
+                        let root = read.get_inode(InodeRef::root()).unwrap();
+                        assert_eq!(root.entries().unwrap().count(), 2 + 1 + 4);
+                    }
+                });
+            }
+            for writer in 0..2u8 {
+                let shared = &shared;
+                scope.spawn(move || {
+                    for round in 0..100u8 {
+                        let path = [b'/', b'a' + (round % 4)];
+                        let fs = shared.write();
+                        let value = writer * 100 + round;
+                        let mut file = fs.open(&path, OpenOptions::new().write(true)).unwrap();
+                        file.write(&[value; 4096]).unwrap();
+                        // A file created and removed under the lock is never seen
+                        fs.create_file(b"/temporary", Permission::all(), 0, 0)
+                            .unwrap();
+                        fs.unlink(b"/temporary").unwrap();
+                    }
+                });
+            }
+        });
+
+        let read = shared.read();
+        assert_eq!(read.lookup_path(b"/temporary"), Err(Error::NotFound));
+        let a = read.lookup_path(b"/a").unwrap();
+        assert_eq!(read.get_inode(a).unwrap().metadata().size, 4096);
+        drop(read);
+        #[cfg(feature = "alloc")]
+        {
+            let fs = shared.into_inner();
+            let counts = crate::check::check_all(&fs, &mut |finding| panic!("{}", finding));
+            assert_eq!(counts.total(), 0);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "alloc")]
+    fn refused() {
+        use crate::device::MemoryDevice;
+        use crate::tests::load_image;
+        use crate::FileSystem;
+
+        type Shared<'device> = SharedFileSystem<'device, parking_lot::RawRwLock>;
+        let mut image = load_image("test_fs");
+        let mut memory = MemoryDevice::new(&mut image);
+        let fs = FileSystem::open_device(&mut memory, 4, 64).unwrap();
+        assert!(matches!(Shared::new(fs), Err(Error::UnsupportedFeature(_))));
+
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let mut fs = device.open();
+        core::mem::forget(fs.begin().unwrap());
+        assert!(matches!(Shared::new(fs), Err(Error::Busy)));
+    }
+}
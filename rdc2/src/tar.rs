@@ -0,0 +1,302 @@
+//! Exporting the tree as a POSIX ustar archive, see `FileSystem::export_tar`
+
+use core::cmp::min;
+
+use super::inode::EntryKind;
+use super::{access, Error, FileSystem};
+
+/// The size of the headers and of the units of the content
+const RECORD: usize = 512;
+const NAME_LEN: usize = 100;
+const PREFIX_LEN: usize = 155;
+
+/// A path relative to the root, without a leading '/'
+struct Path {
+    bytes: [u8; NAME_LEN + 1 + PREFIX_LEN],
+    len: usize,
+    too_long: bool,
+}
+
+impl Path {
+    fn new() -> Self {
+        Path {
+            bytes: [0; NAME_LEN + 1 + PREFIX_LEN],
+            len: 0,
+            too_long: false,
+        }
+    }
+    fn push(&mut self, bytes: &[u8]) {
+        match self.bytes.get_mut(self.len..self.len + bytes.len()) {
+            Some(space) => {
+                space.copy_from_slice(bytes);
+                self.len += bytes.len();
+            }
+            None => self.too_long = true,
+        }
+    }
+    fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+    /// The prefix and the name fields, split on a '/', None if the path does not fit
+    fn split(&self) -> Option<(&[u8], &[u8])> {
+        let path = self.as_bytes();
+        if self.too_long {
+            return None;
+        }
+        if path.len() <= NAME_LEN {
+            return Some((&[], path));
+        }
+        let slash = (1..min(path.len(), PREFIX_LEN + 1))
+            .rev()
+            .filter(|&index| path[index] == b'/')
+            .find(|&index| path.len() - index - 1 <= NAME_LEN && index + 1 < path.len())?;
+        Some((&path[..slash], &path[slash + 1..]))
+    }
+}
+
+/// Write value as a zero padded octal number followed by a NUL, filling field
+fn octal(field: &mut [u8], mut value: u64) {
+    let (digits, end) = field.split_at_mut(field.len() - 1);
+    end[0] = 0;
+    for digit in digits.iter_mut().rev() {
+        *digit = b'0' + (value % 8) as u8;
+        value /= 8;
+    }
+}
+
+impl FileSystem<'_> {
+    /// Write the tree as a POSIX ustar archive to writer, one record of 512 bytes per call.
+    ///
+    /// Regular files, directories and symlinks are exported with their permissions, owner, size
+    /// and modification time, the other types are skipped. Hard links are written once for each
+    /// name. Like the walks, /lost+found is not exported.
+    ///
+    /// Ustar paths are split in a prefix of 155 bytes and a name of 100 bytes, the longer paths
+    /// and the symlink targets longer than 100 bytes are refused with NameTooLong. Nothing is
+    /// written past the entry that does not fit. A failure of writer gives WriteFailed
+    pub fn export_tar<E>(
+        &self,
+        mut writer: impl FnMut(&[u8]) -> Result<(), E>,
+    ) -> Result<(), Error> {
+        let mut write = |record: &[u8; RECORD]| writer(record).map_err(|_| Error::WriteFailed);
+
+        for entry in self.find(b"*") {
+            let inode = self.get_inode(entry.inode)?;
+            let metadata = inode.metadata();
+            let (typeflag, size, target) = match metadata.kind {
+                EntryKind::RegularFile => (b'0', metadata.size, None),
+                EntryKind::Directory => (b'5', 0, None),
+                EntryKind::Symlink => (b'2', 0, Some(inode.read_link()?)),
+                kind => {
+                    log::trace!("Not exporting {} of type {:?}", entry.name, kind);
+                    continue;
+                }
+            };
+
+            let mut path = Path::new();
+            entry.path_components(|component| {
+                if path.len != 0 {
+                    path.push(b"/");
+                }
+                path.push(component);
+            });
+            if metadata.kind == EntryKind::Directory {
+                path.push(b"/");
+            }
+            let (prefix, name) = path.split().ok_or(Error::NameTooLong)?;
+
+            let mut header = [0; RECORD];
+            header[..name.len()].copy_from_slice(name);
+            octal(
+                &mut header[100..108],
+                u64::from(metadata.permissions.bits()),
+            );
+            octal(&mut header[108..116], u64::from(metadata.user_id));
+            octal(&mut header[116..124], u64::from(metadata.group_id));
+            octal(&mut header[124..136], u64::from(size));
+            octal(
+                &mut header[136..148],
+                metadata.modified.seconds.max(0) as u64,
+            );
+            header[156] = typeflag;
+            if let Some(target) = target {
+                header
+                    .get_mut(157..157 + target.len())
+                    .filter(|_| target.len() <= NAME_LEN)
+                    .ok_or(Error::NameTooLong)?
+                    .copy_from_slice(target);
+            }
+            header[257..265].copy_from_slice(b"ustar\x0000");
+            header[345..345 + prefix.len()].copy_from_slice(prefix);
+            // The checksum is computed with its own field filled with spaces
+            header[148..156].copy_from_slice(b"        ");
+            let checksum = header.iter().map(|&byte| u64::from(byte)).sum();
+            octal(&mut header[148..155], checksum);
+            write(&header)?;
+
+            if typeflag == b'0' {
+                // The cursors stop at the first hole and after the direct blocks
+                let block_size = self.block_size;
+                for offset in (0..size as usize).step_by(RECORD) {
+                    let mut record = [0; RECORD];
+                    let len = min(size as usize - offset, RECORD);
+                    // The records don't cross the blocks, holes are read as zeros
+                    if let Some(block) = inode.block_at((offset / block_size) as u32) {
+                        unsafe {
+                            let data = self.checked_block(block)?.add(offset % block_size);
+                            access::copy_from_device(data, record.as_mut_ptr(), len);
+                        }
+                    }
+                    write(&record)?;
+                }
+            }
+        }
+
+        // The end of the archive
+        write(&[0; RECORD])?;
+        write(&[0; RECORD])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    use std::vec::Vec;
+
+    use crate::inode::{MappedBlock, Permission};
+    use crate::tests::{formatted, load_image};
+    use crate::{Error, Ext2Device};
+
+    fn export(image: &mut [u8]) -> Result<Vec<u8>, Error> {
+        let mut device = Ext2Device::from_slice(image).unwrap();
+        let fs = device.open();
+        let mut archive = Vec::new();
+        fs.export_tar(|record| {
+            assert_eq!(record.len(), 512);
+            archive.extend_from_slice(record);
+            Ok::<_, ()>(())
+        })?;
+        Ok(archive)
+    }
+
+    /// Run tar with args followed by the path of the archive, None when tar is not installed
+    fn tar(archive: &[u8], args: &[&str]) -> Option<Vec<u8>> {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(std::format!(
+            "rdc2_{}_{}.tar",
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::write(&path, archive).unwrap();
+        let output = std::process::Command::new("tar")
+            .args(args)
+            .arg(&path)
+            .output();
+        std::fs::remove_file(&path).unwrap();
+        let output = output.ok()?;
+        assert!(output.status.success(), "{:?}", output);
+        Some(output.stdout)
+    }
+
+    fn lines(output: &[u8]) -> Vec<&str> {
+        std::str::from_utf8(output).unwrap().lines().collect()
+    }
+
+    #[test]
+    fn archive() {
+        let mut image = load_image("test_fs_special");
+        let archive = export(&mut image).unwrap();
+        // hello.txt and its content, link, and the end
+        assert_eq!(archive.len(), 5 * 512);
+        assert_eq!(&archive[..10], b"hello.txt\0");
+        assert_eq!(&archive[512..518], b"hello\n");
+        assert_eq!(&archive[1024..1029], b"link\0");
+        assert_eq!(archive[1024 + 156], b'2');
+        assert_eq!(&archive[1024 + 157..1024 + 167], b"hello.txt\0");
+        assert!(archive[3 * 512..].iter().all(|&byte| byte == 0));
+        if let Some(listing) = tar(&archive, &["tvf"]) {
+            let listing = lines(&listing);
+            assert_eq!(listing.len(), 2);
+            assert!(listing[0].starts_with("-rw-r--r-- 0/0") && listing[0].ends_with(" hello.txt"));
+            assert!(
+                listing[1].starts_with("lrwxrwxrwx") && listing[1].ends_with(" link -> hello.txt")
+            );
+        }
+
+        let mut image = load_image("test_fs");
+        let archive = export(&mut image).unwrap();
+        if let Some(listing) = tar(&archive, &["tf"]) {
+            let mut device = Ext2Device::from_slice(&mut image).unwrap();
+            let fs = device.open();
+            let mut names = Vec::new();
+            for entry in fs.find(b"*") {
+                let mut path = Vec::new();
+                entry.path_components(|component| {
+                    if !path.is_empty() {
+                        path.push(b'/');
+                    }
+                    path.extend_from_slice(component);
+                });
+                if fs.get_inode(entry.inode).unwrap().is_dir() {
+                    path.push(b'/');
+                }
+                names.push(std::string::String::from_utf8(path).unwrap());
+            }
+            assert!(names.contains(&"thing/more/".into()));
+            assert_eq!(lines(&listing), names);
+        }
+
+        let mut image = load_image("test_fs_indirect");
+        let archive = export(&mut image).unwrap();
+        if let Some(content) = tar(&archive, &["xOf"]) {
+            let mut device = Ext2Device::from_slice(&mut image).unwrap();
+            let fs = device.open();
+            let big = fs.get_inode(fs.lookup_path(b"/big").unwrap()).unwrap();
+            let mut expected = Vec::new();
+            for block in big.mapped_blocks() {
+                if let MappedBlock::Data(block) = block {
+                    expected.extend_from_slice(unsafe {
+                        core::slice::from_raw_parts(fs.get_block(block), 1024)
+                    });
+                }
+            }
+            assert_eq!(expected.len(), 307200);
+            assert!(content == expected);
+        }
+    }
+
+    #[test]
+    fn long_paths() {
+        let mut image = formatted(1 << 20);
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.open();
+        // Directories of 40 bytes names, from the 3rd one the paths need the prefix
+        let mut path = Vec::new();
+        for level in 0..5 {
+            path.push(b'/');
+            path.extend_from_slice(&[b'a' + level; 40]);
+            fs.create_dir(&path, Permission::all(), 0, 0).unwrap();
+        }
+        drop(fs);
+        let archive = export(&mut image).unwrap();
+        let header = &archive[4 * 512..5 * 512];
+        assert_eq!(&header[345..345 + 122], &path[1..123]);
+        assert_eq!(header[345 + 122], 0);
+        assert_eq!(&header[..82], [&path[124..], b"/"].concat());
+        if let Some(listing) = tar(&archive, &["tf"]) {
+            let listing = lines(&listing);
+            assert_eq!(listing.len(), 5);
+            assert_eq!(listing[4].as_bytes(), [&path[1..], b"/"].concat());
+        }
+
+        // The 6th level does not fit in the name after the longest prefix
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.open();
+        path.push(b'/');
+        path.extend_from_slice(&[b'f'; 40]);
+        fs.create_dir(&path, Permission::all(), 0, 0).unwrap();
+        drop(fs);
+        assert_eq!(export(&mut image), Err(Error::NameTooLong));
+    }
+}
@@ -0,0 +1,353 @@
+//! Staging the writes of several operations and applying them together, see
+//! `FileSystem::begin`.
+//!
+//! During a transaction every block the filesystem reaches is copied on its first access, and
+//! the operations read and write the copies: the device is not touched until `commit`. The
+//! memory used grows with the number of blocks accessed, read or written. `commit` only writes
+//! the blocks that changed, metadata last: the data and directory blocks first, then the
+//! bitmaps and the inode tables, then the group descriptors and the superblock. An interrupted
+//! commit can leak blocks and inodes, but the superblock and the descriptors never count
+//! something that is not written yet.
+
+use alloc::collections::BTreeMap;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use core::ops::{Deref, Range};
+
+use super::metadata::{BlockGroupDescriptor, ExtendedSuperblock, Superblock};
+use super::{access, Error, FileSystem};
+
+/// The copies of the blocks of a transaction
+pub(crate) struct Staged {
+    block_size: usize,
+    /// The blocks of the superblock and the group descriptors, kept contiguous like on the
+    /// device as the filesystem points into them
+    metadata: Range<u32>,
+    metadata_copy: Vec<u8>,
+    blocks: BTreeMap<u32, Vec<u8>>,
+}
+
+impl Staged {
+    /// The copy of the block index of the device at device, made on the first access. The
+    /// copies don't move until the transaction ends
+    pub(crate) unsafe fn block(&mut self, index: u32, device: *mut u8) -> *mut u8 {
+        let block_size = self.block_size;
+        if self.metadata.contains(&index) {
+            let offset = (index - self.metadata.start) as usize * block_size;
+            return self.metadata_copy.as_mut_ptr().add(offset);
+        }
+        self.blocks
+            .entry(index)
+            .or_insert_with(|| {
+                let mut copy = vec![0; block_size];
+                access::copy_from_device(device, copy.as_mut_ptr(), block_size);
+                copy
+            })
+            .as_mut_ptr()
+    }
+
+    /// The staged blocks in the order commit writes them, see the module documentation
+    fn order(
+        &self,
+        descriptors: &[BlockGroupDescriptor],
+        inode_table_blocks: u32,
+    ) -> Vec<(u32, &[u8])> {
+        let is_group_metadata = |block: u32| {
+            descriptors.iter().any(|descriptor| {
+                let table = descriptor.starting_block_of_inode_table;
+                block == descriptor.block_address_of_block_bitmap
+                    || block == descriptor.block_address_of_inode_bitmap
+                    || (table..table.saturating_add(inode_table_blocks)).contains(&block)
+            })
+        };
+        let blocks = self.blocks.iter().map(|(&index, copy)| (index, &copy[..]));
+        let mut order: Vec<_> = blocks
+            .clone()
+            .filter(|&(index, _)| !is_group_metadata(index))
+            .collect();
+        order.extend(blocks.filter(|&(index, _)| is_group_metadata(index)));
+        // The superblock is in the first block, it goes after the descriptors
+        let mut metadata = self
+            .metadata
+            .clone()
+            .zip(self.metadata_copy.chunks(self.block_size));
+        let superblock = metadata.next();
+        order.extend(metadata);
+        order.extend(superblock);
+        order
+    }
+}
+
+/// What the filesystem was before the transaction
+struct Saved {
+    superblock: *mut Superblock,
+    extended: *mut ExtendedSuperblock,
+    block_group_descriptor_table: *mut BlockGroupDescriptor,
+    mounted: bool,
+    mount_state: u16,
+    replay_pending: bool,
+}
+
+/// Operations on a filesystem whose writes are staged until `commit`, see the module
+/// documentation. Dropping it discards them, like `abort`
+pub struct Transaction<'fs, 'device> {
+    fs: &'fs mut FileSystem<'device>,
+    saved: Saved,
+}
+
+impl<'device> FileSystem<'device> {
+    /// Start staging the writes, the transaction gives the whole filesystem. ReadOnly on a
+    /// read-only filesystem
+    pub fn begin(&mut self) -> Result<Transaction<'_, 'device>, Error> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+        let saved = Saved {
+            superblock: self.superblock,
+            extended: self.extended,
+            block_group_descriptor_table: self.block_group_descriptor_table,
+            mounted: self.mounted.get(),
+            mount_state: self.mount_state.get(),
+            replay_pending: self.replay_pending.get(),
+        };
+        let block_size = self.block_size;
+        let metadata = self.metadata_blocks();
+        unsafe {
+            let start = self.fs.add(metadata.start as usize * block_size);
+            let mut metadata_copy = vec![0; metadata.len() * block_size];
+            access::copy_from_device(start, metadata_copy.as_mut_ptr(), metadata_copy.len());
+
+            // The same offsets in the copy
+            let copy = metadata_copy.as_mut_ptr();
+            let moved = |ptr: *mut u8| copy.offset(ptr.offset_from(start));
+            self.superblock = moved(self.superblock as *mut u8) as *mut Superblock;
+            if !self.extended.is_null() {
+                self.extended = moved(self.extended as *mut u8) as *mut ExtendedSuperblock;
+            }
+            self.block_group_descriptor_table =
+                moved(self.block_group_descriptor_table as *mut u8) as *mut BlockGroupDescriptor;
+            self.staged = Some(RefCell::new(Staged {
+                block_size,
+                metadata,
+                metadata_copy,
+                blocks: BTreeMap::new(),
+            }));
+        }
+        log::trace!("Beginning a transaction");
+        Ok(Transaction { fs: self, saved })
+    }
+}
+
+impl<'device> Transaction<'_, 'device> {
+    /// The number of blocks copied so far
+    pub fn staged_blocks(&self) -> usize {
+        self.fs.staged.as_ref().map_or(0, |staged| {
+            let staged = staged.borrow();
+            staged.metadata.len() + staged.blocks.len()
+        })
+    }
+
+    /// Write the blocks that changed to the device, metadata last. Returns the number of
+    /// blocks written
+    pub fn commit(mut self) -> usize {
+        let staged = match self.fs.staged.take() {
+            Some(staged) => staged.into_inner(),
+            None => return 0,
+        };
+        let inode_table_blocks = (self.fs.get_superblock().inode_count_in_group as usize
+            * self.fs.inode_size())
+        .div_ceil(self.fs.block_size) as u32;
+        // The staged descriptors tell the bitmaps and the inode tables apart, the filesystem
+        // still points to them
+        let order = staged.order(
+            self.fs.get_block_group_descriptor_table(),
+            inode_table_blocks,
+        );
+        self.restore();
+        let fs = &*self.fs;
+        let mut written = 0;
+        for (index, copy) in order {
+            unsafe {
+                let block = fs.get_block(index);
+                let unchanged =
+                    (0..fs.block_size).all(|byte| access::read(block.add(byte)) == copy[byte]);
+                if !unchanged {
+                    access::copy_to_device(copy.as_ptr(), block, fs.block_size);
+                    written += 1;
+                }
+            }
+        }
+        log::trace!("Committed a transaction, {} blocks written", written);
+        written
+    }
+
+    /// Discard the staged writes, the device is left as it was before `begin`
+    pub fn abort(mut self) {
+        self.discard()
+    }
+
+    fn discard(&mut self) {
+        if self.fs.staged.take().is_none() {
+            return;
+        }
+        self.restore();
+        let fs = &*self.fs;
+        fs.mounted.set(self.saved.mounted);
+        fs.mount_state.set(self.saved.mount_state);
+        fs.replay_pending.set(self.saved.replay_pending);
+        // The cache may hold entries that were never written
+        if let Some(cache) = &fs.dir_cache {
+            cache.borrow_mut().clear();
+        }
+        log::trace!("Aborted a transaction");
+    }
+
+    /// Point the filesystem back to the device, once the staged blocks are taken
+    fn restore(&mut self) {
+        self.fs.superblock = self.saved.superblock;
+        self.fs.extended = self.saved.extended;
+        self.fs.block_group_descriptor_table = self.saved.block_group_descriptor_table;
+    }
+}
+
+impl<'device> Deref for Transaction<'_, 'device> {
+    type Target = FileSystem<'device>;
+    fn deref(&self) -> &FileSystem<'device> {
+        self.fs
+    }
+}
+
+impl Drop for Transaction<'_, '_> {
+    fn drop(&mut self) {
+        self.discard()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use std::vec::Vec;
+
+    use crate::check::check_all;
+    use crate::inode::Permission;
+    use crate::tests::{formatted, load_image};
+    use crate::{Error, Ext2Device, FileSystem, OpenOptions};
+
+    /// Create a directory and a file of 10 blocks in it
+    fn create(fs: &FileSystem) {
+        fs.create_dir(b"/dir", Permission::all(), 1, 2).unwrap();
+        let mut file = fs
+            .open(b"/dir/file", OpenOptions::new().write(true).create(true))
+            .unwrap();
+        for block in 0..10u8 {
+            file.write(&[block; 1024]).unwrap();
+        }
+    }
+
+    #[test]
+    fn abort() {
+        let mut image = load_image("test_fs");
+        let mut control = image.clone();
+        {
+            let mut device = Ext2Device::from_slice(&mut control).unwrap();
+            let fs = device.open();
+            fs.lookup_path(b"/thing").unwrap();
+        }
+
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let mut fs = device.open();
+        fs.enable_dir_cache(16);
+        let transaction = fs.begin().unwrap();
+        create(&transaction);
+        // Halfway through, the file is only in the transaction
+        assert!(transaction.lookup_path(b"/dir/file").is_ok());
+        assert!(transaction.staged_blocks() > 10);
+        transaction.abort();
+        assert_eq!(fs.lookup_path(b"/dir/file"), Err(Error::NotFound));
+        assert_eq!(fs.lookup_path(b"/dir"), Err(Error::NotFound));
+        fs.lookup_path(b"/thing").unwrap();
+
+        // Dropped without a commit
+        let transaction = fs.begin().unwrap();
+        transaction
+            .create_dir(b"/dir", Permission::all(), 0, 0)
+            .unwrap();
+        transaction.sync().unwrap();
+        drop(transaction);
+        drop(fs);
+        assert!(image == control);
+    }
+
+    #[test]
+    fn commit() {
+        let mut image = formatted(1 << 20);
+        let mut control = image.clone();
+        {
+            let mut device = Ext2Device::from_slice(&mut control).unwrap();
+            create(&device.open());
+        }
+
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let mut fs = device.open();
+        let transaction = fs.begin().unwrap();
+        create(&transaction);
+        // The file, the directory, the root, the bitmaps, the inode table, the descriptors and
+        // the superblock
+        assert!(transaction.commit() >= 17);
+        let mut content = Vec::new();
+        fs.read_file(b"/dir/file", false, |data| {
+            content.extend_from_slice(data);
+            Ok::<_, ()>(())
+        })
+        .unwrap();
+        assert_eq!(content.len(), 10 * 1024);
+        // Nothing changed
+        let transaction = fs.begin().unwrap();
+        transaction.lookup_path(b"/dir/file").unwrap();
+        assert_eq!(transaction.commit(), 0);
+        let counts = check_all(&fs, &mut |finding| panic!("{}", finding));
+        assert_eq!(counts.total(), 0);
+        drop(fs);
+        assert!(image == control);
+    }
+
+    #[test]
+    fn metadata_last() {
+        let mut image = formatted(1 << 20);
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let mut fs = device.open();
+        let transaction = fs.begin().unwrap();
+        create(&transaction);
+        let data = transaction
+            .get_inode(transaction.lookup_path(b"/dir/file").unwrap())
+            .unwrap()
+            .block_at(0)
+            .unwrap();
+        let group = transaction.group_statistics(0).unwrap();
+        let staged = transaction.fs.staged.as_ref().unwrap().borrow();
+        let order: Vec<u32> = staged
+            .order(
+                transaction.get_block_group_descriptor_table(),
+                group.inode_table_blocks,
+            )
+            .iter()
+            .map(|&(index, _)| index)
+            .collect();
+        let position = |block: u32| order.iter().position(|&index| index == block).unwrap();
+        let bitmap = position(group.block_bitmap);
+        let table = position(group.inode_table);
+        assert!(position(data) < bitmap.min(table));
+        // The superblock is in block 1, then the descriptors
+        assert_eq!(order[order.len() - 2..], [2, 1]);
+        assert!(bitmap.max(table) < order.len() - 2);
+    }
+
+    #[test]
+    fn read_only() {
+        let mut image = load_image("test_fs");
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let mut fs = device.try_open_read_only().unwrap();
+        assert_eq!(fs.begin().err(), Some(Error::ReadOnly));
+    }
+}
@@ -0,0 +1,364 @@
+use bstr::BStr;
+
+use super::inode::{root_inode, DirectoryEntries, EntryKind, Inode, InodeRef};
+use super::FileSystem;
+
+/// An entry found by `Walk`
+#[derive(Clone, Copy)]
+pub struct Entry<'fs, 'device> {
+    fs: &'fs FileSystem<'device>,
+    pub inode: InodeRef,
+    /// The directory holding the entry
+    pub parent: InodeRef,
+    pub name: &'fs BStr,
+}
+
+impl<'fs, 'device> Entry<'fs, 'device> {
+    /// Call f with each component of the path of the entry, starting from the root.
+    ///
+    /// The path is rebuilt from the '..' entries, nothing is kept during the walk
+    pub fn path_components(&self, mut f: impl FnMut(&'fs BStr)) {
+        let depth = depth(self.fs, self.parent);
+        for level in (0..depth).rev() {
+            let mut directory = self.parent;
+            for _ in 0..level {
+                directory = parent_of(self.fs, directory).unwrap_or(directory);
+            }
+            let parent = self
+                .fs
+                .get_inode(parent_of(self.fs, directory).unwrap_or(directory));
+            if let Some(name) = parent.ok().and_then(|parent| name_in(&parent, directory)) {
+                f(name);
+            }
+        }
+        f(self.name)
+    }
+}
+
+/// The directory referenced by the '..' entry of directory
+fn parent_of(fs: &FileSystem<'_>, directory: InodeRef) -> Option<InodeRef> {
+    Some(fs.get_inode(directory).ok()?.find_entry(b"..")?.inode)
+}
+
+/// The number of directories between the root and directory, stopping on broken chains
+fn depth(fs: &FileSystem<'_>, mut directory: InodeRef) -> u32 {
+    let mut depth = 0;
+    while directory != root_inode() && depth < fs.get_superblock().inode_count {
+        match parent_of(fs, directory) {
+            Some(parent) => directory = parent,
+            None => break,
+        }
+        depth += 1;
+    }
+    depth
+}
+
+/// The name of the first entry of parent referencing child, other than '.' and '..'
+fn name_in<'fs>(parent: &Inode<'fs, '_>, child: InodeRef) -> Option<&'fs BStr> {
+    parent
+        .get_dir_entries()?
+        .find(|entry| entry.inode == child && entry.name != "." && entry.name != "..")
+        .map(|entry| entry.name)
+}
+
+/// What the walk yields
+#[derive(Clone, Copy)]
+enum Filter<'pattern> {
+    Files,
+    Glob(&'pattern [u8]),
+}
+
+/// Iterator over the tree of the filesystem, see `FileSystem::iter_files` and `FileSystem::find`.
+///
+/// The walk only remembers the current directory and the position in it: when a directory is
+/// done the walk goes back to its '..' and looks for where it was. This keeps the memory and stack
+/// usage constant whatever the depth of the tree.
+pub struct Walk<'fs, 'device> {
+    fs: &'fs FileSystem<'device>,
+    directory: Inode<'fs, 'device>,
+    position: u32,
+    done: bool,
+    filter: Filter<'fs>,
+
+    skip_lost_found: bool,
+    cycle_protection: bool,
+}
+
+impl<'fs, 'device> Walk<'fs, 'device> {
+    /// Yield the regular files
+    pub(crate) fn files(fs: &'fs FileSystem<'device>) -> Self {
+        Self::new(fs, Filter::Files)
+    }
+    /// Yield the entries whose name matches pattern, see `glob_match`
+    pub(crate) fn glob(fs: &'fs FileSystem<'device>, pattern: &'fs [u8]) -> Self {
+        Self::new(fs, Filter::Glob(pattern))
+    }
+    fn new(fs: &'fs FileSystem<'device>, filter: Filter<'fs>) -> Self {
+        Walk {
+            fs,
+            directory: fs.get_root(),
+            position: 0,
+            done: false,
+            filter,
+            skip_lost_found: true,
+            cycle_protection: true,
+        }
+    }
+    /// Do not enter /lost+found, true by default
+    pub fn skip_lost_found(mut self, skip: bool) -> Self {
+        self.skip_lost_found = skip;
+        self
+    }
+    /// Only enter the directories whose '..' is the directory they are found in, true by
+    /// default. Directories linked in several places are then only walked once, without this a
+    /// corrupted filesystem can make the walk loop forever
+    pub fn cycle_protection(mut self, protection: bool) -> Self {
+        self.cycle_protection = protection;
+        self
+    }
+
+    /// Go back to the parent of the current directory, after the entry of the directory.
+    /// Returns false when there is nowhere to go back to
+    fn leave_directory(&mut self) -> bool {
+        let current = self.directory.inode_ref();
+        if current == root_inode() {
+            return false;
+        }
+        let parent = match parent_of(self.fs, current).map(|parent| self.fs.get_inode(parent)) {
+            Some(Ok(parent)) => parent,
+            _ => return false,
+        };
+        let mut entries = DirectoryEntries::at(&parent, 0);
+        let position = loop {
+            match entries.next() {
+                Some(entry)
+                    if entry.inode == current && entry.name != "." && entry.name != ".." =>
+                {
+                    break entries.position()
+                }
+                Some(_) => continue,
+                // The directory is not in its '..'
+                None => return false,
+            }
+        };
+        self.directory = parent;
+        self.position = position;
+        true
+    }
+}
+
+impl<'fs, 'device> Iterator for Walk<'fs, 'device> {
+    type Item = Entry<'fs, 'device>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.done {
+            let mut entries = DirectoryEntries::at(&self.directory, self.position);
+            let entry = match entries.next() {
+                Some(entry) => entry,
+                None => {
+                    self.done = !self.leave_directory();
+                    continue;
+                }
+            };
+            self.position = entries.position();
+            if entry.name == "." || entry.name == ".." {
+                continue;
+            }
+            let current = self.directory.inode_ref();
+            let inode = match self.fs.get_inode(entry.inode) {
+                Ok(inode) => inode,
+                // Only on a corrupted directory
+                Err(_) => continue,
+            };
+            let is_dir = inode.is_dir();
+            if is_dir
+                && self.skip_lost_found
+                && current == root_inode()
+                && entry.name == "lost+found"
+            {
+                continue;
+            }
+            let wanted = match self.filter {
+                Filter::Files => inode.file_type() == EntryKind::RegularFile,
+                Filter::Glob(pattern) => glob_match(pattern, entry.name),
+            };
+            if is_dir {
+                if self.cycle_protection && parent_of(self.fs, entry.inode) != Some(current) {
+                    log::trace!("Not entering {} again", entry.name);
+                } else {
+                    self.directory = inode;
+                    self.position = 0;
+                }
+            }
+            if wanted {
+                return Some(Entry {
+                    fs: self.fs,
+                    inode: entry.inode,
+                    parent: current,
+                    name: entry.name,
+                });
+            }
+        }
+        None
+    }
+}
+
+/// Whether name matches pattern, where '*' matches any number of bytes and '?' exactly one
+pub fn glob_match(pattern: &[u8], name: &[u8]) -> bool {
+    let (mut p, mut n) = (0, 0);
+    // Where to resume after the last '*': the pattern after it and the name it stopped at
+    let mut backtrack = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                p += 1;
+                backtrack = Some((p, n));
+            }
+            Some(&c) if c == b'?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                // Let the last '*' match one more byte
+                Some((star_p, star_n)) => {
+                    p = star_p;
+                    n = star_n + 1;
+                    backtrack = Some((star_p, star_n + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use std::string::String;
+    use std::vec::Vec;
+
+    use bstr::ByteSlice;
+
+    use crate::inode::{Cursor, Permission};
+    use crate::tests::load_image;
+    use crate::Ext2Device;
+
+    fn path(entry: &super::Entry<'_, '_>) -> String {
+        let mut path = String::new();
+        entry.path_components(|component| {
+            path.push('/');
+            path.push_str(component.to_str().unwrap());
+        });
+        path
+    }
+
+    #[test]
+    fn files() {
+        let mut image = load_image("test_fs_back");
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.open();
+        fs.create_file(b"/lost+found/lost", Permission::all(), 0, 0)
+            .unwrap();
+        fs.create_dir(b"/other/empty", Permission::all(), 0, 0)
+            .unwrap();
+        let deep = fs
+            .create_file(b"/thing/more/deep", Permission::all(), 0, 0)
+            .unwrap();
+
+        let files: Vec<_> = fs.iter_files().collect();
+        let paths: Vec<_> = files.iter().map(path).collect();
+        assert_eq!(
+            paths,
+            [
+                "/thing/more/never.txt",
+                "/thing/more/deep",
+                "/other/niche.txt",
+                "/foo.txt"
+            ]
+        );
+        assert_eq!(files[1].inode, deep);
+        assert_eq!(files[1].parent, fs.lookup_path(b"/thing/more").unwrap());
+
+        let paths: Vec<_> = fs
+            .iter_files()
+            .skip_lost_found(false)
+            .map(|f| path(&f))
+            .collect();
+        assert_eq!(paths[0], "/lost+found/lost");
+        assert_eq!(paths.len(), 5);
+    }
+
+    #[test]
+    fn glob() {
+        use super::glob_match;
+        assert!(glob_match(b"", b""));
+        assert!(!glob_match(b"", b"a"));
+        assert!(glob_match(b"*", b""));
+        assert!(glob_match(b"*", b"anything"));
+        assert!(glob_match(b"foo.txt", b"foo.txt"));
+        assert!(!glob_match(b"foo.txt", b"foo.txt2"));
+        assert!(!glob_match(b"foo.txt", b"foo.tx"));
+        assert!(glob_match(b"*.txt", b"foo.txt"));
+        assert!(glob_match(b"*.txt", b".txt"));
+        assert!(!glob_match(b"*.txt", b"foo.txt.gz"));
+        assert!(glob_match(b"?oo*", b"foo.txt"));
+        assert!(!glob_match(b"?oo", b"oo"));
+        assert!(glob_match(b"a*b*c", b"aXbYbZc"));
+        assert!(!glob_match(b"a*b*c", b"aXbYbZ"));
+        assert!(glob_match(b"*a*a*a", b"aaaa"));
+        assert!(!glob_match(b"*a*a*a*b", b"aaaaaaaaaaaaaaaaaaaa"));
+        assert!(glob_match(b"**?", b"x"));
+        assert!(glob_match(b"*.ko", b"ext2.ko"));
+    }
+
+    #[test]
+    fn find() {
+        let mut image = load_image("test_fs_back");
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.open();
+        fs.create_dir(b"/thing/more/texts.d", Permission::all(), 0, 0)
+            .unwrap();
+        fs.create_file(b"/thing/more/texts.d/a.txt", Permission::all(), 0, 0)
+            .unwrap();
+
+        let paths: Vec<_> = fs.find(b"*e*.*").map(|entry| path(&entry)).collect();
+        assert_eq!(
+            paths,
+            [
+                "/thing/more/never.txt",
+                "/thing/more/texts.d",
+                "/other/niche.txt"
+            ]
+        );
+        let paths: Vec<_> = fs.find(b"*.txt").map(|entry| path(&entry)).collect();
+        assert_eq!(paths[1], "/thing/more/texts.d/a.txt");
+        assert_eq!(paths.len(), 4);
+        let more = fs.lookup_path(b"/thing/more").unwrap();
+        assert!(fs.find(b"never.*").all(|entry| entry.parent == more));
+        assert_eq!(fs.find(b"?").count(), 0);
+        assert_eq!(fs.find(b"lost+found").count(), 0);
+        assert_eq!(fs.find(b"lost+found").skip_lost_found(false).count(), 1);
+    }
+
+    #[test]
+    fn cycles() {
+        let mut image = load_image("test_fs_back");
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.open();
+        let a = fs.create_dir(b"/a", Permission::all(), 0, 0).unwrap();
+        fs.create_file(b"/a/file", Permission::all(), 0, 0).unwrap();
+        let b = fs.create_dir(b"/b", Permission::all(), 0, 0).unwrap();
+        fs.create_dir(b"/b/c", Permission::all(), 0, 0).unwrap();
+        // Make /b/c a second link to /a, after '.' and '..'
+        Cursor::at(&fs.get_inode(b).unwrap(), 24)
+            .write(&a.0.to_le_bytes())
+            .unwrap();
+
+        let names: Vec<_> = fs.iter_files().map(|file| file.name).collect();
+        assert_eq!(names, ["never.txt", "niche.txt", "file", "foo.txt"]);
+
+        // Leaving /b/c goes back to /, where /a and then /b/c are found again
+        assert_eq!(fs.iter_files().cycle_protection(false).take(20).count(), 20);
+    }
+}
@@ -0,0 +1,607 @@
+//! Extended attributes, kept in the block referenced by the `acl` field of the inode.
+//!
+//! A block can be shared by several inodes, it is copied before being modified for one of them.
+//!
+//! The attributes stored in the inodes themselves and in dedicated inodes are not supported.
+
+use bstr::{BStr, ByteSlice};
+use core::mem::size_of;
+
+use super::{access, Error};
+
+pub const XATTR_MAGIC: u32 = 0xEA02_0000;
+
+#[derive(Debug)]
+#[repr(C, packed)]
+pub struct XattrHeader {
+    pub magic: u32,
+    /// Number of inodes sharing the block
+    pub refcount: u32,
+    /// Always 1 on ext2
+    pub blocks: u32,
+    pub hash: u32,
+    pub checksum: u32,
+    pub reserved: [u32; 3],
+}
+
+/// The entries follow the header, their names are padded to 4 bytes and the list ends with 4
+/// zero bytes. The values are at the end of the block
+#[derive(Debug)]
+#[repr(C, packed)]
+pub struct RawXattrEntry {
+    pub name_len: u8,
+    pub name_index: u8,
+    /// From the start of the block
+    pub value_offset: u16,
+    /// The inode holding the value, 0 if it is in the block
+    pub value_inode: u32,
+    pub value_size: u32,
+    pub hash: u32,
+}
+
+/// The prefix of the names with index, None for the indexes this driver does not know
+fn prefix(index: u8) -> Option<&'static [u8]> {
+    Some(match index {
+        1 => b"user.",
+        2 => b"system.posix_acl_access",
+        3 => b"system.posix_acl_default",
+        4 => b"trusted.",
+        6 => b"security.",
+        7 => b"system.",
+        8 => b"system.richacl",
+        _ => return None,
+    })
+}
+
+/// An extended attribute, its full name is the prefix followed by the name
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Xattr<'fs> {
+    /// Like `user.` or `security.`
+    pub prefix: &'static BStr,
+    pub name: &'fs BStr,
+    pub value: &'fs [u8],
+}
+
+impl Xattr<'_> {
+    /// Whether the full name of the attribute is name, like `security.selinux`
+    pub fn has_name(&self, name: &[u8]) -> bool {
+        name.len() == self.prefix.len() + self.name.len()
+            && name.starts_with(self.prefix)
+            && name[self.prefix.len()..] == *self.name
+    }
+}
+
+/// The attributes of an xattr block, see `Inode::xattrs`
+#[derive(Debug, Clone)]
+pub struct Xattrs<'fs> {
+    block: &'fs [u8],
+    position: usize,
+}
+
+impl<'fs> Xattrs<'fs> {
+    pub(crate) fn empty() -> Self {
+        Xattrs {
+            block: &[],
+            position: 0,
+        }
+    }
+    /// Check the header and that all the entries and their values are in the block
+    pub(crate) fn new(block: &'fs [u8]) -> Result<Self, Error> {
+        if block.len() < size_of::<XattrHeader>() {
+            return Err(Error::Corrupt("extended attribute block"));
+        }
+        let header = unsafe { access::read(block.as_ptr() as *const XattrHeader) };
+        if { header.magic } != XATTR_MAGIC || { header.blocks } != 1 || { header.refcount } == 0 {
+            log::trace!("Invalid xattr header: {:?}", header);
+            return Err(Error::Corrupt("extended attribute block"));
+        }
+        let mut entries = Xattrs {
+            block,
+            position: size_of::<XattrHeader>(),
+        };
+        while let Some(entry) = entries.next_raw()? {
+            let value_end = usize::from(entry.value_offset) + entry.value_size as usize;
+            if { entry.value_inode } != 0 {
+                return Err(Error::UnsupportedFeature("extended attributes in inodes"));
+            }
+            if value_end > block.len() {
+                return Err(Error::Corrupt("extended attribute value"));
+            }
+        }
+        entries.position = size_of::<XattrHeader>();
+        Ok(entries)
+    }
+    /// The entry at position and advance to the next one, None at the end of the list
+    fn next_raw(&mut self) -> Result<Option<RawXattrEntry>, Error> {
+        let rest = &self.block[self.position..];
+        if rest.len() < 4 {
+            return Err(Error::Corrupt("extended attribute entry"));
+        }
+        if rest[..4] == [0; 4] {
+            return Ok(None);
+        }
+        if rest.len() < size_of::<RawXattrEntry>() {
+            return Err(Error::Corrupt("extended attribute entry"));
+        }
+        let entry = unsafe { access::read(rest.as_ptr() as *const RawXattrEntry) };
+        let len = (size_of::<RawXattrEntry>() + usize::from(entry.name_len) + 3) & !3;
+        if len > rest.len() {
+            return Err(Error::Corrupt("extended attribute entry"));
+        }
+        self.position += len;
+        Ok(Some(entry))
+    }
+}
+
+/// The attributes with an unknown prefix are skipped
+impl<'fs> Iterator for Xattrs<'fs> {
+    type Item = Xattr<'fs>;
+
+    fn next(&mut self) -> Option<Xattr<'fs>> {
+        loop {
+            let (_, entry, name) = self.next_entry()?;
+            let prefix = match prefix(entry.name_index) {
+                Some(prefix) => prefix,
+                None => continue,
+            };
+            let value_start = usize::from(entry.value_offset);
+            return Some(Xattr {
+                prefix: prefix.as_bstr(),
+                name: name.as_bstr(),
+                value: &self.block[value_start..value_start + entry.value_size as usize],
+            });
+        }
+    }
+}
+
+impl<'fs> Xattrs<'fs> {
+    /// The position, header and name of the next entry, whatever its prefix
+    fn next_entry(&mut self) -> Option<(usize, RawXattrEntry, &'fs [u8])> {
+        if self.block.is_empty() {
+            return None;
+        }
+        let start = self.position;
+        // The entries were checked by new
+        let entry = self.next_raw().ok()??;
+        let name_start = start + size_of::<RawXattrEntry>();
+        let name_end = name_start + usize::from(entry.name_len);
+        let block: &'fs [u8] = self.block;
+        Some((start, entry, &block[name_start..name_end]))
+    }
+}
+
+/// The name index and the rest of a full name, InvalidArgument if its prefix is not known
+pub(crate) fn split_name(name: &[u8]) -> Result<(u8, &[u8]), Error> {
+    // The names that are a whole prefix come first, they would match `system.`
+    let (index, rest) = [2, 3, 8, 1, 4, 6, 7]
+        .iter()
+        .find_map(|&index| {
+            let prefix = prefix(index)?;
+            name.strip_prefix(prefix).map(|rest| (index, rest))
+        })
+        .ok_or(Error::InvalidArgument)?;
+    match index {
+        2 | 3 | 8 if !rest.is_empty() => Err(Error::InvalidArgument),
+        1 | 4 | 6 | 7 if rest.is_empty() => Err(Error::InvalidArgument),
+        _ if rest.len() > 255 => Err(Error::NameTooLong),
+        _ => Ok((index, rest)),
+    }
+}
+
+/// The space used by an entry and its value, both are padded to 4 bytes
+fn entry_space(name_len: usize, value_len: usize) -> usize {
+    let pad = |len: usize| (len + 3) & !3;
+    pad(size_of::<RawXattrEntry>() + name_len) + pad(value_len)
+}
+
+/// The order of the entries in a block: by index, then by name length, then by name
+fn entry_key(index: u8, name: &[u8]) -> (u8, usize, &[u8]) {
+    (index, name.len(), name)
+}
+
+/// Where the list of the entries of a checked block ends, where its values start and the
+/// position of the entry called name if there is one
+fn layout(block: &[u8], index: u8, name: &[u8]) -> (usize, usize, Option<usize>) {
+    let mut entries = Xattrs {
+        block,
+        position: size_of::<XattrHeader>(),
+    };
+    let mut values = block.len();
+    let mut found = None;
+    while let Some((start, entry, entry_name)) = entries.next_entry() {
+        if { entry.value_size } != 0 {
+            values = values.min(usize::from(entry.value_offset));
+        }
+        if entry.name_index == index && entry_name == name {
+            found = Some(start);
+        }
+    }
+    (entries.position, values, found)
+}
+
+/// Whether a checked block has the attribute
+pub(crate) fn contains(block: &[u8], index: u8, name: &[u8]) -> bool {
+    layout(block, index, name).2.is_some()
+}
+
+/// The number of entries of a checked block, including the ones with an unknown prefix
+pub(crate) fn entry_count(block: &[u8]) -> usize {
+    let mut entries = Xattrs {
+        block,
+        position: size_of::<XattrHeader>(),
+    };
+    core::iter::from_fn(|| entries.next_entry()).count()
+}
+
+/// Check that the attribute can be set in block, or in a new block if there is none.
+/// XattrTooLarge if it does not fit
+pub(crate) fn check_space(
+    block: Option<&[u8]>,
+    block_size: usize,
+    index: u8,
+    name: &[u8],
+    value: &[u8],
+) -> Result<(), Error> {
+    let free = match block {
+        None => block_size - size_of::<XattrHeader>() - 4,
+        Some(block) => {
+            let (end, values, found) = layout(block, index, name);
+            let replaced = found.map_or(0, |position| {
+                let entry =
+                    unsafe { access::read(block[position..].as_ptr() as *const RawXattrEntry) };
+                entry_space(name.len(), entry.value_size as usize)
+            });
+            values.saturating_sub(end + 4) + replaced
+        }
+    };
+    if entry_space(name.len(), value.len()) > free {
+        return Err(Error::XattrTooLarge);
+    }
+    Ok(())
+}
+
+pub(crate) unsafe fn refcount(block: *const u8) -> u32 {
+    read_field!(block as *const XattrHeader, refcount)
+}
+pub(crate) unsafe fn set_refcount(block: *mut u8, refcount: u32) {
+    write_field!(block as *mut XattrHeader, refcount, refcount)
+}
+
+/// Write the header of a block without attributes
+pub(crate) unsafe fn init(block: *mut u8, block_size: usize) {
+    access::fill(block, 0, block_size);
+    access::write(
+        block as *mut XattrHeader,
+        XattrHeader {
+            magic: XATTR_MAGIC,
+            refcount: 1,
+            blocks: 1,
+            hash: 0,
+            checksum: 0,
+            reserved: [0; 3],
+        },
+    );
+}
+
+/// Remove the attribute from a checked block, returns whether it was there.
+///
+/// The values before it move up to keep them at the end of the block
+pub(crate) unsafe fn remove(block: *mut u8, block_size: usize, index: u8, name: &[u8]) -> bool {
+    let (end, values, position) = {
+        let slice = core::slice::from_raw_parts(block, block_size);
+        layout(slice, index, name)
+    };
+    let position = match position {
+        Some(position) => position,
+        None => return false,
+    };
+    let entry = access::read(block.add(position) as *const RawXattrEntry);
+    let value_size = entry.value_size as usize;
+    if value_size != 0 {
+        let offset = usize::from(entry.value_offset);
+        let padded = entry_space(0, value_size) - size_of::<RawXattrEntry>();
+        access::copy_within(
+            block.add(values),
+            block.add(values + padded),
+            offset - values,
+        );
+        access::fill(block.add(values), 0, padded);
+        let mut other = size_of::<XattrHeader>();
+        while other < end {
+            let other_entry = block.add(other) as *mut RawXattrEntry;
+            let other_offset = usize::from(read_field!(other_entry, value_offset));
+            if read_field!(other_entry, value_size) != 0 && other_offset < offset {
+                write_field!(other_entry, value_offset, (other_offset + padded) as u16);
+            }
+            other += entry_space(usize::from(read_field!(other_entry, name_len)), 0);
+        }
+    }
+    let len = entry_space(name.len(), 0);
+    // The entries after it and the terminating zeros
+    access::copy_within(
+        block.add(position + len),
+        block.add(position),
+        end + 4 - position - len,
+    );
+    access::fill(block.add(end + 4 - len), 0, len);
+    rehash(block, block_size);
+    true
+}
+
+/// Set the attribute in a checked block, its space must have been checked by check_space.
+///
+/// The entries stay sorted and the values are packed at the end of the block
+pub(crate) unsafe fn set(block: *mut u8, block_size: usize, index: u8, name: &[u8], value: &[u8]) {
+    remove(block, block_size, index, name);
+    let slice = core::slice::from_raw_parts(block, block_size);
+    let (end, values, _) = layout(slice, index, name);
+    let mut entries = Xattrs {
+        block: slice,
+        position: size_of::<XattrHeader>(),
+    };
+    let mut position = end;
+    while let Some((start, entry, entry_name)) = entries.next_entry() {
+        if entry_key(entry.name_index, entry_name) > entry_key(index, name) {
+            position = start;
+            break;
+        }
+    }
+
+    let len = entry_space(name.len(), 0);
+    access::copy_within(
+        block.add(position),
+        block.add(position + len),
+        end + 4 - position,
+    );
+    let value_offset = if value.is_empty() {
+        0
+    } else {
+        let offset = values - (entry_space(0, value.len()) - size_of::<RawXattrEntry>());
+        access::fill(block.add(offset), 0, values - offset);
+        access::copy_to_device(value.as_ptr(), block.add(offset), value.len());
+        offset
+    };
+    access::write(
+        block.add(position) as *mut RawXattrEntry,
+        RawXattrEntry {
+            name_len: name.len() as u8,
+            name_index: index,
+            value_offset: value_offset as u16,
+            value_inode: 0,
+            value_size: value.len() as u32,
+            hash: entry_hash(name, value),
+        },
+    );
+    let name_start = block.add(position + size_of::<RawXattrEntry>());
+    access::fill(name_start, 0, len - size_of::<RawXattrEntry>());
+    access::copy_to_device(name.as_ptr(), name_start, name.len());
+    rehash(block, block_size);
+}
+
+/// The hash of an entry, over its name and its value padded with zeros
+fn entry_hash(name: &[u8], value: &[u8]) -> u32 {
+    let hash = name.iter().fold(0u32, |hash, &byte| {
+        (hash << 5) ^ (hash >> 27) ^ u32::from(byte)
+    });
+    value.chunks(4).fold(hash, |hash, chunk| {
+        let mut word = [0; 4];
+        word[..chunk.len()].copy_from_slice(chunk);
+        (hash << 16) ^ (hash >> 16) ^ u32::from_le_bytes(word)
+    })
+}
+
+/// Update the hash of the block, computed from the hashes of its entries
+unsafe fn rehash(block: *mut u8, block_size: usize) {
+    let mut entries = Xattrs {
+        block: core::slice::from_raw_parts(block, block_size),
+        position: size_of::<XattrHeader>(),
+    };
+    let mut hash = 0u32;
+    while let Some((_, entry, _)) = entries.next_entry() {
+        if { entry.hash } == 0 {
+            hash = 0;
+            break;
+        }
+        hash = (hash << 16) ^ (hash >> 16) ^ entry.hash;
+    }
+    write_field!(block as *mut XattrHeader, hash, hash);
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use std::vec::Vec;
+
+    use super::Xattrs;
+    use crate::inode::InodeData;
+    use crate::tests::{check_group_counters, load_image};
+    use crate::{Error, Ext2Device, Inode};
+    use bstr::ByteSlice;
+
+    #[test]
+    fn read_xattrs() {
+        let mut image = load_image("test_fs_xattr");
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.open();
+        let labeled = fs
+            .get_inode(fs.lookup_path(b"/labeled.txt").unwrap())
+            .unwrap();
+        let names: Vec<_> = labeled
+            .xattrs()
+            .unwrap()
+            .map(|xattr| (xattr.prefix.to_str().unwrap(), xattr.name.to_str().unwrap()))
+            .collect();
+        assert_eq!(
+            names,
+            [
+                ("user.", "comment"),
+                ("trusted.", "overlay.opaque"),
+                ("security.", "selinux"),
+                ("security.", "capability"),
+            ]
+        );
+        assert_eq!(labeled.get_xattr(b"user.comment"), Ok(Some(&b"hello"[..])));
+        assert_eq!(
+            labeled.get_xattr(b"security.selinux"),
+            Ok(Some(&b"system_u:object_r:etc_t:s0\0"[..]))
+        );
+        assert_eq!(
+            labeled.get_xattr(b"security.capability").unwrap().unwrap()[..4],
+            [1, 0, 0, 2]
+        );
+        assert_eq!(labeled.get_xattr(b"user.selinux"), Ok(None));
+        assert_eq!(labeled.get_xattr(b"security."), Ok(None));
+
+        let plain = fs
+            .get_inode(fs.lookup_path(b"/plain.txt").unwrap())
+            .unwrap();
+        assert_eq!(plain.xattrs().unwrap().count(), 0);
+        assert_eq!(plain.get_xattr(b"user.comment"), Ok(None));
+    }
+
+    #[test]
+    fn write_xattrs() {
+        let mut image = load_image("test_fs_xattr");
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.open();
+        let get = |path: &[u8]| fs.get_inode(fs.lookup_path(path).unwrap()).unwrap();
+        let names = |inode: &Inode<'_, '_>| -> Vec<_> {
+            inode
+                .xattrs()
+                .unwrap()
+                .map(|xattr| {
+                    let mut name = xattr.prefix.to_vec();
+                    name.extend_from_slice(xattr.name);
+                    (
+                        std::string::String::from_utf8(name).unwrap(),
+                        xattr.value.to_vec(),
+                    )
+                })
+                .collect()
+        };
+
+        let plain = get(b"/plain.txt");
+        let sectors = plain.blocks_used();
+        plain.set_xattr(b"user.mime_type", b"text/plain").unwrap();
+        plain
+            .set_xattr(b"security.selinux", b"unconfined_u\0")
+            .unwrap();
+        plain.set_xattr(b"user.a", b"").unwrap();
+        assert_eq!(plain.blocks_used(), sectors + 2);
+        plain
+            .set_xattr(b"user.mime_type", b"application/octet-stream")
+            .unwrap();
+        assert_eq!(
+            names(&plain),
+            [
+                ("user.a".into(), b"".to_vec()),
+                (
+                    "user.mime_type".into(),
+                    b"application/octet-stream".to_vec()
+                ),
+                ("security.selinux".into(), b"unconfined_u\0".to_vec()),
+            ]
+        );
+        let block = unsafe { (*plain.get_data()).acl };
+        assert_eq!(fs.is_block_allocated(block), Ok(true));
+
+        assert_eq!(
+            plain.set_xattr(b"user.big", &[0; 1024]),
+            Err(Error::XattrTooLarge)
+        );
+        assert_eq!(
+            plain.set_xattr(b"other.a", b""),
+            Err(Error::InvalidArgument)
+        );
+        assert_eq!(plain.set_xattr(b"user.", b""), Err(Error::InvalidArgument));
+        assert_eq!(plain.remove_xattr(b"user.b"), Err(Error::NotFound));
+        assert_eq!(names(&plain).len(), 3);
+
+        let labeled = get(b"/labeled.txt");
+        labeled.remove_xattr(b"user.comment").unwrap();
+        labeled.remove_xattr(b"trusted.overlay.opaque").unwrap();
+        assert_eq!(
+            labeled.get_xattr(b"security.selinux"),
+            Ok(Some(&b"system_u:object_r:etc_t:s0\0"[..]))
+        );
+        assert_eq!(labeled.xattrs().unwrap().count(), 2);
+
+        for name in [&b"user.a"[..], b"security.selinux", b"user.mime_type"] {
+            plain.remove_xattr(name).unwrap();
+        }
+        assert_eq!(
+            unsafe {
+                {
+                    (*plain.get_data()).acl
+                }
+            },
+            0
+        );
+        assert_eq!(plain.blocks_used(), sectors);
+        assert_eq!(fs.is_block_allocated(block), Ok(false));
+        check_group_counters(&fs);
+    }
+
+    #[test]
+    fn shared_block() {
+        let mut image = load_image("test_fs_xattr");
+        let mut device = Ext2Device::from_slice(&mut image).unwrap();
+        let fs = device.open();
+        let get = |path: &[u8]| fs.get_inode(fs.lookup_path(path).unwrap()).unwrap();
+        let labeled = get(b"/labeled.txt");
+        let plain = get(b"/plain.txt");
+        let block = unsafe { (*labeled.get_data()).acl };
+        let share = || unsafe {
+            let data = plain.get_data() as *mut InodeData;
+            (*data).acl = block;
+            (*data).disk_sectors_used += 2;
+            super::set_refcount(fs.get_block(block), 2);
+        };
+
+        share();
+        plain.set_xattr(b"user.comment", b"changed").unwrap();
+        assert_ne!(unsafe { (*plain.get_data()).acl }, block);
+        assert_eq!(labeled.get_xattr(b"user.comment"), Ok(Some(&b"hello"[..])));
+        assert_eq!(plain.get_xattr(b"user.comment"), Ok(Some(&b"changed"[..])));
+        assert_eq!(plain.xattrs().unwrap().count(), 4);
+        assert_eq!(unsafe { super::refcount(fs.get_block(block)) }, 1);
+        plain.release_xattr_block();
+
+        share();
+        fs.unlink(b"/plain.txt").unwrap();
+        assert_eq!(unsafe { super::refcount(fs.get_block(block)) }, 1);
+        assert_eq!(fs.is_block_allocated(block), Ok(true));
+        fs.unlink(b"/labeled.txt").unwrap();
+        assert_eq!(fs.is_block_allocated(block), Ok(false));
+        check_group_counters(&fs);
+    }
+
+    #[test]
+    fn corrupt_xattrs() {
+        let mut block = [0u8; 64];
+        assert_eq!(
+            Xattrs::new(&block).err(),
+            Some(Error::Corrupt("extended attribute block"))
+        );
+        block[..12].copy_from_slice(&[0, 0, 2, 0xea, 1, 0, 0, 0, 1, 0, 0, 0]);
+        assert_eq!(Xattrs::new(&block).unwrap().count(), 0);
+
+        // An entry with a name of 7 bytes and a value past the block
+        block[32..40].copy_from_slice(&[7, 1, 60, 0, 0, 0, 0, 0]);
+        block[40] = 8;
+        assert_eq!(
+            Xattrs::new(&block).err(),
+            Some(Error::Corrupt("extended attribute value"))
+        );
+        block[40] = 4;
+        let xattr = Xattrs::new(&block).unwrap().next().unwrap();
+        assert_eq!((xattr.name.len(), xattr.value.len()), (7, 4));
+
+        // The list is not terminated
+        block[56..].fill(0xff);
+        assert_eq!(
+            Xattrs::new(&block).err(),
+            Some(Error::Corrupt("extended attribute entry"))
+        );
+    }
+}
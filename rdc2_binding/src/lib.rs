@@ -1,7 +1,10 @@
 #![no_std]
+use core::convert::TryFrom;
+
 use rdc2::{
-    inode::{Cursor, DirectoryEntries, DirectoryEntry, EntryKind, Inode, InodeRef},
-    Ext2Device, FileSystem,
+    file::SeekFrom,
+    inode::{Cursor, DirectoryEntries, EntryKind, Inode, InodeRef, Permission},
+    CreateError, Ext2Device, File, FileSystem, Statistics,
 };
 
 trait OptionExt<T> {
@@ -12,7 +15,7 @@ impl<T> OptionExt<T> for Option<T> {
         match self {
             None => -1,
             Some(val) => {
-                unsafe { *location = val };
+                unsafe { location.write(val) };
                 0
             }
         }
@@ -21,16 +24,94 @@ impl<T> OptionExt<T> for Option<T> {
 
 pub const ROOT_INODE: u32 = 2;
 
+pub const FILE_SEEK_START: i32 = 0;
+pub const FILE_SEEK_CURRENT: i32 = 1;
+pub const FILE_SEEK_END: i32 = 2;
+
+pub const CREATE_NOT_A_DIRECTORY: i64 = -1;
+pub const CREATE_NO_FREE_INODES: i64 = -2;
+pub const CREATE_NO_FREE_BLOCKS: i64 = -3;
+pub const CREATE_NAME_TOO_LONG: i64 = -4;
+pub const CREATE_ALREADY_EXISTS: i64 = -5;
+pub const CREATE_DIRECTORY_FULL: i64 = -6;
+pub const CREATE_UNKNOWN_KIND: i64 = -7;
+pub const CREATE_READ_ONLY: i64 = -8;
+pub const CREATE_CORRUPT: i64 = -9;
+pub const CREATE_UNSUPPORTED_KIND: i64 = -10;
+
+/// Write the FileSystem of region in fs and returns 0, or returns -1 if region does not hold a
+/// supported ext2 filesystem
+///
+/// # Safety
+///
+/// region must point to an ext2 filesystem that stays valid for as long as the FileSystem is used
 #[no_mangle]
-pub unsafe extern "C" fn open<'device>(region: *mut u8) -> FileSystem<'device> {
-    core::mem::transmute(Ext2Device::from_ptr(region).open())
+pub unsafe extern "C" fn open<'device>(region: *mut u8, fs: *mut FileSystem<'device>) -> i64 {
+    Ext2Device::from_ptr(region)
+        .try_open()
+        .ok()
+        .map(|opened| core::mem::transmute::<FileSystem<'_>, FileSystem<'device>>(opened))
+        .unwrap_write(fs)
 }
+/// Like open, but nothing is written to region: the modifications fail like on a read-only
+/// filesystem
+///
+/// # Safety
+///
+/// region must point to an ext2 filesystem that stays valid for as long as the FileSystem is used
+#[no_mangle]
+pub unsafe extern "C" fn open_read_only<'device>(
+    region: *mut u8,
+    fs: *mut FileSystem<'device>,
+) -> i64 {
+    Ext2Device::from_ptr(region)
+        .try_open_read_only()
+        .ok()
+        .map(|opened| core::mem::transmute::<FileSystem<'_>, FileSystem<'device>>(opened))
+        .unwrap_write(fs)
+}
+/// Write the inode referenced by inode in inode_ptr and returns 0, or returns -1 if there is no
+/// such inode. inode may be any value, it is checked by FileSystem::get_inode
 #[no_mangle]
 pub extern "C" fn fs_get_inode<'device, 'input>(
     fs: &'input FileSystem<'device>,
     inode: InodeRef,
-) -> Inode<'input, 'device> {
-    fs.get_inode(inode)
+    inode_ptr: *mut Inode<'input, 'device>,
+) -> i64 {
+    fs.get_inode(inode).ok().unwrap_write(inode_ptr)
+}
+
+/// Fill statistics with the usage of the filesystem, like statfs. With recount the free blocks
+/// and inodes are counted in the bitmaps instead of read from the superblock
+#[no_mangle]
+pub extern "C" fn fs_statfs(fs: &FileSystem<'_>, recount: bool, statistics: &mut Statistics) {
+    *statistics = fs.statistics(recount)
+}
+
+/// Mark the filesystem as in use until fs_unmount, does nothing if it is read-only
+#[no_mangle]
+pub extern "C" fn fs_mount_writable(fs: &FileSystem<'_>) {
+    fs.mount_writable()
+}
+
+/// Mark the filesystem as clean again and sync it, returns 0 or -1 on failure. It must be called
+/// before the filesystem is discarded
+#[no_mangle]
+pub extern "C" fn fs_unmount(fs: &FileSystem<'_>) -> i64 {
+    match fs.unmount() {
+        Ok(()) => 0,
+        Err(_) => -1,
+    }
+}
+
+/// Make the metadata of the filesystem consistent and update its backups, returns 0 or -1 on
+/// failure. Open files must be synced with file_sync first
+#[no_mangle]
+pub extern "C" fn fs_sync(fs: &FileSystem<'_>) -> i64 {
+    match fs.sync() {
+        Ok(()) => 0,
+        Err(_) => -1,
+    }
 }
 
 /// Write the Cursor in cursor_ptr if a Cursor can be created from this inode, and returns 0.
@@ -40,7 +121,7 @@ pub extern "C" fn cursor<'inode, 'fs, 'device>(
     inode: &'inode Inode<'fs, 'device>,
     cursor_ptr: *mut Cursor<'inode, 'fs, 'device>,
 ) -> i64 {
-    inode.cursor().unwrap_write(cursor_ptr)
+    inode.cursor().ok().unwrap_write(cursor_ptr)
 }
 
 /// See cursor, puts that cursor at the end of the file
@@ -49,13 +130,27 @@ pub extern "C" fn cursor_at_end<'inode, 'fs, 'device>(
     inode: &'inode Inode<'fs, 'device>,
     cursor_ptr: *mut Cursor<'inode, 'fs, 'device>,
 ) -> i64 {
-    inode.end().unwrap_write(cursor_ptr)
+    inode.end().ok().unwrap_write(cursor_ptr)
 }
 #[no_mangle]
 pub extern "C" fn inode_size<'inode, 'fs, 'device>(inode: &'inode Inode<'fs, 'device>) -> u32 {
     inode.size()
 }
 
+/// Write in block the block of the filesystem holding the block logical_block of the file and
+/// returns 0, like FIBMAP 0 is written for a hole. Returns -1 past the end of the file or if the
+/// pointer is out of the filesystem
+#[no_mangle]
+pub extern "C" fn inode_bmap(inode: &Inode<'_, '_>, logical_block: u32, block: &mut u32) -> i64 {
+    match inode.bmap(logical_block) {
+        Ok(found) => {
+            *block = found.unwrap_or(0);
+            0
+        }
+        Err(_) => -1,
+    }
+}
+
 /// See cursor, creates an iterator on the entries of this directory
 #[no_mangle]
 pub extern "C" fn directory_entries<'inode, 'fs, 'device>(
@@ -65,6 +160,9 @@ pub extern "C" fn directory_entries<'inode, 'fs, 'device>(
     inode.get_dir_entries().unwrap_write(entries)
 }
 
+/// # Safety
+///
+/// ptr must be valid for writes of len bytes
 #[no_mangle]
 pub unsafe extern "C" fn read<'inode, 'fs, 'device>(
     cursor: &mut Cursor<'inode, 'fs, 'device>,
@@ -73,13 +171,64 @@ pub unsafe extern "C" fn read<'inode, 'fs, 'device>(
 ) -> usize {
     cursor.read(core::slice::from_raw_parts_mut(ptr, len))
 }
+/// Returns 0, or -1 if the data could not be written entirely
+///
+/// # Safety
+///
+/// ptr must be valid for reads of len bytes
 #[no_mangle]
 pub unsafe extern "C" fn write<'inode, 'fs, 'device>(
     cursor: &mut Cursor<'inode, 'fs, 'device>,
     ptr: *const u8,
     len: usize,
-) {
-    cursor.write(core::slice::from_raw_parts(ptr, len))
+) -> i64 {
+    match cursor.write(core::slice::from_raw_parts(ptr, len)) {
+        Ok(()) => 0,
+        Err(_) => -1,
+    }
+}
+
+/// Create an inode called name in the directory dir, write its reference in new_inode and
+/// returns 0. On failure returns one of the CREATE_* constants.
+/// perms are the permission bits of the mode, the file type is given by kind
+///
+/// # Safety
+///
+/// name must be valid for reads of name_len bytes
+#[no_mangle]
+pub unsafe extern "C" fn create_inode_in_dir(
+    dir: &Inode<'_, '_>,
+    kind: EntryKind,
+    perms: u16,
+    user_id: u16,
+    group_id: u16,
+    name: *const u8,
+    name_len: usize,
+    new_inode: *mut InodeRef,
+) -> i64 {
+    let name = core::slice::from_raw_parts(name, name_len);
+    match dir.create_inode_in_dir(
+        kind,
+        Permission::from_bits_truncate(perms),
+        user_id,
+        group_id,
+        name,
+    ) {
+        Ok(inode) => {
+            new_inode.write(inode);
+            0
+        }
+        Err(CreateError::NotADirectory) => CREATE_NOT_A_DIRECTORY,
+        Err(CreateError::NoFreeInodes) => CREATE_NO_FREE_INODES,
+        Err(CreateError::NoFreeBlocks) => CREATE_NO_FREE_BLOCKS,
+        Err(CreateError::NameTooLong) => CREATE_NAME_TOO_LONG,
+        Err(CreateError::AlreadyExists) => CREATE_ALREADY_EXISTS,
+        Err(CreateError::DirectoryFull) => CREATE_DIRECTORY_FULL,
+        Err(CreateError::UnknownKind) => CREATE_UNKNOWN_KIND,
+        Err(CreateError::ReadOnly) => CREATE_READ_ONLY,
+        Err(CreateError::Corrupt) => CREATE_CORRUPT,
+        Err(CreateError::UnsupportedKind) => CREATE_UNSUPPORTED_KIND,
+    }
 }
 
 #[repr(C)]
@@ -107,3 +256,62 @@ pub extern "C" fn read_next_entry<'inode, 'fs, 'device>(
         })
         .unwrap_write(entry)
 }
+
+/// See cursor, opens the regular file of this inode
+#[no_mangle]
+pub extern "C" fn file_open<'fs, 'device>(
+    inode: &Inode<'fs, 'device>,
+    file: *mut File<'fs, 'device>,
+) -> i64 {
+    inode.as_file().unwrap_write(file)
+}
+
+/// # Safety
+///
+/// ptr must be valid for writes of len bytes
+#[no_mangle]
+pub unsafe extern "C" fn file_read(file: &mut File<'_, '_>, ptr: *mut u8, len: usize) -> usize {
+    file.read(core::slice::from_raw_parts_mut(ptr, len))
+}
+
+/// Returns 0, or -1 if the data could not be written entirely
+///
+/// # Safety
+///
+/// ptr must be valid for reads of len bytes
+#[no_mangle]
+pub unsafe extern "C" fn file_write(file: &mut File<'_, '_>, ptr: *const u8, len: usize) -> i64 {
+    match file.write(core::slice::from_raw_parts(ptr, len)) {
+        Ok(()) => 0,
+        Err(_) => -1,
+    }
+}
+
+/// Move the position of the file like lseek, whence is one of the FILE_SEEK_* constants.
+/// Returns the new position, or -1 if the position would be invalid
+#[no_mangle]
+pub extern "C" fn file_seek(file: &mut File<'_, '_>, offset: i64, whence: i32) -> i64 {
+    let pos = match whence {
+        FILE_SEEK_START => match u32::try_from(offset) {
+            Ok(offset) => SeekFrom::Start(offset),
+            Err(_) => return -1,
+        },
+        FILE_SEEK_CURRENT => SeekFrom::Current(offset),
+        FILE_SEEK_END => SeekFrom::End(offset),
+        _ => return -1,
+    };
+    file.seek(pos).map(i64::from).unwrap_or(-1)
+}
+
+/// Write the metadata of the file and release its preallocated blocks, it must be called
+/// before the file is discarded
+#[no_mangle]
+pub extern "C" fn file_sync(file: &mut File<'_, '_>) {
+    file.sync()
+}
+
+/// Release the blocks reserved ahead of the writes, file_sync does it too
+#[no_mangle]
+pub extern "C" fn file_discard_preallocation(file: &mut File<'_, '_>) {
+    file.discard_preallocation()
+}